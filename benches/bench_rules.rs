@@ -0,0 +1,171 @@
+#![feature(test)]
+
+extern crate foxbox_taxonomy;
+extern crate foxbox_thinkerbell;
+extern crate test;
+extern crate transformable_channels;
+
+use foxbox_thinkerbell::ast::*;
+use foxbox_thinkerbell::fake_env::*;
+use foxbox_thinkerbell::network::Dispatcher;
+use foxbox_thinkerbell::run::*;
+
+use foxbox_taxonomy::api::User;
+use foxbox_taxonomy::selector::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::{ OnOff, Range, Value };
+
+use std::collections::{ HashMap, HashSet };
+use std::marker::PhantomData;
+use std::thread;
+
+use test::Bencher;
+
+use transformable_channels::mpsc::*;
+
+/// A house with `count` lights, each controlled by its own switch, and
+/// `scripts` scripts sharing one rule per light.
+fn setup(count: usize, scripts: usize) -> (FakeEnv, Vec<Execution<FakeEnv>>, Vec<Id<Getter>>, Receiver<()>, Receiver<Id<Setter>>) {
+    let (tx_env, rx_env) = channel();
+    let (tx_done, rx_done) = channel();
+    let (tx_send, rx_send) = channel();
+    thread::spawn(move || {
+        for msg in rx_env {
+            match msg {
+                FakeEnvEvent::Done => tx_done.send(()).unwrap(),
+                FakeEnvEvent::Send { id, .. } => tx_send.send(id).unwrap(),
+                _ => {}
+            }
+        }
+    });
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let adapter_id = Id::<AdapterId>::new("Adapter");
+    let service_id = Id::<ServiceId>::new("Service");
+    let getters : Vec<_> = (0..count).map(|i| Id::<Getter>::new(&format!("Switch {}", i))).collect();
+    let setters : Vec<_> = (0..count).map(|i| Id::<Setter>::new(&format!("Light {}", i))).collect();
+
+    env.execute(Instruction::AddAdapters(vec![adapter_id.to_string()]));
+    rx_done.recv().unwrap();
+    env.execute(Instruction::AddServices(vec![
+        Service {
+            id: service_id.clone(),
+            adapter: adapter_id.clone(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            tags: HashSet::new(),
+            properties: HashMap::new(),
+        }
+    ]));
+    rx_done.recv().unwrap();
+    env.execute(Instruction::AddGetters(getters.iter().map(|id| Channel {
+        id: id.clone(),
+        adapter: adapter_id.clone(),
+        service: service_id.clone(),
+        tags: HashSet::new(),
+        last_seen: None,
+        mechanism: Getter {
+            updated: None,
+            kind: ChannelKind::LightOn,
+        }
+    }).collect()));
+    rx_done.recv().unwrap();
+    env.execute(Instruction::AddSetters(setters.iter().map(|id| Channel {
+        id: id.clone(),
+        adapter: adapter_id.clone(),
+        service: service_id.clone(),
+        tags: HashSet::new(),
+        last_seen: None,
+        mechanism: Setter {
+            updated: None,
+            kind: ChannelKind::LightOn,
+        }
+    }).collect()));
+    rx_done.recv().unwrap();
+
+    let rule = |source: Vec<GetterSelector>, destination: Vec<SetterSelector>| Rule {
+        conditions: vec![
+            Match {
+                source: source,
+                kind: ChannelKind::LightOn,
+                range: Range::Eq(Value::OnOff(OnOff::On)),
+                duration: None,
+                phantom: PhantomData
+            }
+        ],
+        variable_conditions: vec![],
+        active_when: vec![],
+        execute: vec![
+            Statement::Send(SendValues {
+                destination: destination,
+                value: Value::OnOff(OnOff::On),
+                kind: ChannelKind::LightOn,
+                on_error: None,
+                phantom: PhantomData,
+            })
+        ],
+        phantom: PhantomData
+    };
+
+    // The scripts share an executor and a dispatcher, as in a `ScriptManager`.
+    let executor = Executor::default();
+    let dispatcher = Dispatcher::new(env.clone());
+    let per_script = (count + scripts - 1) / scripts;
+    let execs = getters.chunks(per_script).zip(setters.chunks(per_script)).zip(0..).map(|((getters, setters), i)| {
+        let rules : Vec<_> = getters.iter().zip(setters.iter()).map(|(getter, setter)| {
+            rule(vec![GetterSelector::new().with_id(getter.clone())], vec![SetterSelector::new().with_id(setter.clone())])
+        }).collect();
+        let script = Script {
+            name: format!("Benchmark {}", i),
+            variables: vec![],
+            channels: vec![],
+            derived: vec![],
+            active_when: vec![],
+            rules: rules,
+            phantom: PhantomData,
+        };
+        let (tx_run, _) = channel();
+        let mut exec = Execution::<FakeEnv>::with_dispatcher(executor.clone(), dispatcher.clone());
+        exec.start(env.clone(), script, User::None, tx_run).unwrap();
+        exec
+    }).collect();
+    (env, execs, getters, rx_done, rx_send)
+}
+
+/// Switch a light on and off, with `count` rules spread among `scripts` scripts.
+fn bench_toggle(bencher: &mut Bencher, count: usize, scripts: usize) {
+    let (env, _execs, getters, rx_done, rx_send) = setup(count, scripts);
+    let getter = getters[count / 2].clone();
+    bencher.iter(|| {
+        env.execute(Instruction::InjectGetterValues(vec![(getter.clone(), Ok(Value::OnOff(OnOff::On)))]));
+        rx_done.recv().unwrap();
+        rx_send.recv().unwrap();
+        env.execute(Instruction::InjectGetterValues(vec![(getter.clone(), Ok(Value::OnOff(OnOff::Off)))]));
+        rx_done.recv().unwrap();
+    });
+}
+
+#[bench]
+fn bench_toggle_100_rules(bencher: &mut Bencher) {
+    bench_toggle(bencher, 100, 1);
+}
+
+#[bench]
+fn bench_toggle_1000_rules(bencher: &mut Bencher) {
+    bench_toggle(bencher, 1000, 1);
+}
+
+#[bench]
+fn bench_toggle_5000_rules(bencher: &mut Bencher) {
+    bench_toggle(bencher, 5000, 1);
+}
+
+#[bench]
+fn bench_toggle_100_scripts_1000_rules(bencher: &mut Bencher) {
+    bench_toggle(bencher, 1000, 100);
+}
+
+#[bench]
+fn bench_toggle_1000_scripts_5000_rules(bencher: &mut Bencher) {
+    bench_toggle(bencher, 5000, 1000);
+}
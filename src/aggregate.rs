@@ -0,0 +1,143 @@
+//! Incremental computation of derived getters.
+//!
+//! An `Aggregator` receives the values of the sources of a derived
+//! getter one at a time, as they are reported by watches, and keeps
+//! enough state to update the derived value without walking through
+//! all the sources at each update.
+
+use ast::Aggregate;
+
+use foxbox_taxonomy::services::Getter;
+use foxbox_taxonomy::util::Id;
+use foxbox_taxonomy::values::{ OnOff, Range, Temperature, Value };
+
+use std::collections::HashMap;
+
+/// The state of a derived getter.
+pub struct Aggregator {
+    aggregate: Aggregate,
+
+    /// The latest value of each source.
+    values: HashMap<Id<Getter>, Value>,
+
+    /// For `Average`, the sum of the values of all sources, in Celsius.
+    sum: f64,
+
+    /// For `Any` and `All`, the number of sources whose value is in the range.
+    in_range: usize,
+
+    /// For `Min` and `Max`, the current smallest/largest value.
+    extremum: Option<Value>,
+}
+
+impl Aggregator {
+    pub fn new(aggregate: Aggregate) -> Self {
+        Aggregator {
+            aggregate: aggregate,
+            values: HashMap::new(),
+            sum: 0.,
+            in_range: 0,
+            extremum: None,
+        }
+    }
+
+    /// The current value of the derived getter, or `None` if no source has
+    /// provided a value yet.
+    pub fn value(&self) -> Option<Value> {
+        if self.values.is_empty() {
+            return None;
+        }
+        match self.aggregate {
+            Aggregate::Average =>
+                Some(Value::Temperature(Temperature::C(self.sum / self.values.len() as f64))),
+            Aggregate::Min | Aggregate::Max =>
+                self.extremum.clone(),
+            Aggregate::Any(_) =>
+                Some(Value::OnOff(if self.in_range > 0 { OnOff::On } else { OnOff::Off })),
+            Aggregate::All(_) =>
+                Some(Value::OnOff(if self.in_range == self.values.len() { OnOff::On } else { OnOff::Off })),
+        }
+    }
+
+    /// Record the latest value of a source, or `None` if the source has
+    /// disappeared.
+    ///
+    /// Return the new value of the derived getter if it has changed.
+    pub fn update(&mut self, id: Id<Getter>, value: Option<Value>) -> Option<Value> {
+        let before = self.value();
+        let old = match value {
+            Some(ref value) => self.values.insert(id, value.clone()),
+            None => self.values.remove(&id),
+        };
+        match self.aggregate {
+            Aggregate::Average => {
+                if let Some(ref old) = old {
+                    self.sum -= celsius(old);
+                }
+                if let Some(ref value) = value {
+                    self.sum += celsius(value);
+                }
+            }
+            Aggregate::Any(ref range) | Aggregate::All(ref range) => {
+                if let Some(ref old) = old {
+                    if range.contains(old) {
+                        self.in_range -= 1;
+                    }
+                }
+                if let Some(ref value) = value {
+                    if range.contains(value) {
+                        self.in_range += 1;
+                    }
+                }
+            }
+            Aggregate::Min | Aggregate::Max => {
+                let is_max = self.aggregate == Aggregate::Max;
+                let improves = match (&value, &self.extremum) {
+                    (&Some(ref value), &Some(ref extremum)) => is_better(value, extremum, is_max),
+                    (&Some(_), &None) => true,
+                    (&None, _) => false
+                };
+                if improves {
+                    self.extremum = value.clone();
+                } else if old.is_some() && old == self.extremum {
+                    // The previous extremum has been replaced or removed, we need to
+                    // find the new one.
+                    let mut extremum : Option<Value> = None;
+                    for candidate in self.values.values() {
+                        let improves = match extremum {
+                            None => true,
+                            Some(ref extremum) => is_better(candidate, extremum, is_max)
+                        };
+                        if improves {
+                            extremum = Some(candidate.clone());
+                        }
+                    }
+                    self.extremum = extremum;
+                }
+            }
+        }
+        let after = self.value();
+        if before == after {
+            None
+        } else {
+            after
+        }
+    }
+}
+
+fn celsius(value: &Value) -> f64 {
+    match *value {
+        Value::Temperature(ref temperature) => temperature.as_c(),
+        _ => 0. // Checked during compilation.
+    }
+}
+
+/// Determine whether `value` is strictly larger (if `is_max`) or strictly smaller (otherwise)
+/// than `extremum`.
+fn is_better(value: &Value, extremum: &Value, is_max: bool) -> bool {
+    if is_max {
+        !Range::Leq(extremum.clone()).contains(value)
+    } else {
+        !Range::Geq(extremum.clone()).contains(value)
+    }
+}
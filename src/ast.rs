@@ -8,33 +8,124 @@ use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
 use foxbox_taxonomy::values::*;
 
+use schema::{ count, many, reference, string, strings, Field, Fields };
+
 use std::marker::PhantomData;
 
 /// A thinkerbell scrip"t.
+///
+/// # JSON
+///
+/// A script is represented as an object with the following fields:
+///
+/// - name (string);
+/// - variables (array of VariableDeclaration, optional): the variables
+///   that the rules of this script may read and write;
+/// - channels (array of VirtualChannel, optional): the virtual channels
+///   that this script uses to communicate with other scripts;
+/// - derived (array of DerivedGetter, optional): the getters computed by
+///   this script;
+/// - active_when (array of ActiveWhen, optional): restrictions that
+///   apply to all the rules of this script;
+/// - rules (array of Rule).
 #[derive(Debug)]
 pub struct Script<Ctx> where Ctx: Context {
     pub name: String,
 
+    /// Variables local to this script. Their values survive between
+    /// the execution of rules and, if the script is executed by a
+    /// `ScriptManager`, between restarts.
+    pub variables: Vec<VariableDeclaration>,
+
+    /// Virtual channels used by this script. They are created when the
+    /// script starts, unless another script has already created them.
+    pub channels: Vec<VirtualChannel>,
+
+    /// Getters computed by this script from other getters. They are
+    /// published when the script starts and updated for as long as the
+    /// script is running.
+    pub derived: Vec<DerivedGetter<Ctx>>,
+
+    /// Restrictions on when the rules of this script may fire, in
+    /// addition to the restrictions of each rule.
+    pub active_when: Vec<ActiveWhen>,
+
     /// A set of rules, stating what must be done in which circumstance.
     pub rules: Vec<Rule<Ctx>>,
 
     pub phantom: PhantomData<Ctx>,
 }
 
+/// Scripts are compared structurally, regardless of their context.
+impl<Ctx> PartialEq for Script<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name &&
+            self.variables == other.variables &&
+            self.channels == other.channels &&
+            self.derived == other.derived &&
+            self.active_when == other.active_when &&
+            self.rules == other.rules
+    }
+}
+
 impl Parser<Script<UncheckedCtx>> for Script<UncheckedCtx> {
     fn description() -> String {
         "Script".to_owned()
     }
     fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
         let name =  try!(path.push("name", |path| String::take(path, source, "name")));
+        let variables = match path.push("variables",
+            |path| VariableDeclaration::take_vec(path, source, "variables"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let channels = match path.push("channels",
+            |path| VirtualChannel::take_vec(path, source, "channels"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let derived = match path.push("derived",
+            |path| DerivedGetter::take_vec(path, source, "derived"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let active_when = match path.push("active_when",
+            |path| ActiveWhen::take_vec(path, source, "active_when"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
         let rules = try!(path.push("rules", |path| Rule::take_vec(path, source, "rules")));
         Ok(Script {
             name: name,
+            variables: variables,
+            channels: channels,
+            derived: derived,
+            active_when: active_when,
             rules: rules,
             phantom: PhantomData
         })
     }
 }
+impl Fields for Script<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("name", string),
+            Field::optional("variables", many::<VariableDeclaration>),
+            Field::optional("channels", many::<VirtualChannel>),
+            Field::optional("derived", many::<DerivedGetter<UncheckedCtx>>),
+            Field::optional("active_when", many::<ActiveWhen>),
+            Field::required("rules", many::<Rule<UncheckedCtx>>),
+        ]
+    }
+}
 
 /// A single rule, i.e. "when some condition becomes true, do
 /// something".
@@ -45,6 +136,10 @@ impl Parser<Script<UncheckedCtx>> for Script<UncheckedCtx> {
 ///
 /// - conditions (array of Match): the conditions in which to execute
 ///   the code – *all* conditions must be met;
+/// - variable_conditions (array of VariableMatch, optional): additional
+///   conditions on the variables of the script – *all* must be met;
+/// - active_when (array of ActiveWhen, optional): the rule may only fire
+///   while *all* of these hold;
 /// - execute (array of Statement): the code to execute once all conditions
 ///   are met.
 ///
@@ -80,11 +175,29 @@ pub struct Rule<Ctx> where Ctx: Context {
     /// `conditions` was false and becomes true, we execute `execute`.
     pub conditions: Vec<Match<Ctx>>,
 
+    /// Conditions on the variables of the script. The rule is only
+    /// met if all of these conditions also hold.
+    pub variable_conditions: Vec<VariableMatch>,
+
+    /// Restrictions on when the rule may fire. While they do not hold,
+    /// the rule keeps tracking its conditions but does not fire. If the
+    /// conditions are met once the restrictions hold again, the rule fires.
+    pub active_when: Vec<ActiveWhen>,
+
     /// Stuff to do once `condition` is met.
     pub execute: Vec<Statement<Ctx>>,
 
     pub phantom: PhantomData<Ctx>,
 }
+
+impl<Ctx> PartialEq for Rule<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.conditions == other.conditions &&
+            self.variable_conditions == other.variable_conditions &&
+            self.active_when == other.active_when &&
+            self.execute == other.execute
+    }
+}
 impl Parser<Rule<UncheckedCtx>> for Rule<UncheckedCtx> {
     fn description() -> String {
         "Rule".to_owned()
@@ -94,16 +207,42 @@ impl Parser<Rule<UncheckedCtx>> for Rule<UncheckedCtx> {
         let conditions = try!(path.push("conditions",
             |path| Match::take_vec(path, source, "conditions"))
         );
+        let variable_conditions = match path.push("variable_conditions",
+            |path| VariableMatch::take_vec(path, source, "variable_conditions"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let active_when = match path.push("active_when",
+            |path| ActiveWhen::take_vec(path, source, "active_when"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
         let execute = try!(path.push("execute",
             |path| Statement::take_vec(path, source, "execute"))
         );
         Ok(Rule {
             conditions: conditions,
+            variable_conditions: variable_conditions,
+            active_when: active_when,
             execute: execute,
             phantom: PhantomData,
         })
     }
 }
+impl Fields for Rule<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("conditions", many::<Match<UncheckedCtx>>),
+            Field::optional("variable_conditions", many::<VariableMatch>),
+            Field::optional("active_when", many::<ActiveWhen>),
+            Field::required("execute", many::<Statement<UncheckedCtx>>),
+        ]
+    }
+}
 
 /// An individual match.
 ///
@@ -172,6 +311,15 @@ pub struct Match<Ctx> where Ctx: Context {
 
     pub phantom: PhantomData<Ctx>,
 }
+
+impl<Ctx> PartialEq for Match<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source &&
+            self.kind == other.kind &&
+            self.range == other.range &&
+            self.duration == other.duration
+    }
+}
 impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
     fn description() -> String {
         "Match".to_owned()
@@ -203,15 +351,84 @@ impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
         })
     }
 }
+impl Fields for Match<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("source", many::<GetterSelector>),
+            Field::required("kind", reference::<ChannelKind>),
+            Field::required("range", reference::<Range>),
+            Field::optional("duration", reference::<Duration>),
+        ]
+    }
+}
+
+/// Stuff to actually do.
+///
+/// # JSON
+///
+/// A statement is represented as an object. If the object has a field
+/// `variable`, it is a `SetVariable`, otherwise it is a `SendValues`.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "variable": "door openings",
+///   "operation": {"Increment": 1}
+/// }"#;
+///
+/// match Statement::<UncheckedCtx>::from_str(&source).unwrap() {
+///   Statement::SetVariable(set) => assert_eq!(set.operation, VariableOp::Increment(1)),
+///   other => panic!("Unexpected statement {:?}", other)
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub enum Statement<Ctx> where Ctx: Context {
+    /// Send a value to a set of devices.
+    Send(SendValues<Ctx>),
+
+    /// Change the value of a variable of the script.
+    SetVariable(SetVariable),
+}
+
+impl<Ctx> PartialEq for Statement<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (&Statement::Send(ref a), &Statement::Send(ref b)) => a == b,
+            (&Statement::SetVariable(ref a), &Statement::SetVariable(ref b)) => a == b,
+            _ => false
+        }
+    }
+}
+impl Parser<Statement<UncheckedCtx>> for Statement<UncheckedCtx> {
+    fn description() -> String {
+        "Statement".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if source.find("variable").is_some() {
+            SetVariable::parse(path, source).map(Statement::SetVariable)
+        } else {
+            SendValues::parse(path, source).map(Statement::Send)
+        }
+    }
+}
 
-/// Stuff to actually do. In practice, this means placing calls to devices.
+/// Placing calls to devices.
 ///
 /// # JSON
 ///
-/// A statement is represented as an object with the following fields:
+/// A send is represented as an object with the following fields:
 /// - destination (array of SetterSelector);
 /// - value (Value);
 /// - kind (ChannelKind);
+/// - on_error (ErrorPolicy, optional).
 ///
 /// ```
 /// extern crate foxbox_thinkerbell;
@@ -229,13 +446,13 @@ impl Parser<Match<UncheckedCtx>> for Match<UncheckedCtx> {
 ///   "kind": "LightOn"
 /// }"#;
 ///
-/// let statement = Statement::<UncheckedCtx>::from_str(&source).unwrap();
-/// assert_eq!(statement.value, Value::OnOff(OnOff::Off));
-/// assert_eq!(statement.kind, ChannelKind::LightOn);
+/// let send = SendValues::<UncheckedCtx>::from_str(&source).unwrap();
+/// assert_eq!(send.value, Value::OnOff(OnOff::Off));
+/// assert_eq!(send.kind, ChannelKind::LightOn);
 /// # }
 /// ```
 #[derive(Debug)]
-pub struct Statement<Ctx> where Ctx: Context {
+pub struct SendValues<Ctx> where Ctx: Context {
     /// The set of setters to which to send a command. Note that the
     /// set of setters may change (e.g. when devices are
     /// added/removed) without rebooting the script.
@@ -252,11 +469,25 @@ pub struct Statement<Ctx> where Ctx: Context {
     /// offer `kind`.
     pub kind: ChannelKind,
 
+    /// What to do if some of the setters of `destination` fail to receive
+    /// the value. If `None`, the error is reported and the statement is
+    /// considered done.
+    pub on_error: Option<ErrorPolicy<Ctx>>,
+
     pub phantom: PhantomData<Ctx>,
 }
-impl Parser<Statement<UncheckedCtx>> for Statement<UncheckedCtx> {
+
+impl<Ctx> PartialEq for SendValues<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.destination == other.destination &&
+            self.value == other.value &&
+            self.kind == other.kind &&
+            self.on_error == other.on_error
+    }
+}
+impl Parser<SendValues<UncheckedCtx>> for SendValues<UncheckedCtx> {
     fn description() -> String {
-        "Parser".to_owned()
+        "SendValues".to_owned()
     }
 
     fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
@@ -269,15 +500,832 @@ impl Parser<Statement<UncheckedCtx>> for Statement<UncheckedCtx> {
         let value = try!(path.push("value",
             |path| Value::take(path, source, "value"))
         );
-        Ok(Statement {
+        let on_error = match path.push("on_error",
+            |path| ErrorPolicy::take(path, source, "on_error"))
+        {
+            Err(ParseError::MissingField {..}) => None,
+            Err(err) => return Err(err),
+            Ok(ok) => Some(ok)
+        };
+        Ok(SendValues {
             destination: destination,
             value: value,
             kind: kind,
+            on_error: on_error,
+            phantom: PhantomData,
+        })
+    }
+}
+impl Fields for SendValues<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("destination", many::<SetterSelector>),
+            Field::required("value", reference::<Value>),
+            Field::required("kind", reference::<ChannelKind>),
+            Field::optional("on_error", reference::<ErrorPolicy<UncheckedCtx>>),
+        ]
+    }
+}
+
+/// Retrying a `SendValues` that has failed, with exponential backoff.
+///
+/// # JSON
+///
+/// A retry is represented as an object with the following fields:
+///
+/// - attempts (number) - the maximal number of retries;
+/// - delay (Duration) - the delay before the first retry. The delay is
+///   doubled after each retry, up to `Limits::max_retry_delay`, or to one
+///   day if there is no limit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+impl Parser<Retry> for Retry {
+    fn description() -> String {
+        "Retry".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let attempts = match source.find("attempts").map(|attempts| attempts.as_u64()) {
+            Some(Some(attempts)) if attempts <= u32::max_value() as u64 => attempts as u32,
+            _ => return Err(ParseError::type_error("attempts", &path, "positive integer")),
+        };
+        let delay = try!(path.push("delay", |path| Duration::take(path, source, "delay")));
+        Ok(Retry {
+            attempts: attempts,
+            delay: delay,
+        })
+    }
+}
+impl Fields for Retry {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("attempts", count),
+            Field::required("delay", reference::<Duration>),
+        ]
+    }
+}
+
+/// What to do when a `SendValues` fails for some of its setters.
+///
+/// The setters that have failed are first retried, if `retry` is specified.
+/// If some of them still fail, the value is sent to `fallback`, if specified.
+/// If there is no `fallback` or if sending to `fallback` fails, the
+/// statements of `execute` are executed.
+///
+/// # JSON
+///
+/// An error policy is represented as an object with the following fields:
+///
+/// - retry (Retry, optional);
+/// - fallback (array of SetterSelector, optional) - alternative destinations;
+/// - execute (array of Statement, optional) - the statements to execute
+///   if the value could not be delivered.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "retry": {"attempts": 3, "delay": 1},
+///   "fallback": [{"id": "backup lock"}],
+///   "execute": [{
+///     "destination": [{"id": "siren"}],
+///     "value": {"OnOff": "On"},
+///     "kind": "LightOn"
+///   }]
+/// }"#;
+///
+/// let policy = ErrorPolicy::<UncheckedCtx>::from_str(&source).unwrap();
+/// assert_eq!(policy.retry.unwrap().attempts, 3);
+/// assert_eq!(policy.execute.len(), 1);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ErrorPolicy<Ctx> where Ctx: Context {
+    pub retry: Option<Retry>,
+    pub fallback: Vec<SetterSelector>,
+    pub execute: Vec<Statement<Ctx>>,
+    pub phantom: PhantomData<Ctx>,
+}
+
+impl<Ctx> PartialEq for ErrorPolicy<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.retry == other.retry &&
+            self.fallback == other.fallback &&
+            self.execute == other.execute
+    }
+}
+impl Parser<ErrorPolicy<UncheckedCtx>> for ErrorPolicy<UncheckedCtx> {
+    fn description() -> String {
+        "ErrorPolicy".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let retry = match path.push("retry", |path| Retry::take(path, source, "retry")) {
+            Err(ParseError::MissingField {..}) => None,
+            Err(err) => return Err(err),
+            Ok(ok) => Some(ok)
+        };
+        let fallback = match path.push("fallback",
+            |path| SetterSelector::take_vec(path, source, "fallback"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let execute = match path.push("execute",
+            |path| Statement::take_vec(path, source, "execute"))
+        {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        Ok(ErrorPolicy {
+            retry: retry,
+            fallback: fallback,
+            execute: execute,
             phantom: PhantomData,
         })
     }
 }
+impl Fields for ErrorPolicy<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::optional("retry", reference::<Retry>),
+            Field::optional("fallback", many::<SetterSelector>),
+            Field::optional("execute", many::<Statement<UncheckedCtx>>),
+        ]
+    }
+}
+
+/// The value of a script variable.
+///
+/// # JSON
+///
+/// A counter is represented as `{"Counter": number}`. Any other value is
+/// represented as a `Value`, e.g. `{"OnOff": "On"}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VariableValue {
+    /// An integer counter, e.g. "number of times the door was opened today".
+    Counter(i64),
+
+    /// Any other value, e.g. "the last mode we set".
+    Value(Value),
+}
+impl VariableValue {
+    /// Determine whether two values may be stored in the same variable.
+    pub fn has_same_type(&self, other: &VariableValue) -> bool {
+        match (self, other) {
+            (&VariableValue::Counter(_), &VariableValue::Counter(_)) => true,
+            (&VariableValue::Value(ref a), &VariableValue::Value(ref b)) => a.get_type() == b.get_type(),
+            _ => false
+        }
+    }
+}
+impl Parser<VariableValue> for VariableValue {
+    fn description() -> String {
+        "VariableValue".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let counter = source.find("Counter").map(|counter| counter.as_i64());
+        match counter {
+            Some(Some(counter)) => Ok(VariableValue::Counter(counter)),
+            Some(None) => Err(ParseError::type_error("Counter", &path, "integer")),
+            None => Value::parse(path, source).map(VariableValue::Value)
+        }
+    }
+}
+
+/// The declaration of a script variable.
+///
+/// # JSON
+///
+/// A declaration is represented as an object with the following fields:
+///
+/// - name (string) - the name of the variable, unique in the script;
+/// - initial (VariableValue) - the value of the variable when the script
+///   is first launched or the variable is reset. This also determines
+///   the type of the variable.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "name": "door openings",
+///   "initial": {"Counter": 0}
+/// }"#;
+///
+/// let declaration = VariableDeclaration::from_str(&source).unwrap();
+/// assert_eq!(declaration.initial, VariableValue::Counter(0));
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableDeclaration {
+    pub name: String,
+    pub initial: VariableValue,
+}
+impl Parser<VariableDeclaration> for VariableDeclaration {
+    fn description() -> String {
+        "VariableDeclaration".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let name = try!(path.push("name", |path| String::take(path, source, "name")));
+        let initial = try!(path.push("initial",
+            |path| VariableValue::take(path, source, "initial"))
+        );
+        Ok(VariableDeclaration {
+            name: name,
+            initial: initial,
+        })
+    }
+}
+impl Fields for VariableDeclaration {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("name", string),
+            Field::required("initial", reference::<VariableValue>),
+        ]
+    }
+}
 
+/// An operation on a script variable.
+///
+/// # JSON
+///
+/// - `{"Set": VariableValue}` - replace the value of the variable;
+/// - `{"Increment": number}` - add a (possibly negative) number to a counter;
+/// - `"Reset"` - restore the initial value of the variable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableOp {
+    Set(VariableValue),
+    Increment(i64),
+    Reset,
+}
+impl Parser<VariableOp> for VariableOp {
+    fn description() -> String {
+        "VariableOp".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if let Some("Reset") = source.as_string() {
+            return Ok(VariableOp::Reset);
+        }
+        let increment = source.find("Increment").map(|increment| increment.as_i64());
+        match increment {
+            Some(Some(increment)) => return Ok(VariableOp::Increment(increment)),
+            Some(None) => return Err(ParseError::type_error("Increment", &path, "integer")),
+            None => {}
+        }
+        let value = try!(path.push("Set", |path| VariableValue::take(path, source, "Set")));
+        Ok(VariableOp::Set(value))
+    }
+}
+
+/// Changing the value of a script variable.
+///
+/// # JSON
+///
+/// A variable update is represented as an object with the following fields:
+///
+/// - variable (string) - the name of the variable;
+/// - operation (VariableOp) - what to do with the variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetVariable {
+    pub variable: String,
+    pub operation: VariableOp,
+}
+impl Parser<SetVariable> for SetVariable {
+    fn description() -> String {
+        "SetVariable".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let variable = try!(path.push("variable", |path| String::take(path, source, "variable")));
+        let operation = try!(path.push("operation",
+            |path| VariableOp::take(path, source, "operation"))
+        );
+        Ok(SetVariable {
+            variable: variable,
+            operation: operation,
+        })
+    }
+}
+impl Fields for SetVariable {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("variable", string),
+            Field::required("operation", reference::<VariableOp>),
+        ]
+    }
+}
+
+/// A range of values for a script variable.
+///
+/// # JSON
+///
+/// A range for a counter is represented as `{"Counter": {"min": number, "max": number}}`,
+/// where both `min` and `max` are optional. Any other range is represented as a `Range`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableRange {
+    /// `min <= counter <= max`.
+    Counter {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+
+    /// A range for any other value.
+    Value(Range),
+}
+impl VariableRange {
+    pub fn contains(&self, value: &VariableValue) -> bool {
+        match (self, value) {
+            (&VariableRange::Counter { ref min, ref max }, &VariableValue::Counter(counter)) => {
+                min.map_or(true, |min| min <= counter) && max.map_or(true, |max| counter <= max)
+            }
+            (&VariableRange::Value(ref range), &VariableValue::Value(ref value)) => {
+                range.contains(value)
+            }
+            _ => false
+        }
+    }
+
+    /// Determine whether values of the type of `value` may be compared against this range.
+    pub fn accepts_type_of(&self, value: &VariableValue) -> bool {
+        match (self, value) {
+            (&VariableRange::Counter {..}, &VariableValue::Counter(_)) => true,
+            (&VariableRange::Value(ref range), &VariableValue::Value(ref value)) =>
+                range.get_type().ok() == Some(value.get_type()),
+            _ => false
+        }
+    }
+}
+impl Parser<VariableRange> for VariableRange {
+    fn description() -> String {
+        "VariableRange".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let bounds = match source.find("Counter") {
+            None => None,
+            Some(counter) => Some((
+                counter.find("min").map(|min| min.as_i64()),
+                counter.find("max").map(|max| max.as_i64())
+            ))
+        };
+        match bounds {
+            None => Range::parse(path, source).map(VariableRange::Value),
+            Some((Some(None), _)) => Err(ParseError::type_error("min", &path, "integer")),
+            Some((_, Some(None))) => Err(ParseError::type_error("max", &path, "integer")),
+            Some((min, max)) => Ok(VariableRange::Counter {
+                min: min.and_then(|min| min),
+                max: max.and_then(|max| max),
+            })
+        }
+    }
+}
+
+/// A condition on a script variable.
+///
+/// # JSON
+///
+/// A variable match is represented as an object with the following fields:
+///
+/// - variable (string) - the name of the variable;
+/// - range (VariableRange) - the condition in which the match is considered met.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "variable": "door openings",
+///   "range": {"Counter": {"min": 10}}
+/// }"#;
+///
+/// let match_ = VariableMatch::from_str(&source).unwrap();
+/// assert!(match_.range.contains(&VariableValue::Counter(12)));
+/// assert!(!match_.range.contains(&VariableValue::Counter(3)));
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableMatch {
+    pub variable: String,
+    pub range: VariableRange,
+}
+impl Parser<VariableMatch> for VariableMatch {
+    fn description() -> String {
+        "VariableMatch".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let variable = try!(path.push("variable", |path| String::take(path, source, "variable")));
+        let range = try!(path.push("range",
+            |path| VariableRange::take(path, source, "range"))
+        );
+        Ok(VariableMatch {
+            variable: variable,
+            range: range,
+        })
+    }
+}
+impl Fields for VariableMatch {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("variable", string),
+            Field::required("range", reference::<VariableRange>),
+        ]
+    }
+}
+
+
+/// The declaration of a virtual channel, used to communicate between scripts.
+///
+/// A virtual channel is both a getter and a setter, with the same id. Statements
+/// may send values to it and matches may watch it, exactly as for channels
+/// provided by devices.
+///
+/// # JSON
+///
+/// A virtual channel is represented as an object with the following fields:
+///
+/// - id (string) - the id of both the getter and the setter;
+/// - kind (ChannelKind) - the kind of both the getter and the setter;
+/// - tags (array of string, optional) - tags attached to both the getter and
+///   the setter.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::services::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "id": "vacation mode",
+///   "kind": "LightOn",
+///   "tags": ["house modes"]
+/// }"#;
+///
+/// let channel = VirtualChannel::from_str(&source).unwrap();
+/// assert_eq!(channel.kind, ChannelKind::LightOn);
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtualChannel {
+    pub id: String,
+    pub kind: ChannelKind,
+    pub tags: Vec<String>,
+}
+impl Parser<VirtualChannel> for VirtualChannel {
+    fn description() -> String {
+        "VirtualChannel".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let id = try!(path.push("id", |path| String::take(path, source, "id")));
+        let kind = try!(path.push("kind",
+            |path| ChannelKind::take(path, source, "kind"))
+        );
+        let tags = match path.push("tags", |path| String::take_vec(path, source, "tags")) {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        Ok(VirtualChannel {
+            id: id,
+            kind: kind,
+            tags: tags,
+        })
+    }
+}
+impl Fields for VirtualChannel {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("id", string),
+            Field::required("kind", reference::<ChannelKind>),
+            Field::optional("tags", strings),
+        ]
+    }
+}
+
+/// A function used to compute a derived getter from the values of
+/// a set of getters.
+///
+/// # JSON
+///
+/// - `"Average"`, `"Min"`, `"Max"` - the average, smallest or largest value
+///   of the sources. `Average` is only available for temperatures;
+/// - `{"Any": Range}` - `{"OnOff": "On"}` if any of the sources is in the range,
+///   `{"OnOff": "Off"}` otherwise;
+/// - `{"All": Range}` - `{"OnOff": "On"}` if all of the sources are in the range,
+///   `{"OnOff": "Off"}` otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Aggregate {
+    Average,
+    Min,
+    Max,
+    Any(Range),
+    All(Range),
+}
+impl Parser<Aggregate> for Aggregate {
+    fn description() -> String {
+        "Aggregate".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some("Average") => return Ok(Aggregate::Average),
+            Some("Min") => return Ok(Aggregate::Min),
+            Some("Max") => return Ok(Aggregate::Max),
+            Some(other) => return Err(ParseError::unknown_constant(other, &path)),
+            None => {}
+        }
+        if source.find("Any").is_some() {
+            let range = try!(path.push("Any", |path| Range::take(path, source, "Any")));
+            return Ok(Aggregate::Any(range));
+        }
+        let range = try!(path.push("All", |path| Range::take(path, source, "All")));
+        Ok(Aggregate::All(range))
+    }
+}
+
+/// A getter computed by a script from the values of other getters, e.g.
+/// "the average temperature of all bedroom thermometers" or "true if any
+/// window is open".
+///
+/// Derived getters are published through the API, so that other scripts
+/// and clients may select them by id or by tag.
+///
+/// # JSON
+///
+/// A derived getter is represented as an object with the following fields:
+///
+/// - id (string) - the id of the getter;
+/// - kind (ChannelKind) - the kind of the getter;
+/// - tags (array of string, optional) - tags attached to the getter;
+/// - source (array of GetterSelector) - the getters from which to compute the value;
+/// - source_kind (ChannelKind) - the kind of the getters in `source`;
+/// - aggregate (Aggregate) - how to compute the value.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{
+///   "id": "any window open",
+///   "kind": "LightOn",
+///   "source": [{"tags": ["window"]}],
+///   "source_kind": "OpenClosed",
+///   "aggregate": {"Any": {"Eq": {"OpenClosed": "Open"}}}
+/// }"#;
+///
+/// let derived = DerivedGetter::<UncheckedCtx>::from_str(&source).unwrap();
+/// assert_eq!(derived.id, "any window open");
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DerivedGetter<Ctx> where Ctx: Context {
+    pub id: String,
+    pub kind: ChannelKind,
+    pub tags: Vec<String>,
+
+    /// The getters from which to compute the value. During compilation, we
+    /// make sure that we restrict to the elements of `source` that offer
+    /// `source_kind`.
+    pub source: Vec<GetterSelector>,
+    pub source_kind: ChannelKind,
+
+    pub aggregate: Aggregate,
+
+    pub phantom: PhantomData<Ctx>,
+}
+
+impl<Ctx> PartialEq for DerivedGetter<Ctx> where Ctx: Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id &&
+            self.kind == other.kind &&
+            self.tags == other.tags &&
+            self.source == other.source &&
+            self.source_kind == other.source_kind &&
+            self.aggregate == other.aggregate
+    }
+}
+impl Parser<DerivedGetter<UncheckedCtx>> for DerivedGetter<UncheckedCtx> {
+    fn description() -> String {
+        "DerivedGetter".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let id = try!(path.push("id", |path| String::take(path, source, "id")));
+        let kind = try!(path.push("kind",
+            |path| ChannelKind::take(path, source, "kind"))
+        );
+        let tags = match path.push("tags", |path| String::take_vec(path, source, "tags")) {
+            Err(ParseError::MissingField {..}) => vec![],
+            Err(err) => return Err(err),
+            Ok(ok) => ok
+        };
+        let sources = try!(path.push("source",
+            |path| GetterSelector::take_vec(path, source, "source"))
+        );
+        let source_kind = try!(path.push("source_kind",
+            |path| ChannelKind::take(path, source, "source_kind"))
+        );
+        let aggregate = try!(path.push("aggregate",
+            |path| Aggregate::take(path, source, "aggregate"))
+        );
+        Ok(DerivedGetter {
+            id: id,
+            kind: kind,
+            tags: tags,
+            source: sources,
+            source_kind: source_kind,
+            aggregate: aggregate,
+            phantom: PhantomData,
+        })
+    }
+}
+impl Fields for DerivedGetter<UncheckedCtx> {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("id", string),
+            Field::required("kind", reference::<ChannelKind>),
+            Field::optional("tags", strings),
+            Field::required("source", many::<GetterSelector>),
+            Field::required("source_kind", reference::<ChannelKind>),
+            Field::required("aggregate", reference::<Aggregate>),
+        ]
+    }
+}
+impl<Ctx> DerivedGetter<Ctx> where Ctx: Context {
+    /// The virtual channel used to publish this getter.
+    pub fn as_channel(&self) -> VirtualChannel {
+        VirtualChannel {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            tags: self.tags.clone(),
+        }
+    }
+}
+
+/// A daily window of time, in the local time of the house, as given by
+/// `ExecutableDevEnv::utc_offset`.
+///
+/// # JSON
+///
+/// A window is represented as an object with the following fields:
+///
+/// - from (string) - the time at which the window opens, as "HH:MM";
+/// - to (string) - the time at which the window closes, as "HH:MM".
+///
+/// If `to` is earlier than `from`, the window spans midnight, e.g.
+/// `{"from": "22:00", "to": "07:00"}` is open during the night. If
+/// `from` and `to` are equal, the window is always open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimeWindow {
+    /// Seconds since midnight.
+    pub from: u32,
+
+    /// Seconds since midnight.
+    pub to: u32,
+}
+impl TimeWindow {
+    /// Determine whether the window is open at a given time, in seconds
+    /// since midnight.
+    pub fn contains(&self, time: u32) -> bool {
+        if self.from <= self.to {
+            self.from == self.to || (self.from <= time && time < self.to)
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+
+    /// The number of seconds between a given time, in seconds since midnight,
+    /// and the next time the window opens or closes.
+    pub fn seconds_until_change(&self, time: u32) -> u32 {
+        let next = if self.contains(time) { self.to } else { self.from };
+        (next + SECONDS_PER_DAY - time) % SECONDS_PER_DAY
+    }
+
+    /// The number of seconds the window remains open, if `is_open`, or
+    /// closed, otherwise.
+    pub fn length(&self, is_open: bool) -> u32 {
+        if is_open {
+            (self.to + SECONDS_PER_DAY - self.from) % SECONDS_PER_DAY
+        } else {
+            (self.from + SECONDS_PER_DAY - self.to) % SECONDS_PER_DAY
+        }
+    }
+}
+impl Parser<TimeWindow> for TimeWindow {
+    fn description() -> String {
+        "TimeWindow".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let from = try!(parse_time_of_day(&path, source, "from"));
+        let to = try!(parse_time_of_day(&path, source, "to"));
+        Ok(TimeWindow {
+            from: from,
+            to: to,
+        })
+    }
+}
+impl Fields for TimeWindow {
+    fn fields() -> Vec<Field> {
+        vec![
+            Field::required("from", string),
+            Field::required("to", string),
+        ]
+    }
+}
+
+const SECONDS_PER_DAY : u32 = 24 * 60 * 60;
+
+/// Parse a time of day "HH:MM" into a number of seconds since midnight.
+fn parse_time_of_day(path: &Path, source: &JSON, field_name: &str) -> Result<u32, ParseError> {
+    let time = match source.find(field_name).and_then(|time| time.as_string()) {
+        Some(time) => time,
+        None => return Err(ParseError::type_error(field_name, path, "time of day (HH:MM)"))
+    };
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(hours)), Some(Some(minutes)), None) if hours < 24 && minutes < 60 =>
+            Ok(hours * 3600 + minutes * 60),
+        _ => Err(ParseError::type_error(field_name, path, "time of day (HH:MM)"))
+    }
+}
+
+/// A restriction on when rules may fire.
+///
+/// # JSON
+///
+/// - `{"Window": TimeWindow}` - only during a daily window of time;
+/// - `{"Mode": string}` - only while the house is in a given mode, e.g.
+///   "Away". Modes are managed by the `ScriptManager`.
+///
+/// ```
+/// extern crate foxbox_thinkerbell;
+/// extern crate foxbox_taxonomy;
+///
+/// use foxbox_thinkerbell::ast::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// # fn main() {
+/// let source = r#"{"Window": {"from": "22:00", "to": "07:00"}}"#;
+///
+/// match ActiveWhen::from_str(&source).unwrap() {
+///   ActiveWhen::Window(window) => {
+///     assert!(window.contains(23 * 3600));
+///     assert!(!window.contains(12 * 3600));
+///   }
+///   _ => panic!()
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActiveWhen {
+    Window(TimeWindow),
+    Mode(String),
+}
+impl Parser<ActiveWhen> for ActiveWhen {
+    fn description() -> String {
+        "ActiveWhen".to_owned()
+    }
+
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if source.find("Mode").is_some() {
+            let mode = try!(path.push("Mode", |path| String::take(path, source, "Mode")));
+            return Ok(ActiveWhen::Mode(mode));
+        }
+        let window = try!(path.push("Window", |path| TimeWindow::take(path, source, "Window")));
+        Ok(ActiveWhen::Window(window))
+    }
+}
 
 /// A manner of representing internal nodes.
 ///
@@ -15,18 +15,67 @@
 //! - Transform each `Match` to make sure that the kind of the
 //!   `source` matches the `kind`, even if devices change.
 //! - Transform each `Statement` to make sure that the kind of the
-//!   `destination` matches the `kind`, even if devices change.
+//!   `destination` and of the `fallback` matches the `kind`, even if
+//!   devices change.
+//! - Ensure that each variable is declared only once.
+//! - Ensure that each virtual channel or derived getter is declared only once.
+//! - Ensure that each `DerivedGetter` has at least one `source` and that
+//!   the types of its `kind`, `source_kind` and `aggregate` agree.
+//! - Transform each `DerivedGetter` to make sure that the kind of the
+//!   `source` matches the `source_kind`, even if devices change.
+//! - Ensure that each `SetVariable` and `VariableMatch` refers to a
+//!   declared variable, with a compatible type.
+//!
+//! The compiler also analyzes the ranges of the `Match`es of each `Rule`
+//! that share the same `kind`, and warns about conditions that can never
+//! be met together, conditions made redundant by another condition and
+//! conditions that accept any value. It also warns about rules that may
+//! trigger each other indefinitely (see module `dependencies`). In strict
+//! mode, these warnings are errors.
+//!
+//! Finally, `Compiler::dry_run` checks a script against the devices that
+//! are currently available, and warns about selectors that match no
+//! channel, or only channels of another kind, while
+//! `Compiler::check_permissions` rejects scripts whose owner may not
+//! access the channels they use (see module `permissions`).
+//!
+//! The compiler may also be configured to reject scripts that are too
+//! complex (see `Limits`), as scripts are not trusted.
+//!
+//! Custom checks and rewrites may be added next to the built-in checks,
+//! as a `CompilerPass`.
+//!
+//! Once a script is compiled, `group_watches` merges identical matches,
+//! so that they share a single watch during execution.
+//!
+//! The compiler does not stop at the first error. Rather, it reports
+//! a `Diagnostic` for each error, with the location of the offending
+//! node in the source of the script.
+
+use ast::{ Script, Rule, Statement, SendValues, SetVariable, Match, VariableMatch, VariableOp,
+           VariableValue, DerivedGetter, Aggregate, ErrorPolicy, Retry, Context, UncheckedCtx };
+use dependencies::DependencyGraph;
+use permissions::{ Access, AllowAll, PermissionPolicy };
+use util::map_all;
+use virtual_channels::VirtualChannels;
 
-use ast::{ Script, Rule, Statement, Match, Context, UncheckedCtx };
-use util::*;
+use foxbox_taxonomy::api::{ API, User };
+use foxbox_taxonomy::parse::ParseError;
+use foxbox_taxonomy::selector::{ GetterSelector, SetterSelector };
+use foxbox_taxonomy::services::{ ChannelKind, Getter, Setter };
+use foxbox_taxonomy::util::{ Exactly, Id };
+use foxbox_taxonomy::values::{ Duration, OnOff, OpenClosed, Range, Type, Value };
 
-use foxbox_taxonomy::api::API;
-use foxbox_taxonomy::values::Duration;
+use chrono::{ Duration as ChronoDuration, Local };
 
 use transformable_channels::mpsc::*;
 
-use std::fmt::{ Debug, Formatter, Error as FmtError };
+use std::collections::{ HashMap, HashSet };
+use std::fmt::{ Debug, Display, Formatter, Error as FmtError };
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::u32;
+use std::usize;
 
 /// The environment in which the code is meant to be executed.  This
 /// can typically be instantiated either with actual bindings to
@@ -41,6 +90,34 @@ pub trait ExecutableDevEnv: Send {
     /// A guard returned by `start_timer`. When the guard is dropped, the timer is cancelled.
     type TimerGuard;
     fn start_timer(&self, duration: Duration, timer: Box<ExtSender<()>>) -> Self::TimerGuard;
+
+    /// The virtual channels shared by all scripts executed in this
+    /// environment, or `None` if the environment does not support
+    /// virtual channels.
+    fn virtual_channels(&self) -> Option<&VirtualChannels> {
+        None
+    }
+
+    /// The policy deciding which channels the owner of a script may read
+    /// or write. By default, everything is allowed.
+    fn permissions(&self) -> &PermissionPolicy {
+        static ALLOW_ALL: AllowAll = AllowAll;
+        &ALLOW_ALL
+    }
+
+    /// The offset of the local time of the house from UTC. Time windows
+    /// are evaluated in this local time. By default, the local time of the
+    /// host, as configured in its time zone.
+    fn utc_offset(&self) -> ChronoDuration {
+        let now = Local::now();
+        now.naive_local() - now.naive_utc()
+    }
+
+    /// Custom compiler passes, run on every script executed in this
+    /// environment, e.g. to enforce house-specific policies.
+    fn compiler_passes(&self) -> Vec<Arc<CompilerPass<Self>>> where Self: Sized {
+        vec![]
+    }
 }
 impl<W, A, T> Debug for ExecutableDevEnv<WatchGuard=W, API=A, TimerGuard=T> {
     fn fmt(&self, _: &mut Formatter) -> Result<(), FmtError> {
@@ -77,6 +154,54 @@ pub enum SourceError {
 
     /// A statement doesn't have any destination.
     NoStatementDestination,
+
+    /// A variable is declared several times.
+    DuplicateVariable(String),
+
+    /// A statement or a condition uses a variable that is not declared.
+    UnknownVariable(String),
+
+    /// A virtual channel or a derived getter is declared several times.
+    DuplicateVirtualChannel(String),
+
+    /// A derived getter doesn't have any source.
+    NoDerivedSource(String),
+
+    /// The script has more rules than allowed by `Limits::max_rules`.
+    TooManyRules { max: usize },
+
+    /// A rule has more conditions than allowed by `Limits::max_matches_per_rule`.
+    TooManyMatches { max: usize },
+
+    /// A rule has more statements than allowed by `Limits::max_statements_per_rule`.
+    TooManyStatements { max: usize },
+
+    /// A match or statement has more selectors than allowed by `Limits::max_selectors`.
+    TooManySelectors { max: usize },
+
+    /// The script would register more watches than allowed by `Limits::max_watches`.
+    TooManyWatches { max: usize },
+
+    /// A duration is shorter than `Limits::min_duration`.
+    DurationTooShort { min: Duration },
+
+    /// A retry policy has more attempts than allowed by `Limits::max_retries`.
+    TooManyRetries { max: u32 },
+
+    /// The delay of a retry policy is longer than `Limits::max_retry_delay`.
+    RetryDelayTooLong { max: Duration },
+
+    /// The owner of the script would have more enabled scripts than allowed
+    /// by `Quota::max_scripts`.
+    ScriptQuotaExceeded { max: usize },
+
+    /// The enabled scripts of the owner would have more rules than allowed
+    /// by `Quota::max_rules`.
+    RuleQuotaExceeded { max: usize },
+
+    /// The enabled scripts of the owner would register more watches than
+    /// allowed by `Quota::max_watches`.
+    WatchQuotaExceeded { max: usize },
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -91,85 +216,848 @@ pub enum TypeError {
     /// The value has one type but this type is incompatible with the
     /// kind of the `Statement`.
     KindAndValueDoNotAgree,
+
+    /// A `SetVariable` or a `VariableMatch` uses a value or a range whose
+    /// type is incompatible with the declaration of the variable.
+    VariableTypeDoesNotAgree(String),
+
+    /// The `kind` of a derived getter is incompatible with its `aggregate`
+    /// or with its `source_kind`.
+    AggregateDoesNotAgree(String),
+}
+
+/// Issues with the ranges of the `Match`es of a `Rule`. These are warnings,
+/// unless the compiler is in strict mode.
+#[derive(Clone, Debug, Serialize)]
+pub enum RangeError {
+    /// This match can never be met at the same time as the previous matches
+    /// of the rule on the same getter, or the channels of its kind never
+    /// take a value in range, so the rule never fires.
+    Unsatisfiable,
+
+    /// This match is always met when another match of the rule on the same
+    /// getter is met, so it is useless.
+    Redundant,
+
+    /// This range accepts any value that the channels of its kind may take,
+    /// so the match is met as soon as any value is available.
+    AlwaysTrue,
+}
+
+/// Issues with the dependencies between rules. These are warnings,
+/// unless the compiler is in strict mode.
+#[derive(Clone, Debug, Serialize)]
+pub enum DependencyError {
+    /// The statements of these rules may trigger the conditions of these
+    /// same rules, possibly indefinitely.
+    FeedbackLoop(Vec<usize>),
+}
+
+/// Issues with the channels matched by a selector during a dry run. These
+/// are warnings, unless the compiler is in strict mode.
+#[derive(Clone, Debug, Serialize)]
+pub enum ResolutionError {
+    /// The selector does not match any channel.
+    NoChannel,
+
+    /// The selector only matches channels whose kind differs from the
+    /// kind of the match or statement, so it is ignored.
+    UnexpectedKind {
+        expected: ChannelKind,
+        channels: Vec<String>,
+    },
+}
+
+/// The owner of a script may not access some of the channels it uses.
+/// These are always errors.
+#[derive(Clone, Debug, Serialize)]
+pub enum PermissionError {
+    Denied(Vec<Access>),
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub enum Error {
     SourceError(SourceError),
     TypeError(TypeError),
+    RangeError(RangeError),
+    DependencyError(DependencyError),
+    ResolutionError(ResolutionError),
+    PermissionError(PermissionError),
+
+    /// A custom compiler pass rejected the script, or warns about it.
+    PassError(String),
+
+    /// The source of the script could not be parsed.
+    ParseError(String),
+}
+
+/// A step in a `Location`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum LocationStep {
+    /// A field of an object, e.g. `"rules"`.
+    Field(String),
+
+    /// An element of an array.
+    Index(usize),
+}
+
+/// The location of a node in the source of a script, e.g. `rules[0].execute[1].value`.
+///
+/// Locations follow the same structure as the `parse::Path` used to report
+/// parse errors: the name of each field traversed from the root of the
+/// script, and the index of each array element. Parse errors are reported
+/// at the same locations as compilation diagnostics, see
+/// `Diagnostic::from(ParseError)`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Location(pub Vec<LocationStep>);
+
+impl Location {
+    /// The root of the script.
+    pub fn new() -> Self {
+        Location(vec![])
+    }
+
+    /// The location of a field of this node.
+    pub fn field(&self, name: &str) -> Self {
+        let mut steps = self.0.clone();
+        steps.push(LocationStep::Field(name.to_owned()));
+        Location(steps)
+    }
+
+    /// The location of an element of this node.
+    pub fn index(&self, index: usize) -> Self {
+        let mut steps = self.0.clone();
+        steps.push(LocationStep::Index(index));
+        Location(steps)
+    }
+}
+
+impl<'a> From<&'a ParseError> for Location {
+    /// The node at which the parser has reported an error.
+    ///
+    /// The parser only reports its `Path` as text, e.g. `.rules[1].execute`,
+    /// which is read back step by step. The parser only pushes the fields of
+    /// the grammar, none of which contains `.` or `[`, and array indices.
+    fn from(error: &'a ParseError) -> Self {
+        let at = match *error {
+            ParseError::MissingField { ref at, .. } |
+            ParseError::TypeError { ref at, .. } |
+            ParseError::UnknownConstant { ref at, .. } => at,
+            _ => return Location::new()
+        };
+        let mut steps = vec![];
+        for part in at.split('.') {
+            let mut parts = part.split('[');
+            if let Some(name) = parts.next() {
+                if !name.is_empty() {
+                    steps.push(LocationStep::Field(name.to_owned()));
+                }
+            }
+            for index in parts {
+                match index.trim_right_matches(']').parse() {
+                    Ok(index) => steps.push(LocationStep::Index(index)),
+                    Err(_) => steps.push(LocationStep::Field(index.to_owned()))
+                }
+            }
+        }
+        Location(steps)
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        for (step, i) in self.0.iter().zip(0..) {
+            match *step {
+                LocationStep::Field(ref name) if i == 0 => try!(write!(formatter, "{}", name)),
+                LocationStep::Field(ref name) => try!(write!(formatter, ".{}", name)),
+                LocationStep::Index(index) => try!(write!(formatter, "[{}]", index)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Severity {
+    /// The script cannot be executed.
+    Error,
+
+    /// The script can be executed, but probably does not do what its
+    /// author intended.
+    Warning,
+}
+
+/// An issue found by the compiler.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    /// The node of the script that causes the issue.
+    pub location: Location,
+    pub severity: Severity,
+    pub error: Error,
+}
+
+impl Diagnostic {
+    pub fn error(location: Location, error: Error) -> Self {
+        Diagnostic {
+            location: location,
+            severity: Severity::Error,
+            error: error,
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    /// Report a parse error at the node where the parser has found it.
+    fn from(error: ParseError) -> Self {
+        Diagnostic::error(Location::from(&error), Error::ParseError(format!("{:?}", error)))
+    }
+}
+
+/// The channels matched by the selectors of a rule during a dry run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RuleResolution {
+    /// For each condition, the getters of the expected kind matched by its `source`.
+    pub conditions: Vec<Vec<Id<Getter>>>,
+
+    /// For each statement, the setters of the expected kind matched by its
+    /// `destination`. Always empty for statements that do not send values.
+    pub statements: Vec<Vec<Id<Setter>>>,
+}
+
+/// The result of a dry run.
+#[derive(Clone, Debug, Serialize)]
+pub struct DryRun {
+    /// For each rule, the channels matched by its selectors.
+    pub rules: Vec<RuleResolution>,
+
+    /// All the errors and warnings, including those of the compiler.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Limits on the complexity of a single script, to prevent untrusted
+/// scripts from exhausting the resources of the box. By default, there is
+/// no limit.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_rules: usize,
+    pub max_matches_per_rule: usize,
+
+    /// The maximal number of statements of a rule, including the statements
+    /// executed on errors.
+    pub max_statements_per_rule: usize,
+
+    /// The maximal number of selectors of a `source`, including that of a
+    /// derived getter, of a `destination` or of a `fallback`.
+    pub max_selectors: usize,
+
+    /// The maximal number of calls to `watch_values`, i.e. one per match and
+    /// one per derived getter.
+    pub max_watches: usize,
+
+    /// The minimal duration of a match or of the delay between two retries.
+    pub min_duration: Option<Duration>,
+
+    /// The maximal number of attempts of a retry policy.
+    pub max_retries: u32,
+
+    /// The maximal delay between two retries. The delay, which is doubled
+    /// after each retry, never exceeds this, or one day if there is no limit.
+    pub max_retry_delay: Option<Duration>,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_rules: usize::MAX,
+            max_matches_per_rule: usize::MAX,
+            max_statements_per_rule: usize::MAX,
+            max_selectors: usize::MAX,
+            max_watches: usize::MAX,
+            min_duration: None,
+            max_retries: u32::MAX,
+            max_retry_delay: None,
+        }
+    }
+}
+
+/// Limits on the scripts of a single owner, taken together. Enforced by
+/// `ScriptManager` on enabled scripts. By default, there is no limit.
+#[derive(Clone, Debug)]
+pub struct Quota {
+    pub max_scripts: usize,
+    pub max_rules: usize,
+    pub max_watches: usize,
+}
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            max_scripts: usize::MAX,
+            max_rules: usize::MAX,
+            max_watches: usize::MAX,
+        }
+    }
+}
+
+/// The number of statements, including the statements executed on errors.
+fn count_statements<Ctx>(statements: &[Statement<Ctx>]) -> usize where Ctx: Context {
+    statements.iter().map(|statement| match *statement {
+        Statement::Send(SendValues { on_error: Some(ref policy), .. }) => 1 + count_statements(&policy.execute),
+        _ => 1
+    }).fold(0, |sum, count| sum + count)
+}
+
+/// Turn a compiled script back into an unchecked script, e.g. to check
+/// again a script rewritten by a `CompilerPass`.
+fn uncheck<Ctx>(script: Script<Ctx>) -> Script<UncheckedCtx> where Ctx: Context {
+    Script {
+        name: script.name,
+        variables: script.variables,
+        channels: script.channels,
+        derived: script.derived.into_iter().map(|derived| DerivedGetter {
+            id: derived.id,
+            kind: derived.kind,
+            tags: derived.tags,
+            source: derived.source,
+            source_kind: derived.source_kind,
+            aggregate: derived.aggregate,
+            phantom: PhantomData
+        }).collect(),
+        active_when: script.active_when,
+        rules: script.rules.into_iter().map(|rule| Rule {
+            conditions: rule.conditions.into_iter().map(|match_| Match {
+                source: match_.source,
+                kind: match_.kind,
+                range: match_.range,
+                duration: match_.duration,
+                phantom: PhantomData
+            }).collect(),
+            variable_conditions: rule.variable_conditions,
+            active_when: rule.active_when,
+            execute: uncheck_statements(rule.execute),
+            phantom: PhantomData
+        }).collect(),
+        phantom: PhantomData
+    }
+}
+
+fn uncheck_statements<Ctx>(statements: Vec<Statement<Ctx>>) -> Vec<Statement<UncheckedCtx>> where Ctx: Context {
+    statements.into_iter().map(|statement| match statement {
+        Statement::Send(send) => Statement::Send(SendValues {
+            destination: send.destination,
+            value: send.value,
+            kind: send.kind,
+            on_error: send.on_error.map(|policy| ErrorPolicy {
+                retry: policy.retry,
+                fallback: policy.fallback,
+                execute: uncheck_statements(policy.execute),
+                phantom: PhantomData
+            }),
+            phantom: PhantomData
+        }),
+        Statement::SetVariable(set) => Statement::SetVariable(set)
+    }).collect()
+}
+
+/// The number of calls to `watch_values` needed to execute a script.
+pub fn count_watches<Ctx>(script: &Script<Ctx>) -> usize where Ctx: Context {
+    script.rules.iter().map(|rule| rule.conditions.len()).fold(script.derived.len(), |sum, len| sum + len)
+}
+
+/// A single watch, shared by the matches of a script that watch the same
+/// channels with the same range.
+#[derive(Clone, Debug)]
+pub struct WatchGroup {
+    /// The selectors of the matches, without duplicates.
+    pub source: Vec<GetterSelector>,
+    pub range: Range,
+
+    /// The `(rule_index, condition_index)` of each match sharing this watch.
+    pub members: Vec<(usize, usize)>,
+}
+
+impl WatchGroup {
+    /// `true` if both groups watch the same channels with the same range,
+    /// regardless of the order of their selectors.
+    pub fn same_watch(&self, other: &WatchGroup) -> bool {
+        self.range == other.range &&
+            self.source.len() == other.source.len() &&
+            self.source.iter().all(|selector| other.source.contains(selector))
+    }
+}
+
+/// Group the matches of a compiled script that watch the same channels
+/// with the same range, so that each group needs a single call to
+/// `watch_values`. Matches are canonicalized first, i.e. the order and
+/// duplicates of their selectors are ignored.
+pub fn group_watches<Env>(script: &Script<CompiledCtx<Env>>) -> Vec<WatchGroup> where Env: ExecutableDevEnv {
+    let mut groups : Vec<WatchGroup> = vec![];
+    for (rule, rule_index) in script.rules.iter().zip(0..) {
+        for (condition, condition_index) in rule.conditions.iter().zip(0..) {
+            let mut source = vec![];
+            for selector in &condition.source {
+                if !source.contains(selector) {
+                    source.push(selector.clone());
+                }
+            }
+            let candidate = WatchGroup {
+                source: source,
+                range: condition.range.clone(),
+                members: vec![(rule_index, condition_index)],
+            };
+            match groups.iter().position(|group| group.same_watch(&candidate)) {
+                Some(index) => groups[index].members.push((rule_index, condition_index)),
+                None => groups.push(candidate)
+            }
+        }
+    }
+    groups
+}
+
+/// A custom step of compilation, run next to the built-in checks.
+///
+/// A pass may report diagnostics, with `Error::PassError`, and rewrite the
+/// script. Any diagnostic with `Severity::Error` causes compilation to
+/// fail. In strict mode, warnings are errors.
+pub trait CompilerPass<Env>: Send + Sync where Env: ExecutableDevEnv {
+    /// Run before the built-in checks, on the script as parsed.
+    fn run_unchecked(&self, script: Script<UncheckedCtx>, _: &mut Vec<Diagnostic>) -> Script<UncheckedCtx> {
+        script
+    }
+
+    /// Run after the built-in checks, if they succeeded. The built-in
+    /// checks are then run again on the resulting script.
+    fn run_compiled(&self, script: Script<CompiledCtx<Env>>, _: &mut Vec<Diagnostic>) -> Script<CompiledCtx<Env>> {
+        script
+    }
 }
 
 pub struct Compiler<Env> where Env: ExecutableDevEnv {
+    /// If `true`, warnings are reported as errors.
+    strict: bool,
+    limits: Limits,
+
+    /// Custom passes, in the order in which they were registered.
+    passes: Vec<Arc<CompilerPass<Env>>>,
     phantom: PhantomData<Env>,
 }
 
 impl<Env> Compiler<Env> where Env: ExecutableDevEnv {
     pub fn new() -> Result<Self, Error> {
         Ok(Compiler {
+            strict: false,
+            limits: Limits::default(),
+            passes: vec![],
             phantom: PhantomData
         })
     }
 
+    /// Report warnings as errors.
+    pub fn with_strict(self, strict: bool) -> Self {
+        Compiler {
+            strict: strict,
+            ..self
+        }
+    }
+
+    /// Register a custom pass, which runs after the passes registered
+    /// previously.
+    pub fn with_pass(self, pass: Arc<CompilerPass<Env>>) -> Self {
+        let mut passes = self.passes;
+        passes.push(pass);
+        Compiler {
+            passes: passes,
+            ..self
+        }
+    }
+
+    /// Reject scripts that exceed some limits.
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Compiler {
+            limits: limits,
+            ..self
+        }
+    }
+
     /// Attempt to compile a script.
+    ///
+    /// # Errors
+    ///
+    /// If the script cannot be compiled, return the diagnostics for all the
+    /// errors found in the script.
     pub fn compile(&self, script: Script<UncheckedCtx>)
-                   -> Result<Script<CompiledCtx<Env>>, Error> {
-        self.compile_script(script)
+                   -> Result<Script<CompiledCtx<Env>>, Vec<Diagnostic>> {
+        self.compile_with_warnings(script).map(|(script, _)| script)
     }
 
-    fn compile_script(&self, script: Script<UncheckedCtx>) -> Result<Script<CompiledCtx<Env>>, Error>
-    {
-        if script.rules.len() == 0 {
-            return Err(Error::SourceError(SourceError::NoRule));
+    /// Attempt to compile a script. In case of success, also return the
+    /// warnings found in the script.
+    ///
+    /// # Errors
+    ///
+    /// If the script cannot be compiled, return the diagnostics for all the
+    /// errors and warnings found in the script.
+    pub fn compile_with_warnings(&self, script: Script<UncheckedCtx>)
+                   -> Result<(Script<CompiledCtx<Env>>, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let mut diagnostics = vec![];
+        let mut script = script;
+        for pass in &self.passes {
+            script = self.run_pass(&mut diagnostics, |diagnostics| pass.run_unchecked(script, diagnostics));
         }
-        let rules = try!(map(script.rules, |rule| {
-            self.compile_rule(rule)
+        let mut checks = vec![];
+        let mut script = match self.compile_script(script, &mut checks) {
+            Some(script) => script,
+            None => {
+                diagnostics.extend(checks);
+                return Err(diagnostics)
+            }
+        };
+        if !self.passes.is_empty() {
+            let mut reported = vec![];
+            for pass in &self.passes {
+                script = self.run_pass(&mut reported, |diagnostics| pass.run_compiled(script, diagnostics));
+            }
+            // The passes may have rewritten the script in ways that the
+            // built-in checks reject, e.g. beyond the limits. Only the
+            // diagnostics of the final script are reported.
+            checks.clear();
+            script = match self.compile_script(uncheck(script), &mut checks) {
+                Some(script) => script,
+                None => {
+                    diagnostics.extend(checks);
+                    diagnostics.extend(reported);
+                    return Err(diagnostics)
+                }
+            };
+            checks.extend(reported);
+        }
+        diagnostics.extend(checks);
+        if diagnostics.iter().all(|diagnostic| diagnostic.severity != Severity::Error) {
+            Ok((script, diagnostics))
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    /// Run a custom pass, turning its warnings into errors in strict mode.
+    fn run_pass<T, F>(&self, diagnostics: &mut Vec<Diagnostic>, cb: F) -> T where F: FnOnce(&mut Vec<Diagnostic>) -> T {
+        let mut reported = vec![];
+        let result = cb(&mut reported);
+        diagnostics.extend(reported.into_iter().map(|diagnostic| match diagnostic.severity {
+            Severity::Warning => self.warning(diagnostic.location, diagnostic.error),
+            Severity::Error => diagnostic
         }));
-        Ok(Script {
-            name: script.name,
+        result
+    }
+
+    /// Compile a script and resolve its selectors against the channels
+    /// currently known to `api`, without executing it.
+    pub fn dry_run(&self, script: Script<UncheckedCtx>, api: &Env::API) -> DryRun {
+        let root = Location::new();
+        let mut diagnostics = vec![];
+        let rules = script.rules.iter().zip(0..).map(|(rule, rule_index)| {
+            let location = root.field("rules").index(rule_index);
+            let conditions = rule.conditions.iter().zip(0..).map(|(condition, index)| {
+                let channels = api.get_getter_channels(condition.source.clone());
+                let channels = channels.iter().map(|channel| (channel.id.to_string(), &channel.mechanism.kind)).collect();
+                self.resolve::<Getter>(channels, &condition.kind,
+                    &location.field("conditions").index(index).field("source"), &mut diagnostics)
+            }).collect();
+            let statements = rule.execute.iter().zip(0..).map(|(statement, index)| {
+                match *statement {
+                    Statement::Send(ref send) => {
+                        let channels = api.get_setter_channels(send.destination.clone());
+                        let channels = channels.iter().map(|channel| (channel.id.to_string(), &channel.mechanism.kind)).collect();
+                        self.resolve::<Setter>(channels, &send.kind,
+                            &location.field("execute").index(index).field("destination"), &mut diagnostics)
+                    }
+                    Statement::SetVariable(_) => vec![]
+                }
+            }).collect();
+            RuleResolution {
+                conditions: conditions,
+                statements: statements,
+            }
+        }).collect();
+        match self.compile_with_warnings(script) {
+            Ok((_, warnings)) => diagnostics.extend(warnings),
+            Err(errors) => diagnostics.extend(errors)
+        }
+        DryRun {
             rules: rules,
-            phantom: PhantomData
-        })
+            diagnostics: diagnostics,
+        }
+    }
+
+    /// Check that `owner` may read the getters and write the setters that
+    /// currently match the selectors of a compiled script.
+    ///
+    /// Return one error for each selector matching forbidden channels.
+    pub fn check_permissions(&self, script: &Script<CompiledCtx<Env>>, env: &Env, owner: &User) -> Vec<Diagnostic> {
+        let root = Location::new();
+        let mut diagnostics = vec![];
+        for (rule, rule_index) in script.rules.iter().zip(0..) {
+            let location = root.field("rules").index(rule_index);
+            for (condition, index) in rule.conditions.iter().zip(0..) {
+                let denied : Vec<_> = env.api().get_getter_channels(condition.source.clone()).into_iter()
+                    .map(|channel| Access::Read(channel.id))
+                    .filter(|access| !env.permissions().is_allowed(owner, access))
+                    .collect();
+                if !denied.is_empty() {
+                    diagnostics.push(Diagnostic::error(location.field("conditions").index(index).field("source"),
+                        Error::PermissionError(PermissionError::Denied(denied))));
+                }
+            }
+            self.check_statement_permissions(&rule.execute, env, owner,
+                &location.field("execute"), &mut diagnostics);
+        }
+        diagnostics
+    }
+
+    fn check_statement_permissions(&self, statements: &[Statement<CompiledCtx<Env>>], env: &Env, owner: &User,
+        location: &Location, diagnostics: &mut Vec<Diagnostic>)
+    {
+        let check = |destination: &[SetterSelector], location: Location, diagnostics: &mut Vec<Diagnostic>| {
+            let denied : Vec<_> = env.api().get_setter_channels(destination.to_vec()).into_iter()
+                .map(|channel| Access::Write(channel.id))
+                .filter(|access| !env.permissions().is_allowed(owner, access))
+                .collect();
+            if !denied.is_empty() {
+                diagnostics.push(Diagnostic::error(location,
+                    Error::PermissionError(PermissionError::Denied(denied))));
+            }
+        };
+        for (statement, index) in statements.iter().zip(0..) {
+            let send = match *statement {
+                Statement::Send(ref send) => send,
+                Statement::SetVariable(_) => continue
+            };
+            let location = location.index(index);
+            check(&send.destination, location.field("destination"), diagnostics);
+            if let Some(ref policy) = send.on_error {
+                let location = location.field("on_error");
+                check(&policy.fallback, location.field("fallback"), diagnostics);
+                self.check_statement_permissions(&policy.execute, env, owner,
+                    &location.field("execute"), diagnostics);
+            }
+        }
     }
 
-    fn compile_rule(&self, trigger: Rule<UncheckedCtx>) -> Result<Rule<CompiledCtx<Env>>, Error>
+    /// Keep the channels of the expected kind, warning if there is none.
+    fn resolve<T>(&self, channels: Vec<(String, &ChannelKind)>, kind: &ChannelKind, location: &Location,
+        diagnostics: &mut Vec<Diagnostic>) -> Vec<Id<T>>
     {
+        if channels.is_empty() {
+            diagnostics.push(self.warning(location.clone(),
+                Error::ResolutionError(ResolutionError::NoChannel)));
+            return vec![];
+        }
+        let matched : Vec<_> = channels.iter()
+            .filter(|&&(_, channel_kind)| channel_kind == kind)
+            .map(|&(ref id, _)| Id::new(id))
+            .collect();
+        if matched.is_empty() {
+            diagnostics.push(self.warning(location.clone(),
+                Error::ResolutionError(ResolutionError::UnexpectedKind {
+                    expected: kind.clone(),
+                    channels: channels.into_iter().map(|(id, _)| id).collect(),
+                })));
+        }
+        matched
+    }
+
+    fn check_selectors(&self, len: usize, location: &Location, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        if len <= self.limits.max_selectors {
+            return true;
+        }
+        diagnostics.push(Diagnostic::error(location.clone(),
+            Error::SourceError(SourceError::TooManySelectors { max: self.limits.max_selectors })));
+        false
+    }
+
+    fn check_duration(&self, duration: &Duration, location: &Location, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let min = match self.limits.min_duration {
+            None => return true,
+            Some(ref min) => min
+        };
+        if ChronoDuration::from(duration.clone()) >= ChronoDuration::from(min.clone()) {
+            return true;
+        }
+        diagnostics.push(Diagnostic::error(location.clone(),
+            Error::SourceError(SourceError::DurationTooShort { min: min.clone() })));
+        false
+    }
+
+    fn check_retry(&self, retry: &Retry, location: &Location, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let mut is_ok = self.check_duration(&retry.delay, &location.field("delay"), diagnostics);
+        if retry.attempts > self.limits.max_retries {
+            diagnostics.push(Diagnostic::error(location.field("attempts"),
+                Error::SourceError(SourceError::TooManyRetries { max: self.limits.max_retries })));
+            is_ok = false;
+        }
+        if let Some(ref max) = self.limits.max_retry_delay {
+            if ChronoDuration::from(retry.delay.clone()) > ChronoDuration::from(max.clone()) {
+                diagnostics.push(Diagnostic::error(location.field("delay"),
+                    Error::SourceError(SourceError::RetryDelayTooLong { max: max.clone() })));
+                is_ok = false;
+            }
+        }
+        is_ok
+    }
+
+    fn warning(&self, location: Location, error: Error) -> Diagnostic {
+        Diagnostic {
+            location: location,
+            severity: if self.strict { Severity::Error } else { Severity::Warning },
+            error: error,
+        }
+    }
+
+    fn compile_script(&self, script: Script<UncheckedCtx>, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<Script<CompiledCtx<Env>>>
+    {
+        let root = Location::new();
+        let mut is_ok = true;
+        if script.rules.len() == 0 {
+            diagnostics.push(Diagnostic::error(root.field("rules"),
+                Error::SourceError(SourceError::NoRule)));
+            is_ok = false;
+        }
+        if script.rules.len() > self.limits.max_rules {
+            diagnostics.push(Diagnostic::error(root.field("rules"),
+                Error::SourceError(SourceError::TooManyRules { max: self.limits.max_rules })));
+            is_ok = false;
+        }
+        if count_watches(&script) > self.limits.max_watches {
+            diagnostics.push(Diagnostic::error(root.field("rules"),
+                Error::SourceError(SourceError::TooManyWatches { max: self.limits.max_watches })));
+            is_ok = false;
+        }
+        let mut variables = HashMap::new();
+        for (declaration, index) in script.variables.iter().zip(0..) {
+            if variables.insert(declaration.name.clone(), declaration.initial.clone()).is_some() {
+                diagnostics.push(Diagnostic::error(root.field("variables").index(index).field("name"),
+                    Error::SourceError(SourceError::DuplicateVariable(declaration.name.clone()))));
+                is_ok = false;
+            }
+        }
+        let mut channels = HashSet::new();
+        let declared = script.channels.iter().zip(0..).map(|(channel, index)| (&channel.id, "channels", index))
+            .chain(script.derived.iter().zip(0..).map(|(derived, index)| (&derived.id, "derived", index)));
+        for (id, field, index) in declared {
+            if !channels.insert(id.clone()) {
+                diagnostics.push(Diagnostic::error(root.field(field).index(index).field("id"),
+                    Error::SourceError(SourceError::DuplicateVirtualChannel(id.clone()))));
+                is_ok = false;
+            }
+        }
+        let derived = map_all(script.derived, |derived, index| {
+            self.compile_derived(derived, &root.field("derived").index(index), diagnostics)
+        });
+        let rules = map_all(script.rules, |rule, index| {
+            self.compile_rule(rule, &root.field("rules").index(index), &variables, diagnostics)
+        });
+        let script = match (derived, rules, is_ok) {
+            (Some(derived), Some(rules), true) => Script {
+                name: script.name,
+                variables: script.variables,
+                channels: script.channels,
+                derived: derived,
+                active_when: script.active_when,
+                rules: rules,
+                phantom: PhantomData
+            },
+            _ => return None
+        };
+        for cycle in DependencyGraph::new(&script).cycles() {
+            diagnostics.push(self.warning(root.field("rules").index(cycle[0]),
+                Error::DependencyError(DependencyError::FeedbackLoop(cycle))));
+        }
+        Some(script)
+    }
+
+    fn compile_rule(&self, trigger: Rule<UncheckedCtx>, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<Rule<CompiledCtx<Env>>>
+    {
+        let mut is_ok = true;
         if trigger.execute.len() == 0 {
-            return Err(Error::SourceError(SourceError::NoStatement));
+            diagnostics.push(Diagnostic::error(location.field("execute"),
+                Error::SourceError(SourceError::NoStatement)));
+            is_ok = false;
         }
-        if trigger.conditions.len() == 0 {
-            return Err(Error::SourceError(SourceError::NoMatch));
+        if trigger.conditions.len() == 0 && trigger.variable_conditions.len() == 0 {
+            diagnostics.push(Diagnostic::error(location.field("conditions"),
+                Error::SourceError(SourceError::NoMatch)));
+            is_ok = false;
+        }
+        if trigger.conditions.len() > self.limits.max_matches_per_rule {
+            diagnostics.push(Diagnostic::error(location.field("conditions"),
+                Error::SourceError(SourceError::TooManyMatches { max: self.limits.max_matches_per_rule })));
+            is_ok = false;
+        }
+        if count_statements(&trigger.execute) > self.limits.max_statements_per_rule {
+            diagnostics.push(Diagnostic::error(location.field("execute"),
+                Error::SourceError(SourceError::TooManyStatements { max: self.limits.max_statements_per_rule })));
+            is_ok = false;
+        }
+        let conditions = map_all(trigger.conditions, |match_, index| {
+            self.compile_match(match_, &location.field("conditions").index(index), diagnostics)
+        });
+        if let Some(ref conditions) = conditions {
+            self.analyze_ranges(conditions, &location.field("conditions"), diagnostics);
+        }
+        let variable_conditions = map_all(trigger.variable_conditions, |match_, index| {
+            self.compile_variable_match(match_, &location.field("variable_conditions").index(index),
+                variables, diagnostics)
+        });
+        let execute = map_all(trigger.execute, |statement, index| {
+            self.compile_statement(statement, &location.field("execute").index(index), variables, diagnostics)
+        });
+        match (conditions, variable_conditions, execute, is_ok) {
+            (Some(conditions), Some(variable_conditions), Some(execute), true) => Some(Rule {
+                conditions: conditions,
+                variable_conditions: variable_conditions,
+                active_when: trigger.active_when,
+                execute: execute,
+                phantom: PhantomData
+            }),
+            _ => None
         }
-        let conditions = try!(map(trigger.conditions, |match_| {
-            self.compile_match(match_)
-        }));
-        let execute = try!(map(trigger.execute, |statement| {
-            self.compile_statement(statement)
-        }));
-        Ok(Rule {
-            conditions: conditions,
-            execute: execute,
-            phantom: PhantomData
-        })
     }
 
-    fn compile_match(&self, match_: Match<UncheckedCtx>) -> Result<Match<CompiledCtx<Env>>, Error>
+    fn compile_match(&self, match_: Match<UncheckedCtx>, location: &Location, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<Match<CompiledCtx<Env>>>
     {
+        let mut is_ok = true;
         if match_.source.len() == 0 {
-            return Err(Error::SourceError(SourceError::NoMatchSource));
+            diagnostics.push(Diagnostic::error(location.field("source"),
+                Error::SourceError(SourceError::NoMatchSource)));
+            is_ok = false;
         }
-        let typ = match match_.range.get_type() {
-            Err(_) => return Err(Error::TypeError(TypeError::InvalidRange)),
-            Ok(typ) => typ
-        };
-        if match_.kind.get_type() != typ {
-            return Err(Error::TypeError(TypeError::KindAndRangeDoNotAgree));
+        is_ok &= self.check_selectors(match_.source.len(), &location.field("source"), diagnostics);
+        if let Some(ref duration) = match_.duration {
+            is_ok &= self.check_duration(duration, &location.field("duration"), diagnostics);
+        }
+        match match_.range.get_type() {
+            Err(_) => {
+                diagnostics.push(Diagnostic::error(location.field("range"),
+                    Error::TypeError(TypeError::InvalidRange)));
+                is_ok = false;
+            }
+            Ok(typ) => if match_.kind.get_type() != typ {
+                diagnostics.push(Diagnostic::error(location.field("range"),
+                    Error::TypeError(TypeError::KindAndRangeDoNotAgree)));
+                is_ok = false;
+            }
+        }
+        if !is_ok {
+            return None;
         }
         let source = match_.source
             .iter()
             .map(|input| input.clone()
                  .with_kind(match_.kind.clone()))
             .collect();
-        Ok(Match {
+        Some(Match {
             source: source,
             kind: match_.kind,
             range: match_.range,
@@ -178,24 +1066,387 @@ impl<Env> Compiler<Env> where Env: ExecutableDevEnv {
         })
     }
 
-    fn compile_statement(&self, statement: Statement<UncheckedCtx>) -> Result<Statement<CompiledCtx<Env>>, Error>
+    /// Analyze the ranges of the matches of a rule.
+    ///
+    /// A selector may designate several getters, and a match is met as soon
+    /// as one of them has a value in range, so matches are only compared
+    /// with one another if they watch the same single getter (see
+    /// `is_single_getter`).
+    fn analyze_ranges(&self, conditions: &[Match<CompiledCtx<Env>>], location: &Location,
+        diagnostics: &mut Vec<Diagnostic>)
+    {
+        let domains : Vec<_> = conditions.iter().map(|match_| domain(&match_.kind)).collect();
+        let ranges : Vec<_> = conditions.iter().zip(&domains)
+            .map(|(match_, domain)| intersect(&to_intervals(&match_.range), domain))
+            .collect();
+        let is_comparable = |a: &Match<CompiledCtx<Env>>, b: &Match<CompiledCtx<Env>>|
+            is_single_getter(a) && a.kind == b.kind && a.source == b.source;
+        for (match_, index) in conditions.iter().zip(0..) {
+            if is_subset(&domains[index], &ranges[index]) {
+                diagnostics.push(self.warning(location.index(index).field("range"),
+                    Error::RangeError(RangeError::AlwaysTrue)));
+            }
+
+            // Is this match compatible with the previous matches on the same getter?
+            let previous : Vec<_> = (0..index).filter(|&i| is_comparable(&conditions[i], match_)).collect();
+            let previous_intersection = previous.iter()
+                .fold(domains[index].clone(), |intersection, &i| intersect(&intersection, &ranges[i]));
+            // If the previous matches are already unsatisfiable, this has been reported.
+            if !previous_intersection.is_empty() && intersect(&previous_intersection, &ranges[index]).is_empty() {
+                diagnostics.push(self.warning(location.index(index).field("range"),
+                    Error::RangeError(RangeError::Unsatisfiable)));
+            }
+
+            // Is this match implied by another match on the same getter? Matches with a
+            // duration add a constraint of their own, so they are never redundant.
+            if match_.duration.is_some() {
+                continue;
+            }
+            let is_redundant = conditions.iter().zip(0..).any(|(other, i)| {
+                i != index && is_comparable(other, match_) &&
+                    !ranges[i].is_empty() &&
+                    is_subset(&ranges[i], &ranges[index]) &&
+                    // If both ranges are equal, only report the second one.
+                    (i < index || !is_subset(&ranges[index], &ranges[i]))
+            });
+            if is_redundant {
+                diagnostics.push(self.warning(location.index(index),
+                    Error::RangeError(RangeError::Redundant)));
+            }
+        }
+    }
+
+    fn compile_derived(&self, derived: DerivedGetter<UncheckedCtx>, location: &Location,
+        diagnostics: &mut Vec<Diagnostic>) -> Option<DerivedGetter<CompiledCtx<Env>>>
+    {
+        let mut is_ok = true;
+        if derived.source.len() == 0 {
+            diagnostics.push(Diagnostic::error(location.field("source"),
+                Error::SourceError(SourceError::NoDerivedSource(derived.id.clone()))));
+            is_ok = false;
+        }
+        is_ok &= self.check_selectors(derived.source.len(), &location.field("source"), diagnostics);
+        let source_type = derived.source_kind.get_type();
+        let type_is_ok = match derived.aggregate {
+            Aggregate::Any(ref range) | Aggregate::All(ref range) => {
+                match range.get_type() {
+                    Err(_) => {
+                        diagnostics.push(Diagnostic::error(location.field("aggregate"),
+                            Error::TypeError(TypeError::InvalidRange)));
+                        is_ok = false;
+                        true
+                    }
+                    Ok(typ) if typ != source_type => {
+                        diagnostics.push(Diagnostic::error(location.field("aggregate"),
+                            Error::TypeError(TypeError::KindAndRangeDoNotAgree)));
+                        is_ok = false;
+                        true
+                    }
+                    Ok(_) => derived.kind.get_type() == Type::OnOff
+                }
+            }
+            Aggregate::Min | Aggregate::Max => derived.kind.get_type() == source_type,
+            Aggregate::Average => derived.kind.get_type() == Type::Temperature && source_type == Type::Temperature,
+        };
+        if !type_is_ok {
+            diagnostics.push(Diagnostic::error(location.field("aggregate"),
+                Error::TypeError(TypeError::AggregateDoesNotAgree(derived.id.clone()))));
+            is_ok = false;
+        }
+        if !is_ok {
+            return None;
+        }
+        let source = derived.source
+            .iter()
+            .map(|input| input.clone()
+                 .with_kind(derived.source_kind.clone()))
+            .collect();
+        Some(DerivedGetter {
+            id: derived.id,
+            kind: derived.kind,
+            tags: derived.tags,
+            source: source,
+            source_kind: derived.source_kind,
+            aggregate: derived.aggregate,
+            phantom: PhantomData
+        })
+    }
+
+    fn compile_variable_match(&self, match_: VariableMatch, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>) -> Option<VariableMatch>
     {
+        match variables.get(&match_.variable) {
+            None => {
+                diagnostics.push(Diagnostic::error(location.field("variable"),
+                    Error::SourceError(SourceError::UnknownVariable(match_.variable))));
+                None
+            }
+            Some(initial) if !match_.range.accepts_type_of(initial) => {
+                diagnostics.push(Diagnostic::error(location.field("range"),
+                    Error::TypeError(TypeError::VariableTypeDoesNotAgree(match_.variable))));
+                None
+            }
+            Some(_) => Some(match_)
+        }
+    }
+
+    fn compile_statement(&self, statement: Statement<UncheckedCtx>, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<Statement<CompiledCtx<Env>>>
+    {
+        match statement {
+            Statement::Send(send) =>
+                self.compile_send(send, location, variables, diagnostics).map(Statement::Send),
+            Statement::SetVariable(set) =>
+                self.compile_set_variable(set, location, variables, diagnostics).map(Statement::SetVariable)
+        }
+    }
+
+    fn compile_send(&self, statement: SendValues<UncheckedCtx>, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<SendValues<CompiledCtx<Env>>>
+    {
+        let mut is_ok = true;
         if statement.destination.len() == 0 {
-            return Err(Error::SourceError(SourceError::NoStatementDestination));
+            diagnostics.push(Diagnostic::error(location.field("destination"),
+                Error::SourceError(SourceError::NoStatementDestination)));
+            is_ok = false;
         }
+        is_ok &= self.check_selectors(statement.destination.len(), &location.field("destination"), diagnostics);
         if statement.kind.get_type() != statement.value.get_type() {
-            return Err(Error::TypeError(TypeError::KindAndValueDoNotAgree));
+            diagnostics.push(Diagnostic::error(location.field("value"),
+                Error::TypeError(TypeError::KindAndValueDoNotAgree)));
+            is_ok = false;
+        }
+        let on_error = match statement.on_error {
+            None => None,
+            Some(policy) => {
+                match self.compile_error_policy(policy, &statement.kind, &location.field("on_error"),
+                    variables, diagnostics) {
+                    None => {
+                        is_ok = false;
+                        None
+                    }
+                    Some(policy) => Some(policy)
+                }
+            }
+        };
+        if !is_ok {
+            return None;
         }
         let destination = statement.destination
             .iter()
             .map(|output| output.clone()
                  .with_kind(statement.kind.clone()))
             .collect();
-        Ok(Statement {
+        Some(SendValues {
             destination: destination,
             value: statement.value,
             kind: statement.kind,
+            on_error: on_error,
             phantom: PhantomData
         })
     }
+
+    fn compile_error_policy(&self, policy: ErrorPolicy<UncheckedCtx>, kind: &ChannelKind, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>)
+        -> Option<ErrorPolicy<CompiledCtx<Env>>>
+    {
+        let mut is_ok = self.check_selectors(policy.fallback.len(), &location.field("fallback"), diagnostics);
+        if let Some(ref retry) = policy.retry {
+            is_ok &= self.check_retry(retry, &location.field("retry"), diagnostics);
+        }
+        let fallback = policy.fallback
+            .iter()
+            .map(|output| output.clone()
+                 .with_kind(kind.clone()))
+            .collect();
+        let execute = match map_all(policy.execute, |statement, index| {
+            self.compile_statement(statement, &location.field("execute").index(index), variables, diagnostics)
+        }) {
+            Some(execute) if is_ok => execute,
+            _ => return None
+        };
+        Some(ErrorPolicy {
+            retry: policy.retry,
+            fallback: fallback,
+            execute: execute,
+            phantom: PhantomData
+        })
+    }
+
+    fn compile_set_variable(&self, statement: SetVariable, location: &Location,
+        variables: &HashMap<String, VariableValue>, diagnostics: &mut Vec<Diagnostic>) -> Option<SetVariable>
+    {
+        let initial = match variables.get(&statement.variable) {
+            None => {
+                diagnostics.push(Diagnostic::error(location.field("variable"),
+                    Error::SourceError(SourceError::UnknownVariable(statement.variable))));
+                return None;
+            }
+            Some(initial) => initial
+        };
+        let type_is_ok = match (&statement.operation, initial) {
+            (&VariableOp::Reset, _) => true,
+            (&VariableOp::Increment(_), &VariableValue::Counter(_)) => true,
+            (&VariableOp::Increment(_), _) => false,
+            (&VariableOp::Set(ref value), _) => value.has_same_type(initial),
+        };
+        if !type_is_ok {
+            diagnostics.push(Diagnostic::error(location.field("operation"),
+                Error::TypeError(TypeError::VariableTypeDoesNotAgree(statement.variable))));
+            return None;
+        }
+        Some(statement)
+    }
+}
+
+/// Determine whether some value may be in both ranges.
+pub fn ranges_overlap(a: &Range, b: &Range) -> bool {
+    !intersect(&to_intervals(a), &to_intervals(b)).is_empty()
+}
+
+/// A bound of an interval. `None` stands for infinity.
+#[derive(Clone)]
+struct Bound {
+    value: Value,
+    is_inclusive: bool,
+}
+
+/// An interval of values, between `min` and `max`.
+#[derive(Clone)]
+struct Interval {
+    min: Option<Bound>,
+    max: Option<Bound>,
+}
+
+fn leq(a: &Value, b: &Value) -> bool {
+    Range::Leq(b.clone()).contains(a)
+}
+
+fn lt(a: &Value, b: &Value) -> bool {
+    leq(a, b) && !leq(b, a)
+}
+
+impl Interval {
+    fn is_empty(&self) -> bool {
+        match (&self.min, &self.max) {
+            (&Some(ref min), &Some(ref max)) =>
+                lt(&max.value, &min.value) ||
+                (!lt(&min.value, &max.value) && !(min.is_inclusive && max.is_inclusive)),
+            _ => false
+        }
+    }
+
+    fn intersect(&self, other: &Interval) -> Interval {
+        // The largest of the lower bounds.
+        let min = match (&self.min, &other.min) {
+            (&None, bound) | (bound, &None) => bound.clone(),
+            (&Some(ref a), &Some(ref b)) =>
+                if lt(&a.value, &b.value) || (leq(&a.value, &b.value) && !b.is_inclusive) {
+                    Some(b.clone())
+                } else {
+                    Some(a.clone())
+                }
+        };
+        // The smallest of the upper bounds.
+        let max = match (&self.max, &other.max) {
+            (&None, bound) | (bound, &None) => bound.clone(),
+            (&Some(ref a), &Some(ref b)) =>
+                if lt(&b.value, &a.value) || (leq(&b.value, &a.value) && !b.is_inclusive) {
+                    Some(b.clone())
+                } else {
+                    Some(a.clone())
+                }
+        };
+        Interval {
+            min: min,
+            max: max,
+        }
+    }
+
+    /// Determine whether `self` is included in `other`.
+    fn is_subset(&self, other: &Interval) -> bool {
+        let min_is_ok = match (&self.min, &other.min) {
+            (_, &None) => true,
+            (&None, &Some(_)) => false,
+            (&Some(ref a), &Some(ref b)) => lt(&b.value, &a.value) ||
+                (leq(&b.value, &a.value) && (b.is_inclusive || !a.is_inclusive))
+        };
+        let max_is_ok = match (&self.max, &other.max) {
+            (_, &None) => true,
+            (&None, &Some(_)) => false,
+            (&Some(ref a), &Some(ref b)) => lt(&a.value, &b.value) ||
+                (leq(&a.value, &b.value) && (b.is_inclusive || !a.is_inclusive))
+        };
+        min_is_ok && max_is_ok
+    }
+}
+
+/// Convert a range into a union of non-empty intervals.
+fn to_intervals(range: &Range) -> Vec<Interval> {
+    let inclusive = |value: &Value| Some(Bound { value: value.clone(), is_inclusive: true });
+    let strict = |value: &Value| Some(Bound { value: value.clone(), is_inclusive: false });
+    let intervals = match *range {
+        Range::Leq(ref max) => vec![Interval { min: None, max: inclusive(max) }],
+        Range::Geq(ref min) => vec![Interval { min: inclusive(min), max: None }],
+        Range::Eq(ref value) => vec![Interval { min: inclusive(value), max: inclusive(value) }],
+        Range::BetweenEq { ref min, ref max } => vec![Interval { min: inclusive(min), max: inclusive(max) }],
+        Range::OutOfStrict { ref min, ref max } if lt(max, min) => vec![Interval { min: None, max: None }],
+        Range::OutOfStrict { ref min, ref max } => vec![
+            Interval { min: None, max: strict(min) },
+            Interval { min: strict(max), max: None }
+        ],
+    };
+    intervals.into_iter().filter(|interval| !interval.is_empty()).collect()
+}
+
+fn intersect(a: &[Interval], b: &[Interval]) -> Vec<Interval> {
+    let mut result = vec![];
+    for x in a {
+        for y in b {
+            let interval = x.intersect(y);
+            if !interval.is_empty() {
+                result.push(interval);
+            }
+        }
+    }
+    result
+}
+
+/// Determine whether all the values of `a` are in `b`.
+fn is_subset(a: &[Interval], b: &[Interval]) -> bool {
+    a.iter().all(|x| b.iter().any(|y| x.is_subset(y)))
+}
+
+/// The values that the channels of a kind may take, as a union of
+/// intervals.
+fn domain(kind: &ChannelKind) -> Vec<Interval> {
+    let inclusive = |value: Value| Some(Bound { value: value, is_inclusive: true });
+    let point = |value: Value| Interval { min: inclusive(value.clone()), max: inclusive(value) };
+    let zero = || Value::Duration(Duration::from(ChronoDuration::seconds(0)));
+    match kind.get_type() {
+        Type::Unit => vec![point(Value::Unit)],
+        Type::OnOff => vec![point(Value::OnOff(OnOff::Off)), point(Value::OnOff(OnOff::On))],
+        Type::OpenClosed => vec![point(Value::OpenClosed(OpenClosed::Closed)), point(Value::OpenClosed(OpenClosed::Open))],
+        Type::Duration if *kind == ChannelKind::CurrentTimeOfDay => vec![Interval {
+            min: inclusive(zero()),
+            max: Some(Bound {
+                value: Value::Duration(Duration::from(ChronoDuration::days(1))),
+                is_inclusive: false
+            }),
+        }],
+        Type::Duration => vec![Interval { min: inclusive(zero()), max: None }],
+        _ => vec![Interval { min: None, max: None }]
+    }
+}
+
+/// `true` if the source of a match designates at most one getter, i.e.
+/// a single selector with an id, or the clock: all the getters of
+/// `CurrentTimeOfDay` report the same value.
+fn is_single_getter<Ctx>(match_: &Match<Ctx>) -> bool where Ctx: Context {
+    match_.source.len() == 1 && match (&match_.source[0].id, &match_.kind) {
+        (&Exactly::Exactly(_), _) | (_, &ChannelKind::CurrentTimeOfDay) => true,
+        _ => false
+    }
 }
@@ -0,0 +1,108 @@
+//! Detecting scripts that fight over the same devices.
+//!
+//! Two rules conflict if they send different values to setters of the
+//! same kind and if their conditions may be met at the same time. As
+//! with `dependencies`, the analysis relies on kinds rather than on
+//! actual devices, so it may report conflicts between rules that
+//! control distinct devices of the same kind.
+
+use ast::{ ActiveWhen, Context, Rule, Script, Statement };
+use compile::ranges_overlap;
+use manager::ScriptId;
+
+use foxbox_taxonomy::services::ChannelKind;
+use foxbox_taxonomy::util::Id;
+use foxbox_taxonomy::values::Value;
+
+/// A potential conflict between a statement of a script and a statement
+/// of another script.
+#[derive(Clone, Debug, Serialize)]
+pub struct Conflict {
+    /// The other script.
+    pub script: Id<ScriptId>,
+
+    /// The rule and statement in the script being analyzed.
+    pub rule_index: usize,
+    pub statement_index: usize,
+
+    /// The rule and statement in the other script.
+    pub other_rule_index: usize,
+    pub other_statement_index: usize,
+
+    /// The kind of setters targeted by both statements.
+    pub kind: ChannelKind,
+
+    /// The value sent by the script being analyzed.
+    pub value: Value,
+
+    /// The value sent by the other script.
+    pub other_value: Value,
+}
+
+/// Find the conflicts between a script and another script.
+pub fn find_conflicts<Ctx>(script: &Script<Ctx>, other_id: &Id<ScriptId>, other: &Script<Ctx>) -> Vec<Conflict>
+    where Ctx: Context
+{
+    let mut conflicts = vec![];
+    for (rule, rule_index) in script.rules.iter().zip(0..) {
+        for (other_rule, other_rule_index) in other.rules.iter().zip(0..) {
+            if !may_be_met_together(script, rule, other, other_rule) {
+                continue;
+            }
+            for (statement, statement_index) in rule.execute.iter().zip(0..) {
+                let send = match *statement {
+                    Statement::Send(ref send) => send,
+                    Statement::SetVariable(_) => continue
+                };
+                for (other_statement, other_statement_index) in other_rule.execute.iter().zip(0..) {
+                    let other_send = match *other_statement {
+                        Statement::Send(ref send) => send,
+                        Statement::SetVariable(_) => continue
+                    };
+                    if send.kind != other_send.kind || send.value == other_send.value {
+                        continue;
+                    }
+                    conflicts.push(Conflict {
+                        script: other_id.clone(),
+                        rule_index: rule_index,
+                        statement_index: statement_index,
+                        other_rule_index: other_rule_index,
+                        other_statement_index: other_statement_index,
+                        kind: send.kind.clone(),
+                        value: send.value.clone(),
+                        other_value: other_send.value.clone(),
+                    });
+                }
+            }
+        }
+    }
+    conflicts
+}
+
+/// Determine whether the conditions of two rules of distinct scripts may be met
+/// at the same time.
+fn may_be_met_together<Ctx>(script: &Script<Ctx>, rule: &Rule<Ctx>, other: &Script<Ctx>, other_rule: &Rule<Ctx>)
+    -> bool where Ctx: Context
+{
+    let ranges_agree = rule.conditions.iter().all(|condition| {
+        other_rule.conditions.iter()
+            .filter(|other_condition| other_condition.kind == condition.kind)
+            .all(|other_condition| ranges_overlap(&condition.range, &other_condition.range))
+    });
+    if !ranges_agree {
+        return false;
+    }
+
+    // The house is only ever in one mode.
+    let modes = |script: &Script<Ctx>, rule: &Rule<Ctx>| -> Vec<String> {
+        script.active_when.iter().chain(rule.active_when.iter())
+            .filter_map(|active_when| match *active_when {
+                ActiveWhen::Mode(ref mode) => Some(mode.clone()),
+                ActiveWhen::Window(_) => None
+            })
+            .collect()
+    };
+    let mut all_modes = modes(script, rule);
+    all_modes.extend(modes(other, other_rule));
+    all_modes.windows(2).all(|pair| pair[0] == pair[1])
+}
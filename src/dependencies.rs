@@ -0,0 +1,262 @@
+//! Dependencies between the rules of a script.
+//!
+//! A rule depends on another rule if the statements of the latter may
+//! change something that the conditions of the former observe: a channel
+//! (possibly through a derived getter) or a variable. Dependencies are
+//! computed from the selectors of the script rather than from actual
+//! devices, so they are an over-approximation: a statement and a match of
+//! the same kind are considered dependent unless their selectors designate
+//! distinct services, since a device usually exposes its getters and
+//! setters under distinct ids but in the same service.
+//!
+//! Cycles in the dependency graph are potential feedback loops, e.g. a
+//! rule that turns a light off when it is on and another rule that turns
+//! it on when it is off.
+
+use ast::{ Context, DerivedGetter, Script, Statement };
+
+use foxbox_taxonomy::selector::{ GetterSelector, SetterSelector };
+use foxbox_taxonomy::services::{ ChannelKind, Getter, ServiceId };
+use foxbox_taxonomy::util::{ Exactly, Id };
+
+use std::cmp::min;
+
+/// The dependency graph of the rules of a script.
+pub struct DependencyGraph {
+    /// For each rule, the rules that may be triggered by its statements.
+    edges: Vec<Vec<usize>>,
+}
+
+/// The channels designated by the selectors of a statement or a match.
+struct Channels {
+    kind: ChannelKind,
+
+    /// The services to which the channels belong, or `None` if some
+    /// selector does not restrict the service.
+    services: Option<Vec<Id<ServiceId>>>,
+}
+
+impl Channels {
+    fn new<I>(kind: &ChannelKind, parents: I) -> Self where I: Iterator<Item=Exactly<Id<ServiceId>>> {
+        let mut services = Some(vec![]);
+        for parent in parents {
+            services = match (services, parent) {
+                (Some(mut services), Exactly::Exactly(id)) => {
+                    services.push(id);
+                    Some(services)
+                }
+                _ => None
+            };
+        }
+        Channels {
+            kind: kind.clone(),
+            services: services,
+        }
+    }
+
+    fn of_setters(kind: &ChannelKind, selectors: &[SetterSelector]) -> Self {
+        Self::new(kind, selectors.iter().map(|selector| selector.parent.clone()))
+    }
+
+    fn of_getters(kind: &ChannelKind, selectors: &[GetterSelector]) -> Self {
+        Self::new(kind, selectors.iter().map(|selector| selector.parent.clone()))
+    }
+
+    fn intersects(&self, other: &Channels) -> bool {
+        if self.kind != other.kind {
+            return false;
+        }
+        match (&self.services, &other.services) {
+            (&Some(ref services), &Some(ref others)) => services.iter().any(|service| others.contains(service)),
+            _ => true
+        }
+    }
+}
+
+/// What a rule writes or reads.
+#[derive(Default)]
+struct Footprint {
+    channels: Vec<Channels>,
+
+    /// The indices of the derived getters of the script.
+    derived: Vec<usize>,
+    variables: Vec<String>,
+}
+
+impl Footprint {
+    fn add_derived(&mut self, index: usize) -> bool {
+        if self.derived.contains(&index) {
+            return false;
+        }
+        self.derived.push(index);
+        true
+    }
+
+    fn intersects(&self, other: &Footprint) -> bool {
+        self.channels.iter().any(|channels| other.channels.iter().any(|other| channels.intersects(other))) ||
+            self.derived.iter().any(|index| other.derived.contains(index)) ||
+            self.variables.iter().any(|variable| other.variables.contains(variable))
+    }
+}
+
+fn add_writes<Ctx>(statements: &[Statement<Ctx>], writes: &mut Footprint) where Ctx: Context {
+    for statement in statements {
+        match *statement {
+            Statement::Send(ref send) => {
+                writes.channels.push(Channels::of_setters(&send.kind, &send.destination));
+                if let Some(ref policy) = send.on_error {
+                    writes.channels.push(Channels::of_setters(&send.kind, &policy.fallback));
+                    add_writes(&policy.execute, writes);
+                }
+            }
+            Statement::SetVariable(ref set) => writes.variables.push(set.variable.clone())
+        }
+    }
+}
+
+/// `true` if some selector of `source` may designate the derived getter. Derived
+/// getters are published with the id of their declaration.
+fn may_read_derived<Ctx>(kind: &ChannelKind, source: &[GetterSelector], derived: &DerivedGetter<Ctx>) -> bool
+    where Ctx: Context
+{
+    let id = Id::<Getter>::new(&derived.id);
+    *kind == derived.kind && source.iter().any(|selector| match selector.id {
+        Exactly::Exactly(ref selected) => *selected == id,
+        _ => true
+    })
+}
+
+impl DependencyGraph {
+    pub fn new<Ctx>(script: &Script<Ctx>) -> Self where Ctx: Context {
+        let sources : Vec<_> = script.derived.iter()
+            .map(|derived| Channels::of_getters(&derived.source_kind, &derived.source))
+            .collect();
+        let writes : Vec<_> = script.rules.iter().map(|rule| {
+            let mut writes = Footprint::default();
+            add_writes(&rule.execute, &mut writes);
+            // Writing to the sources of a derived getter also changes the derived getter,
+            // as does changing another derived getter among its sources.
+            let mut has_changed = true;
+            while has_changed {
+                has_changed = false;
+                for (derived, index) in script.derived.iter().zip(0..) {
+                    let is_written = writes.channels.iter().any(|channels| channels.intersects(&sources[index])) ||
+                        writes.derived.iter().any(|&other| {
+                            may_read_derived(&derived.source_kind, &derived.source, &script.derived[other])
+                        });
+                    if is_written {
+                        has_changed |= writes.add_derived(index);
+                    }
+                }
+            }
+            writes
+        }).collect();
+        let reads : Vec<_> = script.rules.iter().map(|rule| {
+            let mut reads = Footprint::default();
+            for condition in &rule.conditions {
+                reads.channels.push(Channels::of_getters(&condition.kind, &condition.source));
+                for (derived, index) in script.derived.iter().zip(0..) {
+                    if may_read_derived(&condition.kind, &condition.source, derived) {
+                        reads.add_derived(index);
+                    }
+                }
+            }
+            for condition in &rule.variable_conditions {
+                reads.variables.push(condition.variable.clone());
+            }
+            reads
+        }).collect();
+        let edges = writes.iter().map(|writes| {
+            reads.iter().zip(0..)
+                .filter(|&(reads, _)| writes.intersects(reads))
+                .map(|(_, target)| target)
+                .collect()
+        }).collect();
+        DependencyGraph {
+            edges: edges
+        }
+    }
+
+    /// The rules that may be triggered by the statements of a rule.
+    pub fn dependents(&self, rule_index: usize) -> &[usize] {
+        &self.edges[rule_index]
+    }
+
+    /// Find the groups of rules that may trigger each other indefinitely.
+    ///
+    /// Each group is a strongly connected component of the graph that
+    /// contains a cycle, with rules sorted by index.
+    pub fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut tarjan = Tarjan {
+            edges: &self.edges,
+            index: 0,
+            indices: vec![None; self.edges.len()],
+            lowlinks: vec![0; self.edges.len()],
+            stack: vec![],
+            on_stack: vec![false; self.edges.len()],
+            components: vec![],
+        };
+        for node in 0..self.edges.len() {
+            if tarjan.indices[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+        let mut cycles : Vec<_> = tarjan.components.into_iter()
+            .filter(|component| component.len() > 1 || self.edges[component[0]].contains(&component[0]))
+            .map(|mut component| {
+                component.sort();
+                component
+            })
+            .collect();
+        cycles.sort();
+        cycles
+    }
+}
+
+/// Tarjan's algorithm for strongly connected components.
+struct Tarjan<'a> {
+    edges: &'a [Vec<usize>],
+    index: usize,
+    indices: Vec<Option<usize>>,
+    lowlinks: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, node: usize) {
+        self.indices[node] = Some(self.index);
+        self.lowlinks[node] = self.index;
+        self.index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        let edges = self.edges;
+        for &target in &edges[node] {
+            match self.indices[target] {
+                None => {
+                    self.visit(target);
+                    self.lowlinks[node] = min(self.lowlinks[node], self.lowlinks[target]);
+                }
+                Some(index) if self.on_stack[target] => {
+                    self.lowlinks[node] = min(self.lowlinks[node], index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.lowlinks[node]) == self.indices[node] {
+            let mut component = vec![];
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
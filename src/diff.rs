@@ -0,0 +1,76 @@
+//! Comparing two versions of a script, to determine which rules may be
+//! kept as they are when a running script is replaced.
+//!
+//! Rules are compared structurally. A rule of the new script is kept if
+//! it is identical to a rule of the old script, even if it has moved.
+//! All the other rules are considered added, and the old rules that are
+//! not kept are considered removed.
+
+use ast::{ Context, Script };
+
+/// The differences between two versions of a script.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScriptDiff {
+    /// For each rule of the new script, the index of the identical rule of
+    /// the old script, or `None` if the rule is new or has changed.
+    pub kept: Vec<Option<usize>>,
+
+    /// The indices of the rules of the old script that are not kept.
+    pub removed: Vec<usize>,
+
+    /// `true` if the two scripts declare different variables, virtual
+    /// channels or derived getters. Such scripts cannot be replaced
+    /// without restarting them.
+    pub needs_restart: bool,
+
+    /// `true` if the two scripts are active at different times or in
+    /// different modes. Kept rules must then be evaluated again.
+    pub active_when_has_changed: bool,
+}
+
+impl ScriptDiff {
+    /// The indices of the rules of the new script that are not kept.
+    pub fn added(&self) -> Vec<usize> {
+        self.kept.iter().enumerate()
+            .filter(|&(_, kept)| kept.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// `true` if the two scripts have the same rules, in the same order.
+    pub fn is_unchanged(&self) -> bool {
+        !self.needs_restart && !self.active_when_has_changed && self.removed.is_empty() &&
+            self.kept.iter().enumerate().all(|(index, kept)| *kept == Some(index))
+    }
+}
+
+/// Compare two versions of a script.
+pub fn diff<Ctx>(old: &Script<Ctx>, new: &Script<Ctx>) -> ScriptDiff where Ctx: Context {
+    let mut is_kept = vec![false; old.rules.len()];
+    let kept = new.rules.iter().enumerate().map(|(new_index, rule)| {
+        // Prefer the rule at the same position, so that duplicate rules
+        // keep their state.
+        let position = if new_index < old.rules.len() && !is_kept[new_index] && old.rules[new_index] == *rule {
+            Some(new_index)
+        } else {
+            old.rules.iter().enumerate()
+                .position(|(old_index, old_rule)| !is_kept[old_index] && old_rule == rule)
+        };
+        if let Some(old_index) = position {
+            is_kept[old_index] = true;
+        }
+        position
+    }).collect();
+    let removed = is_kept.iter().enumerate()
+        .filter(|&(_, &is_kept)| !is_kept)
+        .map(|(index, _)| index)
+        .collect();
+    ScriptDiff {
+        kept: kept,
+        removed: removed,
+        needs_restart: old.variables != new.variables ||
+            old.channels != new.channels ||
+            old.derived != new.derived,
+        active_when_has_changed: old.active_when != new.active_when,
+    }
+}
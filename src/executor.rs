@@ -0,0 +1,154 @@
+//! Multiplexing many tasks on a small pool of threads.
+//!
+//! Each task is pinned to one worker thread, which receives the messages
+//! of all its tasks through a single channel. Messages for a given task
+//! are therefore handled in order, one at a time, and a task never needs
+//! to be `Send` once it has started.
+//!
+//! A task that panics is dropped, without affecting the other tasks of
+//! its worker thread.
+
+use transformable_channels::mpsc::*;
+
+use std::collections::HashMap;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+
+/// A task running on a worker thread.
+pub trait Task<Op> {
+    /// Handle a message sent to the task. Return `false` once the task is
+    /// complete, in which case it is dropped.
+    fn handle(&mut self, op: Op) -> bool;
+
+    /// Called if `handle` has panicked, before the task is dropped. The
+    /// task may be in an inconsistent state, so this should only report
+    /// the failure.
+    fn panicked(&mut self) {}
+}
+
+/// A task waiting to be started on a worker thread.
+pub trait Spawn<Op>: Send {
+    /// Start the task, on the worker thread. `tx` sends messages to the
+    /// task. Return `None` if the task could not start. If this panics, the
+    /// task is dropped.
+    fn spawn(self: Box<Self>, tx: Box<ExtSender<Op>>) -> Option<Box<Task<Op>>>;
+}
+
+enum WorkerOp<Op> {
+    Spawn(usize, Box<Spawn<Op>>),
+    Handle(usize, Op),
+
+    /// All the handles to the pool have been dropped. The worker stops
+    /// once it has no more tasks.
+    Shutdown,
+}
+
+struct Workers<Op> where Op: Send + 'static {
+    senders: Mutex<Vec<Sender<WorkerOp<Op>>>>,
+    next_key: AtomicUsize,
+}
+
+impl<Op> Drop for Workers<Op> where Op: Send + 'static {
+    fn drop(&mut self) {
+        for tx in self.senders.lock().unwrap().iter() {
+            let _ = tx.send(WorkerOp::Shutdown);
+        }
+    }
+}
+
+/// A handle to a pool of worker threads.
+///
+/// Cloning the handle does not create new threads. The threads stop once
+/// all the handles have been dropped and all their tasks are complete.
+pub struct Pool<Op> where Op: Send + 'static {
+    workers: Arc<Workers<Op>>,
+}
+
+impl<Op> Clone for Pool<Op> where Op: Send + 'static {
+    fn clone(&self) -> Self {
+        Pool {
+            workers: self.workers.clone()
+        }
+    }
+}
+
+impl<Op> Pool<Op> where Op: Send + 'static {
+    /// Create a pool with `size` worker threads (at least one).
+    pub fn new(size: usize) -> Self {
+        let senders = (0..if size == 0 { 1 } else { size }).map(|_| {
+            let (tx, rx) = channel();
+            let tx_worker = tx.clone();
+            thread::spawn(move || {
+                run_worker(tx_worker, rx)
+            });
+            tx
+        }).collect();
+        Pool {
+            workers: Arc::new(Workers {
+                senders: Mutex::new(senders),
+                next_key: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    /// The number of worker threads.
+    pub fn size(&self) -> usize {
+        self.workers.senders.lock().unwrap().len()
+    }
+
+    /// Start a task on one of the worker threads. Tasks are distributed
+    /// among workers in a round-robin manner.
+    pub fn spawn(&self, task: Box<Spawn<Op>>) {
+        let key = self.workers.next_key.fetch_add(1, Ordering::Relaxed);
+        let senders = self.workers.senders.lock().unwrap();
+        let _ = senders[key % senders.len()].send(WorkerOp::Spawn(key, task));
+    }
+}
+
+fn run_worker<Op>(tx: Sender<WorkerOp<Op>>, rx: Receiver<WorkerOp<Op>>) where Op: Send + 'static {
+    let mut tasks : HashMap<usize, Box<Task<Op>>> = HashMap::new();
+    let mut is_shutting_down = false;
+    for msg in rx {
+        match msg {
+            WorkerOp::Spawn(key, spawn) => {
+                let tx_task = Box::new(tx.map(move |op| WorkerOp::Handle(key, op)));
+                let result = catch_unwind(AssertUnwindSafe(move || spawn.spawn(tx_task)));
+                match result {
+                    Ok(Some(task)) => {
+                        tasks.insert(key, task);
+                    }
+                    Ok(None) => {}
+                    Err(_) => warn!("[Executor] A task panicked while starting, dropping it.")
+                }
+            }
+            WorkerOp::Handle(key, op) => {
+                let is_running = match tasks.get_mut(&key) {
+                    // The task is complete, drop the message.
+                    None => continue,
+                    Some(task) => {
+                        let result = catch_unwind(AssertUnwindSafe(|| task.handle(op)));
+                        match result {
+                            Ok(is_running) => is_running,
+                            Err(_) => {
+                                warn!("[Executor] A task panicked, dropping it.");
+                                task.panicked();
+                                false
+                            }
+                        }
+                    }
+                };
+                if !is_running {
+                    tasks.remove(&key);
+                }
+            }
+            WorkerOp::Shutdown => {
+                is_shutting_down = true;
+            }
+        }
+        if is_shutting_down && tasks.is_empty() {
+            return;
+        }
+    }
+}
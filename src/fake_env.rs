@@ -1,4 +1,6 @@
 use compile::ExecutableDevEnv;
+use permissions::{ InMemoryPolicy, PermissionPolicy };
+use virtual_channels::VirtualChannels;
 
 use foxbox_taxonomy::api::{ API, Error, User };
 use foxbox_taxonomy::manager::*;
@@ -10,11 +12,10 @@ use std::fmt;
 use std::collections::{ BinaryHeap, HashMap };
 use std::sync::{ Arc, Mutex };
 use std::sync::atomic::{ AtomicBool, Ordering as AtomicOrdering };
-use std::thread;
 
 use transformable_channels::mpsc::*;
 
-use chrono::{ DateTime, UTC };
+use chrono::{ DateTime, Duration as ChronoDuration, UTC };
 
 use serde::ser::{Serialize, Serializer};
 use serde::de::{Deserialize, Deserializer};
@@ -24,6 +25,9 @@ use serde::de::{Deserialize, Deserializer};
 static VERSION : [u32;4] = [0, 0, 0, 0];
 
 /// A back-end holding values and watchers, shared by all the virtual adapters of this simulator.
+///
+/// Adapters and the simulator call the back-end directly, on their own thread,
+/// so a simulator does not need a thread of its own.
 struct TestSharedAdapterBackend {
     /// The latest known value for each getter.
     getter_values: HashMap<Id<Getter>, Result<Value, Error>>,
@@ -172,53 +176,24 @@ impl TestSharedAdapterBackend {
         }
     }
 
-    fn execute(&mut self, op: AdapterOp) {
-        use self::AdapterOp::*;
-        match op {
-            FetchValues { getters, tx } => {
-                let _ = tx.send(self.fetch_values(getters, User::None));
+    fn add_timer(&mut self, timer: Timer) {
+        match self.trigger_timers_until {
+            None => {
+                self.timers.push(timer);
             }
-            SendValues { values, tx } => {
-                let _ = tx.send(self.send_values(values, User::None));
+            Some(ref date) if *date < timer.date => {
+                self.timers.push(timer);
             }
-            Watch { source, tx } => {
-                let _ = tx.send(self.register_watch(source));
-            }
-            Unwatch(key) => {
-                let _ = self.remove_watch(key);
-            }
-            InjectGetterValues(values, tx) => {
-                let _ = self.inject_getter_values(values);
-                let _ = tx.send(FakeEnvEvent::Done);
-            }
-            InjectSetterErrors(errors, tx) => {
-                let _ = self.inject_setter_errors(errors);
-                let _ = tx.send(FakeEnvEvent::Done);
-            }
-            TriggerTimersUntil(date ,tx) => {
-                let _ = self.trigger_timers_until(date.into());
-                let _ = tx.send(FakeEnvEvent::Done);
-            }
-            ResetTimers(tx) => {
-                self.trigger_timers_until = None;
-                self.timers.clear();
-                let _ = tx.send(FakeEnvEvent::Done);
-            }
-            AddTimer(timer) => {
-                match self.trigger_timers_until {
-                    None => {
-                        self.timers.push(timer);
-                    }
-                    Some(ref date) if *date < timer.date => {
-                        self.timers.push(timer);
-                    }
-                    Some(_) => {
-                        timer.trigger()
-                    }
-                }
+            Some(_) => {
+                timer.trigger()
             }
         }
     }
+
+    fn reset_timers(&mut self) {
+        self.trigger_timers_until = None;
+        self.timers.clear();
+    }
 }
 
 /// Events of interest, that should be displayed to the user of the simulator.
@@ -242,13 +217,13 @@ struct TestAdapter {
     id: Id<AdapterId>,
 
     /// The back-end holding the state of this adapter. Shared between all adapters.
-    back_end: Arc<Mutex<Box<ExtSender<AdapterOp>>>>,
+    back_end: Arc<Mutex<TestSharedAdapterBackend>>,
 }
 impl TestAdapter {
-    fn new(id: Id<AdapterId>, back_end: Box<ExtSender<AdapterOp>>) -> Self {
+    fn new(id: Id<AdapterId>, back_end: Arc<Mutex<TestSharedAdapterBackend>>) -> Self {
         TestAdapter {
             id: id,
-            back_end: Arc::new(Mutex::new(back_end)),
+            back_end: back_end,
         }
     }
 }
@@ -278,26 +253,16 @@ impl Adapter for TestAdapter {
     /// expects the adapter to attempt to minimize the connections with the actual devices.
     ///
     /// The AdapterManager is in charge of keeping track of the age of values.
-    fn fetch_values(&self, getters: Vec<Id<Getter>>, _: User) -> ResultMap<Id<Getter>, Option<Value>, Error> {
-        let (tx, rx) = channel();
-        self.back_end.lock().unwrap().send(AdapterOp::FetchValues {
-            getters: getters,
-            tx: Box::new(tx),
-        }).unwrap();
-        rx.recv().unwrap()
+    fn fetch_values(&self, getters: Vec<Id<Getter>>, user: User) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+        self.back_end.lock().unwrap().fetch_values(getters, user)
     }
 
     /// Request that values be sent to channels.
     ///
     /// The AdapterManager always attempts to group calls to `send_values` by `Adapter`, and then
     /// expects the adapter to attempt to minimize the connections with the actual devices.
-    fn send_values(&self, values: HashMap<Id<Setter>, Value>, _: User) -> ResultMap<Id<Setter>, (), Error> {
-        let (tx, rx) = channel();
-        self.back_end.lock().unwrap().send(AdapterOp::SendValues {
-            values: values,
-            tx: Box::new(tx),
-        }).unwrap();
-        rx.recv().unwrap()
+    fn send_values(&self, values: HashMap<Id<Setter>, Value>, user: User) -> ResultMap<Id<Setter>, (), Error> {
+        self.back_end.lock().unwrap().send_values(values, user)
     }
 
     /// Watch a bunch of getters as they change.
@@ -318,20 +283,13 @@ impl Adapter for TestAdapter {
     fn register_watch(&self, source: Vec<(Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)>) ->
             Vec<(Id<Getter>, Result<Box<AdapterWatchGuard>, Error>)>
     {
-        let (tx, rx) = channel();
-        self.back_end.lock().unwrap().send(AdapterOp::Watch {
-            source: source,
-            tx: Box::new(tx),
-        }).unwrap();
-        let received = rx.recv().unwrap();
-        let tx_unregister = self.back_end.lock().unwrap().clone();
+        let received = self.back_end.lock().unwrap().register_watch(source);
         received.iter().map(|&(ref id, ref result)| {
-            let tx_unregister = tx_unregister.clone();
             (id.clone(), match *result {
                 Err(ref err) => Err(err.clone()),
                 Ok(ref key) => {
                     let guard = TestAdapterWatchGuard {
-                        tx: Arc::new(Mutex::new(Box::new(tx_unregister))),
+                        back_end: self.back_end.clone(),
                         key: key.clone()
                     };
                     Ok(Box::new(guard) as Box<AdapterWatchGuard>)
@@ -344,13 +302,13 @@ impl Adapter for TestAdapter {
 /// A watchguard for a TestAdapter
 #[derive(Clone)]
 struct TestAdapterWatchGuard {
-    tx: Arc<Mutex<Box<ExtSender<AdapterOp>>>>,
+    back_end: Arc<Mutex<TestSharedAdapterBackend>>,
     key: usize,
 }
 impl AdapterWatchGuard for TestAdapterWatchGuard {}
 impl Drop for TestAdapterWatchGuard {
     fn drop(&mut self) {
-        let _ = self.tx.lock().unwrap().send(AdapterOp::Unwatch(self.key));
+        self.back_end.lock().unwrap().remove_watch(self.key);
     }
 }
 
@@ -398,9 +356,19 @@ pub struct FakeEnv {
     /// The manager in charge of all adapters.
     manager: Arc<AdapterManager>,
 
+    /// Virtual channels, shared between all scripts using this environment.
+    virtual_channels: Arc<VirtualChannels>,
+
+    /// Permissions, shared between all scripts using this environment.
+    /// Initially, everything is allowed.
+    permissions: Arc<InMemoryPolicy>,
+
+    /// The offset of the local time from UTC. Initially, none.
+    utc_offset: Arc<Mutex<ChronoDuration>>,
+
     ///
     on_event: Box<ExtSender<FakeEnvEvent>>,
-    back_end: Box<ExtSender<AdapterOp>>,
+    back_end: Arc<Mutex<TestSharedAdapterBackend>>,
 }
 impl fmt::Debug for FakeEnv {
     fn fmt(&self, _: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -424,28 +392,48 @@ impl ExecutableDevEnv for FakeEnv {
             on_triggered: timer,
             is_dropped: is_dropped.clone()
         };
-        let _ = self.back_end.send(AdapterOp::AddTimer(trigger));
+        self.back_end.lock().unwrap().add_timer(trigger);
         TimerGuard(is_dropped)
     }
+
+    fn virtual_channels(&self) -> Option<&VirtualChannels> {
+        Some(&*self.virtual_channels)
+    }
+
+    fn permissions(&self) -> &PermissionPolicy {
+        &*self.permissions
+    }
+
+    fn utc_offset(&self) -> ChronoDuration {
+        *self.utc_offset.lock().unwrap()
+    }
 }
 impl FakeEnv {
     pub fn new(on_event: Box<ExtSender<FakeEnvEvent>>) -> Self {
-        let (tx, rx) = channel();
-        let on_event_clone = on_event.clone();
-        thread::spawn(move || {
-            let mut back_end = TestSharedAdapterBackend::new(on_event_clone);
-            for msg in rx {
-                back_end.execute(msg);
-            }
-        });
-
+        let back_end = TestSharedAdapterBackend::new(on_event.clone());
+        let manager = Arc::new(AdapterManager::new());
+        let virtual_channels = VirtualChannels::new(manager.clone()).unwrap();
         FakeEnv {
             on_event: on_event,
-            manager: Arc::new(AdapterManager::new()),
-            back_end: Box::new(tx),
+            manager: manager,
+            virtual_channels: Arc::new(virtual_channels),
+            permissions: Arc::new(InMemoryPolicy::new(true)),
+            utc_offset: Arc::new(Mutex::new(ChronoDuration::zero())),
+            back_end: Arc::new(Mutex::new(back_end)),
         }
     }
 
+    /// The permissions of this environment, which tests may change at any time.
+    pub fn policy(&self) -> &InMemoryPolicy {
+        &self.permissions
+    }
+
+    /// Set the offset of the local time from UTC, used by scripts to
+    /// evaluate their time windows from then on.
+    pub fn set_utc_offset(&self, offset: ChronoDuration) {
+        *self.utc_offset.lock().unwrap() = offset;
+    }
+
     fn report_error<T>(&self, result: Result<T, Error>) {
         match result {
             Ok(_) => {},
@@ -504,16 +492,20 @@ impl FakeEnv {
                 let _ = self.on_event.send(FakeEnvEvent::Done);
             },
             InjectGetterValues(vec) => {
-                self.back_end.send(AdapterOp::InjectGetterValues(vec, self.on_event.clone())).unwrap();
+                self.back_end.lock().unwrap().inject_getter_values(vec);
+                let _ = self.on_event.send(FakeEnvEvent::Done);
             },
             InjectSetterErrors(vec) => {
-                self.back_end.send(AdapterOp::InjectSetterErrors(vec, self.on_event.clone())).unwrap();
+                self.back_end.lock().unwrap().inject_setter_errors(vec);
+                let _ = self.on_event.send(FakeEnvEvent::Done);
             }
             TriggerTimersUntil(date) => {
-                self.back_end.send(AdapterOp::TriggerTimersUntil(date, self.on_event.clone())).unwrap();
+                self.back_end.lock().unwrap().trigger_timers_until(date.into());
+                let _ = self.on_event.send(FakeEnvEvent::Done);
             }
             ResetTimers => {
-                self.back_end.send(AdapterOp::ResetTimers(self.on_event.clone())).unwrap();
+                self.back_end.lock().unwrap().reset_timers();
+                let _ = self.on_event.send(FakeEnvEvent::Done);
             }
 //            _ => unimplemented!()
         }
@@ -545,27 +537,5 @@ pub enum Instruction {
     ResetTimers,
 }
 
-/// Operations internal to a TestAdapter.
-enum AdapterOp {
-    FetchValues {
-        getters: Vec<Id<Getter>>,
-        tx: Box<ExtSender<ResultMap<Id<Getter>, Option<Value>, Error>>>
-    },
-    SendValues {
-        values: HashMap<Id<Setter>, Value>,
-        tx: Box<ExtSender<ResultMap<Id<Setter>, (), Error>>>
-    },
-    Watch {
-        source: Vec<(Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)>,
-        tx: Box<ExtSender<Vec<(Id<Getter>, Result<usize, Error>)>>>
-    },
-    AddTimer(Timer),
-    Unwatch(usize),
-    InjectGetterValues(Vec<(Id<Getter>, Result<Value, Error>)>, Box<ExtSender<FakeEnvEvent>>),
-    InjectSetterErrors(Vec<(Id<Setter>, Option<Error>)>, Box<ExtSender<FakeEnvEvent>>),
-    TriggerTimersUntil(TimeStamp, Box<ExtSender<FakeEnvEvent>>),
-    ResetTimers(Box<ExtSender<FakeEnvEvent>>),
-}
-
 
 
@@ -42,3 +42,30 @@ pub mod fake_env;
 
 /// ScriptManager manages storing and executing scripts.
 pub mod manager;
+
+/// Virtual channels, used by scripts to communicate with each other.
+pub mod virtual_channels;
+
+/// Computing derived getters.
+pub mod aggregate;
+
+/// Dependencies between the rules of a script.
+pub mod dependencies;
+
+/// Detecting scripts that fight over the same devices.
+pub mod conflicts;
+
+/// Deciding which channels the owner of a script may read or write.
+pub mod permissions;
+
+/// Evaluating the matches of rules incrementally.
+pub mod network;
+
+/// A JSON Schema for scripts.
+pub mod schema;
+
+/// Multiplexing many tasks on a small pool of threads.
+pub mod executor;
+
+/// Comparing two versions of a script.
+pub mod diff;
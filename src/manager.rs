@@ -1,17 +1,26 @@
-use ast::Script;
-use compile::ExecutableDevEnv;
-use run::{ Execution, ExecutionEvent, Error as RunError, StartStopError };
+use ast::{ Script, VariableDeclaration, VariableValue };
+use compile::{ count_watches, Compiler, Diagnostic, DryRun, ExecutableDevEnv, Limits, Location, Quota, SourceError };
+use conflicts::{ Conflict, find_conflicts };
+use network::Dispatcher;
+use run::{ Execution, ExecutionEvent, Error as RunError, Executor, ExpiredTimerPolicy, Options, ResumePolicy,
+           RunMode, SeedPolicy, Snapshot, StartStopError };
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::hash::{ Hash, Hasher, SipHasher };
 use std::path::{ Path as FilePath, PathBuf as FilePathBuf };
+use std::thread;
+use std::time::Duration as StdDuration;
 
 use foxbox_taxonomy::api::{ ResultMap, User };
 use foxbox_taxonomy::parse::*;
 use foxbox_taxonomy::util::{ Id };
 
 use rusqlite;
-use transformable_channels::mpsc::{ channel, ExtSender, TransformableSender };
+use serde::de::Deserialize;
+use serde::ser::Serialize;
+use serde_json;
+use transformable_channels::mpsc::{ channel, ExtSender, Sender, TransformableSender };
 
 /// A ScriptManager error.
 #[derive(Serialize, Debug)]
@@ -19,16 +28,26 @@ pub enum Error {
     /// The script you requested (by ID) does not exist.
     NoSuchScriptError,
 
+    /// The house mode you requested has not been added.
+    NoSuchModeError,
+
     /// There was an error executing some SQL.
     SQLError(String),
 
     /// There was an error attempting to run a script. (See `run.rs`.)
     RunError(RunError),
 
-    /// There was an error parsing the script's JSON.
-    ParseError(String),
+    /// There was an error parsing the script's JSON, reported at the same
+    /// location as compilation errors.
+    ParseError(Diagnostic),
+
+    /// Enabling the script would exceed the quota of its owner.
+    QuotaError(SourceError),
 }
 
+/// The minimal delay between two checkpoints of a script, in seconds.
+const CHECKPOINT_INTERVAL_SECS: u64 = 1;
+
 /// A type for ensuring type-safety (Id<ScriptId>).
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
 pub struct ScriptId;
@@ -48,6 +67,21 @@ pub struct ScriptManager<Env, T> where Env: ExecutableDevEnv + Clone + Debug + '
 
     /// The tx end of the channel passed to ScriptManager::new()
     tx: Box<T>,
+
+    /// The limits enforced on each script.
+    limits: Limits,
+
+    /// The quota enforced on the enabled scripts of each owner.
+    quota: Quota,
+
+    /// The threads on which the scripts are executed.
+    executor: Executor,
+
+    /// The watches shared by the running scripts.
+    dispatcher: Dispatcher,
+
+    /// Stores the changes reported by the running scripts.
+    writer: Writer,
 }
 
 impl<Env, T> ScriptManager<Env, T>
@@ -64,11 +98,36 @@ impl<Env, T> ScriptManager<Env, T>
     ///   id, // Record identifier. Primary key.
     ///   source, // Script source. Defines the behavior of the rule.
     ///   is_enabled, // Boolean flag that indicates if the rule is enabled or disabled.
-    ///   owner // User identifier (i32) of the owner of the rule. Defaults to no user (-1).
+    ///   owner, // User identifier (i32) of the owner of the rule. Defaults to no user (-1).
+    ///   is_paused, // Boolean flag that indicates if the rule is paused.
+    ///   is_shadow, // Boolean flag that indicates if the rule runs in `RunMode::Shadow`.
+    ///   expired_timers, // The `ExpiredTimerPolicy` of the rule, as JSON, or NULL for the default.
+    ///   seed // The `SeedPolicy` of the rule, as JSON, or NULL for the default.
     /// }
     ///
     /// The database stores the raw script source, but only after the source has been parsed
     /// to ensure validity.
+    ///
+    /// The database also stores the latest value of the variables of each script, with this
+    /// schema:
+    /// {
+    ///   script_id, // The script declaring the variable.
+    ///   name, // The name of the variable.
+    ///   value // The latest value of the variable, as JSON.
+    /// }
+    ///
+    /// The database stores the latest checkpoint of each running script (see
+    /// `Options::checkpoint`), with this schema:
+    /// {
+    ///   script_id, // The script. Primary key.
+    ///   snapshot // The state of the script, as JSON.
+    /// }
+    ///
+    /// Finally, the database stores the house modes, with this schema:
+    /// {
+    ///   name, // The name of the mode, e.g. "Away". Primary key.
+    ///   is_current // Boolean flag that indicates if the house is currently in this mode.
+    /// }
     pub fn new(env: Env, path: &FilePath, tx: Box<T>) -> Result<Self, Error> {
 
         let connection = try!(rusqlite::Connection::open(&path));
@@ -76,19 +135,58 @@ impl<Env, T> ScriptManager<Env, T>
             id          TEXT NOT NULL PRIMARY KEY,
             source      TEXT NOT NULL,
             is_enabled  BOOL NOT NULL DEFAULT 1,
-            owner       INTEGER NOT NULL DEFAULT -1
+            owner       INTEGER NOT NULL DEFAULT -1,
+            is_paused   BOOL NOT NULL DEFAULT 0,
+            is_shadow   BOOL NOT NULL DEFAULT 0,
+            expired_timers TEXT,
+            seed        TEXT
+        )", &[]));
+        // Databases created by earlier versions do not have the `is_paused`,
+        // `is_shadow`, `expired_timers` and `seed` columns.
+        try!(add_column(&connection, "scripts", "is_paused", "BOOL NOT NULL DEFAULT 0"));
+        try!(add_column(&connection, "scripts", "is_shadow", "BOOL NOT NULL DEFAULT 0"));
+        try!(add_column(&connection, "scripts", "expired_timers", "TEXT"));
+        try!(add_column(&connection, "scripts", "seed", "TEXT"));
+        try!(connection.execute("CREATE TABLE IF NOT EXISTS variables (
+            script_id   TEXT NOT NULL,
+            name        TEXT NOT NULL,
+            value       TEXT NOT NULL,
+            PRIMARY KEY (script_id, name)
+        )", &[]));
+        try!(connection.execute("CREATE TABLE IF NOT EXISTS checkpoints (
+            script_id   TEXT NOT NULL PRIMARY KEY,
+            snapshot    TEXT NOT NULL
+        )", &[]));
+        try!(connection.execute("CREATE TABLE IF NOT EXISTS modes (
+            name        TEXT NOT NULL PRIMARY KEY,
+            is_current  BOOL NOT NULL DEFAULT 0
         )", &[]));
 
         Ok(ScriptManager {
             path: path.to_owned(),
+            dispatcher: Dispatcher::new(env.clone()),
             env: env,
             runners: HashMap::new(),
-            tx: tx
+            tx: tx,
+            limits: Limits::default(),
+            quota: Quota::default(),
+            executor: Executor::default(),
+            writer: Writer::new(path),
         })
     }
 
     /// Load and launch all existing scripts from the database.
+    ///
+    /// Scripts resume from their latest checkpoint, i.e. rules that were met
+    /// remain met, without firing again, and timers resume where they stopped.
+    /// Timers that have expired in the meantime are handled according to the
+    /// `ExpiredTimerPolicy` of each script.
+    ///
+    /// Scripts are started in turn, and a script that would exceed the quota
+    /// of its owner, e.g. because the quota has been lowered since it was
+    /// enabled, is not started.
     pub fn load(&mut self) -> Result<ResultMap<Id<ScriptId>, (), Error>, Error> {
+        self.writer.flush();
         let connection = try!(rusqlite::Connection::open(&self.path));
         let mut result_map = HashMap::new();
         let mut stmt = try!(connection.prepare("SELECT id, source, is_enabled, owner FROM scripts"));
@@ -107,9 +205,11 @@ impl<Env, T> ScriptManager<Env, T>
             };
 
             if is_enabled {
-                result_map.insert(
-                    id.clone(),
-                    self.start_script(&id, &source, &owner).map(|_| ()));
+                let result = self.check_quota_aux(&id, &source, &owner, true)
+                    .and_then(|()| load_run_mode(&self.path, &id))
+                    .and_then(|run_mode| load_checkpoint(&self.path, &id).map(|checkpoint| (run_mode, checkpoint)))
+                    .and_then(|(run_mode, checkpoint)| self.start_script(&id, &source, &owner, run_mode, checkpoint));
+                result_map.insert(id.clone(), result);
             } else {
                 result_map.insert(id.clone(), Ok(()));
             }
@@ -123,8 +223,38 @@ impl<Env, T> ScriptManager<Env, T>
     /// The script may have (User::Id(i32)) or may not have a owner (User::None).
     /// If the script has owner, this value will be propagated to Thinkerbell's
     /// adapter.
-    pub fn put(&mut self, id: &Id<ScriptId>, source: &String, owner: &User) -> Result<(), Error> {
-        try!(self.start_script(&id, &source, &owner));
+    ///
+    /// Return the potential conflicts between this script and the other enabled
+    /// scripts, i.e. rules that may send different values to the same setters at
+    /// the same time. Conflicts do not prevent the script from being stored.
+    ///
+    /// If a script with the same ID is running, the rules that have not changed
+    /// keep their state, e.g. whether they are met and their running timers.
+    ///
+    /// Replacing a script keeps its `RunMode`. New scripts are `RunMode::Live`.
+    pub fn put(&mut self, id: &Id<ScriptId>, source: &String, owner: &User) -> Result<Vec<Conflict>, Error> {
+        let run_mode = try!(load_run_mode(&self.path, id));
+        self.put_with_mode(id, source, owner, run_mode)
+    }
+
+    /// Attempt to add a new script, as `put`, with a specific `RunMode`.
+    ///
+    /// Scripts in `RunMode::Shadow` do not send values, so they are not
+    /// taken into account when looking for conflicts with other scripts.
+    /// They start with the stored values of the variables, but their changes
+    /// to the variables are not stored.
+    pub fn put_with_mode(&mut self, id: &Id<ScriptId>, source: &String, owner: &User, run_mode: RunMode) ->
+        Result<Vec<Conflict>, Error>
+    {
+        try!(self.check_quota(id, source, owner));
+        let conflicts = try!(self.get_conflicts(id, source));
+        try!(self.start_script(&id, &source, &owner, run_mode, None));
+
+        // The stored values of variables that this version of the script
+        // does not declare anymore, or with another type, are stale.
+        let script = try!(Script::from_str(source));
+        self.writer.flush();
+        try!(remove_stale_variables(&self.path, id, &script.variables));
 
         let owner_value: i32 = match *owner {
             User::Id(id) => id,
@@ -132,9 +262,139 @@ impl<Env, T> ScriptManager<Env, T>
         };
 
         let connection = try!(rusqlite::Connection::open(&self.path));
-        connection.execute("INSERT OR REPLACE INTO scripts (id, source, is_enabled, owner)
-                VALUES ($1, $2, $3, $4)", &[&id.to_string(), source, &1, &owner_value])
-            .map(|_| ()).map_err(From::from)
+        // Replacing a paused script keeps it paused.
+        try!(connection.execute("INSERT OR REPLACE INTO scripts (id, source, is_enabled, owner, is_paused, is_shadow,
+                                                                 expired_timers, seed)
+                VALUES ($1, $2, $3, $4, COALESCE((SELECT is_paused FROM scripts WHERE id = $1), 0), $5,
+                        (SELECT expired_timers FROM scripts WHERE id = $1),
+                        (SELECT seed FROM scripts WHERE id = $1))",
+                &[&id.to_string(), source, &1, &owner_value, &(run_mode == RunMode::Shadow)]));
+        Ok(conflicts)
+    }
+
+    /// Find the potential conflicts between a script and the other enabled scripts.
+    pub fn get_conflicts(&self, id: &Id<ScriptId>, source: &String) -> Result<Vec<Conflict>, Error> {
+        let script = try!(Script::from_str(source));
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare("SELECT id, source FROM scripts WHERE is_enabled = 1 AND is_shadow = 0 AND id != $1"));
+        let rows = try!(stmt.query(&[&id.to_string()]));
+        let mut conflicts = vec![];
+        for result_row in rows {
+            let row = try!(result_row);
+            let other_id: String = try!(row.get_checked(0));
+            let other_source: String = try!(row.get_checked(1));
+            match Script::from_str(&other_source) {
+                Ok(other) => conflicts.extend(find_conflicts(&script, &Id::new(&other_id), &other)),
+                Err(err) => {
+                    // The script was valid when it was stored, so this should not happen.
+                    warn!("[Recipe '{}'] Could not parse stored script: {:?}", other_id, err);
+                }
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// Set the limits enforced on each script. Scripts that are already
+    /// running are not affected until they are restarted.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Set the quota enforced on the enabled scripts of each owner. Scripts
+    /// that are already running are not affected, until they are loaded again.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    /// The executor on which scripts are started. By default, all the
+    /// scripts share a single thread.
+    pub fn executor(&self) -> &Executor {
+        &self.executor
+    }
+
+    /// Set the executor on which scripts are started. Scripts that are
+    /// already running remain on their executor until they are restarted.
+    ///
+    /// With the default executor, all the scripts share a single thread,
+    /// which keeps memory usage low, but a script that blocks, e.g. while
+    /// sending values to a slow device, delays all the other scripts. An
+    /// executor with a few threads, e.g. one per core, limits such delays
+    /// to the scripts that share the thread of the blocked script.
+    pub fn set_executor(&mut self, executor: Executor) {
+        self.executor = executor;
+    }
+
+    /// The watches shared by the running scripts. Scripts that watch the
+    /// same channels with the same range share a single watch, and the
+    /// dispatcher knows which conditions of which scripts depend on each
+    /// getter.
+    pub fn dispatcher(&self) -> &Dispatcher {
+        &self.dispatcher
+    }
+
+    /// Check that enabling a script would not exceed the quota of its owner,
+    /// taking into account the other enabled scripts of the owner.
+    fn check_quota(&self, id: &Id<ScriptId>, source: &String, owner: &User) -> Result<(), Error> {
+        self.check_quota_aux(id, source, owner, false)
+    }
+
+    /// As `check_quota`, but if `running_only` is `true`, only take into
+    /// account the other scripts that are running, e.g. while the scripts
+    /// are being loaded.
+    fn check_quota_aux(&self, id: &Id<ScriptId>, source: &String, owner: &User, running_only: bool) ->
+        Result<(), Error>
+    {
+        let script = try!(Script::from_str(source));
+        let owner_value: i32 = match *owner {
+            User::Id(id) => id,
+            User::None => -1
+        };
+        let mut scripts = 1;
+        let mut rules = script.rules.len();
+        let mut watches = count_watches(&script);
+
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare("SELECT id, source FROM scripts WHERE is_enabled = 1 AND owner = $1 AND id != $2"));
+        let rows = try!(stmt.query(&[&owner_value, &id.to_string()]));
+        for result_row in rows {
+            let row = try!(result_row);
+            let other_id: String = try!(row.get_checked(0));
+            let other_source: String = try!(row.get_checked(1));
+            if running_only && !self.runners.contains_key(&Id::new(&other_id)) {
+                continue;
+            }
+            match Script::from_str(&other_source) {
+                Ok(other) => {
+                    scripts += 1;
+                    rules += other.rules.len();
+                    watches += count_watches(&other);
+                }
+                Err(err) => {
+                    // The script was valid when it was stored, so this should not happen.
+                    warn!("[Recipe '{}'] Could not parse stored script: {:?}", other_id, err);
+                }
+            }
+        }
+
+        if scripts > self.quota.max_scripts {
+            Err(Error::QuotaError(SourceError::ScriptQuotaExceeded { max: self.quota.max_scripts }))
+        } else if rules > self.quota.max_rules {
+            Err(Error::QuotaError(SourceError::RuleQuotaExceeded { max: self.quota.max_rules }))
+        } else if watches > self.quota.max_watches {
+            Err(Error::QuotaError(SourceError::WatchQuotaExceeded { max: self.quota.max_watches }))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compile a script against the devices currently available, without
+    /// storing or executing it. Useful to find typos in selectors.
+    pub fn dry_run(&self, source: &String) -> Result<DryRun, Error> {
+        let script = try!(Script::from_str(source));
+        let compiler = try!(Compiler::<Env>::new().map_err(|err|
+            RunError::CompileError(vec![Diagnostic::error(Location::new(), err)])));
+        let compiler = self.env.compiler_passes().into_iter().fold(compiler, |compiler, pass| compiler.with_pass(pass));
+        Ok(compiler.with_limits(self.limits.clone()).dry_run(script, self.env.api()))
     }
 
     /// Enable or disable a script, starting or stopping the script if necessary.
@@ -158,7 +418,9 @@ impl<Env, T> ScriptManager<Env, T>
                                         &[&id.to_string()]));
             },
             (true, false) => {
-                try!(self.start_script(id, &source, &owner));
+                try!(self.check_quota(id, &source, &owner));
+                let run_mode = try!(load_run_mode(&self.path, id));
+                try!(self.start_script(id, &source, &owner, run_mode, None));
                 let connection = try!(rusqlite::Connection::open(&self.path));
                 try!(connection.execute("UPDATE scripts SET is_enabled = 1 WHERE id = $1",
                                         &[&id.to_string()]));
@@ -175,7 +437,12 @@ impl<Env, T> ScriptManager<Env, T>
     /// If the script cannot be stopped (due to an error), it will not be removed.
     pub fn remove(&mut self, id: &Id<ScriptId>) -> Result<(), Error> {
         try!(self.set_enabled(id, false));
+        // The script has stopped, make sure that its last changes do not
+        // land after the deletion.
+        self.writer.flush();
         let connection = try!(rusqlite::Connection::open(&self.path));
+        try!(connection.execute("DELETE FROM variables WHERE script_id = $1", &[&id.to_string()]));
+        try!(connection.execute("DELETE FROM checkpoints WHERE script_id = $1", &[&id.to_string()]));
         connection.execute("DELETE FROM scripts WHERE id = $1", &[&id.to_string()])
             .map(|_| ())
             .map_err(From::from)
@@ -196,9 +463,14 @@ impl<Env, T> ScriptManager<Env, T>
             }
         }
         // Nuke the scripts database.
+        self.writer.flush();
         let connection = try!(rusqlite::Connection::open(&self.path));
         try!(connection.execute("DELETE FROM scripts", &[])
                 .map(|_| ()));
+        try!(connection.execute("DELETE FROM variables", &[])
+                .map(|_| ()));
+        try!(connection.execute("DELETE FROM checkpoints", &[])
+                .map(|_| ()));
         Ok(errors)
     }
 
@@ -223,35 +495,546 @@ impl<Env, T> ScriptManager<Env, T>
         Ok((source, owner))
     }
 
+    /// Get the latest value of the variables of a script, as stored in the database.
+    ///
+    /// Variables that have never changed since the script was first stored are not
+    /// returned.
+    pub fn get_variables(&self, id: &Id<ScriptId>) -> Result<HashMap<String, VariableValue>, Error> {
+        self.writer.flush();
+        load_variables(&self.path, id)
+    }
+
+    /// Add a house mode, e.g. "Away" or "Night". Adding an existing mode does nothing.
+    pub fn add_mode(&mut self, name: &str) -> Result<(), Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        connection.execute("INSERT OR IGNORE INTO modes (name) VALUES ($1)", &[&name])
+            .map(|_| ()).map_err(From::from)
+    }
+
+    /// Remove a house mode. If the house is currently in this mode, it
+    /// leaves this mode.
+    pub fn remove_mode(&mut self, name: &str) -> Result<(), Error> {
+        if try!(self.get_mode()) == Some(name.to_owned()) {
+            try!(self.set_mode(None));
+        }
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        connection.execute("DELETE FROM modes WHERE name = $1", &[&name])
+            .map(|_| ()).map_err(From::from)
+    }
+
+    /// Get the names of all the house modes.
+    pub fn get_modes(&self) -> Result<Vec<String>, Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare("SELECT name FROM modes"));
+        let rows = try!(stmt.query(&[]));
+        let mut modes = Vec::new();
+        for result_row in rows {
+            let row = try!(result_row);
+            modes.push(try!(row.get_checked(0)));
+        }
+        Ok(modes)
+    }
+
+    /// Get the current house mode, if any.
+    pub fn get_mode(&self) -> Result<Option<String>, Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        let mut stmt = try!(connection.prepare("SELECT name FROM modes WHERE is_current = 1"));
+        let mut rows = try!(stmt.query(&[]));
+        match rows.nth(0) {
+            None => Ok(None),
+            Some(row) => Ok(Some(try!(try!(row).get_checked(0))))
+        }
+    }
+
+    /// Switch the house to another mode, or to no mode at all, and inform all
+    /// running scripts. Rules restricted to the new mode whose conditions are
+    /// already met fire immediately.
+    pub fn set_mode(&mut self, name: Option<&str>) -> Result<(), Error> {
+        let connection = try!(rusqlite::Connection::open(&self.path));
+        if let Some(name) = name {
+            let mut stmt = try!(connection.prepare("SELECT name FROM modes WHERE name = $1"));
+            let mut rows = try!(stmt.query(&[&name]));
+            if rows.nth(0).is_none() {
+                return Err(Error::NoSuchModeError);
+            }
+        }
+        try!(connection.execute("UPDATE modes SET is_current = (name = $1)", &[&name.unwrap_or("")]));
+        for runner in self.runners.values() {
+            try!(runner.set_mode(name.map(str::to_owned)));
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of the state of a running script.
+    pub fn snapshot(&self, id: &Id<ScriptId>) -> Result<Snapshot, Error> {
+        match self.runners.get(id) {
+            None => Err(Error::RunError(RunError::StartStopError(StartStopError::NotRunning))),
+            Some(runner) => runner.snapshot().map_err(Error::RunError)
+        }
+    }
+
+    /// Execute the statements of a rule of a running script, regardless of its
+    /// conditions, e.g. to test the rule. If `statement_index` is specified, only
+    /// execute this statement. See `Execution::fire_rule`.
+    pub fn fire_rule(&self, id: &Id<ScriptId>, rule_index: usize, statement_index: Option<usize>) -> Result<(), Error> {
+        let runner = match self.runners.get(id) {
+            None => return Err(Error::RunError(RunError::StartStopError(StartStopError::NotRunning))),
+            Some(runner) => runner
+        };
+        let result = match statement_index {
+            None => runner.fire_rule(rule_index),
+            Some(statement_index) => runner.fire_statement(rule_index, statement_index)
+        };
+        result.map_err(Error::RunError)
+    }
+
+    /// Pause a script: it keeps tracking its rules but does not execute
+    /// statements until it is resumed. The script remains paused if it is
+    /// disabled, then enabled, or if the ScriptManager is restarted.
+    pub fn pause(&mut self, id: &Id<ScriptId>) -> Result<(), Error> {
+        try!(store_is_paused(&self.path, id, true));
+        if let Some(runner) = self.runners.get(id) {
+            try!(runner.pause());
+        }
+        Ok(())
+    }
+
+    /// Resume a paused script. `policy` determines whether the rules that
+    /// have become met while the script was paused fire now.
+    pub fn resume(&mut self, id: &Id<ScriptId>, policy: ResumePolicy) -> Result<(), Error> {
+        try!(store_is_paused(&self.path, id, false));
+        if let Some(runner) = self.runners.get(id) {
+            try!(runner.resume(policy));
+        }
+        Ok(())
+    }
+
+    /// The `RunMode` of a script.
+    pub fn run_mode(&self, id: &Id<ScriptId>) -> Result<RunMode, Error> {
+        load_run_mode(&self.path, id)
+    }
+
+    /// Set what to do with the timers of a script that expire while the
+    /// ScriptManager is not running. Takes effect the next time the
+    /// script is loaded.
+    pub fn set_expired_timer_policy(&mut self, id: &Id<ScriptId>, policy: ExpiredTimerPolicy) -> Result<(), Error> {
+        store_policy(&self.path, id, "expired_timers", &policy)
+    }
+
+    /// What to do with the timers of a script that expire while the
+    /// ScriptManager is not running.
+    pub fn expired_timer_policy(&self, id: &Id<ScriptId>) -> Result<ExpiredTimerPolicy, Error> {
+        load_policy(&self.path, id, "expired_timers")
+    }
+
+    /// Set whether a script fetches the current values of its getters when it
+    /// starts, and whether rules that are initially met fire. Takes effect the
+    /// next time the script is started.
+    pub fn set_seed_policy(&mut self, id: &Id<ScriptId>, policy: SeedPolicy) -> Result<(), Error> {
+        store_policy(&self.path, id, "seed", &policy)
+    }
+
+    /// Whether a script fetches the current values of its getters when it starts.
+    pub fn seed_policy(&self, id: &Id<ScriptId>) -> Result<SeedPolicy, Error> {
+        load_policy(&self.path, id, "seed")
+    }
+
+    /// Return true if the script is paused.
+    pub fn is_paused(&self, id: &Id<ScriptId>) -> Result<bool, Error> {
+        load_is_paused(&self.path, id)
+    }
+
     /// Return true if the script is enabled.
     pub fn is_enabled(&self, id: &Id<ScriptId>) -> bool {
         self.runners.contains_key(id)
     }
 
-    /// Execute a script. If the script is already running, stop the existing script.
-    fn start_script(&mut self, id: &Id<ScriptId>, source: &String, owner: &User) -> Result<(), Error> {
+    /// Execute a script. If the script is already running, replace it in place,
+    /// keeping the state of the rules that have not changed. If the new version
+    /// cannot replace the running script, or if the `RunMode` changes, stop the
+    /// existing script first.
+    ///
+    /// If the script is started afresh, its state is restored from `checkpoint`, if any.
+    fn start_script(&mut self, id: &Id<ScriptId>, source: &String, owner: &User, run_mode: RunMode,
+        checkpoint: Option<Snapshot>) -> Result<(), Error>
+    {
+        // Replace the script if possible. If the new version does not compile,
+        // the existing script keeps running. The database holds the `RunMode`
+        // of the running script.
+        let is_same_mode = try!(load_run_mode(&self.path, id)) == run_mode;
+        if let (true, Some(runner)) = (is_same_mode, self.runners.get(id)) {
+            let parsed_source = try!(Script::from_str(source));
+            let options = Options {
+                limits: self.limits.clone(),
+                version: Some(source_version(source)),
+                ..Options::default()
+            };
+            match runner.reload_with_options(parsed_source, owner.clone(), options) {
+                Ok(_) => return Ok(()),
+                Err(RunError::StartStopError(_)) => {},
+                Err(err) => return Err(Error::RunError(err))
+            }
+        }
+
         // Stop the script is necessary.
         if let Some(mut runner) = self.runners.remove(&id) {
-            let (tx, rx) = channel();
-            runner.stop(move |result| {
-                let _ = tx.send(result);
-            });
-            // If the Thinkerbell script has panicked, ignore it.
-            let _ = rx.recv();
+            stop_and_wait(&mut runner);
+        }
+        // The latest changes of the script we just stopped may still be
+        // waiting to be stored.
+        self.writer.flush();
+
+        // Now start it. A stored checkpoint may not match this version of the script.
+        if checkpoint.is_none() {
+            try!(remove_checkpoint(&self.path, id));
         }
-        // Now start it.
-        let mut runner = Execution::<Env>::new();
+        let mut runner = Execution::<Env>::with_dispatcher(self.executor.clone(), self.dispatcher.clone());
+        let options = Options {
+            variables: try!(load_variables(&self.path, id)),
+            mode: try!(self.get_mode()),
+            limits: self.limits.clone(),
+            paused: try!(load_is_paused(&self.path, id)),
+            run_mode: run_mode,
+            expired_timers: try!(load_policy(&self.path, id, "expired_timers")),
+            seed: try!(load_policy(&self.path, id, "seed")),
+            checkpoints: true,
+            checkpoint_interval: StdDuration::from_secs(CHECKPOINT_INTERVAL_SECS),
+            checkpoint: checkpoint,
+            version: Some(source_version(source)),
+            ..Options::default()
+        };
         let tx_id = id.clone();
+        let is_shadow = run_mode == RunMode::Shadow;
+        let tx_writer = self.writer.tx.clone();
         let tx = self.tx.map(move |event| {
+            match event {
+                ExecutionEvent::VariableChanged { .. } if is_shadow => {
+                    // Scripts in `RunMode::Shadow` start from the values of the
+                    // live script, but must not change them.
+                }
+                ExecutionEvent::VariableChanged { ref name, ref value } => {
+                    // Persist the variable, so that it survives restarts. This
+                    // runs on the thread of the script, so we do not wait for
+                    // the database.
+                    let _ = tx_writer.send(WriteOp::Variable {
+                        id: tx_id.clone(),
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+                ExecutionEvent::Checkpoint { ref snapshot } => {
+                    // Persist the state of the script, so that it survives restarts.
+                    let _ = tx_writer.send(WriteOp::Checkpoint {
+                        id: tx_id.clone(),
+                        snapshot: snapshot.clone(),
+                    });
+                }
+                _ => {}
+            }
             (tx_id.clone(), event)
         });
-        let parsed_source = try!(Path::new().push_str("recipe", |path| Script::from_str_at(path, source)));
-        try!(runner.start(self.env.clone(), parsed_source, owner.clone(), tx));
+        let parsed_source = try!(Script::from_str(source));
+        try!(runner.start_with_options(self.env.clone(), parsed_source, owner.clone(), options, tx));
         self.runners.insert(id.clone(), runner);
         Ok(())
     }
 }
 
+impl<Env, T> Drop for ScriptManager<Env, T> where Env: ExecutableDevEnv + Clone + Debug + 'static {
+    /// Stop the scripts, so that their latest changes are stored before the
+    /// writer is dropped.
+    fn drop(&mut self) {
+        for (_, mut runner) in self.runners.drain() {
+            stop_and_wait(&mut runner);
+        }
+    }
+}
+
+/// Stop a script and wait until it has stopped.
+fn stop_and_wait<Env>(runner: &mut Execution<Env>) where Env: ExecutableDevEnv + Debug + 'static {
+    let (tx, rx) = channel();
+    runner.stop(move |result| {
+        let _ = tx.send(result);
+    });
+    // If the Thinkerbell script has panicked, ignore it.
+    let _ = rx.recv();
+}
+
+/// A hash of the source of a script, to make sure that checkpoints are only
+/// restored by the version of the script that produced them.
+fn source_version(source: &str) -> String {
+    let mut hasher = SipHasher::new();
+    source.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Add a column to a table created by an earlier version of the database,
+/// unless the table already has this column.
+fn add_column(connection: &rusqlite::Connection, table: &str, column: &str, definition: &str) -> Result<(), Error> {
+    let exists = {
+        let mut stmt = try!(connection.prepare(&format!("PRAGMA table_info({})", table)));
+        let rows = try!(stmt.query(&[]));
+        let mut exists = false;
+        for result_row in rows {
+            let row = try!(result_row);
+            // Columns are described by (cid, name, type, notnull, dflt_value, pk).
+            let name: String = try!(row.get_checked(1));
+            exists = exists || name == column;
+        }
+        exists
+    };
+    if exists {
+        return Ok(());
+    }
+    try!(connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), &[]));
+    Ok(())
+}
+
+/// Load the variables of a script from the database.
+fn load_variables(path: &FilePath, id: &Id<ScriptId>) -> Result<HashMap<String, VariableValue>, Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    let mut stmt = try!(connection.prepare("SELECT name, value FROM variables WHERE script_id = $1"));
+    let rows = try!(stmt.query(&[&id.to_string()]));
+    let mut variables = HashMap::new();
+    for result_row in rows {
+        let row = try!(result_row);
+        let name: String = try!(row.get_checked(0));
+        let value: String = try!(row.get_checked(1));
+        match serde_json::from_str(&value) {
+            Ok(value) => {
+                variables.insert(name, value);
+            }
+            Err(err) => {
+                // Don't prevent the script from starting, it will use the initial value.
+                warn!("[Recipe '{}'] Could not parse stored variable {}: {:?}", id.to_string(), name, err);
+            }
+        }
+    }
+    Ok(variables)
+}
+
+/// Determine whether a script is paused. Scripts that are not stored yet
+/// are not paused.
+fn load_is_paused(path: &FilePath, id: &Id<ScriptId>) -> Result<bool, Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    let mut stmt = try!(connection.prepare("SELECT is_paused FROM scripts WHERE id = $1"));
+    let mut rows = try!(stmt.query(&[&id.to_string()]));
+    match rows.nth(0) {
+        None => Ok(false),
+        Some(row) => Ok(try!(try!(row).get_checked(0)))
+    }
+}
+
+/// Load the `RunMode` of a script from the database. Scripts that are not
+/// stored yet are `RunMode::Live`.
+fn load_run_mode(path: &FilePath, id: &Id<ScriptId>) -> Result<RunMode, Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    let mut stmt = try!(connection.prepare("SELECT is_shadow FROM scripts WHERE id = $1"));
+    let mut rows = try!(stmt.query(&[&id.to_string()]));
+    let is_shadow = match rows.nth(0) {
+        None => false,
+        Some(row) => try!(try!(row).get_checked(0))
+    };
+    Ok(if is_shadow { RunMode::Shadow } else { RunMode::Live })
+}
+
+/// Store whether a script is paused.
+fn store_is_paused(path: &FilePath, id: &Id<ScriptId>, is_paused: bool) -> Result<(), Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    let changed = try!(connection.execute("UPDATE scripts SET is_paused = $1 WHERE id = $2",
+                                          &[&is_paused, &id.to_string()]));
+    if changed == 0 {
+        Err(Error::NoSuchScriptError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Load the latest checkpoint of a script from the database, if any.
+fn load_checkpoint(path: &FilePath, id: &Id<ScriptId>) -> Result<Option<Snapshot>, Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    let mut stmt = try!(connection.prepare("SELECT snapshot FROM checkpoints WHERE script_id = $1"));
+    let mut rows = try!(stmt.query(&[&id.to_string()]));
+    let snapshot: String = match rows.nth(0) {
+        None => return Ok(None),
+        Some(row) => try!(try!(row).get_checked(0))
+    };
+    match serde_json::from_str(&snapshot) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(err) => {
+            // Don't prevent the script from starting, it will start afresh.
+            warn!("[Recipe '{}'] Could not parse stored checkpoint: {:?}", id.to_string(), err);
+            Ok(None)
+        }
+    }
+}
+
+/// Store the latest checkpoint of a script.
+fn store_checkpoint(connection: &rusqlite::Connection, id: &Id<ScriptId>, snapshot: &Snapshot) -> Result<(), Error> {
+    let snapshot = try!(serde_json::to_string(snapshot).map_err(|err| Error::SQLError(format!("{:?}", err))));
+    connection.execute("INSERT OR REPLACE INTO checkpoints (script_id, snapshot)
+            VALUES ($1, $2)", &[&id.to_string(), &snapshot])
+        .map(|_| ()).map_err(From::from)
+}
+
+/// Forget the latest checkpoint of a script.
+fn remove_checkpoint(path: &FilePath, id: &Id<ScriptId>) -> Result<(), Error> {
+    let connection = try!(rusqlite::Connection::open(path));
+    connection.execute("DELETE FROM checkpoints WHERE script_id = $1", &[&id.to_string()])
+        .map(|_| ()).map_err(From::from)
+}
+
+/// Load a policy of a script, stored as JSON in `column`, from the database.
+/// Scripts that are not stored yet, or that have no policy, use the default policy.
+fn load_policy<P>(path: &FilePath, id: &Id<ScriptId>, column: &str) -> Result<P, Error> where P: Deserialize + Default {
+    let connection = try!(rusqlite::Connection::open(path));
+    let mut stmt = try!(connection.prepare(&format!("SELECT {} FROM scripts WHERE id = $1", column)));
+    let mut rows = try!(stmt.query(&[&id.to_string()]));
+    let policy: Option<String> = match rows.nth(0) {
+        None => None,
+        Some(row) => try!(try!(row).get_checked(0))
+    };
+    match policy {
+        None => Ok(P::default()),
+        Some(policy) => serde_json::from_str(&policy).map_err(|err| Error::SQLError(format!("{:?}", err)))
+    }
+}
+
+/// Store a policy of a script as JSON in `column`.
+fn store_policy<P>(path: &FilePath, id: &Id<ScriptId>, column: &str, policy: &P) -> Result<(), Error> where P: Serialize {
+    let policy = try!(serde_json::to_string(policy).map_err(|err| Error::SQLError(format!("{:?}", err))));
+    let connection = try!(rusqlite::Connection::open(path));
+    let changed = try!(connection.execute(&format!("UPDATE scripts SET {} = $1 WHERE id = $2", column),
+                                          &[&policy, &id.to_string()]));
+    if changed == 0 {
+        Err(Error::NoSuchScriptError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Store the latest value of a variable of a script.
+fn store_variable(connection: &rusqlite::Connection, id: &Id<ScriptId>, name: &String, value: &VariableValue) -> Result<(), Error> {
+    let value = try!(serde_json::to_string(value).map_err(|err| Error::SQLError(format!("{:?}", err))));
+    connection.execute("INSERT OR REPLACE INTO variables (script_id, name, value)
+            VALUES ($1, $2, $3)", &[&id.to_string(), name, &value])
+        .map(|_| ()).map_err(From::from)
+}
+
+/// Forget the stored values of the variables that are not declared anymore,
+/// or whose type has changed.
+fn remove_stale_variables(path: &FilePath, id: &Id<ScriptId>, declarations: &[VariableDeclaration]) -> Result<(), Error> {
+    let stale : Vec<_> = try!(load_variables(path, id)).into_iter()
+        .filter(|&(ref name, ref value)| !declarations.iter().any(|declaration| {
+            declaration.name == *name && declaration.initial.has_same_type(value)
+        }))
+        .map(|(name, _)| name)
+        .collect();
+    if stale.is_empty() {
+        return Ok(());
+    }
+    let connection = try!(rusqlite::Connection::open(path));
+    for name in stale {
+        try!(connection.execute("DELETE FROM variables WHERE script_id = $1 AND name = $2",
+                                &[&id.to_string(), &name]));
+    }
+    Ok(())
+}
+
+/// A write requested by a running script.
+enum WriteOp {
+    Variable {
+        id: Id<ScriptId>,
+        name: String,
+        value: VariableValue,
+    },
+    Checkpoint {
+        id: Id<ScriptId>,
+        snapshot: Snapshot,
+    },
+
+    /// Reply once all the previous writes are done.
+    Flush(Sender<()>),
+}
+
+/// Stores the changes reported by the running scripts on a dedicated thread,
+/// with a single connection, so that scripts never wait for the database.
+///
+/// If several checkpoints of a script are waiting to be stored, only the
+/// latest one is.
+struct Writer {
+    tx: Sender<WriteOp>,
+}
+
+impl Writer {
+    fn new(path: &FilePath) -> Self {
+        let (tx, rx) = channel();
+        let path = path.to_owned();
+        thread::spawn(move || {
+            let connection = match rusqlite::Connection::open(&path) {
+                Ok(connection) => Some(connection),
+                Err(err) => {
+                    warn!("[ScriptManager] Could not open the database, changes will not be stored: {:?}", err);
+                    None
+                }
+            };
+            while let Ok(msg) = rx.recv() {
+                let mut batch = vec![msg];
+                while let Ok(msg) = rx.try_recv() {
+                    batch.push(msg);
+                }
+                // The index of the latest checkpoint of each script in the batch.
+                let mut latest = HashMap::new();
+                for (index, msg) in batch.iter().enumerate() {
+                    if let WriteOp::Checkpoint { ref id, .. } = *msg {
+                        latest.insert(id.clone(), index);
+                    }
+                }
+                for (index, msg) in batch.into_iter().enumerate() {
+                    match msg {
+                        WriteOp::Variable { id, name, value } => {
+                            if let Some(ref connection) = connection {
+                                if let Err(err) = store_variable(connection, &id, &name, &value) {
+                                    warn!("[Recipe '{}'] Could not store variable {}: {:?}", id.to_string(), name, err);
+                                }
+                            }
+                        }
+                        WriteOp::Checkpoint { id, snapshot } => {
+                            if latest.get(&id) != Some(&index) {
+                                // Superseded by a later checkpoint.
+                                continue;
+                            }
+                            if let Some(ref connection) = connection {
+                                if let Err(err) = store_checkpoint(connection, &id, &snapshot) {
+                                    warn!("[Recipe '{}'] Could not store checkpoint: {:?}", id.to_string(), err);
+                                }
+                            }
+                        }
+                        WriteOp::Flush(tx) => {
+                            let _ = tx.send(());
+                        }
+                    }
+                }
+            }
+        });
+        Writer {
+            tx: tx
+        }
+    }
+
+    /// Wait until the writes requested so far are done.
+    fn flush(&self) {
+        let (tx, rx) = channel();
+        let _ = self.tx.send(WriteOp::Flush(tx));
+        let _ = rx.recv();
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
 
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Error {
@@ -267,6 +1050,6 @@ impl From<RunError> for Error {
 
 impl From<ParseError> for Error {
     fn from(err: ParseError) -> Error {
-        Error::ParseError(format!("{:?}", err))
+        Error::ParseError(Diagnostic::from(err))
     }
 }
@@ -0,0 +1,607 @@
+//! A Rete-style network evaluating the matches of the rules of a script.
+//!
+//! Each distinct match, i.e. each watch (see `compile::group_watches`)
+//! with a given `duration`, becomes an alpha node, which remembers the
+//! getters currently in range. The conjunction of the matches of each
+//! rule is then built from beta nodes, each of which joins a node with an
+//! alpha node. Beta nodes are shared, so rules with common matches share
+//! their partial matches.
+//!
+//! When a getter enters or leaves the range of a watch, only the alpha
+//! nodes of this watch and the nodes downstream from them are evaluated,
+//! so the cost of an update grows with the number of rules it affects
+//! rather than with the size of the script.
+//!
+//! The watches themselves are shared between scripts by a `Dispatcher`:
+//! scripts that watch the same channels with the same range share a
+//! single watch on the environment, and the dispatcher indexes each
+//! getter to the conditions of all the scripts that depend on it. Beta
+//! nodes only join the matches of a rule, so each script keeps those in
+//! a `Network` of its own.
+
+use ast::Script;
+use compile::{ CompiledCtx, ExecutableDevEnv, WatchGroup };
+
+use foxbox_taxonomy::api::{ API, Targetted, WatchEvent };
+use foxbox_taxonomy::services::Getter;
+use foxbox_taxonomy::util::{ Exactly, Id };
+use foxbox_taxonomy::values::Value;
+
+use transformable_channels::mpsc::*;
+
+use chrono::Duration as ChronoDuration;
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Alpha(usize),
+    Beta(usize),
+}
+
+struct AlphaNode {
+    /// The getters currently in range. The node is met iff there is at least one.
+    getters: HashSet<Id<Getter>>,
+
+    /// `true` if the matches of the node have no `duration`, i.e. getters
+    /// are in range as soon as they enter the range of the watch.
+    is_immediate: bool,
+
+    /// The beta nodes joining this node.
+    children: Vec<usize>,
+
+    /// The rules whose only match is this node.
+    rules: Vec<usize>,
+}
+
+struct BetaNode {
+    left: Node,
+    right: usize,
+    is_met: bool,
+
+    /// The beta nodes joining this node.
+    children: Vec<usize>,
+
+    /// The rules whose matches are all joined by this node.
+    rules: Vec<usize>,
+}
+
+pub struct Network {
+    alphas: Vec<AlphaNode>,
+    betas: Vec<BetaNode>,
+
+    /// For each rule and each of its conditions, the alpha node of the condition.
+    per_condition: Vec<Vec<usize>>,
+
+    /// For each watch, its alpha nodes.
+    per_watch: Vec<Vec<usize>>,
+
+    /// For each rule, the node joining all its matches, or `None` if the
+    /// rule has no match.
+    per_rule: Vec<Option<Node>>,
+
+    /// For each getter, the alpha nodes in which it is currently in range.
+    per_getter: HashMap<Id<Getter>, Vec<usize>>,
+}
+
+impl Network {
+    pub fn new<Env>(script: &Script<CompiledCtx<Env>>, watches: &[WatchGroup]) -> Self
+        where Env: ExecutableDevEnv
+    {
+        let mut network = Network {
+            alphas: vec![],
+            betas: vec![],
+            per_condition: script.rules.iter().map(|rule| vec![0; rule.conditions.len()]).collect(),
+            per_watch: vec![vec![]; watches.len()],
+            per_rule: vec![None; script.rules.len()],
+            per_getter: HashMap::new(),
+        };
+
+        // One alpha node per watch and per duration, in milliseconds.
+        let mut alphas : HashMap<(usize, Option<i64>), usize> = HashMap::new();
+        for (watch, watch_index) in watches.iter().zip(0..) {
+            for &(rule_index, condition_index) in &watch.members {
+                let duration = script.rules[rule_index].conditions[condition_index].duration.clone()
+                    .map(|duration| ChronoDuration::from(duration).num_milliseconds());
+                let next = network.alphas.len();
+                let alpha = *alphas.entry((watch_index, duration)).or_insert(next);
+                if alpha == next {
+                    network.alphas.push(AlphaNode {
+                        getters: HashSet::new(),
+                        is_immediate: duration.is_none(),
+                        children: vec![],
+                        rules: vec![],
+                    });
+                    network.per_watch[watch_index].push(alpha);
+                }
+                network.per_condition[rule_index][condition_index] = alpha;
+            }
+        }
+
+        // Join the alpha nodes of each rule, in a canonical order, so that
+        // rules with the same first matches share their beta nodes.
+        let mut betas : HashMap<(Node, usize), usize> = HashMap::new();
+        for rule_index in 0..script.rules.len() {
+            let mut inputs = network.per_condition[rule_index].clone();
+            inputs.sort();
+            inputs.dedup();
+            let mut node = match inputs.first() {
+                None => continue,
+                Some(&alpha) => Node::Alpha(alpha)
+            };
+            for &right in &inputs[1..] {
+                let next = network.betas.len();
+                let beta = *betas.entry((node, right)).or_insert(next);
+                if beta == next {
+                    network.betas.push(BetaNode {
+                        left: node,
+                        right: right,
+                        is_met: false,
+                        children: vec![],
+                        rules: vec![],
+                    });
+                    network.children_mut(node).push(beta);
+                    network.alphas[right].children.push(beta);
+                }
+                node = Node::Beta(beta);
+            }
+            match node {
+                Node::Alpha(alpha) => network.alphas[alpha].rules.push(rule_index),
+                Node::Beta(beta) => network.betas[beta].rules.push(rule_index),
+            }
+            network.per_rule[rule_index] = Some(node);
+        }
+        network
+    }
+
+    fn children_mut(&mut self, node: Node) -> &mut Vec<usize> {
+        match node {
+            Node::Alpha(alpha) => &mut self.alphas[alpha].children,
+            Node::Beta(beta) => &mut self.betas[beta].children,
+        }
+    }
+
+    fn is_node_met(&self, node: Node) -> bool {
+        match node {
+            Node::Alpha(alpha) => !self.alphas[alpha].getters.is_empty(),
+            Node::Beta(beta) => self.betas[beta].is_met,
+        }
+    }
+
+    /// Determine whether all the matches of a rule are met.
+    pub fn matches_are_met(&self, rule_index: usize) -> bool {
+        match self.per_rule[rule_index] {
+            None => true,
+            Some(node) => self.is_node_met(node)
+        }
+    }
+
+    /// The getters currently in the range of a condition, sorted.
+    pub fn getters(&self, rule_index: usize, condition_index: usize) -> Vec<Id<Getter>> {
+        let alpha = self.per_condition[rule_index][condition_index];
+        let mut getters : Vec<_> = self.alphas[alpha].getters.iter().cloned().collect();
+        getters.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+        getters
+    }
+
+    /// A getter has entered (if `is_met`) or left the range of a condition,
+    /// taking its duration into account.
+    ///
+    /// Return the rules whose matches have changed from met to unmet or
+    /// conversely, sorted by index.
+    pub fn update(&mut self, rule_index: usize, condition_index: usize, id: Id<Getter>, is_met: bool) -> Vec<usize> {
+        let alpha = self.per_condition[rule_index][condition_index];
+        let has_changed = if is_met {
+            self.insert(alpha, id)
+        } else {
+            self.remove(alpha, &id)
+        };
+        if has_changed {
+            self.propagate(vec![alpha])
+        } else {
+            vec![]
+        }
+    }
+
+    /// A getter has entered the range of a watch. It is immediately in
+    /// range for the matches without a duration, the other matches wait
+    /// for their timers.
+    ///
+    /// Return the rules whose matches have changed, sorted by index.
+    pub fn enter(&mut self, watch_index: usize, id: &Id<Getter>) -> Vec<usize> {
+        let changed = self.per_watch[watch_index].clone().into_iter()
+            .filter(|&alpha| self.alphas[alpha].is_immediate && self.insert(alpha, id.clone()))
+            .collect();
+        self.propagate(changed)
+    }
+
+    /// A getter has left the range of a watch, hence of all its matches.
+    ///
+    /// Return the rules whose matches have changed, sorted by index.
+    pub fn exit(&mut self, watch_index: usize, id: &Id<Getter>) -> Vec<usize> {
+        let changed = self.per_watch[watch_index].clone().into_iter()
+            .filter(|&alpha| self.remove(alpha, id))
+            .collect();
+        self.propagate(changed)
+    }
+
+    /// Add a getter to an alpha node. Return `true` if the node has become met.
+    fn insert(&mut self, alpha: usize, id: Id<Getter>) -> bool {
+        if !self.alphas[alpha].getters.insert(id.clone()) {
+            return false;
+        }
+        self.per_getter.entry(id).or_insert_with(Vec::new).push(alpha);
+        self.alphas[alpha].getters.len() == 1
+    }
+
+    /// Remove a getter from an alpha node. Return `true` if the node has become unmet.
+    fn remove(&mut self, alpha: usize, id: &Id<Getter>) -> bool {
+        if !self.alphas[alpha].getters.remove(id) {
+            return false;
+        }
+        let is_empty = match self.per_getter.get_mut(id) {
+            Some(alphas) => {
+                alphas.retain(|&other| other != alpha);
+                alphas.is_empty()
+            }
+            None => false
+        };
+        if is_empty {
+            self.per_getter.remove(id);
+        }
+        self.alphas[alpha].getters.is_empty()
+    }
+
+    /// A getter has disappeared. It leaves the range of every condition,
+    /// whichever its watch, hence the index of the alpha nodes of each getter.
+    ///
+    /// Return the rules whose matches have changed, sorted by index.
+    pub fn remove_getter(&mut self, id: &Id<Getter>) -> Vec<usize> {
+        let alphas = match self.per_getter.remove(id) {
+            None => return vec![],
+            Some(alphas) => alphas
+        };
+        let changed = alphas.into_iter().filter(|&alpha| {
+            self.alphas[alpha].getters.remove(id);
+            self.alphas[alpha].getters.is_empty()
+        }).collect();
+        self.propagate(changed)
+    }
+
+    /// Re-evaluate the beta nodes downstream from alpha nodes that have changed.
+    fn propagate(&mut self, alphas: Vec<usize>) -> Vec<usize> {
+        let mut rules = vec![];
+        let mut stack = vec![];
+        for alpha in alphas {
+            rules.extend_from_slice(&self.alphas[alpha].rules);
+            stack.extend_from_slice(&self.alphas[alpha].children);
+        }
+        while let Some(beta) = stack.pop() {
+            let is_met = self.is_node_met(self.betas[beta].left) &&
+                self.is_node_met(Node::Alpha(self.betas[beta].right));
+            if is_met == self.betas[beta].is_met {
+                continue;
+            }
+            self.betas[beta].is_met = is_met;
+            rules.extend_from_slice(&self.betas[beta].rules);
+            stack.extend_from_slice(&self.betas[beta].children);
+        }
+        rules.sort();
+        rules.dedup();
+        rules
+    }
+}
+
+/// A condition of a script that depends on a getter, see `Dispatcher::dependents`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Dependent {
+    /// The name of the script.
+    pub script: String,
+    pub rule_index: usize,
+    pub condition_index: usize,
+}
+
+enum DispatchOp {
+    Subscribe {
+        key: usize,
+        script: String,
+        watch: WatchGroup,
+        tx: Box<ExtSender<WatchEvent>>,
+
+        /// Used to start the watch on the environment, if it does not exist yet.
+        tx_dispatch: Box<ExtSender<DispatchOp>>,
+    },
+    Unsubscribe(usize),
+    SetMembers(usize, Vec<(usize, usize)>),
+    Event {
+        watch_key: usize,
+        event: WatchEvent,
+    },
+    Dependents(Id<Getter>, Sender<Vec<Dependent>>),
+}
+
+/// A handle to the watches shared by many scripts, e.g. all the scripts
+/// of a `ScriptManager`.
+///
+/// The watches are started on the environment given to `Dispatcher::new`,
+/// which should therefore be the environment of the scripts. Cloning the
+/// handle does not create new watches. The dispatcher stops once all the
+/// handles and all the subscriptions have been dropped.
+#[derive(Clone)]
+pub struct Dispatcher {
+    tx: Box<ExtSender<DispatchOp>>,
+    next_key: Arc<AtomicUsize>,
+}
+
+impl Dispatcher {
+    /// Start a dispatcher, on a thread of its own.
+    pub fn new<Env>(env: Env) -> Self where Env: ExecutableDevEnv + 'static {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut state = DispatcherState {
+                env: env,
+                watches: HashMap::new(),
+                next_watch_key: 0,
+                guards: HashMap::new(),
+                subscribers: HashMap::new(),
+                per_getter: HashMap::new(),
+            };
+            for op in rx {
+                state.handle(op);
+            }
+        });
+        Dispatcher {
+            tx: Box::new(tx),
+            next_key: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Subscribe to the events of a watch of a script, starting the watch
+    /// unless another script already has it. A late subscriber first
+    /// receives a `GetterAdded` for each getter already known to the watch,
+    /// followed by an `EnterRange` if the getter is in range. Initialization
+    /// errors reported before the subscription are not repeated.
+    ///
+    /// The subscription stops once the result is dropped.
+    pub fn subscribe(&self, script: &str, watch: &WatchGroup, tx: Box<ExtSender<WatchEvent>>) -> Subscription {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let _ = self.tx.send(DispatchOp::Subscribe {
+            key: key,
+            script: script.to_owned(),
+            watch: watch.clone(),
+            tx: tx,
+            tx_dispatch: self.tx.clone(),
+        });
+        Subscription {
+            key: key,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// The conditions of all the subscribed scripts whose watches currently
+    /// know a getter, whether the getter is in range or not.
+    pub fn dependents(&self, id: &Id<Getter>) -> Vec<Dependent> {
+        let (tx, rx) = channel();
+        let _ = self.tx.send(DispatchOp::Dependents(id.clone(), tx));
+        rx.recv().unwrap_or_else(|_| vec![])
+    }
+}
+
+/// A subscription to a watch of a `Dispatcher`. Dropping it unsubscribes.
+pub struct Subscription {
+    key: usize,
+    tx: Box<ExtSender<DispatchOp>>,
+}
+
+impl Subscription {
+    /// Inform the dispatcher that the matches sharing the watch have
+    /// changed, e.g. because the script has been reloaded.
+    pub fn set_members(&self, members: Vec<(usize, usize)>) {
+        let _ = self.tx.send(DispatchOp::SetMembers(self.key, members));
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self.tx.send(DispatchOp::Unsubscribe(self.key));
+    }
+}
+
+struct SharedWatch {
+    watch: WatchGroup,
+
+    /// The getters known to the watch, with their latest value if they are in range.
+    getters: HashMap<Id<Getter>, Option<Value>>,
+    subscribers: Vec<usize>,
+}
+
+struct Subscriber {
+    script: String,
+    watch_key: usize,
+
+    /// The `(rule_index, condition_index)` of the matches of the script sharing the watch.
+    members: Vec<(usize, usize)>,
+    tx: Box<ExtSender<WatchEvent>>,
+}
+
+struct DispatcherState<Env> where Env: ExecutableDevEnv {
+    env: Env,
+    watches: HashMap<usize, SharedWatch>,
+    next_watch_key: usize,
+
+    /// The guards of `watches`, by key. Watching stops once these are dropped.
+    guards: HashMap<usize, Env::WatchGuard>,
+    subscribers: HashMap<usize, Subscriber>,
+
+    /// For each getter, the watches that know it.
+    per_getter: HashMap<Id<Getter>, HashSet<usize>>,
+}
+
+impl<Env> DispatcherState<Env> where Env: ExecutableDevEnv {
+    fn handle(&mut self, op: DispatchOp) {
+        match op {
+            DispatchOp::Subscribe { key, script, watch, tx, tx_dispatch } => {
+                let existing = self.watches.iter()
+                    .find(|&(_, shared)| shared.watch.same_watch(&watch))
+                    .map(|(&watch_key, _)| watch_key);
+                let watch_key = match existing {
+                    Some(watch_key) => watch_key,
+                    None => {
+                        let watch_key = self.next_watch_key;
+                        self.next_watch_key += 1;
+                        debug!("[Dispatcher] Starting watch {} for {:?}", watch_key, watch.source);
+                        let guard = self.env.api().watch_values(
+                            vec![Targetted {
+                                select: watch.source.clone(),
+                                payload: Exactly::Exactly(watch.range.clone())
+                            }],
+                            Box::new(tx_dispatch.map(move |event| {
+                                DispatchOp::Event {
+                                    watch_key: watch_key,
+                                    event: event,
+                                }
+                            })));
+                        self.guards.insert(watch_key, guard);
+                        self.watches.insert(watch_key, SharedWatch {
+                            watch: watch.clone(),
+                            getters: HashMap::new(),
+                            subscribers: vec![],
+                        });
+                        watch_key
+                    }
+                };
+                {
+                    let shared = self.watches.get_mut(&watch_key).unwrap();
+                    for (id, value) in &shared.getters {
+                        let _ = tx.send(WatchEvent::GetterAdded(id.clone()));
+                        if let Some(ref value) = *value {
+                            let _ = tx.send(WatchEvent::EnterRange {
+                                from: id.clone(),
+                                value: value.clone(),
+                            });
+                        }
+                    }
+                    shared.subscribers.push(key);
+                }
+                self.subscribers.insert(key, Subscriber {
+                    script: script,
+                    watch_key: watch_key,
+                    members: watch.members,
+                    tx: tx,
+                });
+            }
+            DispatchOp::Unsubscribe(key) => {
+                let watch_key = match self.subscribers.remove(&key) {
+                    None => return,
+                    Some(subscriber) => subscriber.watch_key
+                };
+                let is_unused = match self.watches.get_mut(&watch_key) {
+                    None => return,
+                    Some(shared) => {
+                        shared.subscribers.retain(|&other| other != key);
+                        shared.subscribers.is_empty()
+                    }
+                };
+                if is_unused {
+                    debug!("[Dispatcher] Stopping watch {}", watch_key);
+                    self.guards.remove(&watch_key);
+                    let shared = self.watches.remove(&watch_key).unwrap();
+                    for id in shared.getters.keys() {
+                        self.forget(id, watch_key);
+                    }
+                }
+            }
+            DispatchOp::SetMembers(key, members) => {
+                if let Some(subscriber) = self.subscribers.get_mut(&key) {
+                    subscriber.members = members;
+                }
+            }
+            DispatchOp::Dependents(id, tx) => {
+                let mut dependents = vec![];
+                for watch_key in self.per_getter.get(&id).into_iter().flat_map(|watches| watches.iter()) {
+                    for key in &self.watches[watch_key].subscribers {
+                        let subscriber = &self.subscribers[key];
+                        dependents.extend(subscriber.members.iter().map(|&(rule_index, condition_index)| Dependent {
+                            script: subscriber.script.clone(),
+                            rule_index: rule_index,
+                            condition_index: condition_index,
+                        }));
+                    }
+                }
+                dependents.sort_by(|a, b| (&a.script, a.rule_index, a.condition_index).cmp(&(&b.script, b.rule_index, b.condition_index)));
+                let _ = tx.send(dependents);
+            }
+            DispatchOp::Event { watch_key, event } => {
+                if !self.watches.contains_key(&watch_key) {
+                    // The watch has been stopped.
+                    return;
+                }
+                match event {
+                    WatchEvent::GetterRemoved(id) => {
+                        // The getter leaves all the watches that know it at
+                        // once, so other watches may report it again.
+                        let watches = match self.per_getter.remove(&id) {
+                            None => return,
+                            Some(watches) => watches
+                        };
+                        for watch_key in watches {
+                            if let Some(shared) = self.watches.get_mut(&watch_key) {
+                                shared.getters.remove(&id);
+                            }
+                            self.dispatch(watch_key, || WatchEvent::GetterRemoved(id.clone()));
+                        }
+                    }
+                    WatchEvent::GetterAdded(id) => {
+                        self.learn(&id, watch_key, None);
+                        self.dispatch(watch_key, || WatchEvent::GetterAdded(id.clone()));
+                    }
+                    WatchEvent::EnterRange { from, value } => {
+                        self.learn(&from, watch_key, Some(value.clone()));
+                        self.dispatch(watch_key, || WatchEvent::EnterRange { from: from.clone(), value: value.clone() });
+                    }
+                    WatchEvent::ExitRange { from, value } => {
+                        self.learn(&from, watch_key, None);
+                        self.dispatch(watch_key, || WatchEvent::ExitRange { from: from.clone(), value: value.clone() });
+                    }
+                    WatchEvent::InitializationError { channel, error } => {
+                        self.dispatch(watch_key, || WatchEvent::InitializationError { channel: channel.clone(), error: error.clone() });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Record the latest state of a getter in a watch.
+    fn learn(&mut self, id: &Id<Getter>, watch_key: usize, value: Option<Value>) {
+        self.watches.get_mut(&watch_key).unwrap().getters.insert(id.clone(), value);
+        self.per_getter.entry(id.clone()).or_insert_with(HashSet::new).insert(watch_key);
+    }
+
+    /// Remove a watch from the index of a getter.
+    fn forget(&mut self, id: &Id<Getter>, watch_key: usize) {
+        let is_empty = match self.per_getter.get_mut(id) {
+            None => return,
+            Some(watches) => {
+                watches.remove(&watch_key);
+                watches.is_empty()
+            }
+        };
+        if is_empty {
+            self.per_getter.remove(id);
+        }
+    }
+
+    /// Send an event to the subscribers of a watch.
+    fn dispatch<F>(&self, watch_key: usize, event: F) where F: Fn() -> WatchEvent {
+        let shared = match self.watches.get(&watch_key) {
+            None => return,
+            Some(shared) => shared
+        };
+        for key in &shared.subscribers {
+            let _ = self.subscribers[key].tx.send(event());
+        }
+    }
+}
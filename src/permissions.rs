@@ -0,0 +1,74 @@
+//! Deciding which channels the owner of a script may read or write.
+//!
+//! Permissions are checked when a script is compiled, against the
+//! channels that exist at that time, then whenever a value is received
+//! or sent, for channels that appeared later or permissions that changed.
+
+use foxbox_taxonomy::api::User;
+use foxbox_taxonomy::services::{ Getter, Setter };
+use foxbox_taxonomy::util::Id;
+
+use std::sync::RwLock;
+
+/// An operation on a channel.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum Access {
+    /// Reading or watching the value of a getter.
+    Read(Id<Getter>),
+
+    /// Sending a value to a setter.
+    Write(Id<Setter>),
+}
+
+/// A policy deciding whether a user may perform an operation on a channel.
+pub trait PermissionPolicy: Send + Sync {
+    fn is_allowed(&self, user: &User, access: &Access) -> bool;
+}
+
+/// A policy that allows everything. This is the default policy of
+/// `ExecutableDevEnv`.
+pub struct AllowAll;
+impl PermissionPolicy for AllowAll {
+    fn is_allowed(&self, _: &User, _: &Access) -> bool {
+        true
+    }
+}
+
+/// A policy held in memory, which may be changed at any time. Useful
+/// mainly for writing tests.
+pub struct InMemoryPolicy {
+    /// The decision for operations that are not mentioned in `rules`.
+    default: bool,
+
+    /// Explicit decisions. Later decisions override earlier ones.
+    rules: RwLock<Vec<(User, Access, bool)>>,
+}
+
+impl InMemoryPolicy {
+    /// Create a policy that allows (if `default` is `true`) or denies
+    /// (otherwise) everything, until `allow` or `deny` is called.
+    pub fn new(default: bool) -> Self {
+        InMemoryPolicy {
+            default: default,
+            rules: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn allow(&self, user: &User, access: Access) {
+        self.rules.write().unwrap().push((user.clone(), access, true));
+    }
+
+    pub fn deny(&self, user: &User, access: Access) {
+        self.rules.write().unwrap().push((user.clone(), access, false));
+    }
+}
+
+impl PermissionPolicy for InMemoryPolicy {
+    fn is_allowed(&self, user: &User, access: &Access) -> bool {
+        let rules = self.rules.read().unwrap();
+        rules.iter().rev()
+            .find(|&&(ref rule_user, ref rule_access, _)| rule_user == user && rule_access == access)
+            .map(|&(_, _, is_allowed)| is_allowed)
+            .unwrap_or(self.default)
+    }
+}
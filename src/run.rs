@@ -1,35 +1,117 @@
 //! Launching and running the script
 
-use ast::{ Script, Statement, UncheckedCtx } ;
-use compile::{ Compiler, CompiledCtx, ExecutableDevEnv } ;
-pub use compile::{ Error as CompileError, SourceError, TypeError };
-use compile;
+use aggregate::Aggregator;
+use ast::{ ActiveWhen, Script, SendValues, TimeWindow, SetVariable, Statement, UncheckedCtx, VariableDeclaration, VariableMatch,
+           VariableOp, VariableValue } ;
+use compile::{ group_watches, Compiler, CompiledCtx, ExecutableDevEnv, Limits, WatchGroup } ;
+use diff::{ diff, ScriptDiff };
+use executor::{ Pool, Spawn, Task };
+use network::{ Dispatcher, Network, Subscription };
+use permissions::Access;
+pub use compile::{ DependencyError, Diagnostic, Error as CompileError, Location, LocationStep, RangeError,
+                   PermissionError, ResolutionError, Severity, SourceError, TypeError };
+use virtual_channels;
 
 use foxbox_taxonomy::api;
 use foxbox_taxonomy::api::{ API, Error as APIError, Targetted, User, WatchEvent };
+use foxbox_taxonomy::selector::SetterSelector;
 use foxbox_taxonomy::services::{ Getter, Setter };
 use foxbox_taxonomy::util::{ Exactly, Id };
-use foxbox_taxonomy::values::Duration;
+use foxbox_taxonomy::values::{ Duration, TimeStamp, Value };
+
+use chrono::{ DateTime, Duration as ChronoDuration, Timelike, UTC };
 
 use transformable_channels::mpsc::*;
 
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{ HashMap, VecDeque };
+use std::i64;
 use std::fmt;
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::thread;
 use std::sync::Mutex;
+use std::time::{ Duration as StdDuration, Instant };
+
+/// A pool of threads on which scripts are executed.
+///
+/// Many scripts may share a single executor, in which case they are
+/// multiplexed on its threads. Each script is handled by a single thread,
+/// so a script that blocks, e.g. while sending values, delays the other
+/// scripts on the same thread.
+#[derive(Clone)]
+pub struct Executor {
+    pool: Pool<ExecutionOp>,
+}
+
+impl Executor {
+    /// Create an executor with `threads` threads (at least one).
+    pub fn new(threads: usize) -> Self {
+        Executor {
+            pool: Pool::new(threads)
+        }
+    }
+
+    /// The number of threads of the executor.
+    pub fn threads(&self) -> usize {
+        self.pool.size()
+    }
+}
+
+impl Default for Executor {
+    /// An executor with a single thread. This is the cheapest executor,
+    /// but all the scripts that share it wait for one another, see
+    /// `ScriptManager::set_executor`.
+    fn default() -> Self {
+        Executor::new(1)
+    }
+}
 
 /// Running and controlling a single script.
 pub struct Execution<Env> where Env: ExecutableDevEnv + Debug + 'static {
     command_sender: Option<Box<ExtSender<ExecutionOp>>>,
+
+    /// The executor running the script. If `None`, a dedicated executor is
+    /// created when the script starts.
+    executor: Option<Executor>,
+
+    /// The watches shared with other scripts. If `None`, the script
+    /// watches its channels on its own.
+    dispatcher: Option<Dispatcher>,
     phantom: PhantomData<Env>,
 }
 
 impl<Env> Execution<Env> where Env: ExecutableDevEnv + Debug + 'static {
+    /// Prepare to run a script on a thread of its own, i.e. on a dedicated
+    /// executor with a single thread. The script is never delayed by other
+    /// scripts, at the cost of one thread per script. To run many scripts,
+    /// prefer `with_executor`.
     pub fn new() -> Self {
         Execution {
             command_sender: None,
+            executor: None,
+            dispatcher: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Prepare to run a script on a shared executor.
+    pub fn with_executor(executor: Executor) -> Self {
+        Execution {
+            command_sender: None,
+            executor: Some(executor),
+            dispatcher: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Prepare to run a script on a shared executor, sharing its watches
+    /// with the other scripts of `dispatcher`. The dispatcher must watch
+    /// the environment with which the script is started.
+    pub fn with_dispatcher(executor: Executor, dispatcher: Dispatcher) -> Self {
+        Execution {
+            command_sender: None,
+            executor: Some(executor),
+            dispatcher: Some(dispatcher),
             phantom: PhantomData,
         }
     }
@@ -51,6 +133,16 @@ impl<Env> Execution<Env> where Env: ExecutableDevEnv + Debug + 'static {
     pub fn start<S>(&mut self, env: Env, script: Script<UncheckedCtx>, owner: User, on_event: S) ->
         Result<(), Error>
         where S: ExtSender<ExecutionEvent> + Clone
+    {
+        self.start_with_options(env, script, owner, Options::default(), on_event)
+    }
+
+    /// Start executing the script, with non-default options.
+    ///
+    /// See `start` for details.
+    pub fn start_with_options<S>(&mut self, env: Env, script: Script<UncheckedCtx>, owner: User,
+        options: Options, on_event: S) -> Result<(), Error>
+        where S: ExtSender<ExecutionEvent> + Clone
     {
         let name = script.name.clone();
         info!("[Recipe '{}'] Starting compilation of script.", name);
@@ -61,38 +153,222 @@ impl<Env> Execution<Env> where Env: ExecutableDevEnv + Debug + 'static {
             });
             err
         } else {
-            // One-time channel, used to wait until compilation is complete.
+            // One-time channel, used to wait until compilation is complete
+            // and to receive the channel used to control the script.
             let (tx_init, rx_init) = channel();
 
-            let (tx, rx) = channel();
-            self.command_sender = Some(Box::new(tx.clone()));
-            thread::spawn(move || {
-                match ExecutionTask::<Env>::new(script, owner, tx, rx) {
-                    Err(er) => {
-                        info!("[Recipe '{}'] Compilation failed {:?}", name, er);
-                        let _ = on_event.send(ExecutionEvent::Starting {
-                            result: Err(er.clone())
-                        });
-                        let _ = tx_init.send(Err(er));
-                    },
-                    Ok(mut task) => {
-                        info!("[Recipe '{}'] Compilation succeeded.", name);
-                        let _ = on_event.send(ExecutionEvent::Starting {
-                            result: Ok(())
-                        });
-                        let _ = tx_init.send(Ok(()));
-                        task.run(env, on_event);
-                    }
-                }
-            });
+            if self.executor.is_none() {
+                self.executor = Some(Executor::default());
+            }
+            self.executor.as_ref().unwrap().pool.spawn(Box::new(StartTask {
+                env: env,
+                script: script,
+                owner: owner,
+                options: options,
+                on_event: on_event,
+                dispatcher: self.dispatcher.clone(),
+                tx_init: tx_init,
+            }));
             match rx_init.recv() {
-                Ok(result) => result,
+                Ok(Ok(tx)) => {
+                    self.command_sender = Some(tx);
+                    Ok(())
+                }
+                Ok(Err(er)) => Err(er),
                 Err(_) => Err(Error::StartStopError(StartStopError::ThreadError))
             }
         }
     }
 
 
+    /// Inform the script that the house has changed mode, asynchronously.
+    ///
+    /// Rules that are restricted to the new mode and whose conditions are
+    /// already met fire immediately.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet.
+    pub fn set_mode(&self, mode: Option<String>) -> Result<(), Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let _ignored = tx.send(ExecutionOp::SetMode(mode));
+                Ok(())
+            }
+        }
+    }
+
+    /// Pause the script, asynchronously.
+    ///
+    /// While the script is paused, it keeps watching its channels and
+    /// tracking which rules are met, but does not execute any statement.
+    /// Statements waiting to be retried are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet.
+    pub fn pause(&self) -> Result<(), Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let _ignored = tx.send(ExecutionOp::Pause);
+                Ok(())
+            }
+        }
+    }
+
+    /// Resume a paused script, asynchronously. Does nothing if the script
+    /// is not paused.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet.
+    pub fn resume(&self, policy: ResumePolicy) -> Result<(), Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let _ignored = tx.send(ExecutionOp::Resume(policy));
+                Ok(())
+            }
+        }
+    }
+
+    /// Take a snapshot of the state of the script, once it has handled
+    /// the messages sent before this call.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet.
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let (tx_snapshot, rx_snapshot) = channel();
+                let _ignored = tx.send(ExecutionOp::Snapshot(tx_snapshot));
+                rx_snapshot.recv().map_err(|_| Error::StartStopError(StartStopError::ThreadError))
+            }
+        }
+    }
+
+    /// Replace the running script by a new version, once the script has
+    /// handled the messages sent before this call.
+    ///
+    /// Rules that have not changed keep their state, including their
+    /// running timers, even if they have moved. Other rules start afresh,
+    /// without firing, as if the script had just started. If the new
+    /// version does not compile, the current version keeps running.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet,
+    /// and RunningError:CannotReload if the new version declares different
+    /// variables, virtual channels or derived getters.
+    pub fn reload(&self, script: Script<UncheckedCtx>, owner: User) -> Result<ScriptDiff, Error> {
+        self.reload_aux(script, owner, None)
+    }
+
+    /// Replace the running script by a new version, as `reload`, along with
+    /// some of its options. Only `strict`, `limits` and `version` are taken
+    /// into account, the other options only apply when the script starts.
+    pub fn reload_with_options(&self, script: Script<UncheckedCtx>, owner: User, options: Options) ->
+        Result<ScriptDiff, Error>
+    {
+        self.reload_aux(script, owner, Some(options))
+    }
+
+    fn reload_aux(&self, script: Script<UncheckedCtx>, owner: User, options: Option<Options>) ->
+        Result<ScriptDiff, Error>
+    {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let (tx_result, rx_result) = channel();
+                let _ignored = tx.send(ExecutionOp::Reload {
+                    script: script,
+                    owner: owner,
+                    options: options,
+                    on_result: tx_result
+                });
+                match rx_result.recv() {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::StartStopError(StartStopError::ThreadError))
+                }
+            }
+        }
+    }
+
+    /// Execute the statements of a rule, regardless of its conditions,
+    /// once the script has handled the messages sent before this call.
+    ///
+    /// The values are sent on behalf of the owner of the script, as if the
+    /// rule had fired, and reported as `ExecutionEvent::Sent` events marked
+    /// as manual. Firing a rule manually does not affect its state and does
+    /// not count against the circuit breaker, and works even if the script
+    /// is paused.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet,
+    /// and NoSuchRule if the script has no rule `rule_index`.
+    pub fn fire_rule(&self, rule_index: usize) -> Result<(), Error> {
+        self.fire(rule_index, None)
+    }
+
+    /// Execute a single statement of a rule, as `fire_rule`.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet,
+    /// and NoSuchRule or NoSuchStatement if the script has no such statement.
+    pub fn fire_statement(&self, rule_index: usize, statement_index: usize) -> Result<(), Error> {
+        self.fire(rule_index, Some(statement_index))
+    }
+
+    /// Let a rule suspended by the circuit breaker fire again, once the
+    /// script has handled the messages sent before this call. The rule fires
+    /// the next time its conditions become met. Rules that are not suspended
+    /// are unaffected.
+    ///
+    /// # Errors
+    ///
+    /// Produces RunningError:NotRunning if the script is not running yet,
+    /// and NoSuchRule if the script has no rule `rule_index`.
+    pub fn resume_rule(&self, rule_index: usize) -> Result<(), Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let (tx_result, rx_result) = channel();
+                let _ignored = tx.send(ExecutionOp::ResumeRule {
+                    rule_index: rule_index,
+                    on_result: tx_result
+                });
+                match rx_result.recv() {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::StartStopError(StartStopError::ThreadError))
+                }
+            }
+        }
+    }
+
+    fn fire(&self, rule_index: usize, statement_index: Option<usize>) -> Result<(), Error> {
+        match self.command_sender {
+            None => Err(Error::StartStopError(StartStopError::NotRunning)),
+            Some(ref tx) => {
+                let (tx_result, rx_result) = channel();
+                let _ignored = tx.send(ExecutionOp::Fire {
+                    rule_index: rule_index,
+                    statement_index: statement_index,
+                    on_result: tx_result
+                });
+                match rx_result.recv() {
+                    Ok(result) => result,
+                    Err(_) => Err(Error::StartStopError(StartStopError::ThreadError))
+                }
+            }
+        }
+    }
+
     /// Stop executing the script, asynchronously.
     ///
     /// # Errors
@@ -119,377 +395,2071 @@ impl<Env> Drop for Execution<Env> where Env: ExecutableDevEnv + Debug + 'static
     }
 }
 
-/// A script ready to be executed. Each script is meant to be
-/// executed in an individual thread.
-pub struct ExecutionTask<Env> where Env: ExecutableDevEnv {
-    script: Script<CompiledCtx<Env>>,
-    owner: User,
+/// Options for starting the execution of a script.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Values for the variables of the script, e.g. as stored by a
+    /// previous execution. These values replace the initial values
+    /// declared by the script. Values for variables that are not
+    /// declared or that have a different type are ignored.
+    pub variables: HashMap<String, VariableValue>,
 
-    /// Communicating with the thread running script.
-    tx: Box<ExtSender<ExecutionOp>>,
-    rx: Receiver<ExecutionOp>,
-}
+    /// The current mode of the house, if any.
+    pub mode: Option<String>,
 
-#[derive(Debug)]
-pub enum ExecutionEvent {
-    Starting {
-        result: Result<(), Error>,
-    },
-    Stopped {
-        result: Result<(), Error>
-    },
-    Sent {
-        rule_index: usize,
-        statement_index: usize,
-        result: Vec<(Id<Setter>, Result<(), Error>)>
-    },
-    TimerStart {
-        rule_index: usize,
-        condition_index: usize,
-    },
-    TimerCancel {
-        rule_index: usize,
-        condition_index: usize,
-    },
-    ChannelError {
-        id: Id<Getter>,
-        error: APIError,
-    }
-}
+    /// If `true`, refuse to start scripts for which the compiler emits warnings.
+    pub strict: bool,
 
-enum ExecutionOp {
-    /// We have received an update from the AdapterManager.
-    Update {
-        /// The individual event.
-        event: WatchEvent,
+    /// When to suspend rules caught in a feedback loop.
+    pub circuit_breaker: CircuitBreaker,
 
-        /// The rule to which this event applies.
-        rule_index: usize,
+    /// Refuse to start scripts that exceed these limits.
+    pub limits: Limits,
 
-        /// The index to which this event applies.
-        condition_index: usize
-    },
+    /// If `true`, start the script paused (see `Execution::pause`).
+    pub paused: bool,
 
-    /// A channel state has enter/left its target range and we
-    /// have waited long enough to trigger the consequences.
-    UpdateCondition {
-        /// The channel that has changed state.
-        id: Id<Getter>,
+    /// Whether the script actually sends values.
+    pub run_mode: RunMode,
 
-        /// `true` if the condition is now met, `false` otherwise.
-        is_met: bool,
+    /// The state of the script, as checkpointed by a previous execution.
+    /// Rules that were met are met again, without firing, and timers resume
+    /// where they stopped. The checkpoint is ignored if it does not match
+    /// the rules of the script.
+    pub checkpoint: Option<Snapshot>,
 
-        /// The rule to which this event applies.
-        rule_index: usize,
+    /// What to do with the timers of `checkpoint` that have expired in the
+    /// meantime.
+    pub expired_timers: ExpiredTimerPolicy,
 
-        /// The index to which this event applies.
-        condition_index: usize,
-    },
+    /// If `true`, report the state of the script as `ExecutionEvent::Checkpoint`
+    /// whenever it changes.
+    pub checkpoints: bool,
 
-    /// Time to stop executing the script.
-    Stop(Mutex<Box<Fn(Result<(), Error>) + Send>>)
+    /// The minimal delay between two checkpoints. Changes within this delay
+    /// are reported together, once it has elapsed.
+    pub checkpoint_interval: StdDuration,
+
+    /// Whether to fetch the current values of the getters when the script
+    /// starts, rather than waiting for them to change.
+    pub seed: SeedPolicy,
+
+    /// Identifies the version of the script, e.g. a hash of its source.
+    /// Checkpoints record the version of the script, and a checkpoint of
+    /// another version is ignored.
+    pub version: Option<String>,
 }
 
-impl Debug for ExecutionOp {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        use self::ExecutionOp::*;
-        match *self {
-            Update {..} => formatter.write_str("Update"),
-            UpdateCondition { .. } => formatter.write_str("UpdateCondition"),
-            Stop (_) => formatter.write_str("Stop")
-        }
+/// What to do with the current values of the getters when a script starts.
+///
+/// Adapters do not necessarily report the values of getters until they
+/// change, so conditions that are already met may otherwise remain unknown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeedPolicy {
+    /// Do not fetch the values. Conditions are met once the adapters report
+    /// values in range.
+    Ignore,
+
+    /// Fetch the values and update the conditions, but do not fire the rules
+    /// that are initially met. They fire the next time they become met.
+    Arm,
+
+    /// Fetch the values and fire the rules that are initially met.
+    Fire,
+}
+
+impl Default for SeedPolicy {
+    fn default() -> Self {
+        SeedPolicy::Ignore
     }
 }
 
-struct ConditionState {
-    match_is_met: bool,
+/// What to do with the timers of a checkpoint that have expired while the
+/// script was not running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpiredTimerPolicy {
+    /// Forget the timers. The getters need to enter the range again.
+    Discard,
 
-    /// The set of getters for which the condition is met.
-    per_getter: HashSet<Id<Getter>>,
+    /// Consider that the getters have remained in range long enough, but
+    /// do not fire the rules that are met as a consequence.
+    Arm,
 
-    /// If `None`, a duration is attached to this condition and we need to make sure that the
-    /// condition remains true for at least `duration` before we decide whether to proceed with
-    /// statements.
-    duration: Option<Duration>
+    /// Consider that the getters have remained in range long enough, and
+    /// fire the rules that are met as a consequence, as if the timers had
+    /// just expired.
+    Fire,
 }
-struct RuleState<Env> where Env: ExecutableDevEnv {
-    rule_is_met: bool,
-    per_condition: Vec<ConditionState>,
-    ongoing_timer: Option<Env::TimerGuard>, // FIXME: It's actually a guard.
+
+impl Default for ExpiredTimerPolicy {
+    fn default() -> Self {
+        ExpiredTimerPolicy::Fire
+    }
 }
 
-impl<Env> ExecutionTask<Env> where Env: ExecutableDevEnv + Debug {
-    /// Create a new execution task.
+/// Whether a script actually sends values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunMode {
+    /// Send values to setters.
+    Live,
+
+    /// Watch getters and evaluate rules as usual, but instead of sending
+    /// values, report the setters that would have received them, as
+    /// `ExecutionEvent::WouldSend`. Used to try a script before enabling it.
     ///
-    /// The caller is responsible for spawning a new thread and
-    /// calling `run()`.
-    fn new<S>(script: Script<UncheckedCtx>, owner: User, tx: S, rx: Receiver<ExecutionOp>) -> Result<Self, Error>
-        where S: ExtSender<ExecutionOp> + Clone
-    {
-        let compiler = try!(Compiler::new().map_err(|err| Error::CompileError(err)));
-        let script = try!(compiler.compile(script).map_err(|err| Error::CompileError(err)));
+    /// So as not to affect other scripts, the virtual channels and derived
+    /// getters of the script are neither declared nor updated.
+    Shadow,
+}
 
-        Ok(ExecutionTask {
-            script: script,
-            owner: owner,
-            rx: rx,
-            tx: Box::new(tx)
-        })
+impl Default for RunMode {
+    fn default() -> Self {
+        RunMode::Live
     }
+}
 
-    /// Execute the monitoring task.
-    /// This currently expects to be executed in its own thread.
-    fn run<S>(&mut self, env: Env, on_event: S) where S: ExtSender<ExecutionEvent> + Clone {
-        info!("[Recipe '{}'] Starting execution of script", self.script.name);
+/// What to do with rules that have become met while a script was paused,
+/// and are still met when it resumes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResumePolicy {
+    /// Do not fire them. They will fire the next time they become met.
+    Skip,
 
-        let mut witnesses = Vec::new();
-        let api = env.api();
+    /// Fire them immediately.
+    Fire,
+}
 
-        // Generate the state of rules, conditions, getters and start
-        // listening to changes in the getters.
+/// A view of the state of a running script, as returned by
+/// `Execution::snapshot`.
+///
+/// A snapshot may also be used as a checkpoint, to restore the state of
+/// the script after a restart (see `Options::checkpoint`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The state of each rule, in the order of the script.
+    pub rules: Vec<RuleSnapshot>,
 
-        // FIXME: We could optimize requests by detecting if several share a `TargetMap<GetterSelector, Exactly<Range>>`
-        let mut per_rule : Vec<_> = self.script.rules.iter().zip(0 as usize..).map(|(rule, rule_index)| {
-            let per_condition = rule.conditions.iter().zip(0 as usize..).map(|(condition, condition_index)| {
-                // We will often end up watching several times the
-                // same channel. For the moment, we do not attempt to
-                // optimize either I/O (which we expect will be
-                // optimized by `watcher`) or condition checking
-                // (which we should eventually optimize, if we find
-                // out that we end up with large rulesets).
-
-                let getters = api.get_getter_channels(condition.source.clone());
-                info!("[Recipe '{}'] Initializing rule {} condition {}. Currently, it can listen to {} channels.", self.script.name,
-                    rule_index, condition_index, getters.len());
-
-                let rule_index = rule_index.clone();
-                let condition_index = condition_index.clone();
-                witnesses.push(
-                    api.watch_values(
-                        vec![Targetted {
-                            select: condition.source.clone(),
-                            payload: Exactly::Exactly(condition.range.clone())
-                        }],
-                        Box::new(self.tx.map(move |event| {
-                            ExecutionOp::Update {
-                                event: event,
-                                rule_index: rule_index,
-                                condition_index: condition_index
-                            }
-                        }))));
-                ConditionState {
-                    match_is_met: false,
-                    per_getter: HashSet::new(),
-                    duration: condition.duration.clone(),
-                }
-            }).collect();
+    /// `true` if the script is paused.
+    pub is_paused: bool,
 
-            RuleState {
-                rule_is_met: false,
-                per_condition: per_condition,
-                ongoing_timer: None,
-            }
-        }).collect();
+    /// The version of the script, as given by `Options::version`.
+    pub version: Option<String>,
+}
 
-        for msg in self.rx.iter() {
-            match msg {
-                ExecutionOp::Stop(cb) => {
-                    info!("[Recipe '{}'] Shutting down recipe.", self.script.name);
-
-                    // Leave the loop. Watching will stop once
-                    // `witnesses` is dropped.
-                    cb.lock().unwrap()(Ok(()));
-                    return;
-                },
-                ExecutionOp::UpdateCondition { id, is_met, rule_index, condition_index } => {
-                    debug!("[Recipe '{}'] Updating the state of rule {}, condition {} => {}", self.script.name, rule_index, condition_index, is_met);
-                    self.update_conditions(&self.script.name, id, is_met, &mut per_rule,
-                        rule_index, condition_index, &api, &on_event);
-                }
-                ExecutionOp::Update { event, rule_index, condition_index } => {
-                    match event {
-                        WatchEvent::InitializationError {
-                            channel,
-                            error
-                        } => {
-                            info!("[Recipe '{}'] Initialization error for {}: {}", self.script.name, channel, error);
-                            let _ = on_event.send(ExecutionEvent::ChannelError {
-                                id: channel,
-                                error: error,
-                            });
-                        },
-                        WatchEvent::GetterRemoved(id) => {
-                            debug!("[Recipe '{}'] Removed getter {}, resetting condition to `false`", self.script.name, id);
-                            // A getter was removed. Its condition is therefore not met anymore.
-                            let msg = ExecutionOp::UpdateCondition {
-                                id: id.clone(),
-                                is_met: false,
-                                rule_index: rule_index,
-                                condition_index: condition_index
-                            };
-                            // This send will fail only if the thread is already down.
-                            let _ = self.tx.send(msg);
-                        },
-                        WatchEvent::GetterAdded(id) => {
-                            debug!("[Recipe '{}'] Added getter {}.", self.script.name, id);
-                            // An getter was added. Nothing to do.
-                        }
-                        WatchEvent::EnterRange { from: id, value } => {
-                            debug!("[Recipe '{}'] Getter {} has entered the range for rule {}, condition {}: {:?}", self.script.name, id, rule_index, condition_index, value);
-                            // We have entered a range. If there is a
-                            // timer, start it, otherwise update conditions.
-                            let msg = move || {
-                                ExecutionOp::UpdateCondition {
-                                    id: id.clone(),
-                                    is_met: true,
-                                    rule_index: rule_index,
-                                    condition_index: condition_index
-                                }
-                            };
-                            let duration = match per_rule[rule_index].
-                                per_condition[condition_index].
-                                duration {
-                                None => {
-                                    debug!("[Recipe '{}'] No timer for rule {}, condition {}, we should trigger the execution immediately.", self.script.name, rule_index, condition_index);
-                                    let _ = self.tx.send(msg());
-                                    continue
-                                }
-                                Some(ref duration) => {
-                                    debug!("[Recipe '{}'] There is a timer for rule {}, condition {}, we should trigger the execution in {:?}s.", self.script.name, rule_index, condition_index, duration);
-                                    duration.clone()
-                                }
-                            };
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RuleSnapshot {
+    /// `true` if all the conditions of the rule are currently met.
+    pub is_met: bool,
 
-                            let tx = self.tx.map(move |()| {
-                                msg()
-                            });
-                            per_rule[rule_index].ongoing_timer =
-                                Some(env.start_timer(duration.clone(), Box::new(tx)));
-                            let _ = on_event.send(ExecutionEvent::TimerStart {
-                                rule_index: rule_index,
-                                condition_index: condition_index,
-                            });
-                        }
-                        WatchEvent::ExitRange { from: id, value } => {
-                            debug!("[Recipe '{}'] Getter {} has left the range for rule {}, condition {}: {:?}", self.script.name, id, rule_index, condition_index, value);
-                            if per_rule[rule_index].ongoing_timer.is_some() {
-                                debug!("[Recipe '{}'] I need to cancel the timer for rule {}, condition {}", self.script.name, id, rule_index);
-                                // Cancel the timer.
-                                per_rule[rule_index].ongoing_timer.take();
-                                let _ = on_event.send(ExecutionEvent::TimerCancel {
-                                    rule_index: rule_index,
-                                    condition_index: condition_index,
-                                });
-                            }
-                            // Regardless, update the condition.
-                            let msg = ExecutionOp::UpdateCondition {
-                                id: id,
-                                is_met: false,
-                                rule_index: rule_index,
-                                condition_index: condition_index
-                            };
-                            let _ = self.tx.send(msg);
-                        }
-                    }
-                }
-            }
-        };
-    }
+    /// `true` if the circuit breaker has suspended the rule. Suspensions
+    /// are not restored from checkpoints.
+    pub is_suspended: bool,
 
-    /// A getter just entered/left a range. Update the conditions to determine whether
+    /// The state of each match of the rule, in the order of the script.
+    pub conditions: Vec<ConditionSnapshot>,
+
+    /// The timers waiting for a getter to remain in range long enough.
+    pub timers: Vec<TimerSnapshot>,
+
+    /// The latest time at which the rule fired, if any.
+    pub last_fired: Option<TimeStamp>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConditionSnapshot {
+    /// The getters that currently satisfy the match, taking its duration
+    /// into account. The match is met iff there is at least one.
+    pub getters: Vec<Id<Getter>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimerSnapshot {
+    pub condition_index: usize,
+    pub getter: Id<Getter>,
+
+    /// The time at which the condition will become met for this getter,
+    /// unless the getter leaves the range before.
+    pub deadline: TimeStamp,
+}
+
+/// Suspending rules that fire too often.
+///
+/// Only rules that belong to a feedback loop, as detected by the compiler
+/// (see module `dependencies`), are monitored, and only their firings that
+/// may have been caused by the script itself are counted: those that follow
+/// a firing of another rule of the loop, or any firing of a rule that may
+/// trigger itself. Such a rule is suspended once it has fired `max_firings`
+/// times within `window`, and remains suspended for `window`, or until
+/// `Execution::resume_rule`.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    pub max_firings: usize,
+    pub window: StdDuration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            max_firings: 10,
+            window: StdDuration::from_secs(10),
+        }
+    }
+}
+
+/// A script waiting to be compiled and started on a thread of an executor.
+struct StartTask<Env, S> where Env: ExecutableDevEnv + Debug + 'static, S: ExtSender<ExecutionEvent> + Clone {
+    env: Env,
+    script: Script<UncheckedCtx>,
+    owner: User,
+    options: Options,
+    on_event: S,
+    dispatcher: Option<Dispatcher>,
+
+    /// Informs `Execution::start` of whether the script could start.
+    tx_init: Sender<Result<Box<ExtSender<ExecutionOp>>, Error>>,
+}
+
+impl<Env, S> Spawn<ExecutionOp> for StartTask<Env, S>
+    where Env: ExecutableDevEnv + Debug + 'static, S: ExtSender<ExecutionEvent> + Clone
+{
+    fn spawn(self: Box<Self>, tx: Box<ExtSender<ExecutionOp>>) -> Option<Box<Task<ExecutionOp>>> {
+        let StartTask { env, script, owner, options, on_event, dispatcher, tx_init } = *self;
+        let name = script.name.clone();
+        let task = ExecutionTask::<Env>::new(&env, script, owner, options, dispatcher, tx.clone()).and_then(|task| {
+            try!(task.declare_virtual_channels(&env));
+            Ok(task)
+        });
+        match task {
+            Err(er) => {
+                info!("[Recipe '{}'] Compilation failed {:?}", name, er);
+                let _ = on_event.send(ExecutionEvent::Starting {
+                    result: Err(er.clone())
+                });
+                let _ = tx_init.send(Err(er));
+                None
+            },
+            Ok(mut task) => {
+                info!("[Recipe '{}'] Compilation succeeded.", name);
+                let _ = on_event.send(ExecutionEvent::Starting {
+                    result: Ok(())
+                });
+                if !task.warnings.is_empty() {
+                    let _ = on_event.send(ExecutionEvent::CompileWarnings {
+                        diagnostics: task.warnings.clone()
+                    });
+                }
+                let _ = tx_init.send(Ok(tx));
+                let state = task.start(&env);
+                Some(Box::new(RunningTask {
+                    task: task,
+                    state: state,
+                    env: env,
+                    on_event: on_event,
+                }))
+            }
+        }
+    }
+}
+
+/// A script running on a thread of an executor.
+struct RunningTask<Env, S> where Env: ExecutableDevEnv + Debug + 'static, S: ExtSender<ExecutionEvent> + Clone {
+    task: ExecutionTask<Env>,
+    state: ScriptState<Env>,
+    env: Env,
+    on_event: S,
+}
+
+impl<Env, S> Task<ExecutionOp> for RunningTask<Env, S>
+    where Env: ExecutableDevEnv + Debug + 'static, S: ExtSender<ExecutionEvent> + Clone
+{
+    fn handle(&mut self, op: ExecutionOp) -> bool {
+        self.task.handle(&mut self.state, op, &self.env, &self.on_event)
+    }
+
+    fn panicked(&mut self) {
+        error!("[Recipe '{}'] The script has panicked, stopping it.", self.task.script.name);
+        let _ = self.on_event.send(ExecutionEvent::Stopped {
+            result: Err(Error::StartStopError(StartStopError::ThreadError))
+        });
+    }
+}
+
+/// The state of a running script that only exists once the script has started.
+struct ScriptState<Env> where Env: ExecutableDevEnv {
+    /// The guards of the watches of `ExecutionTask::watches`, by key.
+    /// Watching stops once these are dropped.
+    watch_guards: HashMap<usize, WatchGuard<Env>>,
+
+    /// The guards of the watches of the sources of derived getters.
+    derived_guards: Vec<Env::WatchGuard>,
+    per_rule: Vec<RuleState<Env>>,
+
+    /// For each derived getter, the latest values of its sources.
+    aggregators: Vec<Aggregator>,
+}
+
+/// A script ready to be executed. Each script is executed on a thread
+/// of an `Executor`, possibly shared with other scripts.
+pub struct ExecutionTask<Env> where Env: ExecutableDevEnv {
+    script: Script<CompiledCtx<Env>>,
+    owner: User,
+
+    /// The current value of the variables of the script.
+    variables: HashMap<String, VariableValue>,
+
+    /// The warnings emitted by the compiler.
+    warnings: Vec<Diagnostic>,
+
+    /// For each rule, the rules of the feedback loop to which it belongs,
+    /// as reported by the compiler, or nothing.
+    feedback_loops: Vec<Vec<usize>>,
+    circuit_breaker: CircuitBreaker,
+
+    /// The number of times rules of the script have fired, automatically or
+    /// not. See `RuleState::last_write`.
+    writes: usize,
+
+    /// The options used to compile the script, in case it is reloaded.
+    strict: bool,
+    limits: Limits,
+
+    /// For each rule, a key that remains the same if the rule moves
+    /// when the script is reloaded. Timers refer to rules by key.
+    rule_keys: Vec<usize>,
+    next_rule_key: usize,
+
+    /// For each watch, a key that remains the same if the watch moves
+    /// when the script is reloaded. Watch events refer to watches by key.
+    watch_keys: Vec<usize>,
+    next_watch_key: usize,
+
+    /// The timers for statements that are waiting to be retried, indexed
+    /// by a unique key.
+    pending_retries: HashMap<usize, Env::TimerGuard>,
+    next_retry_key: usize,
+
+    /// The current mode of the house, if any.
+    mode: Option<String>,
+
+    /// If `true`, the script keeps track of its rules but does not
+    /// execute statements.
+    is_paused: bool,
+
+    /// Whether statements actually send values.
+    run_mode: RunMode,
+
+    /// The checkpoint to restore when the script starts, if any.
+    checkpoint: Option<Snapshot>,
+    expired_timers: ExpiredTimerPolicy,
+
+    /// If `true`, report changes of state as `ExecutionEvent::Checkpoint`.
+    checkpoints: bool,
+
+    /// The latest state reported as `ExecutionEvent::Checkpoint`.
+    last_checkpoint: Option<Snapshot>,
+
+    /// The minimal delay between two checkpoints.
+    checkpoint_interval: StdDuration,
+
+    /// When the state of the script was last compared to `last_checkpoint`.
+    last_checkpoint_at: Option<Instant>,
+
+    /// The timer of the next checkpoint, if changes are waiting for
+    /// `checkpoint_interval` to elapse.
+    checkpoint_timer: Option<Env::TimerGuard>,
+
+    /// What to do with the current values of the getters at startup.
+    seed: SeedPolicy,
+
+    /// The version of the script, as given by `Options::version`.
+    version: Option<String>,
+
+    /// For each time window used by the script, whether it is currently
+    /// open, and the timer that will open or close it.
+    windows: HashMap<TimeWindow, (bool, Env::TimerGuard)>,
+
+    /// The watches needed by the matches of the script. Identical matches
+    /// share a single watch.
+    watches: Vec<WatchGroup>,
+
+    /// The state of the matches of the rules.
+    network: Network,
+
+    /// The watches shared with other scripts, if any.
+    dispatcher: Option<Dispatcher>,
+
+    /// Sending messages to the script, through its executor.
+    tx: Box<ExtSender<ExecutionOp>>,
+}
+
+/// A watch of a script, either on the environment or shared with other
+/// scripts. Watching stops once this is dropped.
+enum WatchGuard<Env> where Env: ExecutableDevEnv {
+    Own(Env::WatchGuard),
+    Shared(Subscription),
+}
+
+#[derive(Debug)]
+pub enum ExecutionEvent {
+    Starting {
+        result: Result<(), Error>,
+    },
+    /// The script has stopped. If it has panicked, the other scripts of
+    /// its executor keep running, and `result` is a `ThreadError`.
+    Stopped {
+        result: Result<(), Error>
+    },
+    /// The script was compiled with warnings. Sent after `Starting`.
+    CompileWarnings {
+        diagnostics: Vec<Diagnostic>
+    },
+    /// A value was sent. If the statement is part of the `on_error` of
+    /// another statement, `statement_index` is the index of the top-level
+    /// statement in the rule.
+    Sent {
+        rule_index: usize,
+        statement_index: usize,
+        result: Vec<(Id<Setter>, Result<(), Error>)>,
+
+        /// `true` if the statement was executed by `Execution::fire_rule`
+        /// or `Execution::fire_statement` rather than by its rule.
+        is_manual: bool,
+    },
+    /// The script runs in `RunMode::Shadow` and would have sent a value.
+    /// `setters` are the setters that would have received the value. As in
+    /// `RunMode::Live`, setters that the owner of the script may not write
+    /// are not included.
+    WouldSend {
+        rule_index: usize,
+        statement_index: usize,
+        setters: Vec<Id<Setter>>,
+        value: Value,
+
+        /// See `Sent`.
+        is_manual: bool,
+    },
+    /// The state of the script has changed. Only sent if `Options::checkpoints`
+    /// is set. The snapshot may be stored and passed as `Options::checkpoint`
+    /// to restore the state of the script after a restart.
+    Checkpoint {
+        snapshot: Snapshot,
+    },
+    /// A value was sent again to the setters for which it had failed.
+    Retried {
+        rule_index: usize,
+        statement_index: usize,
+        attempt: u32,
+        result: Vec<(Id<Setter>, Result<(), Error>)>
+    },
+    /// All retries have failed, the value was sent to the fallback destination.
+    FallbackSent {
+        rule_index: usize,
+        statement_index: usize,
+        result: Vec<(Id<Setter>, Result<(), Error>)>
+    },
+    TimerStart {
+        rule_index: usize,
+        condition_index: usize,
+        id: Id<Getter>,
+    },
+    TimerCancel {
+        rule_index: usize,
+        condition_index: usize,
+        id: Id<Getter>,
+    },
+    VariableChanged {
+        name: String,
+        value: VariableValue,
+    },
+    /// A rule that belongs to a feedback loop has fired too often and has
+    /// been suspended by the circuit breaker.
+    RuleSuspended {
+        rule_index: usize,
+    },
+    /// A rule suspended by the circuit breaker may fire again, either
+    /// because its suspension has expired or because of `resume_rule`.
+    RuleResumed {
+        rule_index: usize,
+    },
+    ChannelError {
+        id: Id<Getter>,
+        error: APIError,
+    }
+}
+
+enum ExecutionOp {
+    /// We have received an update from the AdapterManager.
+    Update {
+        /// The individual event.
+        event: WatchEvent,
+
+        /// The key of the watch to which this event applies. The event
+        /// applies to every match sharing this watch.
+        watch_key: usize,
+    },
+
+    /// A channel state has enter/left its target range and we
+    /// have waited long enough to trigger the consequences.
+    UpdateCondition {
+        /// The channel that has changed state.
+        id: Id<Getter>,
+
+        /// `true` if the condition is now met, `false` otherwise.
+        is_met: bool,
+
+        /// The key of the rule to which this event applies.
+        rule_key: usize,
+
+        /// The index to which this event applies.
+        condition_index: usize,
+    },
+
+    /// A getter has remained in the range of a condition for the duration
+    /// of the condition.
+    TimerExpired {
+        /// The channel that has remained in range.
+        id: Id<Getter>,
+
+        /// The key of the rule to which this event applies.
+        rule_key: usize,
+
+        /// The index to which this event applies.
+        condition_index: usize,
+
+        /// The generation of the timer.
+        generation: usize,
+    },
+
+    /// Some variables have changed, we need to update the rules that depend on them.
+    UpdateVariables,
+
+    /// Send a snapshot of the state of the script.
+    Snapshot(Sender<Snapshot>),
+
+    /// We have received an update for one of the sources of a derived getter.
+    UpdateDerived {
+        /// The individual event.
+        event: WatchEvent,
+
+        /// The derived getter to which this event applies.
+        derived_index: usize,
+    },
+
+    /// Time to retry a statement that has failed.
+    Retry {
+        /// The key of the timer in `pending_retries`.
+        key: usize,
+
+        /// The key of the rule to which the statement belongs.
+        rule_key: usize,
+
+        /// The path to the statement in the rule, as in `statement_at`.
+        path: Vec<usize>,
+
+        /// The number of the attempt, starting at 1.
+        attempt: u32,
+
+        /// The setters for which the statement has failed.
+        setters: Vec<Id<Setter>>,
+
+        /// `true` if the statement was executed manually.
+        is_manual: bool,
+    },
+
+    /// The house has changed mode.
+    SetMode(Option<String>),
+
+    /// Replace the script, keeping the state of the rules that have
+    /// not changed.
+    Reload {
+        script: Script<UncheckedCtx>,
+        owner: User,
+
+        /// The new options, or `None` to keep the current ones.
+        options: Option<Options>,
+        on_result: Sender<Result<ScriptDiff, Error>>,
+    },
+
+    /// Execute the statements of a rule, or a single statement if
+    /// `statement_index` is specified, regardless of the conditions.
+    Fire {
+        rule_index: usize,
+        statement_index: Option<usize>,
+        on_result: Sender<Result<(), Error>>,
+    },
+
+    /// Let a rule suspended by the circuit breaker fire again.
+    ResumeRule {
+        rule_index: usize,
+        on_result: Sender<Result<(), Error>>,
+    },
+
+    /// Stop executing statements, until `Resume`.
+    Pause,
+    Resume(ResumePolicy),
+
+    /// A time window has opened or closed.
+    UpdateWindow {
+        window: TimeWindow,
+        is_open: bool,
+    },
+
+    /// `Options::checkpoint_interval` has elapsed since the latest checkpoint.
+    Checkpoint,
+
+    /// Time to stop executing the script.
+    Stop(Mutex<Box<Fn(Result<(), Error>) + Send>>)
+}
+
+impl Debug for ExecutionOp {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use self::ExecutionOp::*;
+        match *self {
+            Update {..} => formatter.write_str("Update"),
+            UpdateCondition { .. } => formatter.write_str("UpdateCondition"),
+            TimerExpired { .. } => formatter.write_str("TimerExpired"),
+            UpdateVariables => formatter.write_str("UpdateVariables"),
+            UpdateDerived { .. } => formatter.write_str("UpdateDerived"),
+            Retry { .. } => formatter.write_str("Retry"),
+            SetMode(_) => formatter.write_str("SetMode"),
+            Reload { .. } => formatter.write_str("Reload"),
+            Fire { .. } => formatter.write_str("Fire"),
+            ResumeRule { .. } => formatter.write_str("ResumeRule"),
+            Pause => formatter.write_str("Pause"),
+            Resume(_) => formatter.write_str("Resume"),
+            UpdateWindow { .. } => formatter.write_str("UpdateWindow"),
+            ExecutionOp::Snapshot(_) => formatter.write_str("Snapshot"),
+            ExecutionOp::Checkpoint => formatter.write_str("Checkpoint"),
+            Stop (_) => formatter.write_str("Stop")
+        }
+    }
+}
+
+struct RuleState<Env> where Env: ExecutableDevEnv {
+    rule_is_met: bool,
+
+    /// The timers currently running for the conditions of this rule, indexed
+    /// by condition and by getter. Each getter waits for the duration of
+    /// each condition independently of the other getters and conditions.
+    timers: HashMap<(usize, Id<Getter>), ConditionTimer<Env>>,
+
+    /// The generation of the latest timer started for this rule. Used to
+    /// ignore timers that expire after they have been restarted.
+    timer_generation: usize,
+
+    /// If the rule belongs to a feedback loop, the latest times at which it
+    /// fired because of other rules of the loop.
+    recent_firings: VecDeque<Instant>,
+
+    /// If the circuit breaker has suspended the rule, the time at which it did.
+    suspended_at: Option<Instant>,
+
+    /// The value of `ExecutionTask::writes` the latest time the rule fired,
+    /// if any. Used to determine whether other rules of a feedback loop
+    /// have fired since.
+    last_write: Option<usize>,
+
+    /// The latest time at which the rule fired, if any.
+    last_fired: Option<DateTime<UTC>>,
+
+    /// `true` if the rule has become met while the script was paused.
+    missed_firing: bool,
+}
+
+/// A timer waiting for a getter to remain in the range of a condition.
+struct ConditionTimer<Env> where Env: ExecutableDevEnv {
+    /// See `RuleState::timer_generation`.
+    generation: usize,
+
+    /// The time at which the timer expires.
+    deadline: DateTime<UTC>,
+    guard: Env::TimerGuard,
+}
+
+impl<Env> RuleState<Env> where Env: ExecutableDevEnv {
+    fn new() -> Self {
+        RuleState {
+            rule_is_met: false,
+            timers: HashMap::new(),
+            timer_generation: 0,
+            recent_firings: VecDeque::new(),
+            suspended_at: None,
+            last_write: None,
+            last_fired: None,
+            missed_firing: false,
+        }
+    }
+
+    /// Record that the rule is about to fire. Return `false` if the rule has
+    /// already fired too often recently.
+    fn record_firing(&mut self, circuit_breaker: &CircuitBreaker) -> bool {
+        let now = Instant::now();
+        while let Some(&first) = self.recent_firings.front() {
+            if now.duration_since(first) <= circuit_breaker.window {
+                break;
+            }
+            self.recent_firings.pop_front();
+        }
+        if self.recent_firings.len() >= circuit_breaker.max_firings {
+            return false;
+        }
+        self.recent_firings.push_back(now);
+        true
+    }
+}
+
+impl<Env> ExecutionTask<Env> where Env: ExecutableDevEnv + Debug {
+    /// Create a new execution task.
+    ///
+    /// The caller is responsible for calling `start()` then `handle()`
+    /// for each message received through `tx`.
+    fn new(env: &Env, script: Script<UncheckedCtx>, owner: User, options: Options, dispatcher: Option<Dispatcher>,
+        tx: Box<ExtSender<ExecutionOp>>) ->
+        Result<Self, Error>
+    {
+        let (script, warnings) = try!(Self::compile(env, script, &owner, options.strict, &options.limits));
+        let feedback_loops = Self::feedback_loops(script.rules.len(), &warnings);
+        let watches = group_watches(&script);
+        let network = Network::new(&script, &watches);
+
+        let script_len = script.rules.len();
+        let watches_len = watches.len();
+        let mut options = options;
+        let variables = script.variables.iter().map(|declaration| {
+            let value = match options.variables.remove(&declaration.name) {
+                Some(ref value) if value.has_same_type(&declaration.initial) => value.clone(),
+                _ => declaration.initial.clone()
+            };
+            (declaration.name.clone(), value)
+        }).collect();
+
+        Ok(ExecutionTask {
+            script: script,
+            owner: owner,
+            variables: variables,
+            warnings: warnings,
+            feedback_loops: feedback_loops,
+            writes: 0,
+            strict: options.strict,
+            limits: options.limits,
+            rule_keys: (0..script_len).collect(),
+            next_rule_key: script_len,
+            watch_keys: (0..watches_len).collect(),
+            next_watch_key: watches_len,
+            circuit_breaker: options.circuit_breaker,
+            pending_retries: HashMap::new(),
+            next_retry_key: 0,
+            mode: options.mode,
+            is_paused: options.paused,
+            run_mode: options.run_mode,
+            checkpoint: options.checkpoint,
+            expired_timers: options.expired_timers,
+            checkpoints: options.checkpoints,
+            last_checkpoint: None,
+            checkpoint_interval: options.checkpoint_interval,
+            last_checkpoint_at: None,
+            checkpoint_timer: None,
+            seed: options.seed,
+            version: options.version,
+            windows: HashMap::new(),
+            watches: watches,
+            network: network,
+            dispatcher: dispatcher,
+            tx: tx
+        })
+    }
+
+    /// Compile a script, then check that its owner may access its channels.
+    fn compile(env: &Env, script: Script<UncheckedCtx>, owner: &User, strict: bool, limits: &Limits) ->
+        Result<(Script<CompiledCtx<Env>>, Vec<Diagnostic>), Error>
+    {
+        let compiler = try!(Compiler::new().map_err(|err| Error::CompileError(vec![Diagnostic::error(Location::new(), err)])));
+        let compiler = env.compiler_passes().into_iter().fold(compiler, |compiler, pass| compiler.with_pass(pass));
+        let compiler = compiler.with_strict(strict).with_limits(limits.clone());
+        let (script, warnings) = try!(compiler.compile_with_warnings(script).map_err(|err| Error::CompileError(err)));
+        let denied = compiler.check_permissions(&script, env, owner);
+        if !denied.is_empty() {
+            return Err(Error::CompileError(denied));
+        }
+        Ok((script, warnings))
+    }
+
+    /// For each rule, the rules of the feedback loop to which it belongs, as
+    /// detected by the compiler (see module `dependencies`).
+    fn feedback_loops(len: usize, warnings: &[Diagnostic]) -> Vec<Vec<usize>> {
+        let mut feedback_loops = vec![vec![]; len];
+        for warning in warnings {
+            if let CompileError::DependencyError(DependencyError::FeedbackLoop(ref cycle)) = warning.error {
+                for &rule_index in cycle {
+                    feedback_loops[rule_index] = cycle.clone();
+                }
+            }
+        }
+        feedback_loops
+    }
+
+    /// Create the virtual channels and the derived getters declared by the script, if they
+    /// do not exist yet.
+    fn declare_virtual_channels(&self, env: &Env) -> Result<(), Error> {
+        if self.script.channels.is_empty() && self.script.derived.is_empty() {
+            return Ok(());
+        }
+        if self.run_mode == RunMode::Shadow {
+            return Ok(());
+        }
+        let virtual_channels = match env.virtual_channels() {
+            None => return Err(Error::VirtualChannelError(virtual_channels::Error::NotSupported)),
+            Some(virtual_channels) => virtual_channels
+        };
+        for channel in &self.script.channels {
+            try!(virtual_channels.declare(channel).map_err(Error::VirtualChannelError));
+        }
+        for derived in &self.script.derived {
+            try!(virtual_channels.declare_getter(&derived.as_channel()).map_err(Error::VirtualChannelError));
+        }
+        Ok(())
+    }
+
+    /// Start watching the channels needed by the script.
+    fn start(&mut self, env: &Env) -> ScriptState<Env> {
+        info!("[Recipe '{}'] Starting execution of script", self.script.name);
+
+        // Generate the state of rules, conditions, getters and start
+        // listening to changes in the getters.
+
+        let mut per_rule : Vec<_> = self.script.rules.iter().map(|_| RuleState::new()).collect();
+
+        // Matches that watch the same channels with the same range share
+        // a single watch (see `group_watches`).
+        let mut watch_guards = HashMap::new();
+        for (watch, &watch_key) in self.watches.iter().zip(self.watch_keys.iter()) {
+            watch_guards.insert(watch_key, self.start_watch(env, watch, watch_key));
+        }
+
+        // Start listening to the sources of derived getters.
+        let api = env.api();
+        let mut derived_guards = Vec::new();
+        let aggregators : Vec<_> = self.script.derived.iter().zip(0 as usize..).map(|(derived, derived_index)| {
+            derived_guards.push(
+                api.watch_values(
+                    vec![Targetted {
+                        select: derived.source.clone(),
+                        payload: Exactly::Always
+                    }],
+                    Box::new(self.tx.map(move |event| {
+                        ExecutionOp::UpdateDerived {
+                            event: event,
+                            derived_index: derived_index
+                        }
+                    }))));
+            Aggregator::new(derived.aggregate.clone())
+        }).collect();
+
+        self.start_windows(env);
+
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.restore(&mut per_rule, checkpoint, env);
+        }
+        if self.seed != SeedPolicy::Ignore {
+            self.seed_conditions(&mut per_rule, env);
+        }
+
+        // Rules that only depend on variables, or restored from a checkpoint,
+        // may be met from the start. We do not fire them, as they have not
+        // just become met.
+        for rule_index in 0..per_rule.len() {
+            per_rule[rule_index].rule_is_met = self.rule_is_met(rule_index);
+        }
+
+        ScriptState {
+            watch_guards: watch_guards,
+            derived_guards: derived_guards,
+            per_rule: per_rule,
+            aggregators: aggregators,
+        }
+    }
+
+    /// Restore the state of the rules from a checkpoint, if it matches the script.
+    fn restore(&mut self, per_rule: &mut Vec<RuleState<Env>>, checkpoint: Snapshot, env: &Env) {
+        let is_compatible = checkpoint.version == self.version &&
+            checkpoint.rules.len() == self.script.rules.len() &&
+            checkpoint.rules.iter().zip(self.script.rules.iter()).all(|(saved, rule)| {
+                saved.conditions.len() == rule.conditions.len() &&
+                    saved.timers.iter().all(|timer| timer.condition_index < rule.conditions.len())
+            });
+        if !is_compatible {
+            warn!("[Recipe '{}'] Ignoring a checkpoint that does not match the script.", self.script.name);
+            return;
+        }
+        info!("[Recipe '{}'] Restoring the state of the script from a checkpoint.", self.script.name);
+        let now = UTC::now();
+        for (rule_index, saved) in checkpoint.rules.into_iter().enumerate() {
+            for (condition_index, condition) in saved.conditions.into_iter().enumerate() {
+                for id in condition.getters {
+                    self.network.update(rule_index, condition_index, id, true);
+                }
+            }
+            // Suspensions are not restored: they only last for the window of
+            // the circuit breaker, which is measured from the current run.
+            per_rule[rule_index].last_fired = saved.last_fired.map(Into::into);
+            for timer in saved.timers {
+                let deadline : DateTime<UTC> = timer.deadline.into();
+                if deadline > now {
+                    self.start_condition_timer(env, &mut per_rule[rule_index], rule_index,
+                        timer.condition_index, timer.getter, deadline);
+                    continue;
+                }
+                debug!("[Recipe '{}'] The timer of getter {} for rule {}, condition {} has expired in the meantime, {:?}", self.script.name,
+                    timer.getter, rule_index, timer.condition_index, self.expired_timers);
+                match self.expired_timers {
+                    ExpiredTimerPolicy::Discard => {},
+                    ExpiredTimerPolicy::Arm => {
+                        self.network.update(rule_index, timer.condition_index, timer.getter, true);
+                    }
+                    ExpiredTimerPolicy::Fire => {
+                        // Handled once the script has started, as if the timer had just expired.
+                        let _ = self.tx.send(ExecutionOp::UpdateCondition {
+                            id: timer.getter,
+                            is_met: true,
+                            rule_key: self.rule_keys[rule_index],
+                            condition_index: timer.condition_index
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update the matches with the current values of their getters. Getters
+    /// already restored from a checkpoint are left untouched.
+    fn seed_conditions(&mut self, per_rule: &mut Vec<RuleState<Env>>, env: &Env) {
+        let api = env.api();
+        let selectors = self.watches.iter().flat_map(|watch| watch.source.iter().cloned()).collect();
+        let values : HashMap<_, _> = api.fetch_values(selectors, self.owner.clone()).into_iter()
+            .filter_map(|(id, result)| match result {
+                Ok(Some(_)) if !self.may_read(env, &id) => {
+                    debug!("[Recipe '{}'] Ignoring the value of getter {}, which the owner may not read", self.script.name, id);
+                    None
+                }
+                Ok(Some(value)) => Some((id, value)),
+                Ok(None) => None,
+                Err(err) => {
+                    debug!("[Recipe '{}'] Could not fetch the value of getter {}: {:?}", self.script.name, id, err);
+                    None
+                }
+            })
+            .collect();
+        let now = UTC::now();
+        for watch_index in 0..self.watches.len() {
+            let in_range : Vec<_> = api.get_getter_channels(self.watches[watch_index].source.clone()).into_iter()
+                .map(|channel| channel.id)
+                .filter(|id| match values.get(id) {
+                    Some(value) => self.watches[watch_index].range.contains(value),
+                    None => false
+                })
+                .collect();
+            let members = self.watches[watch_index].members.clone();
+            for id in in_range {
+                for &(rule_index, condition_index) in &members {
+                    if self.network.getters(rule_index, condition_index).contains(&id) ||
+                        per_rule[rule_index].timers.contains_key(&(condition_index, id.clone())) {
+                        continue;
+                    }
+                    debug!("[Recipe '{}'] Getter {} is initially in the range for rule {}, condition {}", self.script.name,
+                        id, rule_index, condition_index);
+                    match self.script.rules[rule_index].conditions[condition_index].duration.clone() {
+                        Some(duration) => {
+                            // We do not know for how long the getter has been in range.
+                            self.start_condition_timer(env, &mut per_rule[rule_index], rule_index,
+                                condition_index, id.clone(), now + ChronoDuration::from(duration));
+                        }
+                        None if self.seed == SeedPolicy::Fire => {
+                            // Handled once the script has started, as if the getter had just entered the range.
+                            let _ = self.tx.send(ExecutionOp::UpdateCondition {
+                                id: id.clone(),
+                                is_met: true,
+                                rule_key: self.rule_keys[rule_index],
+                                condition_index: condition_index
+                            });
+                        }
+                        None => {
+                            self.network.update(rule_index, condition_index, id.clone(), true);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the owner of the script may currently read getter `id`.
+    fn may_read(&self, env: &Env, id: &Id<Getter>) -> bool {
+        env.permissions().is_allowed(&self.owner, &Access::Read(id.clone()))
+    }
+
+    /// (Re)start the timer of a getter for a condition, until `deadline`.
+    /// Replacing a running timer drops, hence cancels, its guard.
+    fn start_condition_timer(&self, env: &Env, rule_state: &mut RuleState<Env>, rule_index: usize,
+        condition_index: usize, id: Id<Getter>, deadline: DateTime<UTC>)
+    {
+        rule_state.timer_generation += 1;
+        let generation = rule_state.timer_generation;
+        let rule_key = self.rule_keys[rule_index];
+        let expired = {
+            let id = id.clone();
+            move || {
+                ExecutionOp::TimerExpired {
+                    id: id.clone(),
+                    rule_key: rule_key,
+                    condition_index: condition_index,
+                    generation: generation
+                }
+            }
+        };
+        let tx = self.tx.map(move |()| {
+            expired()
+        });
+        // The deadline may have passed while restoring or seeding the script.
+        let delay = deadline - UTC::now();
+        let delay = if delay < ChronoDuration::zero() { ChronoDuration::zero() } else { delay };
+        let guard = env.start_timer(Duration::from(delay), Box::new(tx));
+        rule_state.timers.insert((condition_index, id), ConditionTimer {
+            generation: generation,
+            deadline: deadline,
+            guard: guard,
+        });
+    }
+
+    /// Report the state of the script as `ExecutionEvent::Checkpoint`, if it
+    /// has changed since the latest checkpoint. Within `checkpoint_interval`
+    /// of the previous checkpoint, wait for the interval to elapse instead.
+    fn checkpoint<S>(&mut self, per_rule: &[RuleState<Env>], env: &Env, on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        if self.checkpoint_timer.is_some() {
+            // The changes will be reported once the timer expires.
+            return;
+        }
+        if let Some(last_checkpoint_at) = self.last_checkpoint_at {
+            let elapsed = last_checkpoint_at.elapsed();
+            if elapsed < self.checkpoint_interval {
+                let delay = self.checkpoint_interval - elapsed;
+                let delay = ChronoDuration::seconds(delay.as_secs() as i64) +
+                    ChronoDuration::nanoseconds(delay.subsec_nanos() as i64);
+                let tx = self.tx.map(|()| ExecutionOp::Checkpoint);
+                self.checkpoint_timer = Some(env.start_timer(Duration::from(delay), Box::new(tx)));
+                return;
+            }
+        }
+        self.checkpoint_now(per_rule, on_event);
+    }
+
+    /// Report the state of the script as `ExecutionEvent::Checkpoint`, if it
+    /// has changed since the latest checkpoint, regardless of `checkpoint_interval`.
+    fn checkpoint_now<S>(&mut self, per_rule: &[RuleState<Env>], on_event: &S)
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        self.checkpoint_timer = None;
+        self.last_checkpoint_at = Some(Instant::now());
+        let snapshot = self.snapshot(per_rule);
+        if self.last_checkpoint.as_ref() == Some(&snapshot) {
+            return;
+        }
+        self.last_checkpoint = Some(snapshot.clone());
+        let _ = on_event.send(ExecutionEvent::Checkpoint {
+            snapshot: snapshot
+        });
+    }
+
+    /// Start a watch shared by some matches of the script.
+    fn start_watch(&self, env: &Env, watch: &WatchGroup, watch_key: usize) -> WatchGuard<Env> {
+        let tx = Box::new(self.tx.map(move |event| {
+            ExecutionOp::Update {
+                event: event,
+                watch_key: watch_key,
+            }
+        }));
+        if let Some(ref dispatcher) = self.dispatcher {
+            info!("[Recipe '{}'] Subscribing watch {} for conditions {:?}.", self.script.name, watch_key, watch.members);
+            return WatchGuard::Shared(dispatcher.subscribe(&self.script.name, watch, tx));
+        }
+        let api = env.api();
+        let getters = api.get_getter_channels(watch.source.clone());
+        info!("[Recipe '{}'] Initializing watch {} for conditions {:?}. Currently, it can listen to {} channels.", self.script.name,
+            watch_key, watch.members, getters.len());
+        WatchGuard::Own(api.watch_values(
+            vec![Targetted {
+                select: watch.source.clone(),
+                payload: Exactly::Exactly(watch.range.clone())
+            }],
+            tx))
+    }
+
+    /// Determine which time windows are open and start timers to follow
+    /// the windows that are not followed yet. Stop following the windows
+    /// that the script does not use anymore.
+    ///
+    /// Windows are evaluated in the local time of the house, see
+    /// `ExecutableDevEnv::utc_offset`. The offset is read when a window
+    /// starts being followed, so a change of offset, e.g. for daylight
+    /// saving time, delays the next change of the window by the difference.
+    fn start_windows(&mut self, env: &Env) {
+        let now = (UTC::now() + env.utc_offset()).num_seconds_from_midnight();
+        let windows : Vec<_> = self.script.active_when.iter()
+            .chain(self.script.rules.iter().flat_map(|rule| rule.active_when.iter()))
+            .filter_map(|active_when| match *active_when {
+                // Windows that never close do not need a timer.
+                ActiveWhen::Window(window) if window.from != window.to => Some(window),
+                _ => None
+            })
+            .collect();
+        // Dropping the guard of a window stops its timer.
+        let unused : Vec<_> = self.windows.keys().filter(|window| !windows.contains(window)).cloned().collect();
+        for window in unused {
+            self.windows.remove(&window);
+        }
+        for window in windows {
+            if self.windows.contains_key(&window) {
+                continue;
+            }
+            let is_open = window.contains(now);
+            let guard = self.start_window_timer(env, window, !is_open, window.seconds_until_change(now));
+            self.windows.insert(window, (is_open, guard));
+        }
+    }
+
+    /// Replace the script by a new version. Rules that have not changed
+    /// keep their state and their timers. Watches that are still needed
+    /// are kept, so matches of new rules on such watches start with the
+    /// getters currently in range: as an existing match with the same
+    /// duration if any, including its running timers, otherwise as if the
+    /// getters had just entered the range. Other matches start unmet.
+    /// If the script is now active at other times, kept rules are
+    /// evaluated again, and fire if they become met.
+    fn reload<S>(&mut self, watch_guards: &mut HashMap<usize, WatchGuard<Env>>, per_rule: &mut Vec<RuleState<Env>>,
+            script: Script<UncheckedCtx>, owner: User, options: Option<Options>, env: &Env, on_event: &S) ->
+            Result<ScriptDiff, Error>
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let (strict, limits, version) = match options {
+            Some(options) => (options.strict, options.limits, options.version),
+            None => (self.strict, self.limits.clone(), self.version.clone())
+        };
+        let (script, warnings) = try!(Self::compile(env, script, &owner, strict, &limits));
+        let changes = diff(&self.script, &script);
+        if changes.needs_restart {
+            return Err(Error::StartStopError(StartStopError::CannotReload));
+        }
+        info!("[Recipe '{}'] Reloading script, {} rules added, {} rules removed", self.script.name,
+            changes.added().len(), changes.removed.len());
+
+        // Keep the watches that are still needed, start the others.
+        let watches = group_watches(&script);
+        let mut watch_keys = Vec::with_capacity(watches.len());
+        let mut new_watch_guards = HashMap::new();
+        let mut reused = Vec::with_capacity(watches.len());
+        for watch in &watches {
+            let old_index = self.watches.iter().position(|old| old.same_watch(watch));
+            let key = match old_index {
+                Some(old_index) => self.watch_keys[old_index],
+                None => {
+                    let key = self.next_watch_key;
+                    self.next_watch_key += 1;
+                    key
+                }
+            };
+            let guard = match watch_guards.remove(&key) {
+                Some(guard) => {
+                    // The matches sharing the watch may have moved.
+                    if let WatchGuard::Shared(ref subscription) = guard {
+                        subscription.set_members(watch.members.clone());
+                    }
+                    guard
+                }
+                None => self.start_watch(env, watch, key)
+            };
+            watch_keys.push(key);
+            new_watch_guards.insert(key, guard);
+            reused.push(old_index);
+        }
+
+        // Restore the getters currently in range on the watches that are
+        // kept. The timers of new rules are started once the rules have a key.
+        let mut network = Network::new(&script, &watches);
+        let mut timers = Vec::new();
+        let now = UTC::now();
+        for (watch, old_index) in watches.iter().zip(reused.into_iter()) {
+            let old_members = match old_index {
+                None => continue,
+                Some(old_index) => &self.watches[old_index].members
+            };
+            // The getters in range are those that meet a match of the watch,
+            // or that wait for its duration.
+            let mut in_range = Vec::new();
+            for &(old_rule, old_condition) in old_members {
+                let waiting = per_rule[old_rule].timers.keys()
+                    .filter(|&&(condition_index, _)| condition_index == old_condition)
+                    .map(|&(_, ref id)| id.clone());
+                for id in self.network.getters(old_rule, old_condition).into_iter().chain(waiting) {
+                    if !in_range.contains(&id) {
+                        in_range.push(id);
+                    }
+                }
+            }
+            for &(rule_index, condition_index) in &watch.members {
+                let duration = &script.rules[rule_index].conditions[condition_index].duration;
+                let source = old_members.iter().find(|&&(old_rule, old_condition)| {
+                    self.script.rules[old_rule].conditions[old_condition].duration == *duration
+                });
+                match (source, duration) {
+                    (Some(&(old_rule, old_condition)), _) => {
+                        for id in self.network.getters(old_rule, old_condition) {
+                            network.update(rule_index, condition_index, id, true);
+                        }
+                        // Kept rules keep their own timers.
+                        if changes.kept[rule_index].is_some() {
+                            continue;
+                        }
+                        for (&(old_index, ref id), timer) in &per_rule[old_rule].timers {
+                            if old_index == old_condition {
+                                timers.push((rule_index, condition_index, id.clone(), timer.deadline.clone()));
+                            }
+                        }
+                    }
+                    (None, &None) => {
+                        for id in &in_range {
+                            network.update(rule_index, condition_index, id.clone(), true);
+                        }
+                    }
+                    (None, &Some(ref duration)) => {
+                        let deadline = now + ChronoDuration::from(duration.clone());
+                        for id in &in_range {
+                            timers.push((rule_index, condition_index, id.clone(), deadline));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Move the state of the rules that are kept.
+        let mut old_states : Vec<_> = per_rule.drain(..).map(Some).collect();
+        let mut rule_keys = Vec::with_capacity(changes.kept.len());
+        for kept in &changes.kept {
+            match *kept {
+                Some(old_index) => {
+                    per_rule.push(old_states[old_index].take().unwrap());
+                    rule_keys.push(self.rule_keys[old_index]);
+                }
+                None => {
+                    per_rule.push(RuleState::new());
+                    rule_keys.push(self.next_rule_key);
+                    self.next_rule_key += 1;
+                }
+            }
+        }
+
+        // Dropping the guards of the watches that are not needed anymore
+        // stops them. Dropping the state of removed rules cancels their timers.
+        *watch_guards = new_watch_guards;
+        drop(old_states);
+        self.feedback_loops = Self::feedback_loops(script.rules.len(), &warnings);
+        self.script = script;
+        self.owner = owner;
+        self.warnings = warnings;
+        self.strict = strict;
+        self.limits = limits;
+        self.version = version;
+        self.watches = watches;
+        self.watch_keys = watch_keys;
+        self.rule_keys = rule_keys;
+        self.network = network;
+        self.start_windows(env);
+        for (rule_index, condition_index, id, deadline) in timers {
+            self.start_condition_timer(env, &mut per_rule[rule_index], rule_index, condition_index, id, deadline);
+        }
+
+        // As when starting, new rules that are already met do not fire.
+        for rule_index in changes.added() {
+            per_rule[rule_index].rule_is_met = self.rule_is_met(rule_index);
+        }
+
+        // Kept rules depend on when the script is active. As when the mode
+        // changes, those that become met fire.
+        if changes.active_when_has_changed {
+            for rule_index in 0..per_rule.len() {
+                if changes.kept[rule_index].is_some() {
+                    self.update_rule(per_rule, rule_index, env, on_event);
+                }
+            }
+        }
+
+        if !self.warnings.is_empty() {
+            let _ = on_event.send(ExecutionEvent::CompileWarnings {
+                diagnostics: self.warnings.clone()
+            });
+        }
+        Ok(changes)
+    }
+
+    /// The index of a rule from its key, or `None` if the rule has been
+    /// removed by a reload.
+    fn rule_index(&self, rule_key: usize) -> Option<usize> {
+        self.rule_keys.iter().position(|&key| key == rule_key)
+    }
+
+    /// Handle a message. Return `false` once the script has stopped.
+    fn handle<S>(&mut self, state: &mut ScriptState<Env>, msg: ExecutionOp, env: &Env, on_event: &S) -> bool
+        where S: ExtSender<ExecutionEvent> + Clone
+    {
+        use std::mem::replace;
+
+        let per_rule = &mut state.per_rule;
+        let aggregators = &mut state.aggregators;
+        match msg {
+            ExecutionOp::Stop(cb) => {
+                info!("[Recipe '{}'] Shutting down recipe.", self.script.name);
+
+                // Do not lose the changes waiting for the next checkpoint.
+                if self.checkpoint_timer.is_some() {
+                    self.checkpoint_now(per_rule, on_event);
+                }
+
+                // Stop watching. Timers will stop once the state of
+                // the script is dropped.
+                state.watch_guards.clear();
+                state.derived_guards.clear();
+                cb.lock().unwrap()(Ok(()));
+                return false;
+            },
+            ExecutionOp::Snapshot(tx) => {
+                let _ = tx.send(self.snapshot(per_rule));
+            }
+            ExecutionOp::Checkpoint => {
+                if self.checkpoint_timer.is_some() {
+                    self.checkpoint_now(per_rule, on_event);
+                }
+                return true;
+            }
+            ExecutionOp::Reload { script, owner, options, on_result } => {
+                let result = self.reload(&mut state.watch_guards, per_rule, script, owner, options, env, on_event);
+                let _ = on_result.send(result);
+            }
+            ExecutionOp::UpdateCondition { id, is_met, rule_key, condition_index } => {
+                let rule_index = match self.rule_index(rule_key) {
+                    // The rule has been removed in the meantime.
+                    None => return true,
+                    Some(rule_index) => rule_index
+                };
+                debug!("[Recipe '{}'] Updating the state of rule {}, condition {} => {}", self.script.name, rule_index, condition_index, is_met);
+                self.update_conditions(id, is_met, per_rule,
+                    rule_index, condition_index, env, on_event);
+            }
+            ExecutionOp::TimerExpired { id, rule_key, condition_index, generation } => {
+                let rule_index = match self.rule_index(rule_key) {
+                    // The rule has been removed in the meantime, along with its timers.
+                    None => return true,
+                    Some(rule_index) => rule_index
+                };
+                // If the timer has been cancelled or restarted in the
+                // meantime, this event is stale.
+                let key = (condition_index, id.clone());
+                let is_current = match per_rule[rule_index].timers.get(&key) {
+                    Some(timer) => timer.generation == generation,
+                    None => false
+                };
+                if !is_current {
+                    debug!("[Recipe '{}'] Ignoring stale timer for rule {}, condition {}, getter {}", self.script.name, rule_index, condition_index, id);
+                    return true;
+                }
+                per_rule[rule_index].timers.remove(&key);
+                debug!("[Recipe '{}'] Getter {} has remained in range long enough for rule {}, condition {}", self.script.name, id, rule_index, condition_index);
+                self.update_conditions(id, true, per_rule,
+                    rule_index, condition_index, env, on_event);
+            }
+            ExecutionOp::UpdateDerived { event, derived_index } => {
+                let update = match event {
+                    // Getters that the owner may not read do not contribute
+                    // to the value, as if they had been removed.
+                    WatchEvent::EnterRange { ref from, .. } |
+                    WatchEvent::ExitRange { ref from, .. } if !self.may_read(env, from) => {
+                        aggregators[derived_index].update(from.clone(), None)
+                    }
+                    WatchEvent::EnterRange { from: id, value } |
+                    WatchEvent::ExitRange { from: id, value } => {
+                        aggregators[derived_index].update(id, Some(value))
+                    }
+                    WatchEvent::GetterRemoved(id) => {
+                        aggregators[derived_index].update(id, None)
+                    }
+                    WatchEvent::GetterAdded(_) => None,
+                    WatchEvent::InitializationError { channel, error } => {
+                        info!("[Recipe '{}'] Initialization error for {}: {}", self.script.name, channel, error);
+                        let _ = on_event.send(ExecutionEvent::ChannelError {
+                            id: channel,
+                            error: error,
+                        });
+                        None
+                    }
+                };
+                if let Some(value) = update {
+                    let id = &self.script.derived[derived_index].id;
+                    debug!("[Recipe '{}'] Derived getter {} is now {:?}", self.script.name, id, value);
+                    if self.run_mode == RunMode::Shadow {
+                        // The derived getter has not been declared.
+                    } else if let Some(virtual_channels) = env.virtual_channels() {
+                        virtual_channels.set_value(id, value);
+                    }
+                }
+            }
+            ExecutionOp::Fire { rule_index, statement_index, on_result } => {
+                let statements = match self.script.rules.get(rule_index) {
+                    None => Err(Error::NoSuchRule(rule_index)),
+                    Some(rule) => match statement_index {
+                        None => Ok((0..rule.execute.len()).collect()),
+                        Some(statement_index) if statement_index < rule.execute.len() => Ok(vec![statement_index]),
+                        Some(statement_index) => Err(Error::NoSuchStatement(rule_index, statement_index))
+                    }
+                };
+                match statements {
+                    Err(err) => {
+                        let _ = on_result.send(Err(err));
+                    }
+                    Ok(statements) => {
+                        debug!("[Recipe '{}'] Firing statements {:?} of rule {} manually", self.script.name, statements, rule_index);
+                        self.record_write(&mut per_rule[rule_index]);
+                        self.fire(rule_index, statements, true, env, on_event);
+                        let _ = on_result.send(Ok(()));
+                    }
+                }
+            }
+            ExecutionOp::ResumeRule { rule_index, on_result } => {
+                let result = match per_rule.get_mut(rule_index) {
+                    None => Err(Error::NoSuchRule(rule_index)),
+                    Some(rule_state) => {
+                        if rule_state.suspended_at.is_some() {
+                            self.resume_rule(rule_state, rule_index, on_event);
+                        }
+                        Ok(())
+                    }
+                };
+                let _ = on_result.send(result);
+            }
+            ExecutionOp::Pause => {
+                debug!("[Recipe '{}'] Pausing", self.script.name);
+                self.is_paused = true;
+                self.pending_retries.clear();
+            }
+            ExecutionOp::Resume(policy) => {
+                if !self.is_paused {
+                    return true;
+                }
+                debug!("[Recipe '{}'] Resuming, {:?}", self.script.name, policy);
+                self.is_paused = false;
+                for rule_index in 0..per_rule.len() {
+                    let missed = replace(&mut per_rule[rule_index].missed_firing, false);
+                    if missed && policy == ResumePolicy::Fire && per_rule[rule_index].rule_is_met {
+                        self.try_fire(per_rule, rule_index, env, on_event);
+                    }
+                }
+            }
+            ExecutionOp::Retry { key, rule_key, path, attempt, setters, is_manual } => {
+                if self.pending_retries.remove(&key).is_none() {
+                    // The retry was dropped while the script was paused.
+                    return true;
+                }
+                let rule_index = match self.rule_index(rule_key) {
+                    // The rule has been removed in the meantime.
+                    None => return true,
+                    Some(rule_index) => rule_index
+                };
+                debug!("[Recipe '{}'] Retrying statement {:?} of rule {}, attempt {}", self.script.name, path, rule_index, attempt);
+                self.record_write(&mut per_rule[rule_index]);
+                let result = match *self.statement_at(rule_index, &path) {
+                    Statement::Send(ref send) => {
+                        let destination = setters.into_iter()
+                            .map(|id| SetterSelector::new().with_id(id))
+                            .collect();
+                        send.eval_at(destination, env, &self.owner)
+                    }
+                    Statement::SetVariable(_) => return true // Only sends are retried.
+                };
+                let failed = failed_setters(&result);
+                let _ = on_event.send(ExecutionEvent::Retried {
+                    rule_index: rule_index,
+                    statement_index: path[0],
+                    attempt: attempt,
+                    result: result,
+                });
+                if !failed.is_empty() &&
+                    self.handle_send_error(rule_index, path, attempt, failed, is_manual, env, on_event) {
+                    let _ = self.tx.send(ExecutionOp::UpdateVariables);
+                }
+            }
+            ExecutionOp::SetMode(mode) => {
+                debug!("[Recipe '{}'] The house is now in mode {:?}", self.script.name, mode);
+                self.mode = mode;
+                for rule_index in 0..per_rule.len() {
+                    self.update_rule(per_rule, rule_index, env, on_event);
+                }
+            }
+            ExecutionOp::UpdateWindow { window, is_open } => {
+                if !self.windows.contains_key(&window) {
+                    // The window is not used anymore since a reload.
+                    return true;
+                }
+                debug!("[Recipe '{}'] Window {:?} is now open: {}", self.script.name, window, is_open);
+                let guard = self.start_window_timer(env, window, !is_open, window.length(is_open));
+                self.windows.insert(window, (is_open, guard));
+                for rule_index in 0..per_rule.len() {
+                    self.update_rule(per_rule, rule_index, env, on_event);
+                }
+            }
+            ExecutionOp::UpdateVariables => {
+                debug!("[Recipe '{}'] Updating the rules that depend on variables", self.script.name);
+                for rule_index in 0..per_rule.len() {
+                    if !self.script.rules[rule_index].variable_conditions.is_empty() {
+                        self.update_rule(per_rule, rule_index, env, on_event);
+                    }
+                }
+            }
+            ExecutionOp::Update { event, watch_key } => {
+                let watch_index = match self.watch_keys.iter().position(|&key| key == watch_key) {
+                    // The watch has been stopped by a reload.
+                    None => return true,
+                    Some(watch_index) => watch_index
+                };
+                // Dispatch the event to the nodes of this watch in the network,
+                // and to the timers of the matches sharing this watch.
+                let members = self.watches[watch_index].members.clone();
+                let event = match event {
+                    // Getters that the owner may not read, e.g. getters that
+                    // appeared after compilation, are never in range.
+                    WatchEvent::EnterRange { ref from, ref value } if !self.may_read(env, from) => {
+                        debug!("[Recipe '{}'] Ignoring getter {}, which the owner may not read", self.script.name, from);
+                        WatchEvent::ExitRange { from: from.clone(), value: value.clone() }
+                    }
+                    event => event
+                };
+                match event {
+                    WatchEvent::InitializationError {
+                        channel,
+                        error
+                    } => {
+                        info!("[Recipe '{}'] Initialization error for {}: {}", self.script.name, channel, error);
+                        let _ = on_event.send(ExecutionEvent::ChannelError {
+                            id: channel,
+                            error: error,
+                        });
+                    },
+                    WatchEvent::GetterRemoved(id) => {
+                        debug!("[Recipe '{}'] Removed getter {}, resetting its conditions to `false`", self.script.name, id);
+                        // A getter was removed. Its conditions are therefore not met anymore,
+                        // including the conditions that share other watches.
+                        // Its pending timers are cancelled.
+                        for (rule_index, rule_state) in per_rule.iter_mut().enumerate() {
+                            let cancelled : Vec<_> = rule_state.timers.keys()
+                                .filter(|&&(_, ref getter)| *getter == id)
+                                .cloned()
+                                .collect();
+                            for key in cancelled {
+                                rule_state.timers.remove(&key);
+                                let _ = on_event.send(ExecutionEvent::TimerCancel {
+                                    rule_index: rule_index,
+                                    condition_index: key.0,
+                                    id: key.1,
+                                });
+                            }
+                        }
+                        for rule_index in self.network.remove_getter(&id) {
+                            self.update_rule(per_rule, rule_index, env, on_event);
+                        }
+                    },
+                    WatchEvent::GetterAdded(id) => {
+                        debug!("[Recipe '{}'] Added getter {}.", self.script.name, id);
+                        // An getter was added. Nothing to do.
+                    }
+                    WatchEvent::EnterRange { from: id, value } => {
+                        debug!("[Recipe '{}'] Getter {} has entered the range of watch {}: {:?}", self.script.name, id, watch_index, value);
+                        // Matches with a duration wait until the getter has
+                        // remained in range long enough, on a timer per match.
+                        for (rule_index, condition_index) in members {
+                            let duration = match self.script.rules[rule_index].conditions[condition_index].duration {
+                                None => continue,
+                                Some(ref duration) => duration.clone()
+                            };
+                            debug!("[Recipe '{}'] There is a timer for rule {}, condition {}, we should trigger the execution in {:?}s.", self.script.name, rule_index, condition_index, duration);
+                            self.start_condition_timer(env, &mut per_rule[rule_index], rule_index,
+                                condition_index, id.clone(), UTC::now() + ChronoDuration::from(duration));
+                            let _ = on_event.send(ExecutionEvent::TimerStart {
+                                rule_index: rule_index,
+                                condition_index: condition_index,
+                                id: id.clone(),
+                            });
+                        }
+                        // Other matches are met immediately.
+                        for rule_index in self.network.enter(watch_index, &id) {
+                            self.update_rule(per_rule, rule_index, env, on_event);
+                        }
+                    }
+                    WatchEvent::ExitRange { from: id, value } => {
+                        debug!("[Recipe '{}'] Getter {} has left the range of watch {}: {:?}", self.script.name, id, watch_index, value);
+                        // Cancel the timers of this getter for the matches of this
+                        // watch, if any. Other getters and watches are unaffected.
+                        for (rule_index, condition_index) in members {
+                            if per_rule[rule_index].timers.remove(&(condition_index, id.clone())).is_some() {
+                                debug!("[Recipe '{}'] Cancelled the timer of getter {} for rule {}, condition {}", self.script.name, id, rule_index, condition_index);
+                                let _ = on_event.send(ExecutionEvent::TimerCancel {
+                                    rule_index: rule_index,
+                                    condition_index: condition_index,
+                                    id: id.clone(),
+                                });
+                            }
+                        }
+                        // Regardless, the getter is not in range anymore.
+                        for rule_index in self.network.exit(watch_index, &id) {
+                            self.update_rule(per_rule, rule_index, env, on_event);
+                        }
+                    }
+                }
+            }
+        }
+        if self.checkpoints {
+            self.checkpoint(per_rule, env, on_event);
+        }
+        true
+    }
+
+    /// A serializable view of the state of the script.
+    fn snapshot(&self, per_rule: &[RuleState<Env>]) -> Snapshot {
+        let rules = per_rule.iter().enumerate().map(|(rule_index, state)| {
+            let mut timers : Vec<_> = state.timers.iter().map(|(&(condition_index, ref id), timer)| {
+                TimerSnapshot {
+                    condition_index: condition_index,
+                    getter: id.clone(),
+                    deadline: TimeStamp::from(timer.deadline.clone()),
+                }
+            }).collect();
+            timers.sort_by(|a, b| (a.condition_index, a.getter.to_string()).cmp(&(b.condition_index, b.getter.to_string())));
+            RuleSnapshot {
+                is_met: state.rule_is_met,
+                is_suspended: state.suspended_at.map_or(false, |at| at.elapsed() < self.circuit_breaker.window),
+                conditions: (0..self.script.rules[rule_index].conditions.len()).map(|condition_index| {
+                    ConditionSnapshot {
+                        getters: self.network.getters(rule_index, condition_index)
+                    }
+                }).collect(),
+                timers: timers,
+                last_fired: state.last_fired.clone().map(TimeStamp::from),
+            }
+        }).collect();
+        Snapshot {
+            rules: rules,
+            is_paused: self.is_paused,
+            version: self.version.clone(),
+        }
+    }
+
+    /// A getter just entered/left a range. Update the conditions to determine whether
     /// we now need to fire the statements.
-    fn update_conditions<S>(&self, name: &str, id: Id<Getter>, getter_is_met: bool,
+    fn update_conditions<S>(&mut self, id: Id<Getter>, getter_is_met: bool,
             per_rule: &mut Vec<RuleState<Env>>, rule_index: usize, condition_index: usize,
-            api: &Env::API, on_event: &S)
+            env: &Env, on_event: &S)
             where S: ExtSender<ExecutionEvent> + Clone
     {
-        use std::mem::replace;
+        debug!("[Thinkerbell update_condition {}] Updating rule {}, condition {} for getter {} => {}", self.script.name,
+            rule_index, condition_index, id, getter_is_met);
 
-        let was_met = if getter_is_met {
-            !per_rule[rule_index]
-                .per_condition[condition_index]
-                .per_getter
-                .insert(id)
-        } else {
-             per_rule[rule_index]
-                .per_condition[condition_index]
-                .per_getter
-                .remove(&id)
-        };
+        // The match is met iff any of the getters meets the condition. Only
+        // the rules whose matches have changed need to be updated.
+        for rule_index in self.network.update(rule_index, condition_index, id, getter_is_met) {
+            self.update_rule(per_rule, rule_index, env, on_event);
+        }
+    }
 
-        debug!("[Thinkerbell update_condition {}] Updating condition for getter: {} => {}", name, was_met, getter_is_met);
-        if was_met == getter_is_met {
-            debug!("[Thinkerbell update_condition {}] Nothing has changed.", name);
-            // Nothing has changed, no need to update any further.
-            return;
+    /// Start a timer that will open or close a time window.
+    fn start_window_timer(&self, env: &Env, window: TimeWindow, is_open: bool, seconds: u32) -> Env::TimerGuard {
+        let tx = self.tx.map(move |()| {
+            ExecutionOp::UpdateWindow {
+                window: window,
+                is_open: is_open
+            }
+        });
+        env.start_timer(Duration::from(ChronoDuration::seconds(seconds as i64)), Box::new(tx))
+    }
+
+    /// Determine whether a rule is met.
+    ///
+    /// The rule is met iff all of the matches are met, all of the
+    /// conditions on variables hold and the rule is active.
+    fn rule_is_met(&self, rule_index: usize) -> bool {
+        let rule = &self.script.rules[rule_index];
+        self.network.matches_are_met(rule_index)
+        && rule.variable_conditions
+            .iter()
+            .all(|condition| self.variable_condition_is_met(condition))
+        && self.script.active_when
+            .iter()
+            .chain(rule.active_when.iter())
+            .all(|active_when| self.is_active(active_when))
+    }
+
+    fn is_active(&self, active_when: &ActiveWhen) -> bool {
+        match *active_when {
+            ActiveWhen::Mode(ref mode) => self.mode.as_ref() == Some(mode),
+            ActiveWhen::Window(ref window) => match self.windows.get(window) {
+                Some(&(is_open, _)) => is_open,
+                None => window.from == window.to
+            }
         }
+    }
 
-        // 1. Is the match met?
-        //
-        // The match is met iff any of the getters
-        // meets the condition.
-        let some_getter_is_met = getter_is_met ||
-            per_rule[rule_index]
-            .per_condition[condition_index]
-            .per_getter
-            .len() > 0;
+    fn variable_condition_is_met(&self, condition: &VariableMatch) -> bool {
+        match self.variables.get(&condition.variable) {
+            None => false,
+            Some(value) => condition.range.contains(value)
+        }
+    }
 
-        per_rule[rule_index]
-            .per_condition[condition_index]
-            .match_is_met = some_getter_is_met;
+    /// Something that a rule depends upon has changed. Determine whether
+    /// the rule is now met and, if so, fire its statements.
+    fn update_rule<S>(&mut self, per_rule: &mut Vec<RuleState<Env>>, rule_index: usize,
+            env: &Env, on_event: &S)
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        use std::mem::replace;
 
         // 2. Is the condition met?
-        //
-        // The condition is met iff all of the
-        // matches are met.
-        let condition_is_met =
-            per_rule[rule_index]
-            .per_condition
-            .iter()
-            .find(|condition_state| condition_state.match_is_met)
-            .is_some();
+        let condition_is_met = self.rule_is_met(rule_index);
 
         // 3. Are we in a case in which the
         // condition was not met and is now met?
         let condition_was_met =
             replace(&mut per_rule[rule_index].rule_is_met, condition_is_met);
 
-        debug!("[Thinkerbell update_condition {}] Updating condition for rule: {} => {}", name, condition_was_met, condition_is_met);
+        debug!("[Thinkerbell update_condition {}] Updating condition for rule: {} => {}", self.script.name, condition_was_met, condition_is_met);
 
         if !condition_was_met && condition_is_met {
-            // Ahah, we have just triggered the statements!
-            debug!("[Thinkerbell update_condition {}] Triggering {} statements.", name, self.script.rules[rule_index].execute.len());
-            for (statement, statement_index) in self.script.rules[rule_index].execute.iter().zip(0..) {
-                debug!("[Thinkerbell update_condition {}] Triggering statement {}/{}.", name, statement_index, self.script.rules[rule_index].execute.len());
-                let result = statement.eval(&api, &self.owner);
-                debug!("[Thinkerbell update_condition {}] Statement result {}/{}: {:?}.", name, statement_index, self.script.rules[rule_index].execute.len(), result);
-                if result.is_empty() {
-                    warn!("[Recipe '{}'] In rule {}, attempting to trigger statement {}, couldn't find any receiver channel.", name,
-                            rule_index, condition_index);
-                }
-
-                let _ = on_event.send(ExecutionEvent::Sent {
+            self.try_fire(per_rule, rule_index, env, on_event);
+        }
+    }
+
+    /// A rule has just become met. Fire its statements, unless the script
+    /// is paused or the rule is suspended.
+    fn try_fire<S>(&mut self, per_rule: &mut Vec<RuleState<Env>>, rule_index: usize,
+            env: &Env, on_event: &S)
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        if self.is_paused {
+            debug!("[Recipe '{}'] Script is paused, not firing rule {}.", self.script.name, rule_index);
+            per_rule[rule_index].missed_firing = true;
+            return;
+        }
+        if let Some(suspended_at) = per_rule[rule_index].suspended_at {
+            if suspended_at.elapsed() < self.circuit_breaker.window {
+                debug!("[Recipe '{}'] Rule {} is suspended, not firing.", self.script.name, rule_index);
+                return;
+            }
+            self.resume_rule(&mut per_rule[rule_index], rule_index, on_event);
+        }
+        if self.is_caused_by_script(per_rule, rule_index) &&
+            !per_rule[rule_index].record_firing(&self.circuit_breaker) {
+            warn!("[Recipe '{}'] Rule {} fires too often, suspending it.", self.script.name, rule_index);
+            per_rule[rule_index].suspended_at = Some(Instant::now());
+            let _ = on_event.send(ExecutionEvent::RuleSuspended {
+                rule_index: rule_index,
+            });
+            return;
+        }
+        // Ahah, we have just triggered the statements!
+        per_rule[rule_index].last_fired = Some(UTC::now());
+        self.record_write(&mut per_rule[rule_index]);
+        let statements = (0..self.script.rules[rule_index].execute.len()).collect();
+        self.fire(rule_index, statements, false, env, on_event);
+    }
+
+    /// Whether a rule that is about to fire may have been triggered by the
+    /// statements of the script itself, rather than by the outside world.
+    /// This is the case if another rule of its feedback loop has fired since
+    /// it last fired, or if the rule may trigger itself.
+    fn is_caused_by_script(&self, per_rule: &[RuleState<Env>], rule_index: usize) -> bool {
+        let feedback_loop = &self.feedback_loops[rule_index];
+        if feedback_loop.len() == 1 {
+            return true;
+        }
+        let last_write = per_rule[rule_index].last_write;
+        feedback_loop.iter().any(|&other| other != rule_index && per_rule[other].last_write > last_write)
+    }
+
+    /// Record that a rule is about to write, for `is_caused_by_script`.
+    fn record_write(&mut self, rule_state: &mut RuleState<Env>) {
+        rule_state.last_write = Some(self.writes);
+        self.writes += 1;
+    }
+
+    /// Let a rule suspended by the circuit breaker fire again.
+    fn resume_rule<S>(&self, rule_state: &mut RuleState<Env>, rule_index: usize, on_event: &S)
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        info!("[Recipe '{}'] Resuming rule {}.", self.script.name, rule_index);
+        rule_state.suspended_at = None;
+        rule_state.recent_firings.clear();
+        let _ = on_event.send(ExecutionEvent::RuleResumed {
+            rule_index: rule_index,
+        });
+    }
+
+    /// Execute some statements of a rule, given by their index.
+    fn fire<S>(&mut self, rule_index: usize, statements: Vec<usize>, is_manual: bool, env: &Env, on_event: &S)
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let len = statements.len();
+        let mut variables_have_changed = false;
+        debug!("[Thinkerbell update_condition {}] Triggering {} statements.", self.script.name, len);
+        for statement_index in statements {
+            debug!("[Thinkerbell update_condition {}] Triggering statement {}/{}.", self.script.name, statement_index, len);
+            if self.execute_statement(rule_index, vec![statement_index], is_manual, env, on_event) {
+                variables_have_changed = true;
+            }
+        }
+        if variables_have_changed {
+            // Other rules may depend on these variables. We update them asynchronously,
+            // to avoid unbounded recursion between rules that update each other.
+            let _ = self.tx.send(ExecutionOp::UpdateVariables);
+        }
+    }
+
+    /// Find a statement from its path in a rule: the index of the statement
+    /// in the rule, followed by the indices in the `on_error` statements of
+    /// its ancestors.
+    fn statement_at(&self, rule_index: usize, path: &[usize]) -> &Statement<CompiledCtx<Env>> {
+        let mut statement = &self.script.rules[rule_index].execute[path[0]];
+        for index in &path[1..] {
+            statement = match *statement {
+                Statement::Send(SendValues { on_error: Some(ref policy), .. }) => &policy.execute[*index],
+                _ => panic!("Invalid statement path {:?}", path)
+            };
+        }
+        statement
+    }
+
+    /// Execute a single statement. Return `true` if variables have changed.
+    fn execute_statement<S>(&mut self, rule_index: usize, path: Vec<usize>, is_manual: bool, env: &Env, on_event: &S) -> bool
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let set = match *self.statement_at(rule_index, &path) {
+            Statement::SetVariable(ref set) => Some(set.clone()),
+            Statement::Send(_) => None
+        };
+        if let Some(set) = set {
+            return match apply_variable_op(&mut self.variables, &self.script.variables, &set) {
+                Some(value) => {
+                    debug!("[Thinkerbell update_condition {}] Variable {} is now {:?}.", self.script.name, set.variable, value);
+                    let _ = on_event.send(ExecutionEvent::VariableChanged {
+                        name: set.variable,
+                        value: value,
+                    });
+                    true
+                }
+                None => false
+            };
+        }
+
+        if self.run_mode == RunMode::Shadow {
+            if let Statement::Send(ref send) = *self.statement_at(rule_index, &path) {
+                let setters = send.resolve(send.destination.clone(), env, &self.owner).0;
+                debug!("[Thinkerbell update_condition {}] Statement {:?} would send {:?} to {:?}.", self.script.name, path, send.value, setters);
+                let _ = on_event.send(ExecutionEvent::WouldSend {
                     rule_index: rule_index,
-                    statement_index: statement_index,
-                    result: result,
+                    statement_index: path[0],
+                    setters: setters,
+                    value: send.value.clone(),
+                    is_manual: is_manual,
+                });
+            }
+            return false;
+        }
+
+        let result = match *self.statement_at(rule_index, &path) {
+            Statement::Send(ref send) => send.eval(env, &self.owner),
+            Statement::SetVariable(_) => return false
+        };
+        debug!("[Thinkerbell update_condition {}] Statement result {:?}: {:?}.", self.script.name, path, result);
+        if result.is_empty() {
+            warn!("[Recipe '{}'] In rule {}, attempting to trigger statement {:?}, couldn't find any receiver channel.", self.script.name,
+                    rule_index, path);
+        }
+        let failed = failed_setters(&result);
+        let _ = on_event.send(ExecutionEvent::Sent {
+            rule_index: rule_index,
+            statement_index: path[0],
+            result: result,
+            is_manual: is_manual,
+        });
+        if failed.is_empty() {
+            return false;
+        }
+        self.handle_send_error(rule_index, path, 0, failed, is_manual, env, on_event)
+    }
+
+    /// Apply the error policy of a statement after the value could not be
+    /// sent to some setters, either by scheduling a retry, or by sending to the
+    /// fallback and executing the `on_error` statements.
+    ///
+    /// `attempt` is the number of retries so far. Return `true` if variables have changed.
+    fn handle_send_error<S>(&mut self, rule_index: usize, path: Vec<usize>, attempt: u32,
+            failed: Vec<Id<Setter>>, is_manual: bool, env: &Env, on_event: &S) -> bool
+            where S: ExtSender<ExecutionEvent> + Clone
+    {
+        let (retry, has_fallback, execute_len) = match *self.statement_at(rule_index, &path) {
+            Statement::Send(SendValues { on_error: Some(ref policy), .. }) =>
+                (policy.retry.clone(), !policy.fallback.is_empty(), policy.execute.len()),
+            // No policy, the error has already been reported.
+            _ => return false
+        };
+
+        if let Some(retry) = retry {
+            if attempt < retry.attempts {
+                let max = self.limits.max_retry_delay.clone().map(ChronoDuration::from);
+                let delay = backoff(retry.delay.into(), attempt, max);
+                debug!("[Recipe '{}'] Statement {:?} of rule {} will be retried in {:?}", self.script.name, path, rule_index, delay);
+
+                let key = self.next_retry_key;
+                self.next_retry_key += 1;
+                let rule_key = self.rule_keys[rule_index];
+                let tx = self.tx.map(move |()| {
+                    ExecutionOp::Retry {
+                        key: key,
+                        rule_key: rule_key,
+                        path: path.clone(),
+                        attempt: attempt + 1,
+                        setters: failed.clone(),
+                        is_manual: is_manual,
+                    }
                 });
+                let guard = env.start_timer(Duration::from(delay), Box::new(tx));
+                self.pending_retries.insert(key, guard);
+                return false;
+            }
+        }
+
+        if has_fallback {
+            let result = match *self.statement_at(rule_index, &path) {
+                Statement::Send(ref send) => send.eval_fallback(env, &self.owner),
+                Statement::SetVariable(_) => return false
+            };
+            let delivered = !result.is_empty() && failed_setters(&result).is_empty();
+            let _ = on_event.send(ExecutionEvent::FallbackSent {
+                rule_index: rule_index,
+                statement_index: path[0],
+                result: result,
+            });
+            if delivered {
+                return false;
+            }
+        }
+
+        let mut variables_have_changed = false;
+        for index in 0..execute_len {
+            let mut sub_path = path.clone();
+            sub_path.push(index);
+            if self.execute_statement(rule_index, sub_path, is_manual, env, on_event) {
+                variables_have_changed = true;
+            }
+        }
+        variables_have_changed
+    }
+}
+
+/// The setters to which a value could not be sent, except those that the
+/// owner of the script may not write.
+fn failed_setters(result: &[(Id<Setter>, Result<(), Error>)]) -> Vec<Id<Setter>> {
+    result.iter()
+        .filter(|&&(_, ref result)| match *result {
+            // Permissions will not change by retrying.
+            Err(Error::PermissionError(_)) | Ok(_) => false,
+            Err(_) => true
+        })
+        .map(|&(ref id, _)| id.clone())
+        .collect()
+}
+
+/// The delay before the retry that follows `attempt` retries: `delay`,
+/// doubled after each retry, capped to `max`, or to one day if there is no
+/// limit. Never overflows, however large `delay` and `attempt`.
+fn backoff(delay: ChronoDuration, attempt: u32, max: Option<ChronoDuration>) -> ChronoDuration {
+    let max = max.unwrap_or_else(|| ChronoDuration::days(1)).num_milliseconds();
+    let factor = if attempt < 62 { 1i64 << attempt } else { i64::MAX };
+    let delay = delay.num_milliseconds().checked_mul(factor).unwrap_or(max);
+    ChronoDuration::milliseconds(cmp::min(delay, max))
+}
+
+/// Apply an operation to a variable. Return the new value of the variable if it has changed.
+fn apply_variable_op(variables: &mut HashMap<String, VariableValue>, declarations: &[VariableDeclaration],
+    statement: &SetVariable) -> Option<VariableValue>
+{
+    let value = match statement.operation {
+        VariableOp::Set(ref value) => value.clone(),
+        VariableOp::Reset => {
+            match declarations.iter().find(|declaration| declaration.name == statement.variable) {
+                Some(declaration) => declaration.initial.clone(),
+                None => return None // Checked during compilation.
             }
         }
-        debug!("[Thinkerbell update_condition {}] done.", name);
+        VariableOp::Increment(delta) => {
+            match variables.get(&statement.variable) {
+                Some(&VariableValue::Counter(counter)) => VariableValue::Counter(counter.saturating_add(delta)),
+                _ => return None // Checked during compilation.
+            }
+        }
+    };
+    if variables.get(&statement.variable) == Some(&value) {
+        return None;
     }
+    variables.insert(statement.variable.clone(), value.clone());
+    Some(value)
 }
 
 
-impl<Env> Statement<CompiledCtx<Env>> where Env: ExecutableDevEnv {
-    fn eval(&self, api: &Env::API, owner: &User) ->  Vec<(Id<Setter>, Result<(), Error>)> {
-        api.send_values(vec![Targetted {
-            select: self.destination.clone(),
+impl<Env> SendValues<CompiledCtx<Env>> where Env: ExecutableDevEnv {
+    fn eval(&self, env: &Env, owner: &User) ->  Vec<(Id<Setter>, Result<(), Error>)> {
+        self.eval_at(self.destination.clone(), env, owner)
+    }
+
+    fn eval_fallback(&self, env: &Env, owner: &User) ->  Vec<(Id<Setter>, Result<(), Error>)> {
+        match self.on_error {
+            Some(ref policy) => self.eval_at(policy.fallback.clone(), env, owner),
+            None => vec![]
+        }
+    }
+
+    /// Send the value to some destination other than `destination`.
+    ///
+    /// Setters that `owner` may not write are skipped, as they may have
+    /// appeared after the script was compiled.
+    fn eval_at(&self, destination: Vec<SetterSelector>, env: &Env, owner: &User) ->  Vec<(Id<Setter>, Result<(), Error>)> {
+        let api = env.api();
+        let (allowed, denied) = self.resolve(destination, env, owner);
+        let mut result : Vec<_> = denied.into_iter()
+            .map(|id| (id.clone(), Err(Error::PermissionError(Access::Write(id)))))
+            .collect();
+        if allowed.is_empty() {
+            return result;
+        }
+        result.extend(api.send_values(vec![Targetted {
+            select: allowed.into_iter().map(|id| SetterSelector::new().with_id(id)).collect(),
             payload: self.value.clone()
         }], owner.clone())
             .into_iter()
             .map(|(id, result)|
-                 (id, result.map_err(|err| Error::APIError(err))))
-            .collect()
+                 (id, result.map_err(|err| Error::APIError(err)))));
+        result
+    }
+
+    /// The setters of `destination`, as the setters that `owner` may write
+    /// and those it may not.
+    fn resolve(&self, destination: Vec<SetterSelector>, env: &Env, owner: &User) -> (Vec<Id<Setter>>, Vec<Id<Setter>>) {
+        env.api().get_setter_channels(destination).into_iter()
+            .map(|channel| channel.id)
+            .partition(|id| env.permissions().is_allowed(owner, &Access::Write(id.clone())))
     }
 }
 
@@ -500,12 +2470,29 @@ pub enum StartStopError {
     AlreadyRunning,
     NotRunning,
     ThreadError,
+
+    /// The new version of the script declares different variables,
+    /// virtual channels or derived getters, so it cannot replace the
+    /// running script without restarting it.
+    CannotReload,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub enum Error {
-    CompileError(compile::Error),
+    /// The script could not be compiled. This contains all the diagnostics
+    /// produced by the compiler.
+    CompileError(Vec<Diagnostic>),
     StartStopError(StartStopError),
     APIError(api::Error),
+    VirtualChannelError(virtual_channels::Error),
+
+    /// The owner of the script may not write to this setter.
+    PermissionError(Access),
+
+    /// The script has no rule with this index.
+    NoSuchRule(usize),
+
+    /// The rule has no statement with this index, as `(rule_index, statement_index)`.
+    NoSuchStatement(usize, usize),
 }
 
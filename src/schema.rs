@@ -0,0 +1,546 @@
+//! A JSON Schema for scripts.
+//!
+//! Each type that may appear in a script describes its own JSON encoding,
+//! under the name returned by its `Parser::description()`. `script_schema`
+//! collects these descriptions into a single schema (draft 4), which
+//! clients may use to validate scripts before sending them.
+//!
+//! Objects list their fields once, with `Fields`, and their schema is
+//! derived from this list. The schema rejects other fields, which the
+//! parser ignores, so that typos are caught by clients without breaking
+//! the scripts that are already stored. The encodings of `ChannelKind`
+//! and `Value` are checked against the parsers of the taxonomy when the
+//! schema is generated.
+//!
+//! The schema does not check constraints that require typing (e.g. that
+//! a `Range` matches a `ChannelKind`); these are the responsibility of
+//! module `compile`.
+
+use ast::*;
+
+use foxbox_taxonomy::parse::*;
+use foxbox_taxonomy::selector::{ GetterSelector, SetterSelector };
+use foxbox_taxonomy::services::ChannelKind;
+use foxbox_taxonomy::values::{ Duration, Range, Value };
+
+use std::collections::BTreeMap;
+
+/// A type that may appear in a script.
+pub trait Schema: Parser<Self> where Self: Sized {
+    /// The schema of the JSON encoding of this type. References to other
+    /// types use `reference`.
+    fn schema() -> JSON;
+}
+
+/// A field of the JSON encoding of an object.
+pub struct Field {
+    pub name: &'static str,
+
+    /// If `false`, the parser uses a default value when the field is
+    /// missing.
+    pub is_required: bool,
+
+    /// The schema of the value of the field.
+    pub schema: fn() -> JSON,
+}
+impl Field {
+    pub fn required(name: &'static str, schema: fn() -> JSON) -> Self {
+        Field {
+            name: name,
+            is_required: true,
+            schema: schema,
+        }
+    }
+
+    pub fn optional(name: &'static str, schema: fn() -> JSON) -> Self {
+        Field {
+            name: name,
+            is_required: false,
+            schema: schema,
+        }
+    }
+}
+
+/// An object that may appear in a script.
+pub trait Fields {
+    /// All the fields that the parser of this object reads.
+    fn fields() -> Vec<Field>;
+}
+
+/// A reference to the schema of another type, in the definitions of the
+/// script schema.
+pub fn reference<T>() -> JSON where T: Schema {
+    object_from(vec![("$ref", JSON::String(format!("#/definitions/{}", T::description())))])
+}
+
+/// An array of references to the schema of another type.
+pub fn many<T>() -> JSON where T: Schema {
+    array_of(reference::<T>())
+}
+
+pub fn string() -> JSON {
+    typed("string")
+}
+
+pub fn strings() -> JSON {
+    array_of(string())
+}
+
+/// A non-negative integer.
+pub fn count() -> JSON {
+    object_from(vec![
+        ("type", JSON::String("integer".to_owned())),
+        ("minimum", JSON::U64(0)),
+    ])
+}
+
+fn object_from(fields: Vec<(&str, JSON)>) -> JSON {
+    JSON::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+fn typed(typ: &str) -> JSON {
+    object_from(vec![("type", JSON::String(typ.to_owned()))])
+}
+
+fn array_of(items: JSON) -> JSON {
+    object_from(vec![("type", JSON::String("array".to_owned())), ("items", items)])
+}
+
+fn any_of(schemas: Vec<JSON>) -> JSON {
+    object_from(vec![("anyOf", JSON::Array(schemas))])
+}
+
+fn constants(values: &[&str]) -> JSON {
+    object_from(vec![("enum", JSON::Array(values.iter().map(|value| JSON::String((*value).to_owned())).collect()))])
+}
+
+/// An object with the given fields, which are all optional unless listed
+/// in `required`. Other fields are rejected.
+fn object(fields: Vec<(&str, JSON)>, required: &[&str]) -> JSON {
+    let mut schema = vec![
+        ("type", JSON::String("object".to_owned())),
+        ("properties", object_from(fields)),
+        ("additionalProperties", JSON::Bool(false)),
+    ];
+    if !required.is_empty() {
+        schema.push(("required", JSON::Array(required.iter().map(|field| JSON::String((*field).to_owned())).collect())));
+    }
+    object_from(schema)
+}
+
+/// The schema of an object, derived from its `Fields`.
+fn object_of<T>() -> JSON where T: Fields {
+    let fields = T::fields();
+    let required : Vec<_> = fields.iter()
+        .filter(|field| field.is_required)
+        .map(|field| field.name)
+        .collect();
+    object(fields.iter().map(|field| (field.name, (field.schema)())).collect(), &required)
+}
+
+/// An object with a single field, e.g. `{"Mode": "Away"}`.
+fn tagged(tag: &str, schema: JSON) -> JSON {
+    object(vec![(tag, schema)], &[tag])
+}
+
+impl Schema for Script<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for Rule<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for Match<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for Statement<UncheckedCtx> {
+    fn schema() -> JSON {
+        any_of(vec![
+            reference::<SendValues<UncheckedCtx>>(),
+            reference::<SetVariable>(),
+        ])
+    }
+}
+
+impl Schema for SendValues<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for ErrorPolicy<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for Retry {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for SetVariable {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for VariableOp {
+    fn schema() -> JSON {
+        any_of(vec![
+            constants(&["Reset"]),
+            tagged("Set", reference::<VariableValue>()),
+            tagged("Increment", typed("integer")),
+        ])
+    }
+}
+
+impl Schema for VariableValue {
+    fn schema() -> JSON {
+        any_of(vec![
+            tagged("Counter", typed("integer")),
+            reference::<Value>(),
+        ])
+    }
+}
+
+impl Schema for VariableDeclaration {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for VariableMatch {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for VariableRange {
+    fn schema() -> JSON {
+        any_of(vec![
+            tagged("Counter", object(vec![
+                ("min", typed("integer")),
+                ("max", typed("integer")),
+            ], &[])),
+            reference::<Range>(),
+        ])
+    }
+}
+
+impl Schema for VirtualChannel {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for DerivedGetter<UncheckedCtx> {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+impl Schema for Aggregate {
+    fn schema() -> JSON {
+        any_of(vec![
+            constants(&["Average", "Min", "Max"]),
+            tagged("Any", reference::<Range>()),
+            tagged("All", reference::<Range>()),
+        ])
+    }
+}
+
+impl Schema for ActiveWhen {
+    fn schema() -> JSON {
+        any_of(vec![
+            tagged("Window", reference::<TimeWindow>()),
+            tagged("Mode", typed("string")),
+        ])
+    }
+}
+
+impl Schema for TimeWindow {
+    fn schema() -> JSON {
+        object_of::<Self>()
+    }
+}
+
+// Types defined by the taxonomy.
+
+/// The kinds of channels that scripts may use, as `"LightOn"`. Names that
+/// `ChannelKind::parse` rejects are left out of the schema.
+const CHANNEL_KINDS: &'static [&'static str] = &[
+    "Ready",
+    "LightOn",
+    "OpenClosed",
+    "DoorLocked",
+    "CurrentTime",
+    "CurrentTimeOfDay",
+    "RemainingTime",
+    "OvenOn",
+    "OvenTemperature",
+];
+
+impl Schema for ChannelKind {
+    fn schema() -> JSON {
+        let kinds : Vec<_> = CHANNEL_KINDS.iter()
+            .cloned()
+            .filter(|kind| ChannelKind::parse(Path::new(), &mut JSON::String((*kind).to_owned())).is_ok())
+            .collect();
+        constants(&kinds)
+    }
+}
+
+/// The encodings of values, as a tag, the schema of the payload and
+/// examples of payloads, e.g. `{"OnOff": "On"}`. A tag is left out of the
+/// schema unless `Value::parse` accepts all its examples.
+const VALUES: &'static [(&'static str, fn() -> JSON, &'static [&'static str])] = &[
+    ("Unit", empty_array, &["[]"]),
+    ("OnOff", on_off, &[r#""On""#, r#""Off""#]),
+    ("OpenClosed", open_closed, &[r#""Open""#, r#""Closed""#]),
+    ("Duration", seconds, &["0", "5", "2.5"]),
+    ("TimeStamp", string, &[r#""2016-04-10T12:00:00Z""#]),
+    ("Temperature", temperature, &[r#"{"C": 18}"#, r#"{"F": 64.4}"#]),
+    ("String", string, &[r#""foo""#]),
+    ("Json", anything, &[r#"{"foo": [1, 2]}"#]),
+];
+
+fn empty_array() -> JSON {
+    object_from(vec![
+        ("type", JSON::String("array".to_owned())),
+        ("maxItems", JSON::U64(0)),
+    ])
+}
+
+fn on_off() -> JSON {
+    constants(&["On", "Off"])
+}
+
+fn open_closed() -> JSON {
+    constants(&["Open", "Closed"])
+}
+
+/// A number of seconds.
+fn seconds() -> JSON {
+    object_from(vec![
+        ("type", JSON::String("number".to_owned())),
+        ("minimum", JSON::U64(0)),
+    ])
+}
+
+fn temperature() -> JSON {
+    any_of(vec![
+        tagged("C", typed("number")),
+        tagged("F", typed("number")),
+    ])
+}
+
+fn anything() -> JSON {
+    JSON::Object(BTreeMap::new())
+}
+
+impl Schema for Value {
+    fn schema() -> JSON {
+        let values = VALUES.iter()
+            .filter(|&&(tag, _, examples)| examples.iter().all(|example| {
+                let source = format!("{{\"{}\": {}}}", tag, example);
+                Value::from_str(&source).is_ok()
+            }))
+            .map(|&(tag, payload, _)| tagged(tag, payload()))
+            .collect();
+        any_of(values)
+    }
+}
+
+impl Schema for Range {
+    fn schema() -> JSON {
+        let bounds = object(vec![
+            ("min", reference::<Value>()),
+            ("max", reference::<Value>()),
+        ], &["min", "max"]);
+        any_of(vec![
+            tagged("Eq", reference::<Value>()),
+            tagged("Leq", reference::<Value>()),
+            tagged("Geq", reference::<Value>()),
+            tagged("BetweenEq", bounds.clone()),
+            tagged("OutOfStrict", bounds),
+        ])
+    }
+}
+
+impl Schema for Duration {
+    fn schema() -> JSON {
+        seconds()
+    }
+}
+
+impl Schema for GetterSelector {
+    fn schema() -> JSON {
+        // Selectors are extensible, so other fields are accepted.
+        object_from(vec![
+            ("type", JSON::String("object".to_owned())),
+            ("properties", object_from(vec![
+                ("id", typed("string")),
+                ("tags", array_of(typed("string"))),
+                ("kind", reference::<ChannelKind>()),
+            ])),
+        ])
+    }
+}
+
+impl Schema for SetterSelector {
+    fn schema() -> JSON {
+        GetterSelector::schema()
+    }
+}
+
+fn define<T>(definitions: &mut BTreeMap<String, JSON>) where T: Schema {
+    definitions.insert(T::description(), T::schema());
+}
+
+/// The JSON Schema of scripts.
+pub fn script_schema() -> JSON {
+    let mut definitions = BTreeMap::new();
+    define::<Script<UncheckedCtx>>(&mut definitions);
+    define::<Rule<UncheckedCtx>>(&mut definitions);
+    define::<Match<UncheckedCtx>>(&mut definitions);
+    define::<Statement<UncheckedCtx>>(&mut definitions);
+    define::<SendValues<UncheckedCtx>>(&mut definitions);
+    define::<ErrorPolicy<UncheckedCtx>>(&mut definitions);
+    define::<Retry>(&mut definitions);
+    define::<SetVariable>(&mut definitions);
+    define::<VariableOp>(&mut definitions);
+    define::<VariableValue>(&mut definitions);
+    define::<VariableDeclaration>(&mut definitions);
+    define::<VariableMatch>(&mut definitions);
+    define::<VariableRange>(&mut definitions);
+    define::<VirtualChannel>(&mut definitions);
+    define::<DerivedGetter<UncheckedCtx>>(&mut definitions);
+    define::<Aggregate>(&mut definitions);
+    define::<ActiveWhen>(&mut definitions);
+    define::<TimeWindow>(&mut definitions);
+    define::<ChannelKind>(&mut definitions);
+    define::<Value>(&mut definitions);
+    define::<Range>(&mut definitions);
+    define::<Duration>(&mut definitions);
+    define::<GetterSelector>(&mut definitions);
+    define::<SetterSelector>(&mut definitions);
+
+    let mut schema = BTreeMap::new();
+    schema.insert("$schema".to_owned(), JSON::String("http://json-schema.org/draft-04/schema#".to_owned()));
+    schema.insert("$ref".to_owned(), JSON::String(format!("#/definitions/{}", Script::<UncheckedCtx>::description())));
+    schema.insert("definitions".to_owned(), JSON::Object(definitions));
+    JSON::Object(schema)
+}
+
+/// Validate a JSON value against a schema produced by `script_schema`.
+///
+/// This only supports the subset of JSON Schema used by `script_schema`,
+/// listed in `KEYWORDS`, and rejects schemas that use other keywords
+/// rather than ignoring them. In case of error, return the path of the
+/// offending value.
+pub fn validate(schema: &JSON, value: &JSON) -> Result<(), String> {
+    validate_at(schema, schema, value, "script")
+}
+
+/// The keywords of JSON Schema supported by `validate`.
+pub const KEYWORDS: &'static [&'static str] = &[
+    "$schema", "$ref", "definitions",
+    "type", "enum", "minimum", "anyOf",
+    "items", "maxItems",
+    "properties", "additionalProperties", "required", "minProperties", "maxProperties",
+];
+
+fn validate_at(root: &JSON, schema: &JSON, value: &JSON, path: &str) -> Result<(), String> {
+    let keywords = match schema.as_object() {
+        Some(keywords) => keywords,
+        None => return Err(format!("{}: the schema is not an object", path))
+    };
+    if let Some(keyword) = keywords.keys().find(|keyword| !KEYWORDS.iter().any(|known| *known == keyword.as_str())) {
+        return Err(format!("{}: unsupported keyword {} in the schema", path, keyword));
+    }
+    if let Some(target) = schema.find("$ref").and_then(|target| target.as_string()) {
+        let name = target.trim_left_matches("#/definitions/");
+        return match root.find("definitions").and_then(|definitions| definitions.find(name)) {
+            Some(definition) => validate_at(root, definition, value, path),
+            None => Err(format!("{}: unknown definition {}", path, name))
+        };
+    }
+    if let Some(typ) = schema.find("type").and_then(|typ| typ.as_string()) {
+        let is_ok = match typ {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => false
+        };
+        if !is_ok {
+            return Err(format!("{}: expected {}", path, typ));
+        }
+    }
+    if let Some(values) = schema.find("enum").and_then(|values| values.as_array()) {
+        if !values.contains(value) {
+            return Err(format!("{}: unexpected constant", path));
+        }
+    }
+    if let Some(minimum) = schema.find("minimum").and_then(|minimum| minimum.as_f64()) {
+        if value.as_f64().map_or(false, |value| value < minimum) {
+            return Err(format!("{}: expected at least {}", path, minimum));
+        }
+    }
+    if let Some(schemas) = schema.find("anyOf").and_then(|schemas| schemas.as_array()) {
+        if !schemas.iter().any(|schema| validate_at(root, schema, value, path).is_ok()) {
+            return Err(format!("{}: does not match any of the expected forms", path));
+        }
+    }
+    if let Some(max) = schema.find("maxItems").and_then(|max| max.as_u64()) {
+        if value.as_array().map_or(false, |array| array.len() as u64 > max) {
+            return Err(format!("{}: expected at most {} items", path, max));
+        }
+    }
+    if let Some(items) = schema.find("items") {
+        for (item, index) in value.as_array().into_iter().flat_map(|array| array.iter()).zip(0..) {
+            try!(validate_at(root, items, item, &format!("{}[{}]", path, index)));
+        }
+    }
+    if let Some(fields) = value.as_object() {
+        if let Some(min) = schema.find("minProperties").and_then(|min| min.as_u64()) {
+            if (fields.len() as u64) < min {
+                return Err(format!("{}: expected at least {} fields", path, min));
+            }
+        }
+        if let Some(max) = schema.find("maxProperties").and_then(|max| max.as_u64()) {
+            if (fields.len() as u64) > max {
+                return Err(format!("{}: expected at most {} fields", path, max));
+            }
+        }
+        if let Some(required) = schema.find("required").and_then(|required| required.as_array()) {
+            for field in required.iter().filter_map(|field| field.as_string()) {
+                if !fields.contains_key(field) {
+                    return Err(format!("{}: missing field {}", path, field));
+                }
+            }
+        }
+        let properties = schema.find("properties").and_then(|properties| properties.as_object());
+        let additional = schema.find("additionalProperties").and_then(|additional| additional.as_boolean()).unwrap_or(true);
+        for (field, field_value) in fields {
+            match properties.and_then(|properties| properties.get(field)) {
+                Some(field_schema) => try!(validate_at(root, field_schema, field_value, &format!("{}.{}", path, field))),
+                None if !additional => return Err(format!("{}: unexpected field {}", path, field)),
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
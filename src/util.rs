@@ -9,3 +9,21 @@ pub fn map<T, F, U, E>(vec: Vec<T>, cb: F) -> Result<Vec<U>, E> where F: Fn(T) -
     Ok(result)
 }
 
+/// Utility function. A variant of `map` that does not stop in case of error, so that
+/// `cb` may report all the errors. `cb` also receives the index of each element.
+/// Return `None` if `cb` failed for any element.
+pub fn map_all<T, F, U>(vec: Vec<T>, mut cb: F) -> Option<Vec<U>> where F: FnMut(T, usize) -> Option<U> {
+    let mut result = Vec::with_capacity(vec.len());
+    let mut is_ok = true;
+    for (val, index) in vec.into_iter().zip(0..) {
+        match cb(val, index) {
+            Some(val) => result.push(val),
+            None => is_ok = false
+        }
+    }
+    if is_ok {
+        Some(result)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,314 @@
+//! Virtual channels, used by scripts to communicate with each other.
+//!
+//! A virtual channel is a getter and a setter that share the same id
+//! and the same kind, but do not correspond to any device. Whenever
+//! a value is sent to the setter, it becomes the latest value of the
+//! getter, and watchers of the getter are informed.
+//!
+//! Virtual channels are published through the API, so scripts can
+//! select them like any other channel, e.g. with `{"id": "vacation"}`.
+//!
+//! Derived getters (see `ast::DerivedGetter`) are also published as
+//! virtual channels, without a setter. Their value is updated by the
+//! script that declares them.
+
+use ast::VirtualChannel;
+
+use foxbox_taxonomy::api::{ Error as APIError, User };
+use foxbox_taxonomy::manager::*;
+use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::values::*;
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex };
+
+use transformable_channels::mpsc::*;
+
+static VERSION : [u32;4] = [0, 0, 0, 0];
+
+/// An error while declaring a virtual channel.
+#[derive(Clone, Debug, Serialize)]
+pub enum Error {
+    /// The environment does not support virtual channels.
+    NotSupported,
+
+    /// A virtual channel with this id was already declared, with a different kind.
+    KindMismatch {
+        id: String,
+        declared: ChannelKind,
+        requested: ChannelKind,
+    },
+
+    /// A virtual channel with this id was already declared, as a channel
+    /// with a setter if `with_setter` is `true`, or as a derived getter
+    /// otherwise.
+    SetterMismatch {
+        id: String,
+        with_setter: bool,
+    },
+
+    /// The channel could not be published through the API, e.g. because
+    /// a device already uses the same id.
+    APIError(APIError),
+}
+
+/// The state shared by the virtual adapter and the `VirtualChannels`.
+struct VirtualBackEnd {
+    /// The kind of each declared channel, and whether it has a setter.
+    kinds: HashMap<String, (ChannelKind, bool)>,
+
+    /// The latest known value for each getter.
+    values: HashMap<Id<Getter>, Value>,
+
+    /// All watchers, indexed by a unique key.
+    counter: usize,
+    watchers: HashMap<usize, (Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)>,
+}
+
+impl VirtualBackEnd {
+    fn new() -> Self {
+        VirtualBackEnd {
+            kinds: HashMap::new(),
+            values: HashMap::new(),
+            counter: 0,
+            watchers: HashMap::new(),
+        }
+    }
+
+    fn set_value(&mut self, id: Id<Getter>, value: Value) {
+        let old = self.values.insert(id.clone(), value.clone());
+        for watcher in self.watchers.values() {
+            let (ref watched_id, ref range, ref cb) = *watcher;
+            if *watched_id != id {
+                continue;
+            }
+            if let Some(ref range) = *range {
+                let was_met = match old {
+                    Some(ref value) => range.contains(value),
+                    None => false
+                };
+                match (was_met, range.contains(&value)) {
+                    (false, true) => {
+                        let _ = cb.send(WatchEvent::Enter {
+                            id: id.clone(),
+                            value: value.clone()
+                        });
+                    }
+                    (true, false) => {
+                        let _ = cb.send(WatchEvent::Exit {
+                            id: id.clone(),
+                            value: value.clone()
+                        });
+                    }
+                    _ => continue
+                }
+            } else {
+                let _ = cb.send(WatchEvent::Enter {
+                    id: id.clone(),
+                    value: value.clone()
+                });
+            }
+        }
+    }
+}
+
+/// The adapter publishing virtual channels.
+struct VirtualAdapter {
+    id: Id<AdapterId>,
+    back_end: Arc<Mutex<VirtualBackEnd>>,
+}
+
+impl Adapter for VirtualAdapter {
+    fn id(&self) -> Id<AdapterId> {
+        self.id.clone()
+    }
+
+    fn name(&self) -> &str {
+        "Thinkerbell Virtual Channels"
+    }
+    fn vendor(&self) -> &str {
+        "team@link.mozilla.org"
+    }
+    fn version(&self) -> &[u32;4] {
+        &VERSION
+    }
+
+    fn fetch_values(&self, getters: Vec<Id<Getter>>, _: User) -> ResultMap<Id<Getter>, Option<Value>, APIError> {
+        let back_end = self.back_end.lock().unwrap();
+        getters.into_iter().map(|id| {
+            let value = back_end.values.get(&id).cloned();
+            (id, Ok(value))
+        }).collect()
+    }
+
+    fn send_values(&self, values: HashMap<Id<Setter>, Value>, _: User) -> ResultMap<Id<Setter>, (), APIError> {
+        let mut back_end = self.back_end.lock().unwrap();
+        values.into_iter().map(|(id, value)| {
+            back_end.set_value(Id::new(&id.to_string()), value);
+            (id, Ok(()))
+        }).collect()
+    }
+
+    fn register_watch(&self, source: Vec<(Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)>) ->
+            Vec<(Id<Getter>, Result<Box<AdapterWatchGuard>, APIError>)>
+    {
+        let mut back_end = self.back_end.lock().unwrap();
+        source.into_iter().map(|(id, range, tx)| {
+            let key = back_end.counter;
+            back_end.counter += 1;
+            back_end.watchers.insert(key, (id.clone(), range, tx));
+            let guard = VirtualWatchGuard {
+                back_end: self.back_end.clone(),
+                key: key
+            };
+            (id, Ok(Box::new(guard) as Box<AdapterWatchGuard>))
+        }).collect()
+    }
+}
+
+/// A watchguard for a VirtualAdapter.
+struct VirtualWatchGuard {
+    back_end: Arc<Mutex<VirtualBackEnd>>,
+    key: usize,
+}
+impl AdapterWatchGuard for VirtualWatchGuard {}
+impl Drop for VirtualWatchGuard {
+    fn drop(&mut self) {
+        let _ = self.back_end.lock().unwrap().watchers.remove(&self.key);
+    }
+}
+
+/// The virtual channels of an environment.
+///
+/// Environments built on top of an `AdapterManager` may create one
+/// instance of `VirtualChannels` and expose it through
+/// `ExecutableDevEnv::virtual_channels`. All the scripts executed in
+/// this environment then share the same virtual channels.
+pub struct VirtualChannels {
+    manager: Arc<AdapterManager>,
+    adapter: Id<AdapterId>,
+    service: Id<ServiceId>,
+    back_end: Arc<Mutex<VirtualBackEnd>>,
+}
+
+impl VirtualChannels {
+    /// Register the virtual adapter with an `AdapterManager`.
+    pub fn new(manager: Arc<AdapterManager>) -> Result<Self, APIError> {
+        let adapter_id = Id::<AdapterId>::new("virtual@thinkerbell.foxlink");
+        let service_id = Id::<ServiceId>::new("virtual@thinkerbell.foxlink");
+        let back_end = Arc::new(Mutex::new(VirtualBackEnd::new()));
+        try!(manager.add_adapter(Arc::new(VirtualAdapter {
+            id: adapter_id.clone(),
+            back_end: back_end.clone(),
+        })));
+        try!(manager.add_service(Service {
+            id: service_id.clone(),
+            adapter: adapter_id.clone(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            tags: HashSet::new(),
+            properties: HashMap::new(),
+        }));
+        Ok(VirtualChannels {
+            manager: manager,
+            adapter: adapter_id,
+            service: service_id,
+            back_end: back_end,
+        })
+    }
+
+    /// Declare a virtual channel.
+    ///
+    /// Declaring several times the same channel with the same kind is
+    /// not an error, as several scripts typically need to declare the
+    /// channels they use to communicate.
+    ///
+    /// A channel declared with `declare_getter` may not be declared again
+    /// with `declare`, and conversely.
+    pub fn declare(&self, channel: &VirtualChannel) -> Result<(), Error> {
+        self.declare_aux(channel, true)
+    }
+
+    /// Declare a virtual channel that only offers a getter. Its value may
+    /// only be changed with `set_value`.
+    pub fn declare_getter(&self, channel: &VirtualChannel) -> Result<(), Error> {
+        self.declare_aux(channel, false)
+    }
+
+    fn declare_aux(&self, channel: &VirtualChannel, with_setter: bool) -> Result<(), Error> {
+        {
+            let mut back_end = self.back_end.lock().unwrap();
+            if let Some(&(ref kind, has_setter)) = back_end.kinds.get(&channel.id) {
+                if *kind != channel.kind {
+                    return Err(Error::KindMismatch {
+                        id: channel.id.clone(),
+                        declared: kind.clone(),
+                        requested: channel.kind.clone(),
+                    });
+                }
+                if has_setter != with_setter {
+                    return Err(Error::SetterMismatch {
+                        id: channel.id.clone(),
+                        with_setter: has_setter,
+                    });
+                }
+                return Ok(());
+            }
+            back_end.kinds.insert(channel.id.clone(), (channel.kind.clone(), with_setter));
+        }
+        // Publishing the channels may cause the manager to call the adapter, so
+        // we must not hold the lock on the back-end.
+        let result = self.publish(channel, with_setter);
+        if result.is_err() {
+            self.back_end.lock().unwrap().kinds.remove(&channel.id);
+        }
+        result
+    }
+
+    /// Publish the getter and, if `with_setter`, the setter of a channel.
+    /// Either both are published, or none.
+    fn publish(&self, channel: &VirtualChannel, with_setter: bool) -> Result<(), Error> {
+        let tags : HashSet<_> = channel.tags.iter().map(|tag| Id::<TagId>::new(tag)).collect();
+        let getter_id = Id::<Getter>::new(&channel.id);
+        try!(self.manager.add_getter(Channel {
+            id: getter_id.clone(),
+            adapter: self.adapter.clone(),
+            service: self.service.clone(),
+            tags: tags.clone(),
+            last_seen: None,
+            mechanism: Getter {
+                updated: None,
+                kind: channel.kind.clone(),
+            }
+        }).map_err(Error::APIError));
+        if !with_setter {
+            return Ok(());
+        }
+        let result = self.manager.add_setter(Channel {
+            id: Id::<Setter>::new(&channel.id),
+            adapter: self.adapter.clone(),
+            service: self.service.clone(),
+            tags: tags,
+            last_seen: None,
+            mechanism: Setter {
+                updated: None,
+                kind: channel.kind.clone(),
+            }
+        });
+        if let Err(err) = result {
+            let _ = self.manager.remove_getter(&getter_id);
+            return Err(Error::APIError(err));
+        }
+        Ok(())
+    }
+
+    /// Change the value of a virtual channel, informing watchers.
+    pub fn set_value(&self, id: &str, value: Value) {
+        self.back_end.lock().unwrap().set_value(Id::new(id), value)
+    }
+
+    /// Get the latest value sent to a virtual channel, if any.
+    pub fn get_value(&self, id: &str) -> Option<Value> {
+        self.back_end.lock().unwrap().values.get(&Id::new(id)).cloned()
+    }
+}
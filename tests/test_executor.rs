@@ -0,0 +1,73 @@
+extern crate foxbox_thinkerbell;
+extern crate transformable_channels;
+
+use foxbox_thinkerbell::executor::*;
+
+use transformable_channels::mpsc::*;
+
+/// A task that reports the messages it receives, and panics on `None`.
+struct Echo {
+    name: &'static str,
+    tx_events: Sender<(&'static str, Option<usize>)>,
+}
+
+impl Task<Option<usize>> for Echo {
+    fn handle(&mut self, op: Option<usize>) -> bool {
+        match op {
+            None => panic!("Echo {} received None", self.name),
+            Some(value) => {
+                let _ = self.tx_events.send((self.name, Some(value)));
+                true
+            }
+        }
+    }
+
+    fn panicked(&mut self) {
+        let _ = self.tx_events.send((self.name, None));
+    }
+}
+
+struct SpawnEcho {
+    name: &'static str,
+    tx_events: Sender<(&'static str, Option<usize>)>,
+    tx_init: Sender<Box<ExtSender<Option<usize>>>>,
+}
+
+impl Spawn<Option<usize>> for SpawnEcho {
+    fn spawn(self: Box<Self>, tx: Box<ExtSender<Option<usize>>>) -> Option<Box<Task<Option<usize>>>> {
+        self.tx_init.send(tx).unwrap();
+        Some(Box::new(Echo {
+            name: self.name,
+            tx_events: self.tx_events,
+        }))
+    }
+}
+
+#[test]
+fn test_executor_panic() {
+    let pool = Pool::new(1);
+    let (tx_events, rx_events) = channel();
+    let (tx_init, rx_init) = channel();
+    for name in &["first", "second"] {
+        pool.spawn(Box::new(SpawnEcho {
+            name: *name,
+            tx_events: tx_events.clone(),
+            tx_init: tx_init.clone(),
+        }));
+    }
+    let tx_first = rx_init.recv().unwrap();
+    let tx_second = rx_init.recv().unwrap();
+
+    println!("* A task that panics is dropped, and reports it.");
+    tx_first.send(None).unwrap();
+    assert_eq!(rx_events.recv().unwrap(), ("first", None));
+
+    println!("* The other tasks of the same thread keep running.");
+    tx_second.send(Some(1)).unwrap();
+    assert_eq!(rx_events.recv().unwrap(), ("second", Some(1)));
+
+    println!("* Messages sent to the task that has panicked are dropped.");
+    tx_first.send(Some(2)).unwrap();
+    tx_second.send(Some(3)).unwrap();
+    assert_eq!(rx_events.recv().unwrap(), ("second", Some(3)));
+}
@@ -2,6 +2,7 @@
 #![plugin(serde_macros)]
 extern crate foxbox_taxonomy;
 extern crate foxbox_thinkerbell;
+extern crate rusqlite;
 extern crate serde;
 extern crate transformable_channels;
 
@@ -72,3 +73,513 @@ fn test_database_add_remove_script() {
     db.put(&name, &load_json("./examples/ruleset.json"), &User::Id(1)).unwrap();
     assert_eq!(db.get_running_count(), 1);
 }
+
+#[test]
+fn test_database_modes() {
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, Path::new("./test_mode_database.sqlite"), Box::new(tx)).unwrap();
+    for mode in db.get_modes().unwrap() {
+        db.remove_mode(&mode).unwrap();
+    }
+
+    println!("* Initially, the house is in no mode.");
+    assert_eq!(db.get_mode().unwrap(), None);
+
+    println!("* Switching to a mode that has not been added is an error.");
+    match db.set_mode(Some("Away")) {
+        Err(Error::NoSuchModeError) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Switching to a mode that has been added.");
+    db.add_mode("Away").unwrap();
+    db.add_mode("Night").unwrap();
+    let mut modes = db.get_modes().unwrap();
+    modes.sort();
+    assert_eq!(modes, vec!["Away".to_owned(), "Night".to_owned()]);
+    db.set_mode(Some("Away")).unwrap();
+    assert_eq!(db.get_mode().unwrap(), Some("Away".to_owned()));
+    db.set_mode(Some("Night")).unwrap();
+    assert_eq!(db.get_mode().unwrap(), Some("Night".to_owned()));
+
+    println!("* Removing the current mode leaves the house in no mode.");
+    db.remove_mode("Night").unwrap();
+    assert_eq!(db.get_mode().unwrap(), None);
+}
+
+#[test]
+fn test_database_conflicts() {
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, Path::new("./test_conflict_database.sqlite"), Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let heat_when_cold = r#"{
+      "name": "Heat when cold",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "thermometer"}],
+          "kind": "OvenTemperature",
+          "range": {"Leq": {"Temperature": {"C": 18}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "heater"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#.to_owned();
+    let stop_at_night = r#"{
+      "name": "Stop at night",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "clock"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"Geq": {"Duration": 79200}}
+        }],
+        "execute": [{
+          "destination": [{"id": "heater"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#.to_owned();
+
+    println!("* Putting a first script reports no conflict.");
+    let heat_id = Id::<ScriptId>::new("Heat when cold");
+    assert_eq!(db.put(&heat_id, &heat_when_cold, &User::None).unwrap().len(), 0);
+
+    println!("* Putting a script that sends another value to the same kind of setters reports a conflict.");
+    let stop_id = Id::<ScriptId>::new("Stop at night");
+    let conflicts = db.put(&stop_id, &stop_at_night, &User::None).unwrap();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].script, heat_id);
+    assert_eq!(conflicts[0].rule_index, 0);
+    assert_eq!(conflicts[0].other_rule_index, 0);
+
+    println!("* Disabled scripts are not taken into account.");
+    db.set_enabled(&heat_id, false).unwrap();
+    assert_eq!(db.put(&stop_id, &stop_at_night, &User::None).unwrap().len(), 0);
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_quota() {
+    use foxbox_thinkerbell::compile::{ Quota, SourceError };
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let path = Path::new("./test_quota_database.sqlite");
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+    db.set_quota(Quota {
+        max_scripts: 1,
+        ..Quota::default()
+    });
+
+    let first = Id::<ScriptId>::new("First");
+    let second = Id::<ScriptId>::new("Second");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* An owner may not enable more scripts than allowed by the quota.");
+    db.put(&first, &source, &User::Id(1)).unwrap();
+    match db.put(&second, &source, &User::Id(1)) {
+        Err(Error::QuotaError(SourceError::ScriptQuotaExceeded { max: 1 })) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Quotas are per owner.");
+    db.put(&second, &source, &User::Id(2)).unwrap();
+
+    println!("* Overwriting a script does not count it twice.");
+    db.put(&first, &source, &User::Id(1)).unwrap();
+
+    println!("* Disabled scripts are not taken into account.");
+    db.set_enabled(&first, false).unwrap();
+    db.put(&Id::new("Third"), &source, &User::Id(1)).unwrap();
+    match db.set_enabled(&first, true) {
+        Err(Error::QuotaError(SourceError::ScriptQuotaExceeded { max: 1 })) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Loading the scripts enforces the quota.");
+    db.set_quota(Quota::default());
+    db.put(&Id::new("Fourth"), &source, &User::Id(1)).unwrap();
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.set_quota(Quota {
+        max_scripts: 1,
+        ..Quota::default()
+    });
+    let loaded = db.load().unwrap();
+    let exceeded = loaded.values().filter(|result| match **result {
+        Err(Error::QuotaError(SourceError::ScriptQuotaExceeded { max: 1 })) => true,
+        Ok(()) => false,
+        ref other => panic!("Unexpected result {:?}", other)
+    }).count();
+    assert_eq!(exceeded, 1);
+    assert_eq!(db.get_running_count(), 2);
+
+    println!("* Loading the scripts after lowering the quota starts as many scripts as allowed.");
+    db.set_quota(Quota::default());
+    db.put(&Id::new("Fifth"), &source, &User::Id(1)).unwrap();
+    db.put(&Id::new("Sixth"), &source, &User::Id(1)).unwrap();
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    db.set_quota(Quota {
+        max_scripts: 3,
+        ..Quota::default()
+    });
+    let loaded = db.load().unwrap();
+    assert_eq!(loaded.values().filter(|result| result.is_err()).count(), 1);
+    assert_eq!(db.get_running_count(), 4);
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_snapshot() {
+    use foxbox_thinkerbell::run::{ Error as RunError, StartStopError };
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, Path::new("./test_snapshot_database.sqlite"), Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Snapshot");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Running scripts can be inspected.");
+    db.put(&id, &source, &User::None).unwrap();
+    let snapshot = db.snapshot(&id).unwrap();
+    assert_eq!(snapshot.rules.len(), 1);
+    assert!(!snapshot.rules[0].is_met);
+    assert_eq!(snapshot.rules[0].conditions.len(), 2);
+    assert!(snapshot.rules[0].conditions.iter().all(|condition| condition.getters.is_empty()));
+    assert!(snapshot.rules[0].timers.is_empty());
+    assert!(snapshot.rules[0].last_fired.is_none());
+
+    println!("* Disabled scripts cannot.");
+    db.set_enabled(&id, false).unwrap();
+    match db.snapshot(&id) {
+        Err(Error::RunError(RunError::StartStopError(StartStopError::NotRunning))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_pause() {
+    use foxbox_thinkerbell::run::ResumePolicy;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let path = Path::new("./test_pause_database.sqlite");
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Paused");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Scripts are not paused initially.");
+    db.put(&id, &source, &User::None).unwrap();
+    assert!(!db.is_paused(&id).unwrap());
+    assert!(!db.snapshot(&id).unwrap().is_paused);
+
+    println!("* Paused scripts keep running.");
+    db.pause(&id).unwrap();
+    assert!(db.is_paused(&id).unwrap());
+    assert!(db.is_enabled(&id));
+    assert!(db.snapshot(&id).unwrap().is_paused);
+
+    println!("* Scripts remain paused when they are re-enabled or replaced.");
+    db.set_enabled(&id, false).unwrap();
+    db.set_enabled(&id, true).unwrap();
+    assert!(db.snapshot(&id).unwrap().is_paused);
+    db.put(&id, &source, &User::None).unwrap();
+    assert!(db.is_paused(&id).unwrap());
+    assert!(db.snapshot(&id).unwrap().is_paused);
+
+    println!("* Scripts remain paused when the manager restarts.");
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    db.load().unwrap();
+    assert!(db.snapshot(&id).unwrap().is_paused);
+
+    println!("* Scripts can be resumed.");
+    db.resume(&id, ResumePolicy::Skip).unwrap();
+    assert!(!db.is_paused(&id).unwrap());
+    assert!(!db.snapshot(&id).unwrap().is_paused);
+
+    println!("* Only stored scripts can be paused.");
+    match db.pause(&Id::new("Unknown")) {
+        Err(Error::NoSuchScriptError) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_fire_rule() {
+    use foxbox_thinkerbell::run::{ Error as RunError, StartStopError };
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, Path::new("./test_fire_database.sqlite"), Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Fire");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* The rules of running scripts can be fired manually.");
+    db.put(&id, &source, &User::None).unwrap();
+    db.fire_rule(&id, 0, None).unwrap();
+    db.fire_rule(&id, 0, Some(0)).unwrap();
+    match db.fire_rule(&id, 1, None) {
+        Err(Error::RunError(RunError::NoSuchRule(1))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* The rules of disabled scripts cannot.");
+    db.set_enabled(&id, false).unwrap();
+    match db.fire_rule(&id, 0, None) {
+        Err(Error::RunError(RunError::StartStopError(StartStopError::NotRunning))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_variables() {
+    use foxbox_thinkerbell::ast::VariableValue;
+    use foxbox_thinkerbell::run::RunMode;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, Path::new("./test_variables_database.sqlite"), Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Variables");
+    let source = |variables: &str, execute: &str| format!(r#"{{
+      "name": "Counting script",
+      "variables": [{}],
+      "rules": [{{
+        "conditions": [],
+        "variable_conditions": [{{
+          "variable": "count",
+          "range": {{"Counter": {{"min": 0}}}}
+        }}],
+        "execute": [{}]
+      }}]
+    }}"#, variables, execute);
+    let count = r#"{"name": "count", "initial": {"Counter": 0}}"#;
+    let other = r#"{"name": "other", "initial": {"Counter": 0}}"#;
+    let increment_count = r#"{"variable": "count", "operation": {"Increment": 1}}"#;
+    let increment_other = r#"{"variable": "other", "operation": {"Increment": 1}}"#;
+
+    println!("* The variables of running scripts are stored.");
+    db.put(&id, &source(&format!("{}, {}", count, other), &format!("{}, {}", increment_count, increment_other)),
+        &User::None).unwrap();
+    db.fire_rule(&id, 0, None).unwrap();
+    let variables = db.get_variables(&id).unwrap();
+    assert_eq!(variables.get("count"), Some(&VariableValue::Counter(1)));
+    assert_eq!(variables.get("other"), Some(&VariableValue::Counter(1)));
+
+    println!("* Replacing the script forgets the variables it does not declare anymore.");
+    db.put(&id, &source(count, increment_count), &User::None).unwrap();
+    let mut variables = db.get_variables(&id).unwrap();
+    assert_eq!(variables.remove("count"), Some(VariableValue::Counter(1)));
+    assert!(variables.is_empty());
+
+    println!("* Scripts in shadow mode do not store their variables.");
+    db.put_with_mode(&id, &source(count, increment_count), &User::None, RunMode::Shadow).unwrap();
+    db.fire_rule(&id, 0, None).unwrap();
+    assert_eq!(db.get_variables(&id).unwrap().get("count"), Some(&VariableValue::Counter(1)));
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_shadow() {
+    use foxbox_thinkerbell::run::RunMode;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let path = Path::new("./test_shadow_database.sqlite");
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Shadow");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Scripts are live by default.");
+    db.put(&id, &source, &User::None).unwrap();
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Live);
+
+    println!("* Scripts may be put in shadow mode, and remain so when they are replaced.");
+    db.put_with_mode(&id, &source, &User::None, RunMode::Shadow).unwrap();
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Shadow);
+    assert!(db.is_enabled(&id));
+    db.put(&id, &source, &User::None).unwrap();
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Shadow);
+
+    println!("* Scripts remain in shadow mode when the manager restarts.");
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    db.load().unwrap();
+    assert!(db.is_enabled(&id));
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Shadow);
+
+    println!("* Scripts can go live.");
+    db.put_with_mode(&id, &source, &User::None, RunMode::Live).unwrap();
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Live);
+    assert!(db.is_enabled(&id));
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_checkpoint() {
+    use foxbox_thinkerbell::run::ExpiredTimerPolicy;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let path = Path::new("./test_checkpoint_database.sqlite");
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Checkpoint");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Expired timers fire by default.");
+    db.put(&id, &source, &User::None).unwrap();
+    assert_eq!(db.expired_timer_policy(&id).unwrap(), ExpiredTimerPolicy::Fire);
+
+    println!("* The policy is stored per script.");
+    db.set_expired_timer_policy(&id, ExpiredTimerPolicy::Arm).unwrap();
+    assert_eq!(db.expired_timer_policy(&id).unwrap(), ExpiredTimerPolicy::Arm);
+    match db.set_expired_timer_policy(&Id::new("Unknown"), ExpiredTimerPolicy::Discard) {
+        Err(Error::NoSuchScriptError) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Scripts resume from their checkpoint when the manager restarts.");
+    db.fire_rule(&id, 0, None).unwrap();
+    let before = db.snapshot(&id).unwrap();
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    db.load().unwrap();
+    assert_eq!(db.snapshot(&id).unwrap(), before);
+    assert_eq!(db.expired_timer_policy(&id).unwrap(), ExpiredTimerPolicy::Arm);
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_seed() {
+    use foxbox_thinkerbell::run::SeedPolicy;
+
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+
+    let (tx, _) = channel();
+    let path = Path::new("./test_seed_database.sqlite");
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.remove_all().unwrap();
+
+    let id = Id::<ScriptId>::new("Seed");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Scripts ignore the current values by default.");
+    db.put(&id, &source, &User::None).unwrap();
+    assert_eq!(db.seed_policy(&id).unwrap(), SeedPolicy::Ignore);
+
+    println!("* The policy is stored per script, and survives replacements and restarts.");
+    db.set_seed_policy(&id, SeedPolicy::Arm).unwrap();
+    assert_eq!(db.seed_policy(&id).unwrap(), SeedPolicy::Arm);
+    db.put(&id, &source, &User::None).unwrap();
+    assert_eq!(db.seed_policy(&id).unwrap(), SeedPolicy::Arm);
+    match db.set_seed_policy(&Id::new("Unknown"), SeedPolicy::Fire) {
+        Err(Error::NoSuchScriptError) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    db.load().unwrap();
+    assert!(db.is_enabled(&id));
+    assert_eq!(db.seed_policy(&id).unwrap(), SeedPolicy::Arm);
+
+    db.remove_all().unwrap();
+}
+
+#[test]
+fn test_database_upgrade() {
+    use foxbox_thinkerbell::run::{ RunMode, SeedPolicy };
+
+    let path = Path::new("./test_upgrade_database.sqlite");
+    let id = Id::<ScriptId>::new("Upgrade");
+    let source = load_json("./examples/ruleset.json");
+
+    println!("* Creating a database with the schema of earlier versions.");
+    {
+        let connection = rusqlite::Connection::open(path).unwrap();
+        connection.execute("DROP TABLE IF EXISTS scripts", &[]).unwrap();
+        connection.execute("CREATE TABLE scripts (
+            id          TEXT NOT NULL PRIMARY KEY,
+            source      TEXT NOT NULL,
+            is_enabled  BOOL NOT NULL DEFAULT 1,
+            owner       INTEGER NOT NULL DEFAULT -1
+        )", &[]).unwrap();
+        connection.execute("INSERT INTO scripts (id, source, is_enabled, owner) VALUES ($1, $2, 1, -1)",
+            &[&id.to_string(), &source]).unwrap();
+    }
+
+    println!("* Existing scripts get the default settings.");
+    let (tx_env, _) = channel();
+    let env = FakeEnv::new(Box::new(tx_env));
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env.clone(), path, Box::new(tx)).unwrap();
+    db.load().unwrap();
+    assert!(db.is_enabled(&id));
+    assert!(!db.is_paused(&id).unwrap());
+    assert_eq!(db.run_mode(&id).unwrap(), RunMode::Live);
+    assert_eq!(db.seed_policy(&id).unwrap(), SeedPolicy::Ignore);
+
+    println!("* Opening an up-to-date database keeps the settings.");
+    db.pause(&id).unwrap();
+    drop(db);
+    let (tx, _) = channel();
+    let mut db = ScriptManager::new(env, path, Box::new(tx)).unwrap();
+    assert!(db.is_paused(&id).unwrap());
+
+    db.remove_all().unwrap();
+}
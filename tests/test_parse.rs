@@ -40,3 +40,36 @@ fn test_parse_simple_rule() {
     Script::from_str(src).unwrap();
 }
 
+
+#[test]
+fn test_parse_variables() {
+    let src = r#"{
+      "name": "foo",
+      "variables": [{
+        "name": "heating",
+        "initial": {"OnOff": "On"}
+      }, {
+        "name": "count",
+        "initial": {"Counter": 3}
+      }],
+      "rules": [{
+        "conditions": [],
+        "variable_conditions": [{
+          "variable": "count",
+          "range": {"Counter": {"max": 5}}
+        }],
+        "execute": [{
+          "variable": "count",
+          "operation": "Reset"
+        }]
+      }]
+    }"#;
+    let script = Script::from_str(src).unwrap();
+    assert_eq!(script.variables.len(), 2);
+    assert_eq!(script.variables[1].initial, VariableValue::Counter(3));
+    assert_eq!(script.rules[0].variable_conditions.len(), 1);
+    match script.rules[0].execute[0] {
+        Statement::SetVariable(ref set) => assert_eq!(set.operation, VariableOp::Reset),
+        ref other => panic!("Unexpected statement {:?}", other)
+    }
+}
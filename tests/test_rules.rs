@@ -29,8 +29,119 @@ enum Event {
     Run(ExecutionEvent),
 }
 
+/// A `FakeEnv` with a single adapter and a single service, to which tests
+/// add the channels they need.
+struct Fixture {
+    env: FakeEnv,
+    adapter_id: Id<AdapterId>,
+    service_id: Id<ServiceId>,
+    rx_done: Receiver<()>,
+
+    /// The values sent to setters.
+    rx_send: Receiver<(Id<Setter>, Value)>,
+}
+impl Fixture {
+    fn new() -> Self {
+        let (tx_env, rx_env) = channel();
+        let (tx_done, rx_done) = channel();
+        let (tx_send, rx_send) = channel();
+        thread::spawn(move || {
+            for msg in rx_env {
+                match msg {
+                    FakeEnvEvent::Done => tx_done.send(()).unwrap(),
+                    FakeEnvEvent::Send { id, value } => tx_send.send((id, value)).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        let fixture = Fixture {
+            env: FakeEnv::new(Box::new(tx_env)),
+            adapter_id: Id::new("Adapter 1"),
+            service_id: Id::new("Service 1"),
+            rx_done: rx_done,
+            rx_send: rx_send,
+        };
+        fixture.execute(Instruction::AddAdapters(vec![fixture.adapter_id.to_string()]));
+        fixture.execute(Instruction::AddServices(vec![
+            Service {
+                id: fixture.service_id.clone(),
+                adapter: fixture.adapter_id.clone(),
+                getters: HashMap::new(),
+                setters: HashMap::new(),
+                tags: HashSet::new(),
+                properties: HashMap::new(),
+            }
+        ]));
+        fixture
+    }
+
+    /// Execute an instruction and wait until it is complete.
+    fn execute(&self, instruction: Instruction) {
+        self.env.execute(instruction);
+        self.rx_done.recv().unwrap();
+    }
+
+    fn add_getters(&self, ids: &[&Id<Getter>], kind: ChannelKind) {
+        self.execute(Instruction::AddGetters(ids.iter().map(|id| Channel {
+            id: (*id).clone(),
+            adapter: self.adapter_id.clone(),
+            service: self.service_id.clone(),
+            tags: HashSet::new(),
+            last_seen: None,
+            mechanism: Getter {
+                updated: None,
+                kind: kind.clone(),
+            }
+        }).collect()));
+    }
+
+    fn add_setters(&self, ids: &[&Id<Setter>], kind: ChannelKind) {
+        self.execute(Instruction::AddSetters(ids.iter().map(|id| Channel {
+            id: (*id).clone(),
+            adapter: self.adapter_id.clone(),
+            service: self.service_id.clone(),
+            last_seen: None,
+            tags: HashSet::new(),
+            mechanism: Setter {
+                updated: None,
+                kind: kind.clone(),
+            }
+        }).collect()));
+    }
+
+    /// Report new values for some getters.
+    fn inject(&self, values: &[(&Id<Getter>, OnOff)]) {
+        self.execute(Instruction::InjectGetterValues(values.iter()
+            .map(|&(id, ref value)| (id.clone(), Ok(Value::OnOff(value.clone()))))
+            .collect()));
+    }
+}
+
+/// A sender for the events of a script, which passes each event to
+/// `on_event` on a dedicated thread.
+fn listen<F>(on_event: F) -> RawSender<ExecutionEvent> where F: Fn(ExecutionEvent) + Send + 'static {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for event in rx {
+            on_event(event);
+        }
+    });
+    tx
+}
+
+/// Stop a script and wait until it is stopped.
+fn stop(exec: &mut Execution<FakeEnv>) {
+    let (tx, rx) = channel();
+    exec.stop(move |result| {
+        let _ = tx.send(result);
+    });
+    rx.recv().unwrap().unwrap();
+}
+
 #[test]
 fn test_compile() {
+    use foxbox_taxonomy::parse::{ ParseError, Path };
+
     let (tx, rx) : (_, Receiver<Event>)= channel();
 
     let tx_env = Box::new(tx.map(|event| Event::Env(event)));
@@ -48,11 +159,74 @@ fn test_compile() {
 
     println!("* Attempting to parse an run an empty script will raise an error.");
     let script = Script::from_str(r#"{"name": "foo", "rules": []}"#).unwrap();
+    match exec.start(env.clone(), script, User::None, tx_run.clone()) {
+        Err(Error::CompileError(ref diagnostics)) if diagnostics.len() == 1 => {
+            match diagnostics[0].error {
+                CompileError::SourceError(SourceError::NoRule) => {},
+                ref other => panic!("Unexpected error {:?}", other)
+            }
+            assert_eq!(diagnostics[0].location.to_string(), "rules");
+        },
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Attempting to run a script with several errors will report all of them, with their location.");
+    let script = Script::from_str(r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "my getter"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }, {
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "Ready"
+        }]
+      }]
+    }"#).unwrap();
+    let mut exec = Execution::<FakeEnv>::new();
     match exec.start(env, script, User::None, tx_run) {
-        Err(Error::CompileError(CompileError::SourceError(SourceError::NoRule))) => {},
+        Err(Error::CompileError(ref diagnostics)) => {
+            let locations : Vec<_> = diagnostics.iter()
+                .map(|diagnostic| {
+                    assert_eq!(diagnostic.severity, Severity::Error);
+                    diagnostic.location.to_string()
+                })
+                .collect();
+            assert_eq!(locations, vec!["rules[0].conditions[0].source", "rules[1].execute[1].value"]);
+        },
         other => panic!("Unexpected result {:?}", other)
     }
 
+    println!("* Parse errors are reported at the same locations as compilation errors.");
+    let error = Path::new().push("rules", |path| {
+        path.push_str("[1]", |path| {
+            path.push("execute", |path| ParseError::type_error("execute", &path, "array"))
+        })
+    });
+    let diagnostic = Diagnostic::from(error);
+    assert_eq!(diagnostic.location, Location::new().field("rules").index(1).field("execute"));
+    assert_eq!(diagnostic.location.0, vec![LocationStep::Field("rules".to_owned()), LocationStep::Index(1),
+        LocationStep::Field("execute".to_owned())]);
+    assert_eq!(diagnostic.location.to_string(), "rules[1].execute");
+
     println!("//FIXME: Attempting to parse a script with an empty condition will raise an error.");
     println!("//FIXME: Attempting to parse a script with an empty statement will raise an error.");
     println!("//FIXME: Attempting to parse a script with an empty source will raise an error.");
@@ -93,6 +267,10 @@ fn test_run() {
     println!("* Preparing script.");
     let script_1 = Script {
         name: "Test script".to_owned(),
+        variables: vec![],
+        channels: vec![],
+        derived: vec![],
+        active_when: vec![],
         rules: vec![
             Rule {
                 conditions: vec![
@@ -106,15 +284,18 @@ fn test_run() {
                         phantom: PhantomData
                     }
                 ],
+                variable_conditions: vec![],
+                active_when: vec![],
                 execute: vec![
-                    Statement {
+                    Statement::Send(SendValues {
                         destination: vec![
                             SetterSelector::new()
                         ],
                         value: Value::OnOff(OnOff::Off),
                         kind: ChannelKind::LightOn,
+                        on_error: None,
                         phantom: PhantomData,
-                    }
+                    })
                 ],
                 phantom: PhantomData
             }
@@ -518,6 +699,10 @@ fn test_run_with_delay() {
 
     let script_1 = Script {
         name: "Test script".to_owned(),
+        variables: vec![],
+        channels: vec![],
+        derived: vec![],
+        active_when: vec![],
         rules: vec![
             Rule {
                 conditions: vec![
@@ -531,15 +716,18 @@ fn test_run_with_delay() {
                         phantom: PhantomData
                     }
                 ],
+                variable_conditions: vec![],
+                active_when: vec![],
                 execute: vec![
-                    Statement {
+                    Statement::Send(SendValues {
                         destination: vec![
                             SetterSelector::new()
                         ],
                         value: Value::OnOff(OnOff::Off),
                         kind: ChannelKind::LightOn,
+                        on_error: None,
                         phantom: PhantomData,
-                    }
+                    })
                 ],
                 phantom: PhantomData
             }
@@ -775,3 +963,2390 @@ fn test_run_with_delay() {
 
     println!("* Drop complete.");
 }
+
+#[test]
+fn test_run_with_several_conditions() {
+    let fixture = Fixture::new();
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }, {
+          "source": [{"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+
+    println!("* A rule does not fire while only some of its conditions are met.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    fixture.inject(&[(&getter_id_1, OnOff::Off), (&getter_id_2, OnOff::On)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* A rule fires once all its conditions are met.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+
+    println!("* ... and fires again only once all are met again.");
+    fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+    fixture.inject(&[(&getter_id_1, OnOff::Off), (&getter_id_2, OnOff::On)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+}
+
+#[test]
+fn test_run_with_variables() {
+    let fixture = Fixture::new();
+    let (tx_variable, rx_variable) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::VariableChanged { name, value } = event {
+            tx_variable.send((name, value)).unwrap();
+        }
+    });
+    let mut exec = Execution::<FakeEnv>::new();
+
+    println!("* Preparing a script that counts how many times the light was turned on.");
+    let script = Script::from_str(r#"{
+      "name": "Counting script",
+      "variables": [{
+        "name": "count",
+        "initial": {"Counter": 0}
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"kind": "LightOn"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "variable": "count",
+          "operation": {"Increment": 1}
+        }]
+      }, {
+        "conditions": [],
+        "variable_conditions": [{
+          "variable": "count",
+          "range": {"Counter": {"min": 2}}
+        }],
+        "execute": [{
+          "destination": [{"kind": "LightOn"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }, {
+          "variable": "count",
+          "operation": "Reset"
+        }]
+      }]
+    }"#).unwrap();
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+
+    exec.start(fixture.env.clone(), script, User::None, tx_run).unwrap();
+    fixture.add_getters(&[&getter_id_1], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1], ChannelKind::LightOn);
+
+    println!("* Turning the light on increments the counter, without triggering the send.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    assert_eq!(rx_variable.recv().unwrap(), ("count".to_owned(), VariableValue::Counter(1)));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Turning the light off doesn't change the counter.");
+    fixture.inject(&[(&getter_id_1, OnOff::Off)]);
+    rx_variable.try_recv().unwrap_err();
+
+    println!("* Turning the light on a second time triggers the send and resets the counter.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    assert_eq!(rx_variable.recv().unwrap(), ("count".to_owned(), VariableValue::Counter(2)));
+
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    assert_eq!(rx_variable.recv().unwrap(), ("count".to_owned(), VariableValue::Counter(0)));
+
+    println!("* Attempting to run a script that uses an undeclared variable will raise an error.");
+    let script = Script::from_str(r#"{
+      "name": "Broken script",
+      "rules": [{
+        "conditions": [],
+        "variable_conditions": [{
+          "variable": "count",
+          "range": {"Counter": {"min": 2}}
+        }],
+        "execute": [{
+          "variable": "count",
+          "operation": "Reset"
+        }]
+      }]
+    }"#).unwrap();
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    match exec.start(fixture.env.clone(), script, User::None, tx_run) {
+        Err(Error::CompileError(ref diagnostics)) => {
+            // Both the condition and the statement use the undeclared variable.
+            assert_eq!(diagnostics.len(), 2);
+            for diagnostic in diagnostics {
+                match diagnostic.error {
+                    CompileError::SourceError(SourceError::UnknownVariable(ref name)) if name == "count" => {},
+                    ref other => panic!("Unexpected error {:?}", other)
+                }
+            }
+        },
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_virtual_channels() {
+    use foxbox_thinkerbell::compile::ExecutableDevEnv;
+    use foxbox_thinkerbell::virtual_channels::Error as VirtualChannelError;
+
+    let fixture = Fixture::new();
+
+    println!("* Preparing a script that writes to a virtual channel.");
+    let writer = Script::from_str(r#"{
+      "name": "Writer",
+      "channels": [{
+        "id": "vacation mode",
+        "kind": "LightOn"
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "vacation mode"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+
+    println!("* Preparing a script that watches the same virtual channel.");
+    let reader = Script::from_str(r#"{
+      "name": "Reader",
+      "channels": [{
+        "id": "vacation mode",
+        "kind": "LightOn"
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "vacation mode"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+
+    let mut exec_writer = Execution::<FakeEnv>::new();
+    let (tx_writer, _rx_writer) = channel();
+    exec_writer.start(fixture.env.clone(), writer, User::None, tx_writer).unwrap();
+
+    let mut exec_reader = Execution::<FakeEnv>::new();
+    let (tx_reader, _rx_reader) = channel();
+    exec_reader.start(fixture.env.clone(), reader, User::None, tx_reader).unwrap();
+
+    fixture.add_getters(&[&getter_id_1], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1], ChannelKind::LightOn);
+
+    println!("* Writing to the virtual channel triggers the rules of the reader.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Declaring the same virtual channel with another kind will raise an error.");
+    let conflicting = Script::from_str(r#"{
+      "name": "Conflicting",
+      "channels": [{
+        "id": "vacation mode",
+        "kind": "Ready"
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "vacation mode"}],
+          "kind": "Ready",
+          "range": {"Eq": {"Unit": []}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+    let mut exec_conflicting = Execution::<FakeEnv>::new();
+    let (tx_conflicting, _rx_conflicting) = channel();
+    match exec_conflicting.start(fixture.env.clone(), conflicting, User::None, tx_conflicting) {
+        Err(Error::VirtualChannelError(_)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Declaring the same virtual channel with and without a setter will raise an error.");
+    let virtual_channels = fixture.env.virtual_channels().unwrap();
+    let channel = VirtualChannel {
+        id: "night mode".to_owned(),
+        kind: ChannelKind::Ready,
+        tags: vec![],
+    };
+    virtual_channels.declare(&channel).unwrap();
+    match virtual_channels.declare_getter(&channel) {
+        Err(VirtualChannelError::SetterMismatch { ref id, with_setter: true }) => assert_eq!(id, "night mode"),
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_derived_getters() {
+    use foxbox_taxonomy::api::API;
+    use foxbox_thinkerbell::compile::ExecutableDevEnv;
+
+    let fixture = Fixture::new();
+    let mut exec = Execution::<FakeEnv>::new();
+
+    println!("* Preparing a script with a derived getter that is on iff all the lights are on.");
+    let source = r#"{
+      "name": "Derived script",
+      "derived": [{
+        "id": "all lights on",
+        "kind": "LightOn",
+        "tags": ["derived"],
+        "source": [{"id": "Getter 1"}, {"id": "Getter 2"}],
+        "source_kind": "LightOn",
+        "aggregate": {"All": {"Eq": {"OnOff": "On"}}}
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"tags": ["derived"]}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+
+    let (tx_run, _rx_run) = channel();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1], ChannelKind::LightOn);
+
+    println!("* Turning on a single light does not change the derived getter.");
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_2, OnOff::Off)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Turning on both lights changes the derived getter, which triggers the rule.");
+    fixture.inject(&[(&getter_id_2, OnOff::On)]);
+
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    fixture.rx_send.try_recv().unwrap_err();
+    stop(&mut exec);
+
+    println!("* Scripts in shadow mode do not declare their derived getters.");
+    let shadow = source.replace("all lights on", "shadow lights on");
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    exec.start_with_options(fixture.env.clone(), Script::from_str(&shadow).unwrap(), User::None, Options {
+        run_mode: RunMode::Shadow,
+        ..Options::default()
+    }, tx_run).unwrap();
+    let selector = GetterSelector::new().with_id(Id::new("shadow lights on"));
+    assert!(fixture.env.api().get_getter_channels(vec![selector]).is_empty());
+}
+
+#[test]
+fn test_run_with_error_policy() {
+    let fixture = Fixture::new();
+    let (tx_sent, rx_sent) = channel();
+    let tx_run = listen(move |event| match event {
+        ExecutionEvent::Sent { .. } |
+        ExecutionEvent::Retried { .. } |
+        ExecutionEvent::FallbackSent { .. } => tx_sent.send(event).unwrap(),
+        _ => {}
+    });
+
+    println!("* Preparing a script with a statement that is retried, then sent to a fallback.");
+    let script = Script::from_str(r#"{
+      "name": "Test script",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn",
+          "on_error": {
+            "retry": {"attempts": 2, "delay": 1},
+            "fallback": [{"id": "Setter 2"}],
+            "execute": [{
+              "destination": [{"id": "Setter 3"}],
+              "value": {"OnOff": "On"},
+              "kind": "LightOn"
+            }]
+          }
+        }]
+      }]
+    }"#).unwrap();
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+    let setter_id_3 = Id::<Setter>::new("Setter 3");
+
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start(fixture.env.clone(), script, User::None, tx_run).unwrap();
+    fixture.add_getters(&[&getter_id_1], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1, &setter_id_2, &setter_id_3], ChannelKind::LightOn);
+
+    let error = APIError::TypeError(APITypeError {
+        expected: Type::OnOff,
+        got: Type::OpenClosed
+    });
+    fixture.execute(Instruction::InjectSetterErrors(vec![
+        (setter_id_1.clone(), Some(error.clone()))
+    ]));
+
+    println!("* Sending to a failing setter schedules a retry.");
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Sent { ref result, .. } if result.len() == 1 && result[0].1.is_err() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* The first retry fails and schedules another retry, with a longer delay.");
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::milliseconds(1500))));
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Retried { attempt: 1, ref result, .. } if result[0].1.is_err() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Once all retries have failed, the value is sent to the fallback.");
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(10))));
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Retried { attempt: 2, ref result, .. } if result[0].1.is_err() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::FallbackSent { ref result, .. } if result[0].1.is_ok() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_2);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    fixture.execute(Instruction::ResetTimers);
+
+    println!("* If the fallback also fails, the on_error statements are executed.");
+    fixture.execute(Instruction::InjectSetterErrors(vec![
+        (setter_id_2.clone(), Some(error.clone()))
+    ]));
+
+    fixture.inject(&[(&getter_id_1, OnOff::Off)]);
+    fixture.inject(&[(&getter_id_1, OnOff::On)]);
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Sent { .. } => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::milliseconds(1500))));
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Retried { attempt: 1, .. } => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(10))));
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Retried { attempt: 2, .. } => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::FallbackSent { ref result, .. } if result[0].1.is_err() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    match rx_sent.recv().unwrap() {
+        ExecutionEvent::Sent { statement_index: 0, ref result, .. } if result[0].1.is_ok() => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_3);
+    assert_eq!(value, Value::OnOff(OnOff::On));
+    fixture.rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_run_with_activation() {
+    use chrono::Timelike;
+
+    let fixture = Fixture::new();
+
+    // A window that opens in two hours and closes one hour later, in the
+    // local time of the house.
+    let utc_offset = ChronoDuration::minutes(330);
+    fixture.env.set_utc_offset(utc_offset);
+    let minutes = (UTC::now() + utc_offset).num_seconds_from_midnight() / 60;
+    let from = (minutes + 120) % (24 * 60);
+    let to = (minutes + 180) % (24 * 60);
+
+    println!("* Preparing a script with a rule restricted to a mode and a rule restricted to a window.");
+    let script = Script::from_str(&format!(r#"{{
+      "name": "Test script",
+      "rules": [{{
+        "conditions": [{{
+          "source": [{{"id": "Getter 1"}}],
+          "kind": "LightOn",
+          "range": {{"Eq": {{"OnOff": "On"}}}}
+        }}],
+        "active_when": [{{"Mode": "Away"}}],
+        "execute": [{{
+          "destination": [{{"id": "Setter 1"}}],
+          "value": {{"OnOff": "Off"}},
+          "kind": "LightOn"
+        }}]
+      }}, {{
+        "conditions": [{{
+          "source": [{{"id": "Getter 2"}}],
+          "kind": "LightOn",
+          "range": {{"Eq": {{"OnOff": "On"}}}}
+        }}],
+        "active_when": [{{"Window": {{"from": "{:02}:{:02}", "to": "{:02}:{:02}"}}}}],
+        "execute": [{{
+          "destination": [{{"id": "Setter 2"}}],
+          "value": {{"OnOff": "Off"}},
+          "kind": "LightOn"
+        }}]
+      }}]
+    }}"#, from / 60, from % 60, to / 60, to % 60)).unwrap();
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    exec.start(fixture.env.clone(), script, User::None, tx_run).unwrap();
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1, &setter_id_2], ChannelKind::LightOn);
+
+    println!("* Rules whose conditions are met do not fire outside of their mode or window.");
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_2, OnOff::On)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Switching to the mode fires the rule, as its conditions are already met.");
+    exec.set_mode(Some("Away".to_owned())).unwrap();
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Switching to another mode does not fire anything.");
+    exec.set_mode(Some("Home".to_owned())).unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Opening the window fires the rule, as its conditions are already met.");
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::minutes(150))));
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_2);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    fixture.rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_range_analysis() {
+    use foxbox_thinkerbell::compile::Compiler;
+
+    println!("* Conditions that can never hold together are reported as warnings.");
+    let script = Script::from_str(include_str!("../examples/ruleset.json")).unwrap();
+    let (_, warnings) = Compiler::<FakeEnv>::new().unwrap().compile_with_warnings(script).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].severity, Severity::Warning);
+    assert_eq!(warnings[0].location.to_string(), "rules[0].conditions[1].range");
+    match warnings[0].error {
+        CompileError::RangeError(RangeError::Unsatisfiable) => {},
+        ref other => panic!("Unexpected warning {:?}", other)
+    }
+
+    println!("* In strict mode, warnings are errors.");
+    let script = Script::from_str(include_str!("../examples/ruleset.json")).unwrap();
+    match Compiler::<FakeEnv>::new().unwrap().with_strict(true).compile(script) {
+        Err(ref diagnostics) if diagnostics.len() == 1 && diagnostics[0].severity == Severity::Error => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Redundant conditions and conditions that accept any value are reported as warnings.");
+    let script = Script::from_str(r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "my getter"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"Geq": {"Duration": 5}}
+        }, {
+          "source": [{"id": "my getter"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"BetweenEq": {"min": {"Duration": 6}, "max": {"Duration": 8}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "my getter"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"OutOfStrict": {"min": {"Duration": 8}, "max": {"Duration": 6}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+    let (_, warnings) = Compiler::<FakeEnv>::new().unwrap().compile_with_warnings(script).unwrap();
+    for warning in &warnings {
+        let is_expected = match warning.error {
+            CompileError::RangeError(RangeError::Redundant) =>
+                warning.location.to_string() == "rules[0].conditions[0]",
+            CompileError::RangeError(RangeError::AlwaysTrue) =>
+                warning.location.to_string() == "rules[1].conditions[0].range",
+            _ => false
+        };
+        assert!(is_expected, "Unexpected warning {:?}", warning);
+    }
+    assert_eq!(warnings.len(), 2);
+
+    println!("* Matches on different getters of the same kind are not compared.");
+    let script = Script::from_str(r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "kitchen"}],
+          "kind": "OvenTemperature",
+          "range": {"Geq": {"Temperature": {"C": 25}}}
+        }, {
+          "source": [{"id": "bedroom"}],
+          "kind": "OvenTemperature",
+          "range": {"Leq": {"Temperature": {"C": 15}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "kitchen"}],
+          "kind": "OvenTemperature",
+          "range": {"Geq": {"Temperature": {"C": 20}}}
+        }, {
+          "source": [{"id": "bedroom"}],
+          "kind": "OvenTemperature",
+          "range": {"Geq": {"Temperature": {"C": 25}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"tags": ["oven"]}],
+          "kind": "OvenTemperature",
+          "range": {"Geq": {"Temperature": {"C": 25}}}
+        }, {
+          "source": [{"tags": ["oven"]}],
+          "kind": "OvenTemperature",
+          "range": {"Leq": {"Temperature": {"C": 15}}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+    let (_, warnings) = Compiler::<FakeEnv>::new().unwrap().compile_with_warnings(script).unwrap();
+    assert_eq!(warnings.len(), 0);
+
+    println!("* Ranges that cover all the values of their kind are reported as warnings.");
+    let script = Script::from_str(r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"kind": "CurrentTimeOfDay"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"Geq": {"Duration": 0}}
+        }, {
+          "source": [{"id": "my clock"}],
+          "kind": "CurrentTimeOfDay",
+          "range": {"Leq": {"Duration": 86400}}
+        }],
+        "execute": [{
+          "destination": [{"id": "my setter"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+    let (_, warnings) = Compiler::<FakeEnv>::new().unwrap().compile_with_warnings(script).unwrap();
+    let locations : Vec<_> = warnings.iter().map(|warning| {
+        match warning.error {
+            CompileError::RangeError(RangeError::AlwaysTrue) => {},
+            ref other => panic!("Unexpected warning {:?}", other)
+        }
+        warning.location.to_string()
+    }).collect();
+    assert_eq!(locations, vec!["rules[0].conditions[0].range", "rules[0].conditions[1].range"]);
+}
+
+#[test]
+fn test_feedback_loop() {
+    use foxbox_thinkerbell::compile::ExecutableDevEnv;
+
+    let (tx, rx) : (_, Receiver<Event>) = channel();
+
+    let tx_env = Box::new(tx.map(|event| Event::Env(event)));
+    let tx_run = tx.map(|event| Event::Run(event));
+    let (tx_done, rx_done) = channel();
+    let (tx_warnings, rx_warnings) = channel();
+    let (tx_suspended, rx_suspended) = channel();
+
+    let env = FakeEnv::new(tx_env);
+
+    thread::spawn(move || {
+        for msg in rx {
+            match msg {
+                Event::Env(FakeEnvEvent::Done) => tx_done.send(()).unwrap(),
+                Event::Run(ExecutionEvent::CompileWarnings { diagnostics }) => tx_warnings.send(diagnostics).unwrap(),
+                Event::Run(ExecutionEvent::RuleSuspended { rule_index }) => tx_suspended.send(rule_index).unwrap(),
+                _ => {
+                    // Can be useful for debugging, but that's generally noise.
+                    // println!("LOG: {:?}", msg)
+                }
+            }
+        }
+    });
+
+    println!("* Preparing a script whose rules turn a virtual channel on and off indefinitely.");
+    let script = Script::from_str(r#"{
+      "name": "Ping pong",
+      "channels": [{
+        "id": "ping",
+        "kind": "LightOn"
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "ping"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "ping"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "ping"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "Off"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "ping"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let options = Options {
+        circuit_breaker: CircuitBreaker {
+            max_firings: 3,
+            window: std::time::Duration::from_secs(60),
+        },
+        ..Options::default()
+    };
+    exec.start_with_options(env.clone(), script, User::None, options, tx_run).unwrap();
+
+    println!("* The compiler warns about the feedback loop.");
+    let warnings = rx_warnings.recv().unwrap();
+    assert_eq!(warnings.len(), 1);
+    match warnings[0].error {
+        CompileError::DependencyError(DependencyError::FeedbackLoop(ref rules)) => assert_eq!(*rules, vec![0, 1]),
+        ref other => panic!("Unexpected warning {:?}", other)
+    }
+
+    println!("* Once the loop starts, the circuit breaker suspends one of the rules.");
+    // Give the script some time to start watching the channel.
+    thread::sleep(std::time::Duration::from_millis(100));
+    env.virtual_channels().unwrap().set_value("ping", Value::OnOff(OnOff::On));
+    let rule_index = rx_suspended.recv().unwrap();
+    assert!(rule_index == 0 || rule_index == 1);
+
+    println!("* The loop stops.");
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_suspended.try_recv().unwrap_err();
+    rx_done.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_circuit_breaker() {
+    use foxbox_thinkerbell::compile::Compiler;
+
+    println!("* Rules that write and watch distinct services do not form a feedback loop.");
+    let script = Script::from_str(r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"parent": "Service 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"parent": "Service 2"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"parent": "Service 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"parent": "Service 3"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#).unwrap();
+    let (_, warnings) = Compiler::<FakeEnv>::new().unwrap().compile_with_warnings(script).unwrap();
+    assert!(warnings.is_empty(), "Unexpected warnings {:?}", warnings);
+
+    let fixture = Fixture::new();
+    let (tx_events, rx_events) = channel();
+    let tx_run = listen(move |event| match event {
+        ExecutionEvent::RuleSuspended { rule_index } => tx_events.send((true, rule_index)).unwrap(),
+        ExecutionEvent::RuleResumed { rule_index } => tx_events.send((false, rule_index)).unwrap(),
+        _ => {}
+    });
+
+    // The rules may trigger each other, as far as the compiler knows, but
+    // the fake setters do not change the fake getters.
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "Off"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    fixture.inject(&[(&getter_id_1, OnOff::Off), (&getter_id_2, OnOff::On)]);
+    let rx_send = &fixture.rx_send;
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let options = Options {
+        circuit_breaker: CircuitBreaker {
+            max_firings: 3,
+            window: std::time::Duration::from_secs(60),
+        },
+        ..Options::default()
+    };
+    exec.start_with_options(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, options, tx_run).unwrap();
+
+    println!("* Firings caused by the outside world are not counted.");
+    for _ in 0..5 {
+        fixture.inject(&[(&getter_id_1, OnOff::On)]);
+        assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+        fixture.inject(&[(&getter_id_1, OnOff::Off)]);
+    }
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_events.try_recv().unwrap_err();
+
+    println!("* Firings that follow a firing of another rule of the loop are counted.");
+    for _ in 0..3 {
+        fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+        assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+        fixture.inject(&[(&getter_id_2, OnOff::On)]);
+        fixture.inject(&[(&getter_id_1, OnOff::On)]);
+        assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+        fixture.inject(&[(&getter_id_1, OnOff::Off)]);
+    }
+    fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+    assert_eq!(rx_events.recv().unwrap(), (true, 1));
+    assert!(exec.snapshot().unwrap().rules[1].is_suspended);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* A suspended rule may be resumed.");
+    exec.resume_rule(1).unwrap();
+    assert_eq!(rx_events.recv().unwrap(), (false, 1));
+    assert!(!exec.snapshot().unwrap().rules[1].is_suspended);
+    fixture.inject(&[(&getter_id_2, OnOff::On)]);
+    fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+
+    println!("* Unknown rules cannot be resumed.");
+    match exec.resume_rule(2) {
+        Err(Error::NoSuchRule(2)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_dry_run() {
+    use foxbox_thinkerbell::compile::{ Compiler, ExecutableDevEnv };
+
+    let fixture = Fixture::new();
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_2], ChannelKind::Ready);
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }, {
+          "source": [{"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }, {
+          "destination": [{"id": "Setter 2"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    println!("* A dry run reports the channels matched by each selector.");
+    let dry_run = Compiler::<FakeEnv>::new().unwrap().dry_run(Script::from_str(source).unwrap(), fixture.env.api());
+    assert_eq!(dry_run.rules.len(), 1);
+    assert_eq!(dry_run.rules[0].conditions, vec![vec![getter_id.clone()], vec![]]);
+    assert_eq!(dry_run.rules[0].statements, vec![vec![setter_id.clone()], vec![]]);
+
+    println!("* Selectors that match nothing, or only channels of another kind, are reported as warnings.");
+    for diagnostic in &dry_run.diagnostics {
+        let is_expected = match diagnostic.error {
+            CompileError::ResolutionError(ResolutionError::NoChannel) =>
+                diagnostic.location.to_string() == "rules[0].conditions[1].source",
+            CompileError::ResolutionError(ResolutionError::UnexpectedKind { ref channels, .. }) =>
+                diagnostic.location.to_string() == "rules[0].execute[1].destination" &&
+                    *channels == vec![setter_id_2.to_string()],
+            _ => false
+        };
+        assert!(is_expected, "Unexpected diagnostic {:?}", diagnostic);
+        assert_eq!(diagnostic.severity, Severity::Warning);
+    }
+    assert_eq!(dry_run.diagnostics.len(), 2);
+}
+
+#[test]
+fn test_permissions() {
+    use foxbox_thinkerbell::permissions::Access;
+
+    let fixture = Fixture::new();
+    let (tx_sent, rx_sent) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::Sent { result, .. } = event {
+            tx_sent.send(result).unwrap();
+        }
+    });
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1], ChannelKind::LightOn);
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}, {"id": "Setter 2"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    println!("* A script whose owner may not write to one of its setters is rejected.");
+    fixture.env.policy().deny(&User::Id(1), Access::Write(setter_id_1.clone()));
+    let mut exec = Execution::<FakeEnv>::new();
+    match exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::Id(1), tx_run.clone()) {
+        Err(Error::CompileError(ref diagnostics)) if diagnostics.len() == 1 => {
+            assert_eq!(diagnostics[0].location.to_string(), "rules[0].execute[0].destination");
+            match diagnostics[0].error {
+                CompileError::PermissionError(PermissionError::Denied(ref denied)) =>
+                    assert_eq!(*denied, vec![Access::Write(setter_id_1.clone())]),
+                ref other => panic!("Unexpected error {:?}", other)
+            }
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Setters that appear later are checked when values are sent.");
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::Id(2), tx_run.clone()).unwrap();
+    fixture.env.policy().deny(&User::Id(2), Access::Write(setter_id_2.clone()));
+    fixture.add_setters(&[&setter_id_2], ChannelKind::LightOn);
+
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    let (id, value) = fixture.rx_send.recv().unwrap();
+    assert_eq!(id, setter_id_1);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+
+    let result = rx_sent.recv().unwrap();
+    assert_eq!(result.len(), 2);
+    for (id, result) in result {
+        match result {
+            Ok(()) => assert_eq!(id, setter_id_1),
+            Err(Error::PermissionError(Access::Write(ref denied))) => {
+                assert_eq!(id, setter_id_2);
+                assert_eq!(*denied, setter_id_2);
+            }
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+    fixture.rx_send.try_recv().unwrap_err();
+    stop(&mut exec);
+
+    println!("* Getters that appear later are ignored if the owner may not read them.");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"kind": "LightOn"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+    fixture.inject(&[(&getter_id, OnOff::Off)]);
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::Id(3), tx_run).unwrap();
+    fixture.env.policy().deny(&User::Id(3), Access::Read(getter_id_2.clone()));
+    fixture.add_getters(&[&getter_id_2], ChannelKind::LightOn);
+    fixture.inject(&[(&getter_id_2, OnOff::On)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].conditions[0].getters.is_empty());
+
+    println!("* ... while the getters that the owner may read still trigger the rule.");
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id_1.clone(), Value::OnOff(OnOff::Off)));
+    rx_sent.recv().unwrap();
+}
+
+#[test]
+fn test_limits() {
+    use foxbox_thinkerbell::compile::{ Compiler, Limits };
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "getter 1"}, {"id": "getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 1
+        }],
+        "execute": [{
+          "destination": [{"id": "setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "Off"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "setter 1"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    println!("* By default, there is no limit.");
+    Compiler::<FakeEnv>::new().unwrap().compile(Script::from_str(source).unwrap()).unwrap();
+
+    println!("* Each limit that is exceeded is reported where it is exceeded.");
+    let limits = Limits {
+        max_rules: 1,
+        max_selectors: 1,
+        min_duration: Some(Duration::from(ChronoDuration::seconds(60))),
+        ..Limits::default()
+    };
+    match Compiler::<FakeEnv>::new().unwrap().with_limits(limits).compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) => {
+            for diagnostic in diagnostics {
+                let is_expected = match diagnostic.error {
+                    CompileError::SourceError(SourceError::TooManyRules { max: 1 }) =>
+                        diagnostic.location.to_string() == "rules",
+                    CompileError::SourceError(SourceError::TooManySelectors { max: 1 }) =>
+                        diagnostic.location.to_string() == "rules[0].conditions[0].source",
+                    CompileError::SourceError(SourceError::DurationTooShort { .. }) =>
+                        diagnostic.location.to_string() == "rules[0].conditions[0].duration",
+                    _ => false
+                };
+                assert!(is_expected, "Unexpected diagnostic {:?}", diagnostic);
+            }
+            assert_eq!(diagnostics.len(), 3);
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Watches are counted across rules.");
+    let limits = Limits {
+        max_watches: 1,
+        ..Limits::default()
+    };
+    match Compiler::<FakeEnv>::new().unwrap().with_limits(limits).compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) if diagnostics.len() == 1 => match diagnostics[0].error {
+            CompileError::SourceError(SourceError::TooManyWatches { max: 1 }) => {},
+            ref other => panic!("Unexpected error {:?}", other)
+        },
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Statements executed on errors and sources of derived getters are counted too.");
+    let source = r#"{
+      "name": "foo",
+      "derived": [{
+        "id": "all lights on",
+        "kind": "LightOn",
+        "source": [{"id": "getter 1"}, {"id": "getter 2"}],
+        "source_kind": "LightOn",
+        "aggregate": {"All": {"Eq": {"OnOff": "On"}}}
+      }],
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn",
+          "on_error": {
+            "execute": [{
+              "destination": [{"id": "setter 2"}],
+              "value": {"OnOff": "Off"},
+              "kind": "LightOn"
+            }]
+          }
+        }]
+      }]
+    }"#;
+    let limits = Limits {
+        max_statements_per_rule: 1,
+        max_selectors: 1,
+        ..Limits::default()
+    };
+    match Compiler::<FakeEnv>::new().unwrap().with_limits(limits).compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) => {
+            for diagnostic in diagnostics {
+                let is_expected = match diagnostic.error {
+                    CompileError::SourceError(SourceError::TooManyStatements { max: 1 }) =>
+                        diagnostic.location.to_string() == "rules[0].execute",
+                    CompileError::SourceError(SourceError::TooManySelectors { max: 1 }) =>
+                        diagnostic.location.to_string() == "derived[0].source",
+                    _ => false
+                };
+                assert!(is_expected, "Unexpected diagnostic {:?}", diagnostic);
+            }
+            assert_eq!(diagnostics.len(), 2);
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Retry policies are limited in attempts and delay.");
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn",
+          "on_error": {
+            "retry": {"attempts": 100, "delay": 7200}
+          }
+        }]
+      }]
+    }"#;
+    let limits = Limits {
+        max_retries: 10,
+        max_retry_delay: Some(Duration::from(ChronoDuration::hours(1))),
+        ..Limits::default()
+    };
+    match Compiler::<FakeEnv>::new().unwrap().with_limits(limits).compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) => {
+            for diagnostic in diagnostics {
+                let is_expected = match diagnostic.error {
+                    CompileError::SourceError(SourceError::TooManyRetries { max: 10 }) =>
+                        diagnostic.location.to_string() == "rules[0].execute[0].on_error.retry.attempts",
+                    CompileError::SourceError(SourceError::RetryDelayTooLong { .. }) =>
+                        diagnostic.location.to_string() == "rules[0].execute[0].on_error.retry.delay",
+                    _ => false
+                };
+                assert!(is_expected, "Unexpected diagnostic {:?}", diagnostic);
+            }
+            assert_eq!(diagnostics.len(), 2);
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_shared_watches() {
+    use foxbox_thinkerbell::compile::{ group_watches, Compiler };
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}, {"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "Getter 2"}, {"id": "Getter 1"}, {"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }, {
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 2"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    println!("* Identical matches share a single watch, regardless of the order of their selectors.");
+    let script = Compiler::<FakeEnv>::new().unwrap().compile(Script::from_str(source).unwrap()).unwrap();
+    let watches = group_watches(&script);
+    assert_eq!(watches.len(), 2);
+    assert_eq!(watches[0].members, vec![(0, 0), (1, 0)]);
+    assert_eq!(watches[1].members, vec![(1, 1)]);
+
+    println!("* Events on a shared watch reach every match.");
+    let fixture = Fixture::new();
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_ids = vec![Id::<Setter>::new("Setter 1"), Id::<Setter>::new("Setter 2")];
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_ids[0], &setter_ids[1]], ChannelKind::LightOn);
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    let mut sent : Vec<_> = (0..2).map(|_| fixture.rx_send.recv().unwrap().0).collect();
+    sent.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+    assert_eq!(sent, setter_ids);
+}
+
+#[test]
+fn test_network() {
+    use foxbox_thinkerbell::compile::{ group_watches, Compiler };
+    use foxbox_thinkerbell::network::Network;
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "door"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }, {
+          "source": [{"id": "window"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "alarm"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "window"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }, {
+          "source": [{"id": "door"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "light"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "window"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "heater"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+    let script = Compiler::<FakeEnv>::new().unwrap().compile(Script::from_str(source).unwrap()).unwrap();
+    let mut network = Network::new(&script, &group_watches(&script));
+    let door = Id::<Getter>::new("door");
+    let window = Id::<Getter>::new("window");
+
+    println!("* Only the rules whose matches change are reported.");
+    assert_eq!(network.update(0, 0, door.clone(), true), Vec::<usize>::new());
+    assert!(!network.matches_are_met(0));
+    assert_eq!(network.update(2, 0, window.clone(), true), vec![0, 1, 2]);
+    assert!(network.matches_are_met(0) && network.matches_are_met(1) && network.matches_are_met(2));
+
+    println!("* Conditions sharing a watch share their state.");
+    assert_eq!(network.update(1, 0, window.clone(), true), Vec::<usize>::new());
+
+    println!("* Removing a getter resets all the conditions that depend on it.");
+    assert_eq!(network.remove_getter(&door), vec![0, 1]);
+    assert!(!network.matches_are_met(0) && !network.matches_are_met(1) && network.matches_are_met(2));
+    assert_eq!(network.remove_getter(&door), Vec::<usize>::new());
+
+    println!("* An event on a watch updates all the matches of the watch at once.");
+    assert_eq!(network.enter(0, &door), vec![0, 1]);
+    assert!(network.matches_are_met(0) && network.matches_are_met(1));
+    assert_eq!(network.exit(1, &window), vec![0, 1, 2]);
+    assert!(!network.matches_are_met(0) && !network.matches_are_met(1) && !network.matches_are_met(2));
+    assert_eq!(network.getters(1, 1), vec![door.clone()]);
+}
+
+#[test]
+fn test_compiler_passes() {
+    use foxbox_thinkerbell::compile::{ CompiledCtx, Compiler, CompilerPass, Limits };
+    use std::sync::Arc;
+
+    /// No rule may turn lights off unless a condition has held for some time.
+    struct RequireDuration;
+    impl CompilerPass<FakeEnv> for RequireDuration {
+        fn run_compiled(&self, script: Script<CompiledCtx<FakeEnv>>, diagnostics: &mut Vec<Diagnostic>)
+            -> Script<CompiledCtx<FakeEnv>>
+        {
+            for (rule, rule_index) in script.rules.iter().zip(0..) {
+                if rule.conditions.iter().any(|condition| condition.duration.is_some()) {
+                    continue;
+                }
+                for (statement, statement_index) in rule.execute.iter().zip(0..) {
+                    if let Statement::Send(ref send) = *statement {
+                        if send.kind == ChannelKind::LightOn && send.value == Value::OnOff(OnOff::Off) {
+                            diagnostics.push(Diagnostic::error(
+                                Location::new().field("rules").index(rule_index).field("execute").index(statement_index),
+                                CompileError::PassError("Lights may only be turned off after a delay".to_owned())));
+                        }
+                    }
+                }
+            }
+            script
+        }
+    }
+
+    /// Add a default duration to conditions.
+    struct DefaultDuration;
+    impl CompilerPass<FakeEnv> for DefaultDuration {
+        fn run_unchecked(&self, mut script: Script<UncheckedCtx>, _: &mut Vec<Diagnostic>) -> Script<UncheckedCtx> {
+            for rule in &mut script.rules {
+                for condition in &mut rule.conditions {
+                    if condition.duration.is_none() {
+                        condition.duration = Some(Duration::from(ChronoDuration::seconds(60)));
+                    }
+                }
+            }
+            script
+        }
+    }
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    println!("* A custom pass may reject a script.");
+    let compiler = Compiler::<FakeEnv>::new().unwrap().with_pass(Arc::new(RequireDuration));
+    match compiler.compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) if diagnostics.len() == 1 => {
+            assert_eq!(diagnostics[0].location.to_string(), "rules[0].execute[0]");
+            match diagnostics[0].error {
+                CompileError::PassError(_) => {},
+                ref other => panic!("Unexpected error {:?}", other)
+            }
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* A custom pass may rewrite a script before the passes registered after it.");
+    let compiler = Compiler::<FakeEnv>::new().unwrap()
+        .with_pass(Arc::new(DefaultDuration))
+        .with_pass(Arc::new(RequireDuration));
+    let script = compiler.compile(Script::from_str(source).unwrap()).unwrap();
+    assert!(script.rules[0].conditions[0].duration.is_some());
+
+    /// Shorten the durations of conditions, after the built-in checks.
+    struct ShortDuration;
+    impl CompilerPass<FakeEnv> for ShortDuration {
+        fn run_compiled(&self, mut script: Script<CompiledCtx<FakeEnv>>, _: &mut Vec<Diagnostic>)
+            -> Script<CompiledCtx<FakeEnv>>
+        {
+            for rule in &mut script.rules {
+                for condition in &mut rule.conditions {
+                    condition.duration = Some(Duration::from(ChronoDuration::seconds(1)));
+                }
+            }
+            script
+        }
+    }
+
+    println!("* A script rewritten by a custom pass is checked again.");
+    let compiler = Compiler::<FakeEnv>::new().unwrap()
+        .with_limits(Limits {
+            min_duration: Some(Duration::from(ChronoDuration::seconds(60))),
+            ..Limits::default()
+        })
+        .with_pass(Arc::new(ShortDuration));
+    match compiler.compile(Script::from_str(source).unwrap()) {
+        Err(ref diagnostics) if diagnostics.len() == 1 => {
+            assert_eq!(diagnostics[0].location.to_string(), "rules[0].conditions[0].duration");
+            match diagnostics[0].error {
+                CompileError::SourceError(SourceError::DurationTooShort { .. }) => {},
+                ref other => panic!("Unexpected error {:?}", other)
+            }
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_run_with_independent_timers() {
+    let fixture = Fixture::new();
+    let (tx_timer, rx_timer) = channel();
+    let tx_run = listen(move |event| match event {
+        ExecutionEvent::TimerStart { id, .. } => tx_timer.send((true, id)).unwrap(),
+        ExecutionEvent::TimerCancel { id, .. } => tx_timer.send((false, id)).unwrap(),
+        _ => {}
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}, {"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 10
+        }, {
+          "source": [{"id": "Getter 3"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 20
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let getter_id_3 = Id::<Getter>::new("Getter 3");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_2, &getter_id_3], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+
+    let rx_done = &fixture.rx_done;
+    let rx_send = &fixture.rx_send;
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+    sleep(rx_done, rx_send, &rx_timer);
+
+    println!("* Conditions with different durations each have their own timer.");
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_3, OnOff::On)]);
+    let mut started : Vec<_> = (0..2).map(|_| rx_timer.recv().unwrap()).collect();
+    started.sort_by(|a, b| a.1.to_string().cmp(&b.1.to_string()));
+    assert_eq!(started, vec![(true, getter_id_1.clone()), (true, getter_id_3.clone())]);
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(15))));
+    sleep(rx_done, rx_send, &rx_timer);
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(25))));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    sleep(rx_done, rx_send, &rx_timer);
+
+    println!("* A getter leaving the range only cancels its own timer.");
+    fixture.execute(Instruction::ResetTimers);
+    fixture.inject(&[(&getter_id_1, OnOff::Off), (&getter_id_3, OnOff::Off)]);
+    sleep(rx_done, rx_send, &rx_timer);
+
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_2, OnOff::On), (&getter_id_3, OnOff::On)]);
+    for _ in 0..3 {
+        assert!(rx_timer.recv().unwrap().0);
+    }
+
+    fixture.inject(&[(&getter_id_1, OnOff::Off)]);
+    assert_eq!(rx_timer.recv().unwrap(), (false, getter_id_1.clone()));
+    sleep(rx_done, rx_send, &rx_timer);
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(25))));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    sleep(rx_done, rx_send, &rx_timer);
+
+    println!("* Removing a getter cancels its timers.");
+    fixture.execute(Instruction::ResetTimers);
+    fixture.inject(&[(&getter_id_2, OnOff::Off), (&getter_id_3, OnOff::Off)]);
+    sleep(rx_done, rx_send, &rx_timer);
+
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_3, OnOff::On)]);
+    for _ in 0..2 {
+        assert!(rx_timer.recv().unwrap().0);
+    }
+
+    fixture.execute(Instruction::RemoveGetters(vec![
+        getter_id_3.clone()
+    ]));
+    assert_eq!(rx_timer.recv().unwrap(), (false, getter_id_3.clone()));
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(25))));
+    sleep(rx_done, rx_send, &rx_timer);
+}
+
+#[test]
+fn test_shared_executor() {
+    let fixture = Fixture::new();
+    let (tx_started, rx_started) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::Starting { result } = event {
+            tx_started.send(result).unwrap();
+        }
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+
+    println!("* Many scripts can run on a few threads.");
+    let executor = Executor::new(2);
+    assert_eq!(executor.threads(), 2);
+    let mut executions : Vec<_> = (0..10).map(|_| {
+        let mut exec = Execution::<FakeEnv>::with_executor(executor.clone());
+        exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run.clone()).unwrap();
+        assert!(rx_started.recv().unwrap().is_ok());
+        exec
+    }).collect();
+
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    for _ in 0..10 {
+        assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    }
+
+    println!("* Starting a script twice on a shared executor still fails.");
+    let result = executions[0].start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run.clone());
+    match result {
+        Err(Error::StartStopError(StartStopError::AlreadyRunning)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    rx_started.recv().unwrap().unwrap_err();
+
+    println!("* Stopping scripts does not affect the other scripts on the same executor.");
+    for exec in executions.iter_mut().skip(5) {
+        stop(exec);
+    }
+
+    fixture.inject(&[(&getter_id, OnOff::Off)]);
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    for _ in 0..5 {
+        assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    }
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_dispatcher() {
+    use foxbox_thinkerbell::network::{ Dependent, Dispatcher };
+
+    let fixture = Fixture::new();
+    let (tx_started, rx_started) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::Starting { result } = event {
+            tx_started.send(result).unwrap();
+        }
+    });
+
+    let source = |name: &str, setter: &str, value: &str| format!(r#"{{
+      "name": "{}",
+      "rules": [{{
+        "conditions": [{{
+          "source": [{{"id": "Getter 1"}}],
+          "kind": "LightOn",
+          "range": {{"Eq": {{"OnOff": "On"}}}}
+        }}],
+        "execute": [{{
+          "destination": [{{"id": "{}"}}],
+          "value": {{"OnOff": "{}"}},
+          "kind": "LightOn"
+        }}]
+      }}]
+    }}"#, name, setter, value);
+    let dependent = |script: &str| Dependent {
+        script: script.to_owned(),
+        rule_index: 0,
+        condition_index: 0,
+    };
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1, &setter_id_2], ChannelKind::LightOn);
+
+    let executor = Executor::default();
+    let dispatcher = Dispatcher::new(fixture.env.clone());
+    let start = |name: &str, setter: &str, value: &str| {
+        let mut exec = Execution::<FakeEnv>::with_dispatcher(executor.clone(), dispatcher.clone());
+        exec.start(fixture.env.clone(), Script::from_str(&source(name, setter, value)).unwrap(), User::None, tx_run.clone()).unwrap();
+        assert!(rx_started.recv().unwrap().is_ok());
+        exec
+    };
+
+    println!("* Getters are indexed to the conditions of the scripts that depend on them.");
+    let mut foo = start("foo", "Setter 1", "Off");
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id_1.clone(), Value::OnOff(OnOff::Off)));
+    assert_eq!(dispatcher.dependents(&getter_id), vec![dependent("foo")]);
+
+    println!("* A script sharing a watch learns the getters already in range.");
+    let _bar = start("bar", "Setter 2", "On");
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id_2.clone(), Value::OnOff(OnOff::On)));
+    assert_eq!(dispatcher.dependents(&getter_id), vec![dependent("bar"), dependent("foo")]);
+
+    println!("* Stopping a script does not stop the watch of the other scripts.");
+    stop(&mut foo);
+    assert_eq!(dispatcher.dependents(&getter_id), vec![dependent("bar")]);
+    fixture.inject(&[(&getter_id, OnOff::Off)]);
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id_2.clone(), Value::OnOff(OnOff::On)));
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+
+    println!("* Removed getters are removed from the index.");
+    fixture.execute(Instruction::RemoveGetters(vec![getter_id.clone()]));
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(dispatcher.dependents(&getter_id), Vec::<Dependent>::new());
+}
+
+#[test]
+fn test_snapshot() {
+    let fixture = Fixture::new();
+    let (tx_timer, rx_timer) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::TimerStart { .. } = event {
+            tx_timer.send(()).unwrap();
+        }
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}, {"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 10
+        }, {
+          "source": [{"id": "Getter 3"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_3 = Id::<Getter>::new("Getter 3");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_3], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+
+    println!("* A script that is not running cannot be inspected.");
+    let mut exec = Execution::<FakeEnv>::new();
+    match exec.snapshot() {
+        Err(Error::StartStopError(StartStopError::NotRunning)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Initially, nothing is met.");
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+    let snapshot = exec.snapshot().unwrap();
+    assert_eq!(snapshot.rules.len(), 1);
+    assert!(!snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].conditions[0].getters.is_empty());
+    assert!(snapshot.rules[0].conditions[1].getters.is_empty());
+    assert!(snapshot.rules[0].timers.is_empty());
+    assert!(snapshot.rules[0].last_fired.is_none());
+
+    println!("* Getters waiting for a duration appear as pending timers.");
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_3, OnOff::On)]);
+    rx_timer.recv().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].conditions[0].getters.is_empty());
+    assert_eq!(snapshot.rules[0].conditions[1].getters, vec![getter_id_3.clone()]);
+    assert_eq!(snapshot.rules[0].timers.len(), 1);
+    assert_eq!(snapshot.rules[0].timers[0].condition_index, 0);
+    assert_eq!(snapshot.rules[0].timers[0].getter, getter_id_1);
+    assert!(snapshot.rules[0].timers[0].deadline > TimeStamp::from(UTC::now() + ChronoDuration::seconds(5)));
+
+    println!("* Once the timer has expired, the rule is met and has fired.");
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(15))));
+    assert_eq!(fixture.rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    let snapshot = exec.snapshot().unwrap();
+    assert!(snapshot.rules[0].is_met);
+    assert_eq!(snapshot.rules[0].conditions[0].getters, vec![getter_id_1.clone()]);
+    assert!(snapshot.rules[0].timers.is_empty());
+    assert!(snapshot.rules[0].last_fired.is_some());
+}
+
+#[test]
+fn test_pause_resume() {
+    let fixture = Fixture::new();
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+
+    let toggle = |value: OnOff| fixture.inject(&[(&getter_id, value)]);
+    let rx_send = &fixture.rx_send;
+
+    let mut exec = Execution::<FakeEnv>::new();
+    let (tx_run, _rx_run) = channel();
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+
+    println!("* A paused script tracks its rules but does not fire them.");
+    exec.pause().unwrap();
+    toggle(OnOff::On);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(snapshot.is_paused);
+    assert!(snapshot.rules[0].is_met);
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Resuming with `Skip` does not fire rules that are already met.");
+    exec.resume(ResumePolicy::Skip).unwrap();
+    assert!(!exec.snapshot().unwrap().is_paused);
+    rx_send.try_recv().unwrap_err();
+
+    toggle(OnOff::Off);
+    toggle(OnOff::On);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+
+    println!("* Resuming with `Fire` fires rules that have become met while paused.");
+    toggle(OnOff::Off);
+    exec.pause().unwrap();
+    toggle(OnOff::On);
+    exec.snapshot().unwrap();
+    rx_send.try_recv().unwrap_err();
+    exec.resume(ResumePolicy::Fire).unwrap();
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+
+    println!("* ... but not rules that are not met anymore.");
+    toggle(OnOff::Off);
+    exec.pause().unwrap();
+    toggle(OnOff::On);
+    toggle(OnOff::Off);
+    exec.resume(ResumePolicy::Fire).unwrap();
+    exec.snapshot().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* ... nor rules that were already met before the pause.");
+    toggle(OnOff::On);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    exec.pause().unwrap();
+    exec.resume(ResumePolicy::Fire).unwrap();
+    exec.snapshot().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_reload() {
+    let fixture = Fixture::new();
+    let (tx_timer, rx_timer) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::TimerStart { .. } = event {
+            tx_timer.send(()).unwrap();
+        }
+    });
+
+    let rule_timer = r#"{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 10
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }"#;
+    let rule_immediate = |value: &str| format!(r#"{{
+        "conditions": [{{
+          "source": [{{"id": "Getter 2"}}],
+          "kind": "LightOn",
+          "range": {{"Eq": {{"OnOff": "On"}}}}
+        }}],
+        "execute": [{{
+          "destination": [{{"id": "Setter 1"}}],
+          "value": {{"OnOff": "{}"}},
+          "kind": "LightOn"
+        }}]
+      }}"#, value);
+    let script = |rules: Vec<String>| {
+        Script::from_str(&format!(r#"{{"name": "foo", "rules": [{}]}}"#, rules.join(","))).unwrap()
+    };
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    let rx_send = &fixture.rx_send;
+
+    println!("* A script that is not running cannot be reloaded.");
+    let mut exec = Execution::<FakeEnv>::new();
+    match exec.reload(script(vec![rule_timer.to_owned()]), User::None) {
+        Err(Error::StartStopError(StartStopError::NotRunning)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    exec.start(fixture.env.clone(), script(vec![rule_timer.to_owned(), rule_immediate("On")]), User::None, tx_run).unwrap();
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_2, OnOff::On)]);
+    rx_timer.recv().unwrap();
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+
+    println!("* Reloading the same script changes nothing.");
+    let diff = exec.reload(script(vec![rule_timer.to_owned(), rule_immediate("On")]), User::None).unwrap();
+    assert!(diff.is_unchanged());
+
+    println!("* A script that does not compile does not replace the running script.");
+    match exec.reload(script(vec![]), User::None) {
+        Err(Error::CompileError(_)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(exec.snapshot().unwrap().rules.len(), 2);
+
+    println!("* A script that declares different variables cannot be reloaded.");
+    let source = format!(r#"{{
+        "name": "foo",
+        "variables": [{{"name": "count", "initial": {{"Counter": 0}}}}],
+        "rules": [{}]
+    }}"#, rule_timer);
+    match exec.reload(Script::from_str(&source).unwrap(), User::None) {
+        Err(Error::StartStopError(StartStopError::CannotReload)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(exec.snapshot().unwrap().rules.len(), 2);
+
+    println!("* Unchanged rules keep their state, even if they move. New rules start without firing.");
+    let diff = exec.reload(script(vec![rule_immediate("Off"), rule_timer.to_owned()]), User::None).unwrap();
+    assert_eq!(diff.kept, vec![None, Some(0)]);
+    assert_eq!(diff.removed, vec![1]);
+    let snapshot = exec.snapshot().unwrap();
+    assert_eq!(snapshot.rules.len(), 2);
+    assert!(snapshot.rules[0].is_met);
+    assert_eq!(snapshot.rules[0].conditions[0].getters, vec![getter_id_2.clone()]);
+    assert!(snapshot.rules[0].last_fired.is_none());
+    assert!(!snapshot.rules[1].is_met);
+    assert_eq!(snapshot.rules[1].timers.len(), 1);
+    assert_eq!(snapshot.rules[1].timers[0].getter, getter_id_1);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* The timers of unchanged rules still fire.");
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(15))));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    assert!(exec.snapshot().unwrap().rules[1].is_met);
+    fixture.execute(Instruction::ResetTimers);
+
+    println!("* New rules fire once their conditions change.");
+    fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+    fixture.inject(&[(&getter_id_2, OnOff::On)]);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+
+    println!("* New rules on a watch that is kept start with the getters in range, even with another duration.");
+    let rule_delayed = rule_timer.replace("\"duration\": 10", "\"duration\": 20");
+    let rule_undelayed = rule_timer.replace(",\n          \"duration\": 10", "");
+    let rules = vec![rule_immediate("Off"), rule_timer.to_owned(), rule_delayed, rule_undelayed];
+    let diff = exec.reload(script(rules.clone()), User::None).unwrap();
+    assert_eq!(diff.kept, vec![Some(0), Some(1), None, None]);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[2].is_met);
+    assert_eq!(snapshot.rules[2].timers.len(), 1);
+    assert_eq!(snapshot.rules[2].timers[0].getter, getter_id_1);
+    assert!(snapshot.rules[3].is_met);
+    assert_eq!(snapshot.rules[3].conditions[0].getters, vec![getter_id_1.clone()]);
+    assert!(snapshot.rules[3].last_fired.is_none());
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(25))));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    assert!(exec.snapshot().unwrap().rules[2].is_met);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* Kept rules are evaluated again if the script becomes active at other times.");
+    let source = format!(r#"{{"name": "foo", "active_when": [{{"Mode": "Away"}}], "rules": [{}]}}"#, rules.join(","));
+    let diff = exec.reload(Script::from_str(&source).unwrap(), User::None).unwrap();
+    assert!(diff.active_when_has_changed);
+    assert_eq!(diff.kept, vec![Some(0), Some(1), Some(2), Some(3)]);
+    assert!(exec.snapshot().unwrap().rules.iter().all(|rule| !rule.is_met));
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* As when the mode changes, kept rules that become met fire.");
+    let diff = exec.reload(script(rules), User::None).unwrap();
+    assert!(diff.active_when_has_changed);
+    assert!(exec.snapshot().unwrap().rules.iter().all(|rule| rule.is_met));
+    for _ in 0..4 {
+        assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    }
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_fire_rule() {
+    let fixture = Fixture::new();
+    let (tx_sent, rx_sent) = channel();
+    let tx_run = listen(move |event| {
+        if let ExecutionEvent::Sent { rule_index, statement_index, is_manual, .. } = event {
+            tx_sent.send((rule_index, statement_index, is_manual)).unwrap();
+        }
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }, {
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    let rx_send = &fixture.rx_send;
+
+    println!("* A script that is not running cannot be fired.");
+    let mut exec = Execution::<FakeEnv>::new();
+    match exec.fire_rule(0) {
+        Err(Error::StartStopError(StartStopError::NotRunning)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    exec.start(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, tx_run).unwrap();
+
+    println!("* Firing a rule executes all its statements, regardless of its conditions.");
+    exec.fire_rule(0).unwrap();
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+    assert_eq!(rx_sent.recv().unwrap(), (0, 0, true));
+    assert_eq!(rx_sent.recv().unwrap(), (0, 1, true));
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].last_fired.is_none());
+
+    println!("* Statements can be fired one at a time, even if the script is paused.");
+    exec.pause().unwrap();
+    exec.fire_statement(0, 1).unwrap();
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+    assert_eq!(rx_sent.recv().unwrap(), (0, 1, true));
+    exec.resume(ResumePolicy::Skip).unwrap();
+
+    println!("* Unknown rules and statements are rejected.");
+    match exec.fire_rule(1) {
+        Err(Error::NoSuchRule(1)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match exec.fire_statement(0, 2) {
+        Err(Error::NoSuchStatement(0, 2)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Rules that fire by themselves are not marked as manual.");
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    assert_eq!(rx_sent.recv().unwrap(), (0, 0, false));
+    assert_eq!(rx_sent.recv().unwrap(), (0, 1, false));
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_sent.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_shadow() {
+    let fixture = Fixture::new();
+    let (tx_would_send, rx_would_send) = channel();
+    let tx_run = listen(move |event| match event {
+        ExecutionEvent::Sent { .. } => panic!("Shadow scripts do not send values"),
+        ExecutionEvent::WouldSend { rule_index, setters, value, is_manual, .. } =>
+            tx_would_send.send((rule_index, setters, value, is_manual)).unwrap(),
+        _ => {}
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"kind": "LightOn"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id_1 = Id::<Setter>::new("Setter 1");
+    let setter_id_2 = Id::<Setter>::new("Setter 2");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id_1, &setter_id_2], ChannelKind::LightOn);
+
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start_with_options(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, Options {
+        run_mode: RunMode::Shadow,
+        ..Options::default()
+    }, tx_run).unwrap();
+
+    println!("* Shadow scripts report the setters they would send values to, instead of sending.");
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+    let (rule_index, mut setters, value, is_manual) = rx_would_send.recv().unwrap();
+    setters.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
+    assert_eq!(rule_index, 0);
+    assert_eq!(setters, vec![setter_id_1.clone(), setter_id_2.clone()]);
+    assert_eq!(value, Value::OnOff(OnOff::Off));
+    assert!(!is_manual);
+    assert!(exec.snapshot().unwrap().rules[0].is_met);
+
+    println!("* ... including when rules are fired manually.");
+    exec.fire_rule(0).unwrap();
+    let (_, _, _, is_manual) = rx_would_send.recv().unwrap();
+    assert!(is_manual);
+
+    thread::sleep(std::time::Duration::from_millis(100));
+    fixture.rx_send.try_recv().unwrap_err();
+}
+
+#[test]
+fn test_checkpoint() {
+    let fixture = Fixture::new();
+    let (tx_timer, rx_timer) = channel();
+    let (tx_checkpoint, rx_checkpoint) = channel();
+    let tx_run = listen(move |event| match event {
+        ExecutionEvent::TimerStart { .. } => tx_timer.send(()).unwrap(),
+        ExecutionEvent::Checkpoint { snapshot } => tx_checkpoint.send(snapshot).unwrap(),
+        _ => {}
+    });
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}},
+          "duration": 10
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }, {
+        "conditions": [{
+          "source": [{"id": "Getter 2"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "On"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id_1 = Id::<Getter>::new("Getter 1");
+    let getter_id_2 = Id::<Getter>::new("Getter 2");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id_1, &getter_id_2], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    let rx_send = &fixture.rx_send;
+
+    let start = |checkpoint: Option<Snapshot>, policy: ExpiredTimerPolicy| {
+        let mut exec = Execution::<FakeEnv>::new();
+        exec.start_with_options(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, Options {
+            checkpoint: checkpoint,
+            expired_timers: policy,
+            checkpoints: true,
+            ..Options::default()
+        }, tx_run.clone()).unwrap();
+        exec
+    };
+
+    println!("* Running scripts report their state as checkpoints.");
+    let mut exec = start(None, ExpiredTimerPolicy::Fire);
+    fixture.inject(&[(&getter_id_1, OnOff::On), (&getter_id_2, OnOff::On)]);
+    rx_timer.recv().unwrap();
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+    let snapshot = exec.snapshot().unwrap();
+    thread::sleep(std::time::Duration::from_millis(100));
+    let mut checkpoint = None;
+    while let Ok(latest) = rx_checkpoint.try_recv() {
+        checkpoint = Some(latest);
+    }
+    let checkpoint = checkpoint.unwrap();
+    assert_eq!(checkpoint, snapshot);
+    assert!(checkpoint.rules[1].is_met);
+    assert_eq!(checkpoint.rules[0].timers.len(), 1);
+    stop(&mut exec);
+
+    println!("* Restored rules remain met without firing again, and timers resume where they stopped.");
+    let mut exec = start(Some(checkpoint.clone()), ExpiredTimerPolicy::Fire);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(snapshot.rules[1].is_met);
+    assert_eq!(snapshot.rules[1].conditions[0].getters, vec![getter_id_2.clone()]);
+    assert!(!snapshot.rules[0].is_met);
+    assert_eq!(snapshot.rules[0].timers, checkpoint.rules[0].timers);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    fixture.execute(Instruction::TriggerTimersUntil(TimeStamp::from(UTC::now() + ChronoDuration::seconds(15))));
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    stop(&mut exec);
+    fixture.execute(Instruction::ResetTimers);
+
+    let mut expired = checkpoint.clone();
+    expired.rules[0].timers[0].deadline = TimeStamp::from(UTC::now() - ChronoDuration::seconds(60));
+
+    println!("* Expired timers may be discarded...");
+    let mut exec = start(Some(expired.clone()), ExpiredTimerPolicy::Discard);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].timers.is_empty());
+    stop(&mut exec);
+
+    println!("* ... or considered complete, without firing...");
+    let mut exec = start(Some(expired.clone()), ExpiredTimerPolicy::Arm);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(snapshot.rules[0].is_met);
+    assert!(snapshot.rules[0].timers.is_empty());
+    stop(&mut exec);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_send.try_recv().unwrap_err();
+
+    println!("* ... or fire as if they had just expired.");
+    let mut exec = start(Some(expired.clone()), ExpiredTimerPolicy::Fire);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    assert!(exec.snapshot().unwrap().rules[0].is_met);
+    stop(&mut exec);
+
+    println!("* Checkpoints that do not match the script are ignored.");
+    let mut mismatched = checkpoint.clone();
+    mismatched.rules.pop();
+    let mut exec = start(Some(mismatched), ExpiredTimerPolicy::Fire);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[1].is_met);
+    assert!(snapshot.rules[0].timers.is_empty());
+    stop(&mut exec);
+
+    println!("* ... as are checkpoints of another version of the script.");
+    let mut other_version = checkpoint.clone();
+    other_version.version = Some("other".to_owned());
+    let mut exec = start(Some(other_version), ExpiredTimerPolicy::Fire);
+    let snapshot = exec.snapshot().unwrap();
+    assert!(!snapshot.rules[1].is_met);
+    assert!(snapshot.rules[0].timers.is_empty());
+    stop(&mut exec);
+
+    println!("* Checkpoints are at least `checkpoint_interval` apart. Pending changes are reported when the script stops.");
+    fixture.inject(&[(&getter_id_2, OnOff::Off)]);
+    thread::sleep(std::time::Duration::from_millis(100));
+    while let Ok(_) = rx_checkpoint.try_recv() {}
+    let mut exec = Execution::<FakeEnv>::new();
+    exec.start_with_options(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, Options {
+        checkpoints: true,
+        checkpoint_interval: std::time::Duration::from_secs(3600),
+        ..Options::default()
+    }, tx_run.clone()).unwrap();
+    fixture.inject(&[(&getter_id_2, OnOff::On)]);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::On)));
+    assert!(!rx_checkpoint.recv().unwrap().rules[1].is_met);
+    thread::sleep(std::time::Duration::from_millis(100));
+    rx_checkpoint.try_recv().unwrap_err();
+    stop(&mut exec);
+    assert!(rx_checkpoint.recv().unwrap().rules[1].is_met);
+}
+
+#[test]
+fn test_seed() {
+    let fixture = Fixture::new();
+
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [{
+          "source": [{"id": "Getter 1"}],
+          "kind": "LightOn",
+          "range": {"Eq": {"OnOff": "On"}}
+        }],
+        "execute": [{
+          "destination": [{"id": "Setter 1"}],
+          "value": {"OnOff": "Off"},
+          "kind": "LightOn"
+        }]
+      }]
+    }"#;
+
+    let getter_id = Id::<Getter>::new("Getter 1");
+    let setter_id = Id::<Setter>::new("Setter 1");
+    fixture.add_getters(&[&getter_id], ChannelKind::LightOn);
+    fixture.add_setters(&[&setter_id], ChannelKind::LightOn);
+    let rx_send = &fixture.rx_send;
+
+    // The light is already on when the scripts start.
+    fixture.inject(&[(&getter_id, OnOff::On)]);
+
+    let start = |seed| {
+        let (tx_run, _) = channel();
+        let mut exec = Execution::<FakeEnv>::new();
+        exec.start_with_options(fixture.env.clone(), Script::from_str(source).unwrap(), User::None, Options {
+            seed: seed,
+            ..Options::default()
+        }, tx_run).unwrap();
+        thread::sleep(std::time::Duration::from_millis(100));
+        exec
+    };
+
+    println!("* By default, scripts only react to changes.");
+    let mut exec = start(SeedPolicy::Ignore);
+    assert!(!exec.snapshot().unwrap().rules[0].is_met);
+    rx_send.try_recv().unwrap_err();
+    stop(&mut exec);
+
+    println!("* Scripts may consider the current values without firing.");
+    let mut exec = start(SeedPolicy::Arm);
+    assert!(exec.snapshot().unwrap().rules[0].is_met);
+    rx_send.try_recv().unwrap_err();
+    stop(&mut exec);
+
+    println!("* Scripts may fire the rules that are met at start.");
+    let mut exec = start(SeedPolicy::Fire);
+    assert_eq!(rx_send.recv().unwrap(), (setter_id.clone(), Value::OnOff(OnOff::Off)));
+    assert!(exec.snapshot().unwrap().rules[0].is_met);
+    stop(&mut exec);
+}
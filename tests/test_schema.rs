@@ -0,0 +1,196 @@
+extern crate foxbox_taxonomy;
+extern crate foxbox_thinkerbell;
+extern crate serde_json;
+
+use foxbox_thinkerbell::ast::*;
+use foxbox_thinkerbell::schema::*;
+
+use foxbox_taxonomy::parse::*;
+
+use std::fs::{ read_dir, File };
+use std::io::Read;
+
+fn load_json(path: &str) -> String {
+    let mut file = File::open(path).unwrap();
+    let mut source = String::new();
+    file.read_to_string(&mut source).unwrap();
+    source
+}
+
+#[test]
+fn test_schema_accepts_fixtures() {
+    let schema = script_schema();
+    let mut paths = vec!["./examples/ruleset.json".to_owned()];
+    for entry in read_dir("./tests/fixtures").unwrap() {
+        paths.push(entry.unwrap().path().to_str().unwrap().to_owned());
+    }
+    for path in paths {
+        println!("* Checking {} against the parser and the schema.", path);
+        let source = load_json(&path);
+        Script::<UncheckedCtx>::from_str(&source).unwrap();
+        let json : JSON = serde_json::from_str(&source).unwrap();
+        if let Err(err) = validate(&schema, &json) {
+            panic!("{} is rejected by the schema: {}", path, err);
+        }
+    }
+}
+
+#[test]
+fn test_schema_rejects_invalid_scripts() {
+    let schema = script_schema();
+    let sources = vec![
+        // Missing rules.
+        r#"{"name": "foo"}"#,
+        // A statement without destination.
+        r#"{
+          "name": "foo",
+          "rules": [{
+            "conditions": [],
+            "execute": [{"value": {"OnOff": "On"}, "kind": "LightOn"}]
+          }]
+        }"#,
+        // An unknown aggregate.
+        r#"{
+          "name": "foo",
+          "derived": [{
+            "id": "bar",
+            "kind": "LightOn",
+            "source": [{"id": "Getter 1"}],
+            "source_kind": "LightOn",
+            "aggregate": "Median"
+          }],
+          "rules": []
+        }"#,
+    ];
+    for source in sources {
+        println!("* Checking that the parser and the schema both reject {}", source);
+        assert!(Script::<UncheckedCtx>::from_str(source).is_err());
+        let json : JSON = serde_json::from_str(source).unwrap();
+        assert!(validate(&schema, &json).is_err());
+    }
+}
+
+#[test]
+fn test_schema_and_parser_agree_on_fields() {
+    let schema = script_schema();
+    println!("* Unknown fields are ignored by the parser, but rejected by the schema.");
+    let source = r#"{
+      "name": "foo",
+      "rules": [{
+        "conditions": [],
+        "execute": [],
+        "comment": "not a field"
+      }]
+    }"#;
+    Script::<UncheckedCtx>::from_str(source).unwrap();
+    let json : JSON = serde_json::from_str(source).unwrap();
+    assert!(validate(&schema, &json).is_err());
+
+    let sources = vec![
+        // An unknown channel kind.
+        r#"{
+          "name": "foo",
+          "channels": [{"id": "bar", "kind": "NotAKind"}],
+          "rules": []
+        }"#,
+        // A malformed value.
+        r#"{
+          "name": "foo",
+          "rules": [{
+            "conditions": [],
+            "execute": [{
+              "destination": [{"id": "Setter 1"}],
+              "value": {"OnOff": "Maybe"},
+              "kind": "LightOn"
+            }]
+          }]
+        }"#,
+    ];
+    for source in sources {
+        println!("* Checking that the parser and the schema both reject {}", source);
+        assert!(Script::<UncheckedCtx>::from_str(source).is_err());
+        let json : JSON = serde_json::from_str(source).unwrap();
+        assert!(validate(&schema, &json).is_err());
+    }
+
+    println!("* Removing a field is rejected by the parser iff the field is required.");
+    fn check_required<T>(source: &str) where T: Fields + Parser<T> {
+        T::from_str(source).unwrap();
+        let json : JSON = serde_json::from_str(source).unwrap();
+        for field in T::fields() {
+            let mut json = json.clone();
+            json.as_object_mut().unwrap().remove(field.name);
+            let source = serde_json::to_string(&json).unwrap();
+            assert!(T::from_str(&source).is_err() == field.is_required, "{}.{}", T::description(), field.name);
+        }
+    }
+    check_required::<Script<UncheckedCtx>>(r#"{
+      "name": "foo",
+      "variables": [],
+      "channels": [],
+      "derived": [],
+      "active_when": [],
+      "rules": []
+    }"#);
+    check_required::<Rule<UncheckedCtx>>(r#"{
+      "conditions": [],
+      "variable_conditions": [],
+      "active_when": [],
+      "execute": []
+    }"#);
+    check_required::<Match<UncheckedCtx>>(r#"{
+      "source": [{"id": "Getter 1"}],
+      "kind": "LightOn",
+      "range": {"Eq": {"OnOff": "On"}},
+      "duration": 60
+    }"#);
+    check_required::<SendValues<UncheckedCtx>>(r#"{
+      "destination": [{"id": "Setter 1"}],
+      "value": {"OnOff": "Off"},
+      "kind": "LightOn",
+      "on_error": {}
+    }"#);
+    check_required::<ErrorPolicy<UncheckedCtx>>(r#"{
+      "retry": {"attempts": 2, "delay": 1},
+      "fallback": [{"id": "Setter 2"}],
+      "execute": []
+    }"#);
+    check_required::<Retry>(r#"{"attempts": 2, "delay": 1}"#);
+    check_required::<SetVariable>(r#"{"variable": "foo", "operation": "Reset"}"#);
+    check_required::<VariableDeclaration>(r#"{"name": "foo", "initial": {"Counter": 0}}"#);
+    check_required::<VariableMatch>(r#"{"variable": "foo", "range": {"Counter": {"min": 2}}}"#);
+    check_required::<VirtualChannel>(r#"{"id": "foo", "kind": "LightOn", "tags": []}"#);
+    check_required::<DerivedGetter<UncheckedCtx>>(r#"{
+      "id": "foo",
+      "kind": "LightOn",
+      "tags": [],
+      "source": [{"id": "Getter 1"}],
+      "source_kind": "LightOn",
+      "aggregate": {"All": {"Eq": {"OnOff": "On"}}}
+    }"#);
+    check_required::<TimeWindow>(r#"{"from": "22:00", "to": "07:00"}"#);
+}
+
+#[test]
+fn test_schema_uses_supported_keywords() {
+    fn check(schema: &JSON) {
+        for (keyword, value) in schema.as_object().unwrap() {
+            assert!(KEYWORDS.contains(&keyword.as_str()), "unsupported keyword {}", keyword);
+            match keyword.as_str() {
+                "definitions" | "properties" => for schema in value.as_object().unwrap().values() {
+                    check(schema);
+                },
+                "anyOf" => for schema in value.as_array().unwrap() {
+                    check(schema);
+                },
+                "items" => check(value),
+                _ => {}
+            }
+        }
+    }
+    check(&script_schema());
+
+    println!("* The validator rejects keywords that it does not support.");
+    let schema : JSON = serde_json::from_str(r#"{"type": "string", "pattern": "^[a-z]+$"}"#).unwrap();
+    assert!(validate(&schema, &JSON::String("foo".to_owned())).is_err());
+}